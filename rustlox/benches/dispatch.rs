@@ -0,0 +1,40 @@
+// Purpose: Criterion benchmarks for the scanner and the VM's dispatch loop.
+//
+// The Lox scripts next to this file measure whole programs; run them with
+// `rustlox bench`. These isolate the two hot loops so changes to them can
+// be compared with `cargo bench`.
+
+use criterion::black_box;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use rustlox::scanner::new_scanner;
+use rustlox::scanner::TokenType;
+use rustlox::Vm;
+
+fn scan(c: &mut Criterion) {
+    let source = include_str!("binary_trees.lox").repeat(20);
+    c.bench_function("scan binary_trees x20", |b| {
+        b.iter(|| {
+            let mut scanner = new_scanner(black_box(source.clone()));
+            let mut count = 0;
+            while scanner.scan_token().token_type != TokenType::EOF {
+                count += 1;
+            }
+            count
+        })
+    });
+}
+
+fn dispatch(c: &mut Criterion) {
+    // A loop of cheap instructions, so the time is mostly dispatch.
+    let source = "var sum = 0; for (var i = 0; i < 100000; i = i + 1) { sum = sum + i; }";
+    let mut vm = Vm::new();
+    let script = vm.compile(source).unwrap();
+    c.bench_function("dispatch loop 100k", |b| {
+        b.iter(|| vm.apply(black_box(script), &[]).unwrap())
+    });
+}
+
+criterion_group!(benches, scan, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+// Purpose: Fuzzes the scanner, compiler and optimizer with arbitrary
+// source, which must be rejected with compile errors rather than panics.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustlox::Vm;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+    let mut vm = Vm::builder().optimize(true).build();
+    vm.set_stderr(std::io::sink());
+    let _ = vm.compile(source);
+});
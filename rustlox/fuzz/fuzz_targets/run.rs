@@ -0,0 +1,91 @@
+// Purpose: Fuzzes the VM by running arbitrary source under tight limits.
+// Scripts may fail however they like, but not panic or crash.
+//
+// The VM gets no capabilities and a platform that can't read files or
+// stdin, so a script can't wait on input or leave the process.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustlox::platform::CommandOutput;
+use rustlox::platform::Platform;
+use rustlox::policy::Policy;
+use rustlox::Vm;
+
+#[derive(Debug)]
+struct FuzzPlatform;
+
+impl Platform for FuzzPlatform {
+    fn clock(&self) -> f64 {
+        0.0
+    }
+
+    // Fixed, so crashes reproduce.
+    fn random_seed(&self) -> u64 {
+        1
+    }
+
+    fn os_name(&self) -> &str {
+        "fuzz"
+    }
+
+    fn read_file(&self, path: &str) -> Result<String, String> {
+        Err(format!("no file '{}'", path))
+    }
+
+    fn write_file(&self, path: &str, _contents: &str) -> Result<(), String> {
+        Err(format!("can't write '{}'", path))
+    }
+
+    fn read_line(&self) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+
+    fn current_dir(&self) -> Result<String, String> {
+        Err("no working directory".to_string())
+    }
+
+    fn set_current_dir(&self, path: &str) -> Result<(), String> {
+        Err(format!("can't change to '{}'", path))
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn exit(&self, _code: i32) -> Result<(), String> {
+        Err("can't exit".to_string())
+    }
+
+    fn env_var(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn set_env_var(&self, name: &str, _value: &str) -> Result<(), String> {
+        Err(format!("can't set '{}'", name))
+    }
+
+    fn exec(&self, command: &str) -> Result<CommandOutput, String> {
+        Err(format!("can't run '{}'", command))
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks whether to run the optimized code.
+    let Some((&flags, source)) = data.split_first() else {
+        return;
+    };
+    let Ok(source) = std::str::from_utf8(source) else {
+        return;
+    };
+    let mut vm = Vm::builder()
+        .optimize(flags & 1 != 0)
+        .policy(Policy::pure())
+        .platform(FuzzPlatform)
+        .instruction_budget(100_000)
+        .heap_limit(16 << 20)
+        .build();
+    vm.set_stdout(std::io::sink());
+    vm.set_stderr(std::io::sink());
+    let _ = vm.interpret(source);
+});
@@ -0,0 +1,136 @@
+// Purpose: Pooled memory for heap objects.
+//
+// Every object type has a fixed size, so instead of asking the system
+// allocator for each object, the arena carves objects out of large blocks,
+// with one pool per slot size. The collector hands freed objects back to
+// their pool's free list, and the next object of that size reuses the
+// slot; the blocks themselves are only returned to the system with the
+// arena. Variable-length parts of objects, such as string characters and
+// list items, are still allocated on their own.
+
+use std::alloc::Layout;
+use std::mem::align_of;
+use std::mem::size_of;
+
+// Bytes in each block. Objects are at most a few hundred bytes, so a block
+// holds many of them.
+const BLOCK_SIZE: usize = 64 * 1024;
+// Slot sizes are multiples of this, which is also every slot's alignment.
+const SLOT_ALIGN: usize = 16;
+
+/// Fixed-size slots for objects, grouped by size.
+#[derive(Debug, Default)]
+pub struct Arena {
+    // pools[i] has slots of (i + 1) * SLOT_ALIGN bytes.
+    pools: Vec<Pool>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    blocks: Vec<*mut u8>,
+    // Freed slots, each holding a pointer to the next.
+    free: *mut FreeSlot,
+    // The part of the newest block that hasn't been handed out yet.
+    next: *mut u8,
+    end: *mut u8,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+impl Arena {
+    /// Allocates uninitialized memory for a `T`, which the caller must
+    /// write before using.
+    pub fn alloc<T>(&mut self) -> *mut T {
+        let class = size_class::<T>();
+        if class >= self.pools.len() {
+            self.pools.resize_with(class + 1, Pool::default);
+        }
+        self.pools[class].alloc((class + 1) * SLOT_ALIGN) as *mut T
+    }
+
+    /// Gives the slot of a `T` from [`Arena::alloc`] back to its pool.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from this arena's `alloc::<T>()` and not been
+    /// freed since, and its value must already have been dropped or moved
+    /// out. Nothing may use it afterwards.
+    pub unsafe fn free<T>(&mut self, ptr: *mut T) {
+        let slot = ptr as *mut FreeSlot;
+        let pool = &mut self.pools[size_class::<T>()];
+        unsafe { slot.write(FreeSlot { next: pool.free }) };
+        pool.free = slot;
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for pool in &self.pools {
+            for &block in &pool.blocks {
+                unsafe { std::alloc::dealloc(block, block_layout()) };
+            }
+        }
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Pool {
+        Pool {
+            blocks: Vec::new(),
+            free: std::ptr::null_mut(),
+            next: std::ptr::null_mut(),
+            end: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl Pool {
+    fn alloc(&mut self, size: usize) -> *mut u8 {
+        if !self.free.is_null() {
+            let slot = self.free;
+            self.free = unsafe { (*slot).next };
+            return slot as *mut u8;
+        }
+        if (self.end as usize) - (self.next as usize) < size {
+            // The rest of the current block is too small, and is left
+            // unused.
+            let block = unsafe { std::alloc::alloc(block_layout()) };
+            if block.is_null() {
+                std::alloc::handle_alloc_error(block_layout());
+            }
+            self.blocks.push(block);
+            self.next = block;
+            self.end = unsafe { block.add(BLOCK_SIZE) };
+        }
+        let slot = self.next;
+        self.next = unsafe { slot.add(size) };
+        slot
+    }
+}
+
+// The index of the pool for values of type T.
+fn size_class<T>() -> usize {
+    assert!(align_of::<T>() <= SLOT_ALIGN && size_of::<T>() <= BLOCK_SIZE);
+    size_of::<T>().max(1).div_ceil(SLOT_ALIGN) - 1
+}
+
+fn block_layout() -> Layout {
+    Layout::from_size_align(BLOCK_SIZE, SLOT_ALIGN).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut arena = Arena::default();
+        let first = arena.alloc::<[u64; 4]>();
+        unsafe { first.write([1, 2, 3, 4]) };
+        unsafe { arena.free(first) };
+        // A freed slot is the next one handed out for its size.
+        assert_eq!(arena.alloc::<[u64; 4]>(), first);
+    }
+}
@@ -0,0 +1,858 @@
+// Purpose: Parses Lox into a syntax tree, for the tree-walking engine in
+// treewalk.rs.
+//
+// The tree covers the language of the book as rustlox compiles it: classes
+// without inheritance, closures and the usual statements. Variables are
+// resolved while parsing, the way the compiler resolves them, so the tree
+// says which local slot, captured variable or global each name refers to.
+// Errors come out with the compiler's messages and lines. Syntax from the
+// extensions, such as lists, string interpolation or try blocks, is
+// reported as an error.
+
+use crate::error::Diagnostic;
+use crate::error::Severity;
+use crate::scanner::new_scanner;
+use crate::scanner::Scanner;
+use crate::scanner::Token;
+use crate::scanner::TokenType;
+use std::sync::Arc;
+
+// The same limits as the compiler's, for the same reason: deeper code is a
+// compile error rather than a Rust stack overflow.
+const MAX_NESTING: usize = 256;
+const MAX_FUNCTION_NESTING: usize = 32;
+
+const UNSUPPORTED: &str = "Not supported by the AST engine.";
+
+/// Where a variable lives, as resolved by the parser.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variable {
+    /// A slot of the running function. Slot 0 holds `this` in methods, and
+    /// the function itself otherwise; parameters come next.
+    Local(usize),
+    /// One of the running closure's captured variables.
+    Upvalue(usize),
+    Global(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+/// An expression. `line` is the line a runtime error in it is reported on,
+/// the same one the compiler gives the instruction that fails.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(Literal),
+    Variable { variable: Variable, line: usize },
+    Assign { variable: Variable, value: Box<Expr>, line: usize },
+    /// `-` or `!`.
+    Unary { operator: TokenType, operand: Box<Expr>, line: usize },
+    Binary { left: Box<Expr>, operator: TokenType, right: Box<Expr>, line: usize },
+    /// `and` or `or`, which only evaluate `right` when they need it.
+    Logical { left: Box<Expr>, operator: TokenType, right: Box<Expr> },
+    Call { callee: Box<Expr>, arguments: Vec<Expr>, line: usize },
+    /// A method call, `object.name(arguments)`, which doesn't create a
+    /// bound method.
+    Invoke { object: Box<Expr>, name: String, arguments: Vec<Expr>, line: usize },
+    Get { object: Box<Expr>, name: String, line: usize },
+    Set { object: Box<Expr>, name: String, value: Box<Expr>, line: usize },
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    /// Defines a global, or the next local slot.
+    Var { variable: Variable, initializer: Option<Expr> },
+    Block(Vec<Stmt>),
+    If { condition: Expr, then_branch: Box<Stmt>, else_branch: Option<Box<Stmt>> },
+    /// `for` loops are parsed into a block holding a while loop.
+    While { condition: Expr, body: Box<Stmt> },
+    Return(Option<Expr>),
+    /// Like `Var`, except that the slot is defined before the closure is
+    /// created, so that the function can capture itself.
+    Function { variable: Variable, function: Arc<Function> },
+    Class { variable: Variable, name: String, methods: Vec<Arc<Function>> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionKind {
+    Function,
+    Method,
+    /// An `init` method, which returns `this`.
+    Initializer,
+}
+
+/// A captured variable: a local slot of the enclosing function when
+/// `is_local`, or else one of the enclosing closure's own captures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capture {
+    pub is_local: bool,
+    pub index: usize,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub kind: FunctionKind,
+    pub arity: usize,
+    pub body: Vec<Stmt>,
+    /// What a closure of the function captures, in order.
+    pub captures: Vec<Capture>,
+    /// The lines of the declaration's name and closing brace.
+    pub line: usize,
+    pub end_line: usize,
+}
+
+/// Parses a script into its statements, or returns the compile errors in
+/// it.
+///
+/// ```
+/// use rustlox::vm::Engine;
+/// use rustlox::Vm;
+///
+/// let mut vm = Vm::builder().engine(Engine::Ast).build();
+/// let (_, stdout, _) = vm.interpret_captured("var a = 1; print a + 2;");
+/// assert_eq!(stdout, "3\n");
+///
+/// let error = vm.interpret("print 1 +;").unwrap_err();
+/// assert_eq!(error.diagnostics[0].message, "Error at ';': Expect expression.");
+/// ```
+pub fn parse(source: &str) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+    let mut parser = Parser {
+        scanner: new_scanner(source.to_string()),
+        current: Token::default(),
+        previous: Token::default(),
+        functions: vec![Scope::new(None)],
+        class_depth: 0,
+        diagnostics: Vec::new(),
+        panic_mode: false,
+        nesting: 0,
+    };
+    parser.advance();
+    let mut program = Vec::new();
+    while !parser.match_token(TokenType::EOF) {
+        program.push(parser.declaration());
+    }
+    if parser.diagnostics.is_empty() {
+        Ok(program)
+    } else {
+        Err(parser.diagnostics)
+    }
+}
+
+struct Parser {
+    scanner: Scanner,
+    current: Token,
+    previous: Token,
+    // The functions being parsed, innermost last. The first is the script.
+    functions: Vec<Scope>,
+    class_depth: usize,
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    nesting: usize,
+}
+
+// The variables of a function being parsed.
+struct Scope {
+    // None for the script.
+    kind: Option<FunctionKind>,
+    locals: Vec<Local>,
+    captures: Vec<Capture>,
+    depth: i32,
+}
+
+struct Local {
+    name: String,
+    // -1 until its initializer has been parsed.
+    depth: i32,
+}
+
+impl Scope {
+    fn new(kind: Option<FunctionKind>) -> Scope {
+        let this = match kind {
+            Some(FunctionKind::Method) | Some(FunctionKind::Initializer) => "this",
+            _ => "",
+        };
+        Scope {
+            kind,
+            locals: vec![Local { name: this.to_string(), depth: 0 }],
+            captures: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Result<Option<usize>, &'static str> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if local.name == name {
+                if local.depth == -1 {
+                    return Err("Cannot read local variable in its own initializer.");
+                }
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    fn add_capture(&mut self, capture: Capture) -> usize {
+        if let Some(index) = self.captures.iter().position(|&c| c == capture) {
+            return index;
+        }
+        self.captures.push(capture);
+        self.captures.len() - 1
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary | Precedence::Call => Precedence::Call,
+        }
+    }
+}
+
+// The precedence of the infix operators, including the ones that are only
+// there to be reported as unsupported.
+fn infix_precedence(token_type: TokenType) -> Precedence {
+    match token_type {
+        TokenType::LeftParen | TokenType::Dot | TokenType::LeftBracket => Precedence::Call,
+        TokenType::Star | TokenType::Slash | TokenType::Percent => Precedence::Factor,
+        TokenType::Plus | TokenType::Minus => Precedence::Term,
+        TokenType::Greater | TokenType::GreaterEqual |
+        TokenType::Less | TokenType::LessEqual => Precedence::Comparison,
+        TokenType::EqualEqual | TokenType::BangEqual => Precedence::Equality,
+        TokenType::And => Precedence::And,
+        TokenType::Or => Precedence::Or,
+        _ => Precedence::None,
+    }
+}
+
+fn is_compound_assignment(token_type: TokenType) -> bool {
+    matches!(token_type,
+        TokenType::PlusEqual | TokenType::MinusEqual | TokenType::StarEqual |
+        TokenType::SlashEqual | TokenType::PercentEqual)
+}
+
+impl Parser {
+    fn advance(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.token_type != TokenType::Error {
+                break;
+            }
+            let message = self.current.text().to_string();
+            self.error_at_current(&message);
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = std::mem::take(&mut self.current);
+        self.error_at(&token, message);
+        self.current = token;
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = std::mem::take(&mut self.previous);
+        self.error_at(&token, message);
+        self.previous = token;
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let mut report = String::from("Error");
+        if token.token_type == TokenType::EOF {
+            report.push_str(" at end");
+        } else if token.token_type != TokenType::Error {
+            report.push_str(&format!(" at '{}'", token.text()));
+        }
+        if !message.is_empty() {
+            report.push_str(&format!(": {}", message));
+        }
+        self.diagnostics.push(Diagnostic {
+            line: token.line as usize,
+            column: token.column,
+            span: self.scanner.span(token),
+            message: report,
+            severity: Severity::Error,
+        });
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.current.token_type == token_type {
+            self.advance();
+            return;
+        }
+        self.error_at_current(message);
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn line(&self) -> usize {
+        self.previous.line as usize
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::EOF {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+
+            match self.current.token_type {
+                TokenType::Class | TokenType::Fun | TokenType::Var |
+                TokenType::Const | TokenType::Import | TokenType::For |
+                TokenType::If | TokenType::While |
+                TokenType::Print | TokenType::Return | TokenType::Switch |
+                TokenType::Break | TokenType::Continue | TokenType::Try |
+                TokenType::Throw => return,
+                _ => (),
+            }
+
+            self.advance();
+        }
+    }
+
+    fn enter_nesting(&mut self) -> bool {
+        if self.nesting == MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.skip_group();
+            return false;
+        }
+        self.nesting += 1;
+        true
+    }
+
+    // Skips the current token, and everything up to its closing bracket if
+    // it is an opening one, as the compiler does.
+    fn skip_group(&mut self) {
+        let mut depth = 0usize;
+        loop {
+            match self.current.token_type {
+                TokenType::EOF => break,
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.advance();
+            if depth == 0 {
+                break;
+            }
+        }
+        self.panic_mode = false;
+    }
+
+    // Variables
+
+    fn resolve(&mut self, name: &str) -> Variable {
+        let innermost = self.functions.len() - 1;
+        if let Some(slot) = self.resolve_local(innermost, name) {
+            return Variable::Local(slot);
+        }
+        if let Some(index) = self.resolve_capture(innermost, name) {
+            return Variable::Upvalue(index);
+        }
+        Variable::Global(name.to_string())
+    }
+
+    fn resolve_local(&mut self, function: usize, name: &str) -> Option<usize> {
+        match self.functions[function].resolve_local(name) {
+            Ok(slot) => slot,
+            Err(message) => {
+                self.error(message);
+                Some(0)
+            }
+        }
+    }
+
+    fn resolve_capture(&mut self, function: usize, name: &str) -> Option<usize> {
+        if function == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(function - 1, name) {
+            return Some(self.functions[function].add_capture(Capture { is_local: true, index: slot }));
+        }
+        let index = self.resolve_capture(function - 1, name)?;
+        Some(self.functions[function].add_capture(Capture { is_local: false, index }))
+    }
+
+    // Declares the variable named by the previous token, returning where it
+    // will live.
+    fn declare_variable(&mut self) -> Variable {
+        let name = self.previous.text().to_string();
+        let scope = self.functions.last().unwrap();
+        if scope.depth == 0 {
+            return Variable::Global(name);
+        }
+        let duplicate = scope.locals.iter().rev()
+            .take_while(|local| local.depth == -1 || local.depth >= scope.depth)
+            .any(|local| local.name == name);
+        if duplicate {
+            self.error("Already variable with this name in this scope.");
+        }
+        let scope = self.scope();
+        scope.locals.push(Local { name, depth: -1 });
+        Variable::Local(scope.locals.len() - 1)
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.scope();
+        if scope.depth == 0 {
+            return;
+        }
+        scope.locals.last_mut().unwrap().depth = scope.depth;
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope().depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scope();
+        scope.depth -= 1;
+        while scope.locals.last().is_some_and(|local| local.depth > scope.depth) {
+            scope.locals.pop();
+        }
+    }
+
+    // Declarations and statements
+
+    fn declaration(&mut self) -> Stmt {
+        let stmt = if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else if self.check(TokenType::Const) || self.check(TokenType::Import) {
+            self.advance();
+            self.error(UNSUPPORTED);
+            Stmt::Block(Vec::new())
+        } else {
+            self.statement()
+        };
+        if self.panic_mode {
+            self.synchronize();
+        }
+        stmt
+    }
+
+    fn class_declaration(&mut self) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let name = self.previous.text().to_string();
+        let variable = self.declare_variable();
+        self.mark_initialized();
+        self.class_depth += 1;
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.consume(TokenType::Identifier, "Expect method name.");
+            let kind = if self.previous.text() == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            methods.push(self.function(kind));
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+
+        self.class_depth -= 1;
+        Stmt::Class { variable, name, methods }
+    }
+
+    fn fun_declaration(&mut self) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect function name.");
+        let variable = self.declare_variable();
+        self.mark_initialized();
+        let function = self.function(FunctionKind::Function);
+        Stmt::Function { variable, function }
+    }
+
+    fn function(&mut self, kind: FunctionKind) -> Arc<Function> {
+        let name = self.previous.text().to_string();
+        let line = self.line();
+        if self.functions.len() - 1 == MAX_FUNCTION_NESTING {
+            self.error("Too many nested functions.");
+            self.skip_group();
+            self.skip_group();
+            return Arc::new(Function { name, kind, arity: 0, body: Vec::new(), captures: Vec::new(), line, end_line: line });
+        }
+        self.functions.push(Scope::new(Some(kind)));
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if arity == 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                arity += 1;
+                self.consume(TokenType::Identifier, "Expect parameter name.");
+                self.declare_variable();
+                self.mark_initialized();
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        let body = self.block();
+
+        let scope = self.functions.pop().unwrap();
+        Arc::new(Function {
+            name,
+            kind,
+            arity,
+            body,
+            captures: scope.captures,
+            line,
+            end_line: self.line(),
+        })
+    }
+
+    fn var_declaration(&mut self) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        self.finish_var_declaration()
+    }
+
+    // The rest of a var declaration, after its name.
+    fn finish_var_declaration(&mut self) -> Stmt {
+        let variable = self.declare_variable();
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+        self.mark_initialized();
+        Stmt::Var { variable, initializer }
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if !self.enter_nesting() {
+            return Stmt::Block(Vec::new());
+        }
+        let stmt = if self.match_token(TokenType::Print) {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after value.");
+            Stmt::Print(value)
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            let statements = self.block();
+            self.end_scope();
+            Stmt::Block(statements)
+        } else if matches!(self.current.token_type,
+            TokenType::Switch | TokenType::Break | TokenType::Continue |
+            TokenType::Try | TokenType::Throw) {
+            self.advance();
+            self.error(UNSUPPORTED);
+            Stmt::Block(Vec::new())
+        } else {
+            self.expression_statement()
+        };
+        self.nesting -= 1;
+        stmt
+    }
+
+    fn expression_statement(&mut self) -> Stmt {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        Stmt::Expression(expr)
+    }
+
+    // The statements of a block, after its '{'.
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            statements.push(self.declaration());
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        statements
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_token(TokenType::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+        Stmt::If { condition, then_branch, else_branch }
+    }
+
+    fn while_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let body = Box::new(self.statement());
+        Stmt::While { condition, body }
+    }
+
+    fn for_statement(&mut self) -> Stmt {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let initializer = if self.match_token(TokenType::Semicolon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            if self.check(TokenType::Identifier) && self.current.text() == "in" {
+                self.error_at_current(UNSUPPORTED);
+            }
+            Some(self.finish_var_declaration())
+        } else {
+            Some(self.expression_statement())
+        };
+
+        let condition = if self.match_token(TokenType::Semicolon) {
+            Expr::Literal(Literal::Bool(true))
+        } else {
+            let condition = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            condition
+        };
+
+        let increment = if self.match_token(TokenType::RightParen) {
+            None
+        } else {
+            let increment = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            Some(increment)
+        };
+
+        let mut body = self.statement();
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
+        }
+        self.end_scope();
+
+        let mut statements: Vec<Stmt> = initializer.into_iter().collect();
+        statements.push(Stmt::While { condition, body: Box::new(body) });
+        Stmt::Block(statements)
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+        let kind = self.functions.last().unwrap().kind;
+        if kind.is_none() {
+            self.error("Cannot return from top-level code.");
+        }
+        if self.match_token(TokenType::Semicolon) {
+            return Stmt::Return(None);
+        }
+        if kind == Some(FunctionKind::Initializer) {
+            self.error("Can't return a value from an initializer.");
+        }
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        Stmt::Return(Some(value))
+    }
+
+    // Expressions
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        if !self.enter_nesting() {
+            return Expr::Literal(Literal::Nil);
+        }
+        self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
+        let Some(mut expr) = self.prefix(can_assign) else {
+            self.error("Expect expression.");
+            self.nesting -= 1;
+            return Expr::Literal(Literal::Nil);
+        };
+
+        while precedence <= infix_precedence(self.current.token_type) {
+            self.advance();
+            expr = self.infix(expr, can_assign);
+        }
+
+        if can_assign && (self.match_token(TokenType::Equal) || is_compound_assignment(self.current.token_type)) {
+            self.error("Invalid assignment target.");
+        }
+        self.nesting -= 1;
+        expr
+    }
+
+    // Parses the expression starting with the previous token, or returns
+    // None if no expression starts with it.
+    fn prefix(&mut self, can_assign: bool) -> Option<Expr> {
+        let expr = match self.previous.token_type {
+            TokenType::LeftParen => {
+                let expr = self.expression();
+                self.consume(TokenType::RightParen, "Expect ')' after expression.");
+                expr
+            }
+            TokenType::Minus | TokenType::Bang => {
+                let operator = self.previous.token_type;
+                let operand = Box::new(self.parse_precedence(Precedence::Unary));
+                Expr::Unary { operator, operand, line: self.line() }
+            }
+            TokenType::Identifier => self.variable(can_assign),
+            TokenType::This => {
+                if self.class_depth == 0 {
+                    self.error("Can't use 'this' outside of a class.");
+                    return Some(Expr::Literal(Literal::Nil));
+                }
+                self.variable(false)
+            }
+            TokenType::String => {
+                let text = self.previous.text();
+                Expr::Literal(Literal::String(text[1..text.len() - 1].to_string()))
+            }
+            TokenType::Number => {
+                // The scanner only accepts underscores between digits.
+                let value = self.previous.text().replace('_', "").parse::<f64>().unwrap();
+                Expr::Literal(Literal::Number(value))
+            }
+            TokenType::Nil => Expr::Literal(Literal::Nil),
+            TokenType::True => Expr::Literal(Literal::Bool(true)),
+            TokenType::False => Expr::Literal(Literal::Bool(false)),
+            TokenType::LeftBracket | TokenType::LeftBrace | TokenType::Interpolation |
+            TokenType::Fun | TokenType::Yield => {
+                self.error(UNSUPPORTED);
+                Expr::Literal(Literal::Nil)
+            }
+            _ => return None,
+        };
+        Some(expr)
+    }
+
+    // Parses the rest of an expression whose operator is the previous
+    // token.
+    fn infix(&mut self, left: Expr, can_assign: bool) -> Expr {
+        let operator = self.previous.token_type;
+        match operator {
+            TokenType::LeftParen => {
+                let arguments = self.argument_list();
+                Expr::Call { callee: Box::new(left), arguments, line: self.line() }
+            }
+            TokenType::Dot => self.dot(left, can_assign),
+            TokenType::LeftBracket | TokenType::Percent => {
+                self.error(UNSUPPORTED);
+                left
+            }
+            TokenType::And | TokenType::Or => {
+                let precedence = infix_precedence(operator);
+                let right = Box::new(self.parse_precedence(precedence));
+                Expr::Logical { left: Box::new(left), operator, right }
+            }
+            _ => {
+                let precedence = infix_precedence(operator).next();
+                let right = Box::new(self.parse_precedence(precedence));
+                Expr::Binary { left: Box::new(left), operator, right, line: self.line() }
+            }
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let name = self.previous.text().to_string();
+        let line = self.line();
+        let variable = self.resolve(&name);
+        if can_assign && self.match_token(TokenType::Equal) {
+            let value = Box::new(self.expression());
+            return Expr::Assign { variable, value, line: self.line() };
+        }
+        if can_assign && is_compound_assignment(self.current.token_type) {
+            self.error_at_current(UNSUPPORTED);
+        }
+        Expr::Variable { variable, line }
+    }
+
+    fn dot(&mut self, object: Expr, can_assign: bool) -> Expr {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.text().to_string();
+        let object = Box::new(object);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            let value = Box::new(self.expression());
+            Expr::Set { object, name, value, line: self.line() }
+        } else if can_assign && is_compound_assignment(self.current.token_type) {
+            self.error_at_current(UNSUPPORTED);
+            Expr::Get { object, name, line: self.line() }
+        } else if self.match_token(TokenType::LeftParen) {
+            let arguments = self.argument_list();
+            Expr::Invoke { object, name, arguments, line: self.line() }
+        } else {
+            Expr::Get { object, name, line: self.line() }
+        }
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arguments.push(self.expression());
+                if arguments.len() == 256 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arguments
+    }
+}
@@ -0,0 +1,277 @@
+// Purpose: Serialized compiled functions (.loxc files), so scripts can be
+// compiled ahead of time and embedded with include_lox!.
+//
+// Layout, with integers stored little-endian:
+//
+//   file      = "LOXC" version:u8 globals function
+//   globals   = len:u32 string...
+//   function  = name arity:u8 upvalues:u32 max_locals:u32 line:u32 end_line:u32
+//               code lines constants locals
+//   name      = 0 | 1 string
+//   string    = len:u32 bytes
+//   code      = len:u32 bytes
+//   lines     = len:u32 (start:u32 line:i32)...
+//   constants = len:u32 constant...
+//   constant  = 0 (nil) | 1 (false) | 2 (true) | 3 f64 | 4 string | 5 function
+//   locals    = len:u32 (string slot:u16 start:u32 end:u32)...
+//
+// The locals are debug symbols; stripped files have none. Global slots in
+// the code index the globals list, the names of the compiling VM's slots,
+// and are renumbered for the loading VM's slots when a file is read.
+
+use crate::chunk::Chunk;
+use crate::chunk::LineRun;
+use crate::chunk::LocalSymbol;
+use crate::chunk::OpCode;
+use crate::debug::operand_len;
+use crate::error::LoxError;
+use crate::object::Obj;
+use crate::object::ObjArray;
+use crate::object::ObjFunction;
+use crate::table::Globals;
+use crate::value::Value;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+// Bump whenever the opcodes or this layout change, so stale files are
+// rejected instead of misread.
+pub const VERSION: u8 = 21;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// `globals` are those of the VM that compiled `function`. With `strip`,
+// local variable names are left out.
+pub fn write_bytecode(function: *const ObjFunction, globals: &Globals, strip: bool) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_u32(&mut out, globals.len());
+    for slot in 0..globals.len() {
+        write_string(&mut out, unsafe { (*globals.name(slot)).as_str() });
+    }
+    write_function(&mut out, function, strip);
+    out
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: *const ObjFunction, strip: bool) {
+    let function = unsafe { &*function };
+    if function.name.is_null() {
+        out.push(0);
+    } else {
+        out.push(1);
+        write_string(out, unsafe { (*function.name).as_str() });
+    }
+    out.push(function.arity);
+    write_u32(out, function.upvalue_count);
+    write_u32(out, function.max_locals);
+    write_u32(out, function.line);
+    write_u32(out, function.end_line);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    write_u32(out, chunk.lines.len());
+    for run in &chunk.lines {
+        write_u32(out, run.start);
+        out.extend_from_slice(&run.line.to_le_bytes());
+    }
+    write_u32(out, chunk.constants.values.len());
+    for constant in &chunk.constants.values {
+        if constant.is_nil() {
+            out.push(0);
+        } else if constant.is_bool() {
+            out.push(if constant.as_bool() { 2 } else { 1 });
+        } else if constant.is_number() {
+            out.push(3);
+            out.extend_from_slice(&constant.as_number().to_le_bytes());
+        } else if constant.is_string() {
+            out.push(4);
+            write_string(out, constant.as_str());
+        } else if constant.is_function() {
+            out.push(5);
+            write_function(out, constant.as_function(), strip);
+        } else {
+            unreachable!("the compiler only emits nil, bool, number, string and function constants");
+        }
+    }
+    if strip {
+        write_u32(out, 0);
+        return;
+    }
+    write_u32(out, chunk.locals.len());
+    for local in &chunk.locals {
+        write_string(out, &local.name);
+        out.extend_from_slice(&local.slot.to_le_bytes());
+        write_u32(out, local.start);
+        write_u32(out, local.end);
+    }
+}
+
+pub fn read_bytecode(bytes: &[u8], obj_array: &mut ObjArray, globals: &mut Globals) -> Result<*const ObjFunction, LoxError> {
+    if !is_bytecode(bytes) {
+        return Err(invalid("missing LOXC header"));
+    }
+    let mut reader = Reader { bytes, pos: MAGIC.len(), global_slots: Vec::new() };
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(LoxError::runtime(format!(
+            "Bytecode version {} is not supported; expected version {}.", version, VERSION)));
+    }
+    let globals_len = reader.u32()?;
+    for _ in 0..globals_len {
+        let name = reader.string()?;
+        let slot = globals.slot(obj_array.copy_string(&name));
+        reader.global_slots.push(u16::try_from(slot).map_err(|_| invalid("too many globals"))?);
+    }
+    let function = reader.function(obj_array)?;
+    if reader.pos != bytes.len() {
+        return Err(invalid("trailing data"));
+    }
+    Ok(function)
+}
+
+fn invalid(reason: &str) -> LoxError {
+    LoxError::runtime(format!("Invalid bytecode: {}.", reason))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // The loading VM's slot for each of the file's global slots.
+    global_slots: Vec<u16>,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoxError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(invalid("unexpected end of data"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoxError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoxError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, LoxError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn function(&mut self, obj_array: &mut ObjArray) -> Result<*const ObjFunction, LoxError> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(invalid("bad function name")),
+        };
+        let arity = self.u8()?;
+        let upvalue_count = self.u32()?;
+        if upvalue_count > u8::MAX as usize + 1 {
+            return Err(invalid("too many upvalues"));
+        }
+        let max_locals = self.u32()?;
+        let line = self.u32()?;
+        let end_line = self.u32()?;
+
+        let mut chunk = Chunk::default();
+        let code_len = self.u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+        let lines_len = self.u32()?;
+        for _ in 0..lines_len {
+            let start = self.u32()?;
+            let line = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+            chunk.lines.push(LineRun { start, line });
+        }
+        // Every byte needs a line: the runs must start at 0 and go up.
+        let ordered = chunk.lines.windows(2).all(|pair| pair[0].start < pair[1].start);
+        let covered = match (chunk.lines.first(), chunk.lines.last()) {
+            (Some(first), Some(last)) => first.start == 0 && last.start < chunk.code.len(),
+            _ => chunk.code.is_empty(),
+        };
+        if !ordered || !covered {
+            return Err(invalid("line table does not match code"));
+        }
+        let constants_len = self.u32()?;
+        for _ in 0..constants_len {
+            let constant = match self.u8()? {
+                0 => Value::nil(),
+                1 => Value::bool(false),
+                2 => Value::bool(true),
+                3 => Value::number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                4 => {
+                    let s = self.string()?;
+                    Value::object(obj_array.copy_string(&s) as *const Obj)
+                }
+                5 => Value::object(self.function(obj_array)? as *const Obj),
+                _ => return Err(invalid("unknown constant type")),
+            };
+            chunk.add_constant(constant);
+        }
+        self.renumber_globals(&mut chunk)?;
+        let locals_len = self.u32()?;
+        for _ in 0..locals_len {
+            let name = self.string()?;
+            let slot = u16::from_le_bytes(self.take(2)?.try_into().unwrap());
+            let start = self.u32()?;
+            let end = self.u32()?;
+            chunk.locals.push(LocalSymbol { name, slot, start, end });
+        }
+
+        let function = obj_array.new_function(chunk);
+        unsafe {
+            (*function).arity = arity;
+            (*function).upvalue_count = upvalue_count;
+            (*function).max_locals = max_locals;
+            (*function).line = line;
+            (*function).end_line = end_line;
+            if let Some(name) = name {
+                (*function).name = obj_array.copy_string(&name);
+            }
+        }
+        Ok(function)
+    }
+
+    // Rewrites the global slots in a chunk's code from the file's numbering
+    // to the loading VM's.
+    fn renumber_globals(&self, chunk: &mut Chunk) -> Result<(), LoxError> {
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = OpCode::try_from(chunk.code[offset]).map_err(|_| invalid("unknown opcode"))?;
+            if let OpCode::Closure = op {
+                let constant = chunk.code.get(offset + 1).and_then(|&index| chunk.constants.values.get(index as usize));
+                if !constant.is_some_and(|constant| constant.is_function()) {
+                    return Err(invalid("closure of a non-function"));
+                }
+            }
+            let len = operand_len(chunk, offset, &op);
+            if offset + len >= chunk.code.len() {
+                return Err(invalid("truncated instruction"));
+            }
+            if let OpCode::GetGlobalSlot | OpCode::SetGlobalSlot = op {
+                let slot = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+                let slot = *self.global_slots.get(slot).ok_or_else(|| invalid("global slot out of range"))?;
+                chunk.code[offset + 1] = (slot >> 8) as u8;
+                chunk.code[offset + 2] = slot as u8;
+            }
+            offset += 1 + len;
+        }
+        Ok(())
+    }
+}
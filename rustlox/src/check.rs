@@ -0,0 +1,291 @@
+// Purpose: Static checks on compiled code, enabled with VmBuilder::check
+// or `--check`.
+//
+// The checks read the bytecode rather than the source, so they see the
+// code as it will run. They look for calls to global functions and classes
+// with the wrong number of arguments, globals that nothing defines, and
+// arithmetic on constants of the wrong type. Findings are warnings, since
+// the code may never run; --deny-warnings makes them errors.
+//
+// Each chunk is run through symbolically, remembering which instruction
+// pushed each value on the stack. Where control flow joins, at jump
+// targets, what was on the stack is forgotten, so findings are only made
+// from straight-line code.
+
+use crate::chunk::Chunk;
+use crate::chunk::OpCode;
+use crate::debug::jump_labels;
+use crate::debug::operand_len;
+use crate::debug::visit_functions;
+use crate::error::Diagnostic;
+use crate::error::Severity;
+use crate::table::Globals;
+use crate::table::StringMap;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Checks `script` and every function in it against `globals`, the
+/// variables it will run with, and returns what it finds as warnings.
+/// With `coerce`, operands that [`Config::coerce`] allows aren't errors.
+///
+/// [`Config::coerce`]: crate::vm::Config::coerce
+///
+/// ```
+/// use rustlox::Vm;
+///
+/// let mut vm = Vm::builder().check(true).build();
+/// let source = "\
+/// fun add(a, b) { return a + b; }
+/// fun never() {
+///   print add(1);
+///   print \"a\" - 1;
+///   print nope;
+/// }";
+/// let (_, _, stderr) = vm.interpret_captured(source);
+/// assert_eq!(stderr, "\
+/// [line 3] Warning: Expected 2 arguments but got 1 in call to 'add'.
+/// [line 4] Warning: Operands must be numbers.
+/// [line 5] Warning: Undefined variable 'nope'.
+/// ");
+/// ```
+pub fn check_script(script: Value, globals: &Globals, coerce: bool) -> Vec<Diagnostic> {
+    let mut definitions = Definitions::default();
+    visit_functions(script, &mut |_, chunk| definitions.collect(chunk, globals));
+    let mut diagnostics = Vec::new();
+    visit_functions(script, &mut |_, chunk| check_chunk(chunk, globals, &definitions, coerce, &mut diagnostics));
+    diagnostics
+}
+
+// What the code does to global variables, by slot.
+#[derive(Default)]
+struct Definitions {
+    // How often each global is defined.
+    defined: HashMap<usize, usize>,
+    assigned: HashSet<usize>,
+    // The arity of each global defined as a function, or as a class
+    // (its initializer's arity).
+    arities: HashMap<usize, usize>,
+}
+
+impl Definitions {
+    fn collect(&mut self, chunk: &Chunk, globals: &Globals) {
+        let code = decode(chunk);
+        // The global last read, which is the class methods are being
+        // added to when OP_METHOD follows.
+        let mut last_global = None;
+        for (i, instruction) in code.iter().enumerate() {
+            match instruction.op {
+                OpCode::DefineGlobal => {
+                    let Some(slot) = global_slot(chunk, globals, instruction.operand) else {
+                        continue;
+                    };
+                    *self.defined.entry(slot).or_insert(0) += 1;
+                    match i.checked_sub(1).map(|previous| &code[previous]) {
+                        Some(previous) if matches!(previous.op, OpCode::Closure) => {
+                            let function = chunk.constants.values[previous.operand as usize];
+                            self.arities.insert(slot, unsafe { (*function.as_function()).arity } as usize);
+                        }
+                        Some(previous) if matches!(previous.op, OpCode::Class) => {
+                            self.arities.insert(slot, 0);
+                        }
+                        _ => {}
+                    }
+                }
+                OpCode::SetGlobalSlot => {
+                    self.assigned.insert(slot_operand(chunk, instruction.offset));
+                }
+                OpCode::GetGlobalSlot => last_global = Some(slot_operand(chunk, instruction.offset)),
+                OpCode::Method => {
+                    let name = chunk.constants.values[instruction.operand as usize];
+                    let initializer = i.checked_sub(1).map(|previous| &code[previous])
+                        .filter(|previous| matches!(previous.op, OpCode::Closure));
+                    if let (Some(class), Some(closure), "init") = (last_global, initializer, name.as_str()) {
+                        let function = chunk.constants.values[closure.operand as usize];
+                        self.arities.insert(class, unsafe { (*function.as_function()).arity } as usize);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The arity of the function or class in `slot`, if it is defined once
+    // and never reassigned.
+    fn arity(&self, slot: usize) -> Option<usize> {
+        if self.defined.get(&slot) != Some(&1) || self.assigned.contains(&slot) {
+            return None;
+        }
+        self.arities.get(&slot).copied()
+    }
+}
+
+struct Instruction {
+    offset: usize,
+    op: OpCode,
+    // The first operand byte, or the argument count of OP_INVOKE.
+    operand: u8,
+}
+
+// The chunk's instructions, up to the first byte that isn't an opcode.
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let Ok(op) = OpCode::try_from(chunk.code[offset]) else {
+            break;
+        };
+        let len = operand_len(chunk, offset, &op);
+        let operand = match op {
+            OpCode::Invoke | OpCode::TailInvoke => chunk.code[offset + 2],
+            _ if len > 0 => chunk.code[offset + 1],
+            _ => 0,
+        };
+        code.push(Instruction { offset, op, operand });
+        offset += 1 + len;
+    }
+    code
+}
+
+fn slot_operand(chunk: &Chunk, offset: usize) -> usize {
+    (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize
+}
+
+// The slot of the global named by the constant at `index`.
+fn global_slot(chunk: &Chunk, globals: &Globals, index: u8) -> Option<usize> {
+    let name = chunk.constants.values[index as usize];
+    globals.find(name.as_string())
+}
+
+// The value an instruction always pushes, if it is a constant.
+fn constant(chunk: &Chunk, instruction: &Instruction) -> Option<Value> {
+    match instruction.op {
+        OpCode::Constant => Some(chunk.constants.values[instruction.operand as usize]),
+        OpCode::ConstantLong => {
+            let offset = instruction.offset;
+            let index = (chunk.code[offset + 1] as usize) << 16
+                | (chunk.code[offset + 2] as usize) << 8
+                | chunk.code[offset + 3] as usize;
+            Some(chunk.constants.values[index])
+        }
+        OpCode::SmallInt => Some(Value::number(instruction.operand as f64)),
+        OpCode::Nil => Some(Value::nil()),
+        OpCode::True => Some(Value::bool(true)),
+        OpCode::False => Some(Value::bool(false)),
+        _ => None,
+    }
+}
+
+// How many values an instruction pops and pushes. Instructions that only
+// look at the top of the stack, like OP_SET_LOCAL, pop and push it again.
+fn stack_io(op: OpCode, operand: u8) -> (usize, usize) {
+    let operand = operand as usize;
+    match op {
+        OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False |
+        OpCode::SmallInt | OpCode::GetGlobal | OpCode::GetGlobalSlot | OpCode::GetLocal | OpCode::GetLocalLong |
+        OpCode::GetUpvalue | OpCode::Closure | OpCode::Class | OpCode::Import | OpCode::IterNext => (0, 1),
+        OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Modulo |
+        OpCode::Equal | OpCode::Greater | OpCode::Less | OpCode::IndexGet | OpCode::SetProperty => (2, 1),
+        OpCode::Negate | OpCode::Not | OpCode::ToString | OpCode::GetProperty | OpCode::SetGlobal |
+        OpCode::SetGlobalSlot | OpCode::SetLocal | OpCode::SetLocalLong | OpCode::SetUpvalue | OpCode::Yield => (1, 1),
+        OpCode::Print | OpCode::Pop | OpCode::DefineGlobal | OpCode::CloseUpvalue | OpCode::Throw |
+        OpCode::Return | OpCode::Method => (1, 0),
+        OpCode::Dup => (1, 2),
+        OpCode::Dup2 => (2, 4),
+        OpCode::Swap => (2, 2),
+        OpCode::IndexSet => (3, 1),
+        OpCode::Call | OpCode::TailCall | OpCode::Invoke | OpCode::TailInvoke => (operand + 1, 1),
+        OpCode::BuildList => (operand, 1),
+        OpCode::PopN => (operand, 0),
+        OpCode::BuildMap => (2 * operand, 1),
+        OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop | OpCode::PushHandler | OpCode::PopHandler => (0, 0),
+    }
+}
+
+fn check_chunk(
+    chunk: &Chunk,
+    globals: &Globals,
+    definitions: &Definitions,
+    coerce: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let code = decode(chunk);
+    let labels = jump_labels(chunk);
+    // Which instruction pushed each value, for values pushed since the
+    // last jump target. Values from before it read as None.
+    let mut stack: Vec<Option<usize>> = Vec::new();
+    // Each undefined global is reported once per line.
+    let mut undefined: StringMap<HashSet<usize>> = StringMap::default();
+    for (i, instruction) in code.iter().enumerate() {
+        if labels.contains_key(&instruction.offset) {
+            stack.clear();
+        }
+        let line = chunk.get_line(instruction.offset) as usize;
+        let mut warn = |message: String| diagnostics.push(Diagnostic {
+            line,
+            column: 0,
+            span: None,
+            message: format!("Warning: {}", message),
+            severity: Severity::Warning,
+        });
+
+        let (pops, pushes) = stack_io(instruction.op, instruction.operand);
+        let inputs: Vec<Option<&Instruction>> = (0..pops).rev()
+            .map(|depth| stack.len().checked_sub(depth + 1).and_then(|index| stack[index]).map(|producer| &code[producer]))
+            .collect();
+        match instruction.op {
+            OpCode::GetGlobalSlot | OpCode::SetGlobalSlot => {
+                let slot = slot_operand(chunk, instruction.offset);
+                if globals.get(slot).is_none() && !definitions.defined.contains_key(&slot) {
+                    let name = globals.name(slot);
+                    if undefined.entry(name).or_default().insert(line) {
+                        warn(format!("Undefined variable '{}'.", unsafe { (*name).as_str() }));
+                    }
+                }
+            }
+            OpCode::Call | OpCode::TailCall => {
+                let callee = inputs[0].filter(|callee| matches!(callee.op, OpCode::GetGlobalSlot));
+                if let Some(callee) = callee {
+                    let slot = slot_operand(chunk, callee.offset);
+                    let args = instruction.operand as usize;
+                    if let Some(arity) = definitions.arity(slot).filter(|&arity| arity != args) {
+                        let name = unsafe { (*globals.name(slot)).as_str() };
+                        warn(format!("Expected {} arguments but got {} in call to '{}'.", arity, args, name));
+                    }
+                }
+            }
+            OpCode::Negate => {
+                let operand = inputs[0].and_then(|input| constant(chunk, input));
+                if operand.is_some_and(|value| !value.is_number()) {
+                    warn("Operand must be a number.".to_string());
+                }
+            }
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Modulo |
+            OpCode::Greater | OpCode::Less => {
+                let a = inputs[0].and_then(|input| constant(chunk, input));
+                let b = inputs[1].and_then(|input| constant(chunk, input));
+                if let (Some(a), Some(b)) = (a, b) {
+                    let numbers = a.is_number() && b.is_number();
+                    let mixed = a.is_string() && b.is_number() || a.is_number() && b.is_string();
+                    let coerced = coerce && mixed && matches!(instruction.op, OpCode::Add | OpCode::Multiply);
+                    if matches!(instruction.op, OpCode::Add) {
+                        if !(numbers || a.is_string() && b.is_string() || coerced) {
+                            warn("Operands must be two numbers or two strings.".to_string());
+                        }
+                    } else if !(numbers || coerced) {
+                        warn("Operands must be numbers.".to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        stack.truncate(stack.len().saturating_sub(pops));
+        for _ in 0..pushes {
+            stack.push(if pops == 0 { Some(i) } else { None });
+        }
+        if matches!(instruction.op, OpCode::Jump | OpCode::Loop | OpCode::Return | OpCode::Throw) {
+            stack.clear();
+        }
+    }
+}
@@ -4,9 +4,10 @@ use crate::value::ValueArray;
 use crate::value::Value;
 use num_enum::TryFromPrimitive;
 use num_enum::IntoPrimitive;
+use std::sync::Arc;
 
 #[repr(u8)]
-#[derive(Debug, TryFromPrimitive, IntoPrimitive)]
+#[derive(Debug, Clone, Copy, TryFromPrimitive, IntoPrimitive)]
 pub enum OpCode {
     Constant,
     Return,
@@ -33,23 +34,217 @@ pub enum OpCode {
     Jump,
     Loop,
     Call,
+    Dup,
+    Swap,
+    SmallInt,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Invoke,
+    ConstantLong,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    BuildMap,
+    Modulo,
+    ToString,
+    GetGlobalSlot,
+    SetGlobalSlot,
+    PushHandler,
+    PopHandler,
+    Throw,
+    Import,
+    TailCall,
+    IterNext,
+    PopN,
+    GetLocalLong,
+    SetLocalLong,
+    Yield,
+    TailInvoke,
+    Dup2,
 }
     
+// A local variable's name and the bytecode range where its slot holds it,
+// for debuggers and stack traces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalSymbol {
+    pub name: String,
+    pub slot: u16,
+    // From just after the variable is initialized to the end of its scope.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The text that chunks were compiled from, shared by every function in
+/// it, so stack traces can show the lines they pass through.
+#[derive(Debug)]
+pub struct Source {
+    /// The file's path, or where the text came from, such as "repl".
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    /// The text of 1-based `line`, if there is one.
+    pub fn line(&self, line: usize) -> Option<&str> {
+        self.text.lines().nth(line.checked_sub(1)?)
+    }
+}
+
+// The line of the code from `start` up to the next run's start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRun {
+    pub start: usize,
+    pub line: i32,
+}
+
 #[derive(Debug, Default)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ValueArray,
-    pub lines: Vec<i32>
+    // Run-length encoded, since most lines compile to several bytes.
+    pub lines: Vec<LineRun>,
+    // Debug symbols. Empty for bytecode compiled with them stripped.
+    pub locals: Vec<LocalSymbol>,
+    // What the chunk was compiled from. None for bytecode files.
+    pub source: Option<Arc<Source>>,
 }
 
 impl Chunk {
+    // Chunks hold values, which aren't Send or Sync, but are still counted
+    // atomically: hooks can keep a clone of one, and the Vm that shares it
+    // with them may be sent to another thread.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub(crate) fn into_shared(self) -> Arc<Chunk> {
+        Arc::new(self)
+    }
+
     pub fn write_chunk(&mut self, code: u8, line: i32) {
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun { start: self.code.len(), line });
+        }
         self.code.push(code);
-        self.lines.push(line);
+    }
+
+    pub fn emit_op(&mut self, op: OpCode, line: i32) {
+        self.write_chunk(op as u8, line);
+    }
+
+    pub fn emit_op_u8(&mut self, op: OpCode, operand: u8, line: i32) {
+        self.write_chunk(op as u8, line);
+        self.write_chunk(operand, line);
+    }
+
+    // For OP_INVOKE and OP_SUPER_INVOKE: a name constant, then an argument
+    // count.
+    pub fn emit_op_u8_u8(&mut self, op: OpCode, first: u8, second: u8, line: i32) {
+        self.emit_op_u8(op, first, line);
+        self.write_chunk(second, line);
+    }
+
+    // Operands wider than a byte are big-endian.
+    pub fn emit_op_u16(&mut self, op: OpCode, operand: u16, line: i32) {
+        self.write_chunk(op as u8, line);
+        for byte in &operand.to_be_bytes() {
+            self.write_chunk(*byte, line);
+        }
+    }
+
+    // Only the low three bytes of `operand` are written.
+    pub fn emit_op_u24(&mut self, op: OpCode, operand: u32, line: i32) {
+        self.write_chunk(op as u8, line);
+        for byte in &operand.to_be_bytes()[1..] {
+            self.write_chunk(*byte, line);
+        }
+    }
+
+    /// Writes a forward jump whose distance is filled in later by
+    /// [`Chunk::patch_jump`], and returns the offset of its operand.
+    pub fn emit_jump(&mut self, op: OpCode, line: i32) -> usize {
+        self.emit_op_u16(op, u16::MAX, line);
+        self.code.len() - 2
+    }
+
+    // Points the jump whose operand is at `offset` to the end of the code.
+    pub fn patch_jump(&mut self, offset: usize) -> Result<(), &'static str> {
+        let jump = self.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            return Err("Too much code to jump over.");
+        }
+        self.code[offset..offset + 2].copy_from_slice(&(jump as u16).to_be_bytes());
+        Ok(())
+    }
+
+    // Writes an OP_LOOP back to `loop_start`.
+    pub fn emit_loop(&mut self, loop_start: usize, line: i32) -> Result<(), &'static str> {
+        let offset = self.code.len() - loop_start + 3;
+        if offset > u16::MAX as usize {
+            // Still write the instruction, so the code stays decodable.
+            self.emit_op_u16(OpCode::Loop, 0, line);
+            return Err("Loop body too large.");
+        }
+        self.emit_op_u16(OpCode::Loop, offset as u16, line);
+        Ok(())
+    }
+
+    // Removes the last byte written.
+    pub fn pop_byte(&mut self) {
+        self.code.pop();
+        if self.lines.last().is_some_and(|run| run.start == self.code.len()) {
+            self.lines.pop();
+        }
+    }
+
+    // Removes the code from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        while self.lines.last().is_some_and(|run| run.start >= len) {
+            self.lines.pop();
+        }
+    }
+
+    /// The source line of the byte at `offset`.
+    pub fn get_line(&self, offset: usize) -> i32 {
+        let run = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[run - 1].line
     }
     
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.write(value);
         self.constants.values.len() - 1
     }
+
+    // The local variables live at the instruction at `offset`.
+    pub fn locals_at(&self, offset: usize) -> impl Iterator<Item = &LocalSymbol> {
+        self.locals.iter().filter(move |local| local.start <= offset && offset < local.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_jump_points_past_the_code_after_it() {
+        let mut chunk = Chunk::default();
+        let jump = chunk.emit_jump(OpCode::Jump, 1);
+        chunk.emit_op(OpCode::Nil, 1);
+        chunk.patch_jump(jump).unwrap();
+        assert_eq!(chunk.code[jump..], [0, 1, OpCode::Nil as u8]);
+    }
+
+    #[test]
+    fn lines_are_run_length_encoded() {
+        let mut chunk = Chunk::default();
+        for (byte, line) in [(0, 1), (0, 1), (0, 3)] {
+            chunk.write_chunk(byte, line);
+        }
+        assert_eq!(chunk.lines.len(), 2);
+        assert_eq!((chunk.get_line(1), chunk.get_line(2)), (1, 3));
+    }
 }
@@ -4,28 +4,67 @@ use crate::scanner::TokenType;
 use crate::scanner::Scanner;
 use crate::value::Value;
 use crate::chunk::Chunk;
+use crate::chunk::LocalSymbol;
 use crate::debug::disassemble_chunk;
 use crate::chunk::OpCode;
+use crate::error::Diagnostic;
+use crate::error::Severity;
 use crate::object::Obj;
 use crate::object::ObjArray;
 use crate::object::ObjFunction;
+use crate::object::ObjModule;
+use crate::object::ObjString;
+use crate::table::Globals;
 use num_enum::IntoPrimitive;
 use num_enum::TryFromPrimitive;
+use std::collections::HashMap;
 use std::rc::Rc;
-
-const DEBUG: bool = false;
+use std::sync::Arc;
 
 struct Parser<'a> {
-    compiler: Rc<Compiler>,
+    compiler: Box<Compiler>,
+    // The innermost class being compiled, if any.
+    class_compiler: Option<Box<ClassCompiler>>,
     rules: [ParseRule; TOKEN_COUNT],
     scanner: Scanner,
     obj_array: &'a mut ObjArray,
+    // The globals of the module being compiled, or the VM's own, which
+    // global variables get their slots from.
+    globals: &'a mut Globals,
+    // The module being compiled, or null for a script.
+    module: *mut ObjModule,
+    diagnostics: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,
     current: Token,
     previous: Token,
     had_error: bool,
     panic_mode: bool,
+    print_code: bool,
+    kind: ScriptKind,
+    // Code length just after the last top-level expression statement.
+    last_expression_end: Option<usize>,
+    // The last string literal compiled, while it is still the end of its
+    // function's code and no jump lands after it: its function, the
+    // offset of its constant instruction and its value.
+    string_literal: Option<(*mut ObjFunction, usize, *const ObjString)>,
+    // Whether the last block compiled returns before its end, so that the
+    // block around a block statement knows the code after it is unreachable.
+    block_returned: bool,
+    // How deeply the statements and expressions being parsed are nested,
+    // and how many functions enclose them.
+    nesting: usize,
+    function_nesting: usize,
 }
 
+// Slots of the locals are two-byte operands at most.
+const MAX_LOCALS: usize = u16::MAX as usize + 1;
+
+// Deeper code is a compile error rather than a Rust stack overflow.
+const MAX_NESTING: usize = 256;
+// Each function being compiled holds a Compiler on the Rust stack, so
+// functions get a tighter limit.
+const MAX_FUNCTION_NESTING: usize = 32;
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 enum Precedence {
@@ -51,14 +90,14 @@ struct ParseRule {
 impl ParseRule {
     fn new(prefix: Option<fn(&mut Parser, bool)>, infix: Option<fn(&mut Parser, bool)>, precedence: Precedence) -> ParseRule {
         ParseRule {
-            prefix: prefix,
-            infix: infix,
-            precedence: precedence,
+            prefix,
+            infix,
+            precedence,
         }
     }
 }
 
-const TOKEN_COUNT: usize = 40;
+const TOKEN_COUNT: usize = 61;
 const NONE_RULE: ParseRule = ParseRule{
     prefix: None,
     infix: None,
@@ -71,13 +110,17 @@ fn rules_table() -> [ParseRule; TOKEN_COUNT] {
     table[TokenType::RightParen as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::LeftBrace as usize] =
-        ParseRule::new(None, None, Precedence::None);
+        ParseRule::new(Some(map), None, Precedence::None);
     table[TokenType::RightBrace as usize] =
         ParseRule::new(None, None, Precedence::None);
+    table[TokenType::LeftBracket as usize] =
+        ParseRule::new(Some(list), Some(index), Precedence::Call);
+    table[TokenType::RightBracket as usize] =
+        ParseRule::new(None, None, Precedence::None);
     table[TokenType::Comma as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::Dot as usize] =
-        ParseRule::new(None, None, Precedence::None);
+        ParseRule::new(None, Some(dot), Precedence::Call);
     table[TokenType::Minus as usize] =
         ParseRule::new(Some(unary), Some(binary), Precedence::Term);
     table[TokenType::Plus as usize] =
@@ -88,6 +131,8 @@ fn rules_table() -> [ParseRule; TOKEN_COUNT] {
         ParseRule::new(None, Some(binary), Precedence::Factor);
     table[TokenType::Star as usize] =
         ParseRule::new(None, Some(binary), Precedence::Factor);
+    table[TokenType::Percent as usize] =
+        ParseRule::new(None, Some(binary), Precedence::Factor);
     table[TokenType::Bang as usize] =
         ParseRule::new(Some(unary), None, Precedence::None);
     table[TokenType::BangEqual as usize] =
@@ -108,6 +153,8 @@ fn rules_table() -> [ParseRule; TOKEN_COUNT] {
         ParseRule::new(Some(variable), None, Precedence::None);
     table[TokenType::String as usize] =
         ParseRule::new(Some(string), None, Precedence::None);
+    table[TokenType::Interpolation as usize] =
+        ParseRule::new(Some(interpolation), None, Precedence::None);
     table[TokenType::Number as usize] =
         ParseRule::new(Some(number), None, Precedence::None);
     table[TokenType::And as usize] =
@@ -121,7 +168,7 @@ fn rules_table() -> [ParseRule; TOKEN_COUNT] {
     table[TokenType::For as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::Fun as usize] =
-        ParseRule::new(None, None, Precedence::None);
+        ParseRule::new(Some(fun_expression), None, Precedence::None);
     table[TokenType::If as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::Nil as usize] =
@@ -135,83 +182,263 @@ fn rules_table() -> [ParseRule; TOKEN_COUNT] {
     table[TokenType::Super as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::This as usize] =
-        ParseRule::new(None, None, Precedence::None);
+        ParseRule::new(Some(this_), None, Precedence::None);
     table[TokenType::True as usize] =
         ParseRule::new(Some(literal), None, Precedence::None);
     table[TokenType::Var as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::While as usize] =
         ParseRule::new(None, None, Precedence::None);
+    table[TokenType::Yield as usize] =
+        ParseRule::new(Some(yield_), None, Precedence::None);
     table[TokenType::Error as usize] =
         ParseRule::new(None, None, Precedence::None);
     table[TokenType::EOF as usize] =
         ParseRule::new(None, None, Precedence::None);
-    return table;
+    table
 }
 
 #[derive(Debug, PartialEq)]
 pub enum FunctionType {
     Function,
+    Initializer,
+    Method,
     Script,
 }
 
+/// Where top-level code comes from, which decides what happens to a
+/// trailing expression statement.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ScriptKind {
+    /// A script or module. The value is discarded.
+    File,
+    /// A line of REPL input. The value is returned, and echoed if the line
+    /// leaves off the `;`.
+    Repl,
+    /// Source run by eval(). The value is returned, and the `;` is optional.
+    Eval,
+}
+
 pub struct Compiler {
-    enclosing: Option<Rc<Compiler>>,
+    enclosing: Option<Box<Compiler>>,
     function: *mut ObjFunction,
     function_type: FunctionType,
     
-    locals: [Local; u8::MAX as usize + 1],
-    local_count: usize,
+    // The locals in scope, by slot.
+    locals: Vec<Local>,
+    upvalues: [Upvalue; u8::MAX as usize + 1],
     scope_depth: i32,
+    // Enclosing loops of the code being compiled, innermost last.
+    loops: Vec<Loop>,
+    // Number of try blocks the code being compiled is inside.
+    try_depth: usize,
+    // Offset of the last OP_CALL or OP_INVOKE emitted, to turn into a tail
+    // call if a return follows it.
+    last_call: Option<usize>,
+    // Where each number and string is in the function's constant table,
+    // so repeated ones share a slot.
+    constants: HashMap<ConstantKey, usize>,
 }
 
-#[derive(Default, Copy, Clone)]
+// Numbers are keyed by their bits, so that 0 and -0 stay apart, and strings
+// by their interned object.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(*const ObjString),
+}
+
+impl ConstantKey {
+    fn of(value: Value) -> Option<ConstantKey> {
+        if value.is_number() {
+            Some(ConstantKey::Number(value.as_number().to_bits()))
+        } else if value.is_string() {
+            Some(ConstantKey::String(value.as_string()))
+        } else {
+            None
+        }
+    }
+}
+
+// A loop being compiled, for the break and continue statements in it.
+pub struct Loop {
+    // Where continue jumps to: the condition, or a for loop's increment.
+    start: usize,
+    // Scope depth outside the body; deeper locals are popped when jumping.
+    scope_depth: i32,
+    // Jumps from break statements, patched once the loop's end is known.
+    break_jumps: Vec<usize>,
+    // Try depth outside the body; deeper handlers are popped when jumping.
+    try_depth: usize,
+}
+
+pub struct ClassCompiler {
+    enclosing: Option<Box<ClassCompiler>>,
+}
+
+#[derive(Default, Clone)]
 pub struct Local {
     name: Token,
     depth: i32,
+    is_captured: bool,
+    // Declared with `const`, so it can't be assigned to.
+    constant: bool,
+    // Whether any code reads the variable, for the unused variable warning.
+    read: bool,
+    // A parameter or catch variable, whose value the caller or thrower
+    // supplies, so leaving it unread isn't a mistake.
+    bound: bool,
+}
+
+// A variable captured from an enclosing function: either one of its locals,
+// or one of its own upvalues.
+#[derive(Default, Copy, Clone)]
+pub struct Upvalue {
+    index: u8,
+    is_local: bool,
+    constant: bool,
 }
 
 pub fn new_compiler(function: *mut ObjFunction, function_type: FunctionType) -> Compiler {
     let mut compiler = Compiler{
         enclosing: None,
-        function: function,
-        function_type: function_type,
+        function,
+        function_type,
         
-        locals: [Local::default(); u8::MAX as usize + 1],
-        local_count: 0,
+        locals: Vec::new(),
+        upvalues: [Upvalue::default(); u8::MAX as usize + 1],
         scope_depth: 0,
+        loops: Vec::new(),
+        try_depth: 0,
+        last_call: None,
+        constants: HashMap::new(),
     };
 
-    let local = &mut compiler.locals[0];
-    local.depth = 0;
-    local.name = Token::default();
-    compiler.local_count += 1;
-    return compiler;
+    // Slot 0 holds the function being called, or the receiver in methods,
+    // where `this` resolves to it like any other local.
+    let is_method = compiler.function_type == FunctionType::Method ||
+        compiler.function_type == FunctionType::Initializer;
+    let name = if is_method { synthetic_token("this") } else { Token::default() };
+    compiler.locals.push(Local { name, depth: 0, ..Local::default() });
+    compiler
+}
+
+// A token for a name the compiler refers to that doesn't appear in the
+// source.
+fn synthetic_token(text: &'static str) -> Token {
+    Token {
+        token_type: TokenType::Identifier,
+        source: Rc::from(text),
+        range: 0..text.len(),
+        line: 0,
+        column: 0,
+    }
+}
+
+impl Compiler {
+    // Errors are returned as messages for the parser to report.
+    fn resolve_local(&self, name: &Token) -> Result<Option<u16>, &'static str> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if name.text() == local.name.text() {
+                if local.depth == -1 {
+                    return Err("Cannot read local variable in its own initializer.");
+                }
+                return Ok(Some(i as u16));
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, &'static str> {
+        let enclosing = match &mut self.enclosing {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+        if let Some(local) = enclosing.resolve_local(name)? {
+            // Closures refer to captured locals with a one-byte index.
+            let Ok(index) = u8::try_from(local) else {
+                return Err("Can't capture a local variable past the first 256 in a function.");
+            };
+            enclosing.locals[local as usize].is_captured = true;
+            let constant = enclosing.locals[local as usize].constant;
+            return self.add_upvalue(index, true, constant).map(Some);
+        }
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            let constant = enclosing.upvalues[upvalue as usize].constant;
+            return self.add_upvalue(upvalue, false, constant).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool, constant: bool) -> Result<u8, &'static str> {
+        let function = unsafe { &mut *self.function };
+        let upvalue_count = function.upvalue_count;
+        for i in 0..upvalue_count {
+            let upvalue = &self.upvalues[i];
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return Ok(i as u8);
+            }
+        }
+        if upvalue_count == u8::MAX as usize + 1 {
+            return Err("Too many closure variables in function.");
+        }
+        self.upvalues[upvalue_count] = Upvalue { index, is_local, constant };
+        function.upvalue_count += 1;
+        Ok(upvalue_count as u8)
+    }
 }
 
-pub fn compile(source: String, chunk: Rc<Chunk>, obj_array: &mut ObjArray) -> Option<*const ObjFunction> {
-    let func = obj_array.new_function(chunk);
+// Compile errors are collected as they are found, and returned together if
+// compilation fails. Warnings are returned with the function if it doesn't.
+pub fn compile(source: String, obj_array: &mut ObjArray, globals: &mut Globals, module: *mut ObjModule, print_code: bool, kind: ScriptKind) -> Result<(*const ObjFunction, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let func = obj_array.new_function(Chunk::default());
+    unsafe {
+        (*func).module = module;
+    }
     let mut parser = Parser{
-        compiler: Rc::new(new_compiler(func, FunctionType::Script)),
+        compiler: Box::new(new_compiler(func, FunctionType::Script)),
+        class_compiler: None,
         rules: rules_table(),
         scanner: new_scanner(source),
-        obj_array: obj_array,
+        obj_array,
+        globals,
+        module,
+        diagnostics: Vec::new(),
+        warnings: Vec::new(),
         current: Token::default(),
         previous: Token::default(),
         had_error: false,
         panic_mode: false,
+        print_code,
+        kind,
+        last_expression_end: None,
+        string_literal: None,
+        block_returned: false,
+        nesting: 0,
+        function_nesting: 0,
     };
     parser.advance();
 
     while !parser.match_token(TokenType::EOF) {
         parser.declaration();
     }
+
+    let code_len = parser.current_chunk().code.len();
+    if parser.last_expression_end == Some(code_len) {
+        // Return the value instead of popping it.
+        let chunk = parser.current_chunk();
+        chunk.pop_byte();
+        parser.emit_op(OpCode::Return);
+    }
     
     let func = parser.end_compiler();
     if parser.had_error {
-        return None;
+        return Err(parser.diagnostics);
     }
-    return Some(func);
+    // Unused locals are found at the end of their scope, after anything
+    // inside it, so put the warnings back in source order.
+    parser.warnings.sort_by_key(|warning| (warning.line, warning.column));
+    Ok((func, parser.warnings))
 }
 
 impl Parser<'_> {
@@ -222,7 +449,8 @@ impl Parser<'_> {
             if self.current.token_type != TokenType::Error {
                 break;
             }
-            self.error_at_current("");
+            let message = self.current.text().to_string();
+            self.error_at_current(&message);
         }
     }
 
@@ -243,20 +471,39 @@ impl Parser<'_> {
             return;
         }
         self.panic_mode = true;
-        
-        eprint!("[line {}] Error", token.line);
+        let diagnostic = self.diagnostic(token, Severity::Error, message);
+        self.diagnostics.push(diagnostic);
+        self.had_error = true;
+    }
+
+    // Warnings don't stop compilation, or put the parser in panic mode.
+    fn warning_at(&mut self, token: &Token, message: &str) {
+        let diagnostic = self.diagnostic(token, Severity::Warning, message);
+        self.warnings.push(diagnostic);
+    }
+
+    fn diagnostic(&self, token: &Token, severity: Severity, message: &str) -> Diagnostic {
+        let mut report = String::from(match severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        });
         if token.token_type == TokenType::EOF {
-            eprint!(" at end");
+            report.push_str(" at end");
         } else if token.token_type == TokenType::Error {
-            eprint!(" at '{}'", token.text());
+            // Nothing.
         } else {
-            eprint!(" at '{}'", token.text());
+            report.push_str(&format!(" at '{}'", token.text()));
         }
-        if message != "" {
-            eprint!(": {}", message);
+        if !message.is_empty() {
+            report.push_str(&format!(": {}", message));
+        }
+        Diagnostic {
+            line: token.line as usize,
+            column: token.column,
+            span: self.scanner.span(token),
+            message: report,
+            severity,
         }
-        eprintln!();
-        self.had_error = true;
     }
 
     fn consume(&mut self, token_type: TokenType, message: &str) {
@@ -272,31 +519,47 @@ impl Parser<'_> {
             return false;
         }
         self.advance();
-        return true;
+        true
     }
 
     fn check(&self, token_type: TokenType) -> bool {
-        return self.current.token_type == token_type;
+        self.current.token_type == token_type
     }
 
-    fn emit_byte(&mut self, byte: u8) {
+    fn emit_op(&mut self, op: OpCode) {
         let line = self.previous.line;
-        self.current_chunk().write_chunk(byte, line);
+        self.current_chunk().emit_op(op, line);
+    }
+
+    fn emit_op_u8(&mut self, op: OpCode, operand: u8) {
+        let line = self.previous.line;
+        self.current_chunk().emit_op_u8(op, operand, line);
+    }
+
+    fn emit_op_u16(&mut self, op: OpCode, operand: u16) {
+        let line = self.previous.line;
+        self.current_chunk().emit_op_u16(op, operand, line);
     }
 
     fn current_chunk(&mut self) -> &mut Chunk {
         unsafe {
-            return Rc::get_mut(&mut (*(*self.compiler).function).chunk).unwrap();
+            Arc::get_mut(&mut (*self.compiler.function).chunk).unwrap()
         }
     }
 
     fn end_compiler(&mut self) -> *const ObjFunction {
         self.emit_return();
+        // Function bodies have no end_scope(); their locals live until the
+        // function returns.
+        for slot in 1..self.compiler.locals.len() {
+            self.close_local_symbol(slot);
+            self.check_unused(slot);
+        }
         
-        if DEBUG && !self.had_error {
+        if self.print_code && !self.had_error {
             let mut name = "<script>";
             unsafe {
-                let name_ref = &(*(*self.compiler).function).name.as_ref();
+                let name_ref = &(*self.compiler.function).name.as_ref();
                 if name_ref.is_some() {
                     name = name_ref.unwrap().as_str();
                 }
@@ -305,24 +568,30 @@ impl Parser<'_> {
         }
         
         
-        return self.compiler.function;
+        self.compiler.function
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Nil as u8);
-        self.emit_byte(OpCode::Return as u8);
-    }
-
-    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+        // Initializers always return the new instance.
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_op_u8(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration();
         } else {
             self.statement();
         }
@@ -332,6 +601,43 @@ impl Parser<'_> {
         }
     }
 
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_op_u8(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        let enclosing = self.class_compiler.take();
+        self.class_compiler = Some(Box::new(ClassCompiler { enclosing }));
+
+        // Keep the class on the stack while its methods are attached.
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::Pop);
+
+        self.class_compiler = self.class_compiler.take().unwrap().enclosing;
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        let function_type = if name.text() == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_op_u8(OpCode::Method, constant);
+    }
+
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
@@ -349,8 +655,11 @@ impl Parser<'_> {
 
             match self.current.token_type {
                 TokenType::Class | TokenType::Fun | TokenType::Var |
-                TokenType::For | TokenType::If | TokenType::While |
-                TokenType::Print | TokenType::Return => return,
+                TokenType::Const | TokenType::Import | TokenType::For |
+                TokenType::If | TokenType::While |
+                TokenType::Print | TokenType::Return | TokenType::Switch |
+                TokenType::Break | TokenType::Continue | TokenType::Try |
+                TokenType::Throw => return,
                 _ => (),
             }
 
@@ -359,19 +668,100 @@ impl Parser<'_> {
     }
 
     fn var_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        self.finish_var_declaration();
+    }
+
+    // The rest of a var declaration, after its name.
+    fn finish_var_declaration(&mut self) {
+        let global = self.declare_parsed_variable();
+        self.mark_constant(false);
         if self.match_token(TokenType::Equal) {
             self.expression();
         } else {
-            self.emit_byte(OpCode::Nil as u8);
+            self.emit_op(OpCode::Nil);
         }
         self.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
         self.define_variable(global);
     }
 
+    // Like a var declaration, but the variable must be initialized and
+    // can't be assigned to afterwards.
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        self.mark_constant(true);
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after constant declaration.");
+        self.define_variable(global);
+    }
+
+    // Records whether the variable just parsed is a constant. Globals
+    // keep the flag in the VM's table, so that assignments compiled before
+    // the declaration fail when they run.
+    fn mark_constant(&mut self, constant: bool) {
+        if self.compiler.scope_depth > 0 {
+            if let Some(local) = self.compiler.locals.last_mut() {
+                local.constant = constant;
+            }
+            return;
+        }
+        let name = self.obj_array.copy_string(self.previous.text());
+        let slot = self.globals.slot(name);
+        self.globals.set_constant(slot, constant);
+    }
+
+    // `import "path";` loads the module and binds it to a variable named
+    // after the file, or to the name given with `as`.
+    fn import_declaration(&mut self) {
+        self.consume(TokenType::String, "Expect module path after 'import'.");
+        let text = self.previous.text();
+        let path = text[1..text.len() - 1].to_string();
+        let value = Value::object(self.obj_array.copy_string(&path) as *const Obj);
+        let path_constant = self.make_constant(value);
+
+        let name = if self.check(TokenType::Identifier) && self.current.text() == "as" {
+            self.advance();
+            self.consume(TokenType::Identifier, "Expect module name after 'as'.");
+            self.previous.clone()
+        } else {
+            let stem = std::path::Path::new(&path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+            let valid = stem.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+                stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                self.error("Module file name is not an identifier; name it with 'as'.");
+            }
+            Token {
+                token_type: TokenType::Identifier,
+                source: Rc::from(stem),
+                range: 0..stem.len(),
+                line: self.previous.line,
+                column: 0,
+            }
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+
+        let previous = std::mem::replace(&mut self.previous, name);
+        self.declare_variable();
+        self.mark_constant(false);
+        let global = if self.compiler.scope_depth > 0 {
+            0
+        } else {
+            let name = self.previous.clone();
+            self.identifier_constant(&name)
+        };
+        self.previous = previous;
+        self.emit_op_u8(OpCode::Import, path_constant);
+        self.define_variable(global);
+    }
+
     fn parse_variable(&mut self, error_message: &str) -> u8 {
         self.consume(TokenType::Identifier, error_message);
+        self.declare_parsed_variable()
+    }
 
+    // Declares the variable named by the previous token.
+    fn declare_parsed_variable(&mut self) -> u8 {
         self.declare_variable();
         if self.compiler.scope_depth > 0 {
             return 0;
@@ -380,13 +770,13 @@ impl Parser<'_> {
         let token = std::mem::take(&mut self.previous);
         let result = self.identifier_constant(&token);
         self.previous = token;
-        return result;
+        result
     }
 
     fn identifier_constant(&mut self, name: &Token) -> u8 {
         let text = name.text();
-        let value = self.obj_array.copy_string(&text);
-        return self.make_constant(Value::object(value as *const Obj));
+        let value = Value::object(self.obj_array.copy_string(text) as *const Obj);
+        self.make_constant(value)
     }
 
     fn define_variable(&mut self, global: u8) {
@@ -394,7 +784,7 @@ impl Parser<'_> {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(OpCode::DefineGlobal as u8, global);
+        self.emit_op_u8(OpCode::DefineGlobal, global);
     }
 
     fn argument_list(&mut self) -> u8 {
@@ -412,36 +802,48 @@ impl Parser<'_> {
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after arguments.");
-        return arg_count;
+        arg_count
     }
 
     fn function(&mut self, function_type: FunctionType) {
-        let chunk = Rc::new(Chunk::default());
-        
-        let mut func = self.obj_array.new_function(chunk);
-        let name = self.previous.text();
+        if self.function_nesting == MAX_FUNCTION_NESTING {
+            self.error("Too many nested functions.");
+            // Skip the parameters and the body.
+            self.skip_group();
+            self.skip_group();
+            return;
+        }
+        self.function_nesting += 1;
+        let func = self.obj_array.new_function(Chunk::default());
+        // Anonymous functions are compiled straight after their `fun`.
+        let name = match self.previous.token_type {
+            TokenType::Fun => "anonymous",
+            _ => self.previous.text(),
+        };
         unsafe {
-            (*func).name = self.obj_array.copy_string(&name);
+            (*func).name = self.obj_array.copy_string(name);
+            (*func).module = self.module;
+            (*func).line = self.previous.line as usize;
         }
         
-        let mut compiler = new_compiler(func, function_type);
-        let saved = self.compiler.clone();
-        compiler.enclosing = Some(saved.clone());
-        self.compiler = Rc::new(compiler);
+        let compiler = new_compiler(func, function_type);
+        let enclosing = std::mem::replace(&mut self.compiler, Box::new(compiler));
+        self.compiler.enclosing = Some(enclosing);
         
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
 
         if !self.check(TokenType::RightParen) {
             loop {
-                let function = Rc::get_mut(&mut self.compiler).unwrap().function;
-                let mut f = unsafe { &mut *function };
+                let function = self.compiler.function;
+                let f = unsafe { &mut *function };
                 if f.arity == 255 {
                     self.error_at_current("Can't have more than 255 parameters.");
                 }
                 f.arity += 1;
 
                 let param_constant = self.parse_variable("Expect parameter name.");
+                self.mark_bound();
                 self.define_variable(param_constant);
 
                 if !self.match_token(TokenType::Comma) {
@@ -453,21 +855,49 @@ impl Parser<'_> {
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
+        unsafe { (*self.compiler.function).end_line = self.previous.line as usize };
 
         let function = self.end_compiler();
         
-        self.compiler = saved;
+        let enclosing = self.compiler.enclosing.take().unwrap();
+        let compiler = std::mem::replace(&mut self.compiler, enclosing);
         let constant = self.make_constant(Value::object(function as *const Obj));
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        self.emit_op_u8(OpCode::Closure, constant);
+        // Followed by an operand pair for each variable it captures.
+        let line = self.previous.line;
+        let chunk = self.current_chunk();
+        for upvalue in &compiler.upvalues[..unsafe { (*function).upvalue_count }] {
+            chunk.write_chunk(upvalue.is_local as u8, line);
+            chunk.write_chunk(upvalue.index, line);
+        }
+        self.function_nesting -= 1;
     }
 
     fn mark_initialized(&mut self) {
         if self.compiler.scope_depth == 0 {
             return;
         }
-        let index = self.compiler.local_count - 1;
+        let index = self.compiler.locals.len() - 1;
         let scope_depth = self.compiler.scope_depth;
-        Rc::get_mut(&mut self.compiler).unwrap().locals[index].depth = scope_depth;
+        let local = &mut self.compiler.locals[index];
+        if local.depth != -1 {
+            return;
+        }
+        local.depth = scope_depth;
+        let name = local.name.text().to_string();
+        let start = self.current_chunk().code.len();
+        self.current_chunk().locals.push(LocalSymbol { name, slot: index as u16, start, end: usize::MAX });
+    }
+
+    // Ends the live range of the local in `slot` at the current offset.
+    fn close_local_symbol(&mut self, slot: usize) {
+        let end = self.current_chunk().code.len();
+        let symbol = self.current_chunk().locals.iter_mut()
+            .rev()
+            .find(|symbol| symbol.slot as usize == slot && symbol.end == usize::MAX);
+        if let Some(symbol) = symbol {
+            symbol.end = end;
+        }
     }
 
     fn declare_variable(&mut self) {
@@ -475,8 +905,8 @@ impl Parser<'_> {
             return;
         }
 
-        let name = self.previous;
-        for i in (0..self.compiler.local_count).rev() {
+        let name = self.previous.clone();
+        for i in (0..self.compiler.locals.len()).rev() {
             let local = &self.compiler.locals[i];
             if local.depth != -1 && local.depth < self.compiler.scope_depth {
                 break;
@@ -490,55 +920,202 @@ impl Parser<'_> {
     }
     
     fn add_local(&mut self, name: Token) {
-        if self.compiler.local_count == u8::MAX as usize + 1 {
+        if self.compiler.locals.len() == MAX_LOCALS {
             self.error_at(&name, "Too many local variables in function.");
             return;
         }
 
-        let local_count = self.compiler.local_count;
-        let mut local = &mut Rc::get_mut(&mut self.compiler).unwrap().locals[local_count];
-        local.name = name;
-        local.depth = -1;
-        Rc::get_mut(&mut self.compiler).unwrap().local_count += 1;
+        self.compiler.locals.push(Local { name, depth: -1, ..Local::default() });
+        let function = unsafe { &mut *self.compiler.function };
+        function.max_locals = function.max_locals.max(self.compiler.locals.len());
+    }
+
+    // Marks the local just declared as a parameter or catch variable.
+    fn mark_bound(&mut self) {
+        let name = self.previous.text();
+        if let Some(local) = self.compiler.locals.last_mut().filter(|local| local.name.text() == name) {
+            local.bound = true;
+        }
+    }
+
+    // Warns about the local in `slot`, which is going out of scope, if no
+    // code read it. Closures that capture it count as reading it. Names
+    // starting with an underscore are exempt, as are parameters, catch
+    // variables and the hidden locals the compiler declares for itself.
+    fn check_unused(&mut self, slot: usize) {
+        let local = &self.compiler.locals[slot];
+        let name = local.name.text();
+        if local.read || local.is_captured || local.bound || name.is_empty() || name.starts_with('_') || local.name.line == 0 {
+            return;
+        }
+        let token = local.name.clone();
+        self.warning_at(&token, "Local variable is never read.");
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
         let get_op: OpCode;
         let set_op: OpCode;
         let resolved = self.resolve_local(name);
-        let arg: u8;
-        if resolved.is_some() {
-            arg = resolved.unwrap();
+        let arg: u16;
+        let constant: bool;
+        if let Some(slot) = resolved {
+            arg = slot;
             get_op = OpCode::GetLocal;
             set_op = OpCode::SetLocal;
+            constant = self.compiler.locals[arg as usize].constant;
+        } else if let Some(upvalue) = self.resolve_upvalue(name) {
+            arg = upvalue as u16;
+            get_op = OpCode::GetUpvalue;
+            set_op = OpCode::SetUpvalue;
+            constant = self.compiler.upvalues[arg as usize].constant;
+        } else if let Some(slot) = self.global_slot(name) {
+            arg = slot;
+            get_op = OpCode::GetGlobalSlot;
+            set_op = OpCode::SetGlobalSlot;
+            constant = self.globals.is_constant(slot as usize);
         } else {
-            arg = self.identifier_constant(name);
+            arg = self.identifier_constant(name) as u16;
             get_op = OpCode::GetGlobal;
             set_op = OpCode::SetGlobal;
+            constant = false;
         }
 
         if can_assign && self.match_token(TokenType::Equal) {
+            if constant {
+                self.error("Cannot assign to constant.");
+            }
+            self.expression();
+            self.emit_variable(set_op, arg);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            if constant {
+                self.error("Cannot assign to constant.");
+            }
+            // `a += b` compiles like `a = a + b`.
+            self.mark_read(get_op, arg);
+            self.emit_variable(get_op, arg);
             self.expression();
-            self.emit_bytes(set_op as u8, arg);
+            self.emit_op(op);
+            self.emit_variable(set_op, arg);
         } else {
-            self.emit_bytes(get_op as u8, arg);
+            self.mark_read(get_op, arg);
+            self.emit_variable(get_op, arg);
         }
     }
 
-    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
-        for i in (0..self.compiler.local_count).rev() {
-            let local = &self.compiler.locals[i];
-            if name.text() == local.name.text() {
-                if local.depth == -1 {
-                    self.error("Cannot read local variable in its own initializer.");
-                }
-                return Some(i as u8);
+    fn mark_read(&mut self, get_op: OpCode, arg: u16) {
+        if matches!(get_op, OpCode::GetLocal) {
+            self.compiler.locals[arg as usize].read = true;
+        }
+    }
+
+    // The slot of the global variable `name`, resolved now even if the
+    // variable is only declared later. Globals past the slot operand's
+    // range fall back to being looked up by name.
+    fn global_slot(&mut self, name: &Token) -> Option<u16> {
+        let name = self.obj_array.copy_string(name.text());
+        u16::try_from(self.globals.slot(name)).ok()
+    }
+
+    // Locals past slot 255 are reached with the long forms of OP_GET_LOCAL
+    // and OP_SET_LOCAL, which take a two-byte slot.
+    fn emit_variable(&mut self, op: OpCode, arg: u16) {
+        match op {
+            OpCode::GetGlobalSlot | OpCode::SetGlobalSlot => self.emit_op_u16(op, arg),
+            OpCode::GetLocal if arg > u8::MAX as u16 => self.emit_op_u16(OpCode::GetLocalLong, arg),
+            OpCode::SetLocal if arg > u8::MAX as u16 => self.emit_op_u16(OpCode::SetLocalLong, arg),
+            _ => self.emit_op_u8(op, arg as u8),
+        }
+    }
+
+    // Emits one literal part of an interpolated string, joined to the
+    // parts before it.
+    fn emit_string_part(&mut self, text: &str, parts: &mut usize) {
+        if text.is_empty() {
+            return;
+        }
+        let value = self.obj_array.copy_string(text);
+        self.emit_constant(Value::object(value as *const Obj));
+        if *parts > 0 {
+            self.emit_op(OpCode::Add);
+        }
+        *parts += 1;
+    }
+
+    // Consumes a compound assignment operator such as `+=`, returning the
+    // arithmetic it applies.
+    fn compound_assignment(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.current.token_type {
+            TokenType::PlusEqual => OpCode::Add,
+            TokenType::MinusEqual => OpCode::Subtract,
+            TokenType::StarEqual => OpCode::Multiply,
+            TokenType::SlashEqual => OpCode::Divide,
+            TokenType::PercentEqual => OpCode::Modulo,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn resolve_local(&mut self, name: &Token) -> Option<u16> {
+        match self.compiler.resolve_local(name) {
+            Ok(slot) => slot,
+            Err(message) => {
+                self.error(message);
+                Some(0)
+            }
+        }
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        match self.compiler.resolve_upvalue(name) {
+            Ok(index) => index,
+            Err(message) => {
+                self.error(message);
+                Some(0)
             }
         }
-        return None;
+    }
+
+    // Counts one more level of nesting. Past the limit, it reports an error
+    // and skips the code that is too deep.
+    fn enter_nesting(&mut self) -> bool {
+        if self.nesting == MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.skip_group();
+            return false;
+        }
+        self.nesting += 1;
+        true
+    }
+
+    // Skips the current token, along with everything up to its closing
+    // bracket if it is an opening one. That leaves the parser where the
+    // enclosing code continues, so it leaves panic mode too; synchronizing
+    // would skip the closing brackets of every enclosing block.
+    fn skip_group(&mut self) {
+        let mut depth = 0usize;
+        loop {
+            match self.current.token_type {
+                TokenType::EOF => break,
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.advance();
+            if depth == 0 {
+                break;
+            }
+        }
+        self.panic_mode = false;
     }
 
     fn statement(&mut self) {
+        if !self.enter_nesting() {
+            return;
+        }
         if self.match_token(TokenType::Print) {
             self.print_statement();
         } else if self.match_token(TokenType::If) {
@@ -549,6 +1126,16 @@ impl Parser<'_> {
             self.while_statement();
         } else if self.match_token(TokenType::For) {
             self.for_statement();
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -556,6 +1143,7 @@ impl Parser<'_> {
         } else {
             self.expression_statement();
         }
+        self.nesting -= 1;
     }
 
     fn for_statement(&mut self) {
@@ -564,7 +1152,13 @@ impl Parser<'_> {
         if self.match_token(TokenType::Semicolon) {
             // No initializer.
         } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            // `in` is only a keyword here, so it can still name variables.
+            if self.check(TokenType::Identifier) && self.current.text() == "in" {
+                let name = self.previous.clone();
+                return self.for_in_statement(name);
+            }
+            self.finish_var_declaration();
         } else {
             self.expression_statement();
         }
@@ -572,18 +1166,20 @@ impl Parser<'_> {
         let mut loop_start = self.current_chunk().code.len();
         let mut exit_jump = None;
         if !self.match_token(TokenType::Semicolon) {
+            let condition = self.current.clone();
             self.expression();
+            self.check_constant_condition(loop_start, &condition, true);
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
 
-            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse as u8));
-            self.emit_byte(OpCode::Pop as u8);
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
         }
 
         if !self.match_token(TokenType::RightParen) {
-            let body_jump = self.emit_jump(OpCode::Jump as u8);
+            let body_jump = self.emit_jump(OpCode::Jump);
             let increment_start = self.current_chunk().code.len();
             self.expression();
-            self.emit_byte(OpCode::Pop as u8);
+            self.emit_op(OpCode::Pop);
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
 
             self.emit_loop(loop_start);
@@ -591,40 +1187,241 @@ impl Parser<'_> {
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         if let Some(exit_jump) = exit_jump {
             self.patch_jump(exit_jump);
-            self.emit_byte(OpCode::Pop as u8);
+            self.emit_op(OpCode::Pop);
         }
+        self.end_loop();
 
         self.end_scope();
     }
 
+    // `for (var x in collection)` runs the body with x bound to each item
+    // of a list, each key of a map or each character of a string, in a
+    // fresh variable each time. The collection and the position in it live
+    // in hidden locals below x, which OP_ITER_NEXT reads and updates.
+    fn for_in_statement(&mut self, name: Token) {
+        self.advance();
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+        self.emit_op_u8(OpCode::SmallInt, 0);
+        for _ in 0..2 {
+            self.add_local(synthetic_token(""));
+            let slot = self.compiler.locals.len() - 1;
+            self.compiler.locals[slot].depth = self.compiler.scope_depth;
+        }
+
+        let loop_start = self.current_chunk().code.len();
+        let exit_jump = self.emit_jump(OpCode::IterNext);
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.end_loop();
+        self.end_scope();
+    }
+
+    // Cases are tested in order and only the first match runs; there is no
+    // fall-through. The default case, if any, must come last.
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.begin_scope();
+        self.expression();
+        // The value lives in a local without a name, so case bodies can
+        // declare locals of their own above it.
+        self.add_local(synthetic_token(""));
+        let subject = self.compiler.locals.len() - 1;
+        self.compiler.locals[subject].depth = self.compiler.scope_depth;
+        self.consume(TokenType::RightParen, "Expect ')' after value.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.match_token(TokenType::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+                self.emit_variable(OpCode::GetLocal, subject as u16);
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+                self.emit_op(OpCode::Equal);
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+                self.emit_op(OpCode::Pop);
+            } else if self.match_token(TokenType::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
+                has_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                self.advance();
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // Each case's statements get their own scope, ending at the next case.
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case) && !self.check(TokenType::Default) &&
+            !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.current.clone();
         self.expression();
+        self.check_constant_condition(loop_start, &condition, true);
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
-        self.emit_byte(OpCode::Pop as u8);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop as u8);
+        self.emit_op(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = self.compiler.scope_depth;
+        let try_depth = self.compiler.try_depth;
+        self.compiler.loops.push(Loop { start, scope_depth, break_jumps: Vec::new(), try_depth });
+    }
+
+    // Sends the loop's break statements to the current offset.
+    fn end_loop(&mut self) {
+        if let Some(finished) = self.compiler.loops.pop() {
+            for jump in finished.break_jumps {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    // Emits the pops for locals declared inside the innermost loop's body,
+    // without ending their scopes; code after the jump still uses them.
+    fn discard_loop_locals(&mut self, scope_depth: i32) {
+        let mut pops = 0;
+        for i in (0..self.compiler.locals.len()).rev() {
+            if self.compiler.locals[i].depth <= scope_depth {
+                break;
+            }
+            if self.compiler.locals[i].is_captured {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                pops += 1;
+            }
+        }
+        self.emit_pops(pops);
+    }
+
+    // Emits the pops for handlers of try blocks inside the innermost loop's
+    // body, which a jump out of the body leaves.
+    fn discard_loop_handlers(&mut self, try_depth: usize) {
+        for _ in try_depth..self.compiler.try_depth {
+            self.emit_op(OpCode::PopHandler);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let (scope_depth, try_depth) = match self.compiler.loops.last() {
+            Some(innermost) => (innermost.scope_depth, innermost.try_depth),
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                return;
+            }
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+        self.discard_loop_locals(scope_depth);
+        self.discard_loop_handlers(try_depth);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.compiler.loops.last_mut().unwrap().break_jumps.push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        let (start, scope_depth, try_depth) = match self.compiler.loops.last() {
+            Some(innermost) => (innermost.start, innermost.scope_depth, innermost.try_depth),
+            None => {
+                self.error("Can't use 'continue' outside of a loop.");
+                return;
+            }
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+        self.discard_loop_locals(scope_depth);
+        self.discard_loop_handlers(try_depth);
+        self.emit_loop(start);
+    }
+
+    // The handler pushed before the body sends errors raised in it to the
+    // catch clause, with the error's value pushed as the clause's variable.
+    fn try_statement(&mut self) {
+        let handler = self.emit_jump(OpCode::PushHandler);
+        self.compiler.try_depth += 1;
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.compiler.try_depth -= 1;
+        self.emit_op(OpCode::PopHandler);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(handler);
+        if !self.match_token(TokenType::Catch) {
+            self.error_at_current("Expect 'catch' after try block.");
+            return;
+        }
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.begin_scope();
+        self.consume(TokenType::Identifier, "Expect error variable name.");
+        self.declare_variable();
+        self.mark_bound();
+        self.mark_initialized();
+        self.consume(TokenType::RightParen, "Expect ')' after error variable.");
+        self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+        self.block();
+        self.end_scope();
+        self.patch_jump(end_jump);
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_op(OpCode::Throw);
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_byte(OpCode::Loop as u8);
-        let offset = self.current_chunk().code.len() - loop_start + 2;
-        if offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+        let line = self.previous.line;
+        if let Err(message) = self.current_chunk().emit_loop(loop_start, line) {
+            self.error(message);
         }
-        self.emit_byte((offset >> 8) as u8);
-        self.emit_byte((offset & 0xff) as u8);
     }
 
     fn return_statement(&mut self) {
@@ -635,24 +1432,61 @@ impl Parser<'_> {
         if self.match_token(TokenType::Semicolon) {
             self.emit_return();
         } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
-            self.emit_byte(OpCode::Return as u8);
+            // A call whose result is returned straight away can reuse the
+            // frame, unless a try block has to catch its errors.
+            let len = self.current_chunk().code.len();
+            if let Some(call) = self.compiler.last_call.filter(|_| self.compiler.try_depth == 0) {
+                let code = &mut self.current_chunk().code;
+                if call + 2 == len && code[call] == OpCode::Call as u8 {
+                    code[call] = OpCode::TailCall as u8;
+                } else if call + 3 == len && code[call] == OpCode::Invoke as u8 {
+                    code[call] = OpCode::TailInvoke as u8;
+                }
+            }
+            self.emit_op(OpCode::Return);
         }
     }
 
+    // Warns about a condition whose code, starting at `start`, is a single
+    // literal, so the branch or loop it controls never varies. Loops on a
+    // true literal are left alone, as that's how an endless loop is written.
+    fn check_constant_condition(&mut self, start: usize, condition: &Token, is_loop: bool) {
+        let code = &self.current_chunk().code[start..];
+        let value = match code {
+            [op] if *op == OpCode::True as u8 => true,
+            [op] if *op == OpCode::False as u8 || *op == OpCode::Nil as u8 => false,
+            // Numbers and strings, which are always true.
+            [op, _] if *op == OpCode::Constant as u8 || *op == OpCode::SmallInt as u8 => true,
+            [op, _, _, _] if *op == OpCode::ConstantLong as u8 => true,
+            _ => return,
+        };
+        if is_loop && value {
+            return;
+        }
+        let message = if value { "Condition is always true." } else { "Condition is always false." };
+        self.warning_at(condition, message);
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.current.clone();
+        let start = self.current_chunk().code.len();
         self.expression();
+        self.check_constant_condition(start, &condition, false);
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
-        self.emit_byte(OpCode::Pop as u8);
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
         self.statement();
 
-        let else_jump = self.emit_jump(OpCode::Jump as u8);
+        let else_jump = self.emit_jump(OpCode::Jump);
         self.patch_jump(then_jump);
-        self.emit_byte(OpCode::Pop as u8);
+        self.emit_op(OpCode::Pop);
 
         if self.match_token(TokenType::Else) {
             self.statement();
@@ -661,78 +1495,175 @@ impl Parser<'_> {
     }
 
     fn patch_jump(&mut self, offset: usize) {
-        let jump = self.current_chunk().code.len() - offset - 2;
-        if jump > u16::MAX as usize {
-            self.error("Too much code to jump over.");
+        // The literal before the jump target may not be the only way there.
+        self.string_literal = None;
+        if let Err(message) = self.current_chunk().patch_jump(offset) {
+            self.error(message);
         }
-        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
-    fn emit_jump(&mut self, instruction: u8) -> usize {
-        self.emit_byte(instruction);
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
-        return self.current_chunk().code.len() - 2;
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        let line = self.previous.line;
+        self.current_chunk().emit_jump(op, line)
     }
 
     fn block(&mut self) {
+        // Only the first statement after a return is reported.
+        let mut returned = false;
+        let mut warned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if returned && !warned {
+                let token = self.current.clone();
+                self.warning_at(&token, "Unreachable code.");
+                warned = true;
+            }
+            let nested = self.check(TokenType::LeftBrace);
+            returned |= self.check(TokenType::Return);
             self.declaration();
+            returned |= nested && self.block_returned;
         }
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        self.block_returned = returned;
     }
 
     fn begin_scope(&mut self) {
-        Rc::get_mut(&mut self.compiler).unwrap().scope_depth += 1;
+        self.compiler.scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        Rc::get_mut(&mut self.compiler).unwrap().scope_depth -= 1;
+        self.compiler.scope_depth -= 1;
+
+        let mut pops = 0;
+        while self.compiler.locals.last().is_some_and(|local| local.depth > self.compiler.scope_depth) {
+            let slot = self.compiler.locals.len() - 1;
+            self.close_local_symbol(slot);
+            self.check_unused(slot);
+            if self.compiler.locals[slot].is_captured {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                pops += 1;
+            }
+            self.compiler.locals.pop();
+        }
+        self.emit_pops(pops);
+    }
 
-        while self.compiler.local_count > 0 &&
-            self.compiler.locals[self.compiler.local_count - 1].depth > self.compiler.scope_depth {
-            self.emit_byte(OpCode::Pop as u8);
-            Rc::get_mut(&mut self.compiler).unwrap().local_count -= 1;
+    // Pops `count` values, with OP_POP_N for more than one so the VM
+    // dispatches a single instruction.
+    fn emit_pops(&mut self, count: usize) {
+        let mut count = count;
+        while count > 1 {
+            let n = count.min(u8::MAX as usize);
+            self.emit_op_u8(OpCode::PopN, n as u8);
+            count -= n;
+        }
+        for _ in 0..count {
+            self.emit_op(OpCode::Pop);
         }
     }
 
     fn expression_statement(&mut self) {
         self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Pop as u8);
+        let top_level = self.kind != ScriptKind::File && self.compiler.function_type == FunctionType::Script && self.compiler.scope_depth == 0;
+        if top_level && self.check(TokenType::EOF) {
+            // A bare expression ending REPL input is echoed, so `1 + 2`
+            // prints 3.
+            if self.kind == ScriptKind::Repl {
+                self.emit_op(OpCode::Dup);
+                self.emit_op(OpCode::Print);
+            }
+        } else {
+            self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        }
+        self.emit_op(OpCode::Pop);
+        if top_level {
+            self.last_expression_end = Some(self.current_chunk().code.len());
+        }
     }
 
     fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Print as u8);
+        self.emit_op(OpCode::Print);
     }
     
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    // Values past the first 256 constants are loaded with OP_CONSTANT_LONG
+    // and a 24-bit index.
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        let constant = self.add_constant(value, (1 << 24) - 1);
+        if constant <= u8::MAX as usize {
+            self.emit_op_u8(OpCode::Constant, constant as u8);
+        } else if constant < 1 << 24 {
+            let line = self.previous.line;
+            self.current_chunk().emit_op_u24(OpCode::ConstantLong, constant as u32, line);
+        } else {
+            self.error("Too many constants in one chunk.");
+        }
+    }
+
+    // Returns the index of `value` in the constant table, reusing an equal
+    // number or string already there if its index is at most `max`.
+    fn add_constant(&mut self, value: Value, max: usize) -> usize {
+        let key = ConstantKey::of(value);
+        if let Some(&index) = key.as_ref().and_then(|key| self.compiler.constants.get(key)) {
+            if index <= max {
+                return index;
+            }
+        }
+        let index = self.current_chunk().add_constant(value);
+        if let Some(key) = key {
+            self.compiler.constants.entry(key).or_insert(index);
+        }
+        index
+    }
+
+    fn emit_string_literal(&mut self, value: *const ObjString) {
+        let start = self.current_chunk().code.len();
+        self.emit_constant(Value::object(value as *const Obj));
+        self.string_literal = Some((self.compiler.function, start, value));
+    }
+
+    // The offset and value of the string literal that the code ends with,
+    // if it does.
+    fn last_string_literal(&mut self) -> Option<(usize, *const ObjString)> {
+        let (function, start, value) = self.string_literal?;
+        if function != self.compiler.function {
+            return None;
+        }
+        let code = &self.current_chunk().code;
+        let len = match OpCode::try_from(*code.get(start)?) {
+            Ok(OpCode::Constant) => 2,
+            _ => 4,
+        };
+        (start + len == code.len()).then_some((start, value))
     }
 
+    // For instructions with a one-byte constant operand, such as names and
+    // functions.
     fn make_constant(&mut self, value: Value) -> u8 {
-        let chunk = self.current_chunk();
-        let constant = chunk.add_constant(value);
-        if constant > usize::MAX {
+        let constant = self.add_constant(value, u8::MAX as usize);
+        if constant > u8::MAX as usize {
             self.error("Too many constants in one chunk.");
             return 0;
         }
-        return constant as u8;
+        constant as u8
     }
     
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter_nesting() {
+            return;
+        }
         self.advance();
         let prefix_rule = self.rules[self.previous.token_type as usize].prefix;
         if prefix_rule.is_none() {
             self.error("Expect expression.");
+            self.nesting -= 1;
             return;
         }
         let can_assign = precedence <= Precedence::Assignment;
@@ -743,14 +1674,16 @@ impl Parser<'_> {
             let infix_rule = self.get_rule(self.previous.token_type).infix;
             if infix_rule.is_none() {
                 self.error("Expect expression.");
+                self.nesting -= 1;
                 return;
             }
             infix_rule.unwrap()(self, can_assign);
         }
 
-        if can_assign && self.match_token(TokenType::Equal) {
+        if can_assign && (self.match_token(TokenType::Equal) || self.compound_assignment(true).is_some()) {
             self.error("Invalid assignment target.");
         }
+        self.nesting -= 1;
     }
 
     fn get_rule(&self, token_type: TokenType) -> &ParseRule {
@@ -758,25 +1691,143 @@ impl Parser<'_> {
     }
 }
 
+// An anonymous function, as in `list.map(fun (x) { return x * 2; })`. A
+// statement starting with `fun` is always a declaration, so calling one
+// straight away needs parentheses: `(fun () { ... })();`.
+fn fun_expression(parser: &mut Parser, _can_assign: bool) {
+    parser.function(FunctionType::Function);
+}
+
 fn and_(parser: &mut Parser, _can_assign: bool) {
-    let end_jump = parser.emit_jump(OpCode::JumpIfFalse as u8);
-    parser.emit_byte(OpCode::Pop as u8);
+    let end_jump = parser.emit_jump(OpCode::JumpIfFalse);
+    parser.emit_op(OpCode::Pop);
     parser.parse_precedence(Precedence::And);
     parser.patch_jump(end_jump);
 }
 
 fn or_(parser: &mut Parser, _can_assign: bool) {
-    let else_jump = parser.emit_jump(OpCode::JumpIfFalse as u8);
-    let end_jump = parser.emit_jump(OpCode::Jump as u8);
+    let else_jump = parser.emit_jump(OpCode::JumpIfFalse);
+    let end_jump = parser.emit_jump(OpCode::Jump);
     parser.patch_jump(else_jump);
-    parser.emit_byte(OpCode::Pop as u8);
+    parser.emit_op(OpCode::Pop);
     parser.parse_precedence(Precedence::Or);
     parser.patch_jump(end_jump);
 }
 
 fn call(parser: &mut Parser, _can_assign: bool) {
     let arg_count = parser.argument_list();
-    parser.emit_bytes(OpCode::Call as u8, arg_count);
+    parser.compiler.last_call = Some(parser.current_chunk().code.len());
+    parser.emit_op_u8(OpCode::Call, arg_count);
+}
+
+fn dot(parser: &mut Parser, can_assign: bool) {
+    parser.consume(TokenType::Identifier, "Expect property name after '.'.");
+    let previous = parser.previous.clone();
+    let name = parser.identifier_constant(&previous);
+
+    if can_assign && parser.match_token(TokenType::Equal) {
+        parser.expression();
+        parser.emit_op_u8(OpCode::SetProperty, name);
+    } else if let Some(op) = parser.compound_assignment(can_assign) {
+        // Keep the instance for OP_SET_PROPERTY while reading the field.
+        parser.emit_op(OpCode::Dup);
+        parser.emit_op_u8(OpCode::GetProperty, name);
+        parser.expression();
+        parser.emit_op(op);
+        parser.emit_op_u8(OpCode::SetProperty, name);
+    } else if parser.match_token(TokenType::LeftParen) {
+        // Calling a method directly skips creating a bound method.
+        let arg_count = parser.argument_list();
+        let line = parser.previous.line;
+        parser.compiler.last_call = Some(parser.current_chunk().code.len());
+        parser.current_chunk().emit_op_u8_u8(OpCode::Invoke, name, arg_count, line);
+    } else {
+        parser.emit_op_u8(OpCode::GetProperty, name);
+    }
+}
+
+fn this_(parser: &mut Parser, _can_assign: bool) {
+    if parser.class_compiler.is_none() {
+        parser.error("Can't use 'this' outside of a class.");
+        return;
+    }
+    variable(parser, false);
+}
+
+// `yield value` pauses the coroutine running it, handing the value to the
+// resume() call, and evaluates to the value the next resume() passes in.
+// A bare `yield` yields nil.
+fn yield_(parser: &mut Parser, _can_assign: bool) {
+    if parser.compiler.function_type == FunctionType::Script {
+        parser.error("Can't yield from top-level code.");
+    }
+    match parser.current.token_type {
+        TokenType::Semicolon | TokenType::RightParen | TokenType::RightBracket |
+        TokenType::RightBrace | TokenType::Comma | TokenType::Colon | TokenType::EOF => {
+            parser.emit_op(OpCode::Nil);
+        }
+        _ => parser.parse_precedence(Precedence::Assignment),
+    }
+    parser.emit_op(OpCode::Yield);
+}
+
+fn list(parser: &mut Parser, _can_assign: bool) {
+    let mut item_count = 0;
+    if !parser.check(TokenType::RightBracket) {
+        loop {
+            parser.expression();
+            if item_count == 255 {
+                parser.error("Can't have more than 255 items in a list literal.");
+            }
+            item_count += 1;
+            if !parser.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    parser.consume(TokenType::RightBracket, "Expect ']' after list items.");
+    parser.emit_op_u8(OpCode::BuildList, item_count as u8);
+}
+
+// A '{' that starts a statement opens a block, so map literals only parse
+// where an expression is expected.
+fn map(parser: &mut Parser, _can_assign: bool) {
+    let mut entry_count = 0;
+    if !parser.check(TokenType::RightBrace) {
+        loop {
+            parser.expression();
+            parser.consume(TokenType::Colon, "Expect ':' after map key.");
+            parser.expression();
+            if entry_count == 255 {
+                parser.error("Can't have more than 255 entries in a map literal.");
+            }
+            entry_count += 1;
+            if !parser.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+    }
+    parser.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+    parser.emit_op_u8(OpCode::BuildMap, entry_count as u8);
+}
+
+fn index(parser: &mut Parser, can_assign: bool) {
+    parser.expression();
+    parser.consume(TokenType::RightBracket, "Expect ']' after index.");
+    if can_assign && parser.match_token(TokenType::Equal) {
+        parser.expression();
+        parser.emit_op(OpCode::IndexSet);
+    } else if let Some(op) = parser.compound_assignment(can_assign) {
+        // Keep the collection and index for OP_INDEX_SET while reading the
+        // element.
+        parser.emit_op(OpCode::Dup2);
+        parser.emit_op(OpCode::IndexGet);
+        parser.expression();
+        parser.emit_op(op);
+        parser.emit_op(OpCode::IndexSet);
+    } else {
+        parser.emit_op(OpCode::IndexGet);
+    }
 }
 
 fn grouping(parser: &mut Parser, _can_assign: bool) {
@@ -785,27 +1836,61 @@ fn grouping(parser: &mut Parser, _can_assign: bool) {
 }
 
 fn variable(parser: &mut Parser, can_assign: bool) {
-    let previous = std::mem::take(&mut parser.previous);
+    // Clone rather than take the token: emitting reads its line number.
+    let previous = parser.previous.clone();
     parser.named_variable(&previous, can_assign);
-    parser.previous = previous;
 }
 
 fn number(parser: &mut Parser, _can_assign: bool) {
-    let value = parser.previous.text().parse::<f64>().unwrap();
+    // The scanner only accepts underscores between digits, as separators.
+    let value = parser.previous.text().replace('_', "").parse::<f64>().unwrap();
+    // Small integers are common enough to get an opcode that carries the
+    // value in its operand instead of a constant table entry.
+    if value.fract() == 0.0 && (0.0..=255.0).contains(&value) {
+        parser.emit_op_u8(OpCode::SmallInt, value as u8);
+        return;
+    }
     parser.emit_constant(Value::number(value));
 }
 
 fn string(parser: &mut Parser, _can_assign: bool) {
     let text = parser.previous.text();
     let value = parser.obj_array.copy_string(&text[1..text.len() - 1]);
-    parser.emit_constant(Value::object(value as *const Obj));
+    parser.emit_string_literal(value);
+}
+
+// "a ${b} c" compiles like "a " + b + " c", with b converted to a string
+// the way print would show it. Empty parts are left out.
+fn interpolation(parser: &mut Parser, _can_assign: bool) {
+    let mut parts = 0;
+    loop {
+        // The part between the opening '"' or '}' and the "${".
+        let text = parser.previous.text().to_string();
+        parser.emit_string_part(&text[1..text.len() - 2], &mut parts);
+        parser.expression();
+        parser.emit_op(OpCode::ToString);
+        if parts > 0 {
+            parser.emit_op(OpCode::Add);
+        }
+        parts += 1;
+        if !parser.match_token(TokenType::Interpolation) {
+            break;
+        }
+    }
+    if !parser.check(TokenType::String) {
+        parser.error_at_current("Expect '}' after interpolated expression.");
+        return;
+    }
+    parser.advance();
+    let text = parser.previous.text().to_string();
+    parser.emit_string_part(&text[1..text.len() - 1], &mut parts);
 }
 
 fn literal(parser: &mut Parser, _can_assign: bool) {
     match parser.previous.token_type {
-        TokenType::False => parser.emit_byte(OpCode::False.into()),
-        TokenType::Nil => parser.emit_byte(OpCode::Nil.into()),
-        TokenType::True => parser.emit_byte(OpCode::True.into()),
+        TokenType::False => parser.emit_op(OpCode::False),
+        TokenType::Nil => parser.emit_op(OpCode::Nil),
+        TokenType::True => parser.emit_op(OpCode::True),
         _ => unreachable!(),
     }
 }
@@ -815,14 +1900,15 @@ fn unary(parser: &mut Parser, _can_assign: bool) {
     parser.parse_precedence(Precedence::Unary);
     
     match operator_type {
-        TokenType::Minus => parser.emit_byte(OpCode::Negate as u8),
-        TokenType::Bang => parser.emit_byte(OpCode::Not as u8),
+        TokenType::Minus => parser.emit_op(OpCode::Negate),
+        TokenType::Bang => parser.emit_op(OpCode::Not),
         _ => unreachable!(),
     }
 }
 
 fn binary(parser: &mut Parser, _can_assign: bool) {
     let operator_type = parser.previous.token_type;
+    let left = parser.last_string_literal();
     let rule = parser.get_rule(operator_type);
 
     let p: u8 = rule.precedence.into();
@@ -830,21 +1916,37 @@ fn binary(parser: &mut Parser, _can_assign: bool) {
         Precedence::try_from(p + 1).unwrap());
     
     match operator_type {
-        TokenType::Plus => parser.emit_byte(OpCode::Add.into()),
-        TokenType::Minus => parser.emit_byte(OpCode::Subtract.into()),
-        TokenType::Star => parser.emit_byte(OpCode::Multiply.into()),
-        TokenType::Slash => parser.emit_byte(OpCode::Divide.into()),
+        TokenType::Plus => {
+            // Two string literals are joined here rather than each time
+            // the code runs.
+            match (left, parser.last_string_literal()) {
+                (Some((start, a)), Some((_, b))) => {
+                    let joined = unsafe { format!("{}{}", (*a).as_str(), (*b).as_str()) };
+                    parser.current_chunk().truncate(start);
+                    let value = parser.obj_array.take_string(joined);
+                    parser.emit_string_literal(value);
+                }
+                _ => parser.emit_op(OpCode::Add),
+            }
+        }
+        TokenType::Minus => parser.emit_op(OpCode::Subtract),
+        TokenType::Star => parser.emit_op(OpCode::Multiply),
+        TokenType::Slash => parser.emit_op(OpCode::Divide),
+        TokenType::Percent => parser.emit_op(OpCode::Modulo),
         TokenType::BangEqual => {
-            parser.emit_bytes(OpCode::Equal.into(), OpCode::Not.into());
+            parser.emit_op(OpCode::Equal);
+            parser.emit_op(OpCode::Not);
         },
-        TokenType::EqualEqual => parser.emit_byte(OpCode::Equal.into()),
-        TokenType::Greater => parser.emit_byte(OpCode::Greater.into()),
+        TokenType::EqualEqual => parser.emit_op(OpCode::Equal),
+        TokenType::Greater => parser.emit_op(OpCode::Greater),
         TokenType::GreaterEqual => {
-            parser.emit_bytes(OpCode::Less.into(), OpCode::Not.into());
+            parser.emit_op(OpCode::Less);
+            parser.emit_op(OpCode::Not);
         },
-        TokenType::Less => parser.emit_byte(OpCode::Less.into()),
+        TokenType::Less => parser.emit_op(OpCode::Less),
         TokenType::LessEqual => {
-            parser.emit_bytes(OpCode::Greater.into(), OpCode::Not.into());
+            parser.emit_op(OpCode::Greater);
+            parser.emit_op(OpCode::Not);
         },
         _ => unreachable!(),
     }
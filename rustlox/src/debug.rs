@@ -2,130 +2,628 @@
 
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
+use crate::scanner::scan_tokens;
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    print!("{:16}\n", name);
+fn simple_instruction(out: &mut String, name: &str, offset: usize) -> usize {
+    let _ = writeln!(out, "{}", name);
     offset + 1
 }
 
-fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn constant_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
     let constant = chunk.code[offset + 1];
-    print!("{:16} {:4} '", name, constant);
-    chunk.constants.values[constant as usize].print();
-    print!("'\n");
+    let _ = writeln!(out, "{:16} {:4} '{}'", name, constant, chunk.constants.values[constant as usize].display());
     offset + 2
 }
 
-fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+fn constant_long_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = long_operand(chunk, offset);
+    let _ = writeln!(out, "{:16} {:4} '{}'", name, constant, chunk.constants.values[constant].display());
+    offset + 4
+}
+
+// The 24-bit constant index of the OP_CONSTANT_LONG at `offset`.
+fn long_operand(chunk: &Chunk, offset: usize) -> usize {
+    (chunk.code[offset + 1] as usize) << 16 |
+        (chunk.code[offset + 2] as usize) << 8 |
+        chunk.code[offset + 3] as usize
+}
+
+fn byte_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
     let slot = chunk.code[offset + 1];
-    print!("{:16} {:4}\n", name, slot);
+    let _ = writeln!(out, "{:16} {:4}", name, slot);
     offset + 2
 }
 
-fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usize {
+// OP_GET_LOCAL or OP_SET_LOCAL, or their long forms when `wide`, with the
+// variable's name when the chunk has debug symbols for it.
+fn local_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize, wide: bool) -> usize {
+    let slot = if wide {
+        (chunk.code[offset + 1] as u16) << 8 | chunk.code[offset + 2] as u16
+    } else {
+        chunk.code[offset + 1] as u16
+    };
+    let _ = match chunk.locals_at(offset).find(|local| local.slot == slot) {
+        Some(local) => writeln!(out, "{:16} {:4} '{}'", name, slot, local.name),
+        None => writeln!(out, "{:16} {:4}", name, slot),
+    };
+    offset + if wide { 3 } else { 2 }
+}
+
+// An instruction with a two-byte operand, such as a global's slot.
+fn short_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let operand = (chunk.code[offset + 1] as usize) << 8 | chunk.code[offset + 2] as usize;
+    let _ = writeln!(out, "{:16} {:4}", name, operand);
+    offset + 3
+}
+
+fn invoke_instruction(out: &mut String, name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let arg_count = chunk.code[offset + 2];
+    let _ = writeln!(out, "{:16} ({} args) {:4} '{}'",
+        name, arg_count, constant, chunk.constants.values[constant as usize].display());
+    offset + 3
+}
+
+fn closure_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    let constant = chunk.code[offset + 1];
+    let function = chunk.constants.values[constant as usize];
+    let _ = writeln!(out, "{:16} {:4} {}", "OP_CLOSURE", constant, function.display());
+
+    let mut offset = offset + 2;
+    for _ in 0..unsafe { (*function.as_function()).upvalue_count } {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        let _ = writeln!(out, "{:04}    |                     {} {}",
+            offset, if is_local == 1 { "local" } else { "upvalue" }, index);
+        offset += 2;
+    }
+    offset
+}
+
+// Signed distance and absolute target of the jump at `offset`.
+fn jump_target(sign: i32, chunk: &Chunk, offset: usize) -> (i32, usize) {
     let jump = ((chunk.code[offset + 1] as i32) << 8) | chunk.code[offset + 2] as i32;
-    print!("{:16} {:4} -> {}\n", name, offset, (offset as i32) + 3 + (sign * jump));
+    (sign * jump, ((offset as i32) + 3 + (sign * jump)) as usize)
+}
+
+fn jump_instruction(out: &mut String, name: &str, sign: i32, chunk: &Chunk, offset: usize, labels: &Labels) -> usize {
+    let (distance, target) = jump_target(sign, chunk, offset);
+    let _ = match labels.get(&target) {
+        Some(label) => writeln!(out, "{:16} {:+4} -> L{}", name, distance, label),
+        None => writeln!(out, "{:16} {:+4} -> {}", name, distance, target),
+    };
     offset + 3
 }
 
+// Label numbers for jump targets, keyed by bytecode offset.
+type Labels = BTreeMap<usize, usize>;
+
+/// Numbers every jump and loop target in `chunk` as L1, L2, ... in offset
+/// order.
+pub fn jump_labels(chunk: &Chunk) -> BTreeMap<usize, usize> {
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = match OpCode::try_from(chunk.code[offset]) {
+            Ok(op) => op,
+            Err(_) => {
+                offset += 1;
+                continue;
+            }
+        };
+        match op {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler | OpCode::IterNext => targets.push(jump_target(1, chunk, offset).1),
+            OpCode::Loop => targets.push(jump_target(-1, chunk, offset).1),
+            _ => {}
+        }
+        offset += 1 + operand_len(chunk, offset, &op);
+    }
+    targets.sort_unstable();
+    targets.dedup();
+    targets.into_iter().enumerate().map(|(i, target)| (target, i + 1)).collect()
+}
+
+/// Prints the instruction at `offset` and returns the offset of the next
+/// one.
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
+    let mut out = String::new();
+    let next = write_instruction(&mut out, chunk, offset);
+    print!("{}", out);
+    next
+}
+
+/// Like [`disassemble_instruction`], but appends the text to `out`.
+pub fn write_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    disassemble_labeled(out, chunk, offset, &Labels::new())
+}
 
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
+fn disassemble_labeled(out: &mut String, chunk: &Chunk, offset: usize, labels: &Labels) -> usize {
+    if let Some(label) = labels.get(&offset) {
+        let _ = writeln!(out, "L{}:", label);
+    }
+    let _ = write!(out, "{:04} ", offset);
+
+    if offset > 0 && chunk.get_line(offset) == chunk.get_line(offset - 1) {
+        let _ = write!(out, "   | ");
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        let _ = write!(out, "{:4} ", chunk.get_line(offset));
     }
     
     let instruction = chunk.code[offset];
     match OpCode::try_from(instruction) {
         Ok(OpCode::Call) => {
-            return byte_instruction("OP_CALL", chunk, offset)
+            byte_instruction(out, "OP_CALL", chunk, offset)
+        }
+        Ok(OpCode::TailCall) => {
+            byte_instruction(out, "OP_TAIL_CALL", chunk, offset)
         }
         Ok(OpCode::DefineGlobal) => {
-            return constant_instruction("OP_DEFINE_GLOBAL", chunk, offset)
+            constant_instruction(out, "OP_DEFINE_GLOBAL", chunk, offset)
         }
         Ok(OpCode::SetGlobal) => {
-            return constant_instruction("OP_SET_GLOBAL", chunk, offset)
+            constant_instruction(out, "OP_SET_GLOBAL", chunk, offset)
+        }
+        Ok(OpCode::Import) => {
+            constant_instruction(out, "OP_IMPORT", chunk, offset)
         }
         Ok(OpCode::GetGlobal) => {
-            return constant_instruction("OP_GET_GLOBAL", chunk, offset)
+            constant_instruction(out, "OP_GET_GLOBAL", chunk, offset)
+        }
+        Ok(OpCode::GetGlobalSlot) => {
+            short_instruction(out, "OP_GET_GLOBAL_SLOT", chunk, offset)
+        }
+        Ok(OpCode::SetGlobalSlot) => {
+            short_instruction(out, "OP_SET_GLOBAL_SLOT", chunk, offset)
         }
         Ok(OpCode::GetLocal) => {
-            return byte_instruction("OP_GET_LOCAL", chunk, offset)
+            local_instruction(out, "OP_GET_LOCAL", chunk, offset, false)
         }
         Ok(OpCode::SetLocal) => {
-            return byte_instruction("OP_SET_LOCAL", chunk, offset)
+            local_instruction(out, "OP_SET_LOCAL", chunk, offset, false)
+        }
+        Ok(OpCode::GetLocalLong) => {
+            local_instruction(out, "OP_GET_LOCAL_LONG", chunk, offset, true)
+        }
+        Ok(OpCode::SetLocalLong) => {
+            local_instruction(out, "OP_SET_LOCAL_LONG", chunk, offset, true)
         }
         Ok(OpCode::JumpIfFalse) => {
-            return jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset)
+            jump_instruction(out, "OP_JUMP_IF_FALSE", 1, chunk, offset, labels)
         }
         Ok(OpCode::Jump) => {
-            return jump_instruction("OP_JUMP", 1, chunk, offset)
+            jump_instruction(out, "OP_JUMP", 1, chunk, offset, labels)
         }
         Ok(OpCode::Loop) => {
-            return jump_instruction("OP_LOOP", -1, chunk, offset)
+            jump_instruction(out, "OP_LOOP", -1, chunk, offset, labels)
+        }
+        Ok(OpCode::PushHandler) => {
+            jump_instruction(out, "OP_PUSH_HANDLER", 1, chunk, offset, labels)
+        }
+        Ok(OpCode::IterNext) => {
+            jump_instruction(out, "OP_ITER_NEXT", 1, chunk, offset, labels)
+        }
+        Ok(OpCode::PopHandler) => {
+            simple_instruction(out, "OP_POP_HANDLER", offset)
+        }
+        Ok(OpCode::Throw) => {
+            simple_instruction(out, "OP_THROW", offset)
+        }
+        Ok(OpCode::Yield) => {
+            simple_instruction(out, "OP_YIELD", offset)
         }
         Ok(OpCode::Pop) => {
-            return simple_instruction("OP_POP", offset)
+            simple_instruction(out, "OP_POP", offset)
+        }
+        Ok(OpCode::PopN) => {
+            byte_instruction(out, "OP_POP_N", chunk, offset)
+        }
+        Ok(OpCode::Dup) => {
+            simple_instruction(out, "OP_DUP", offset)
+        }
+        Ok(OpCode::Dup2) => {
+            simple_instruction(out, "OP_DUP2", offset)
+        }
+        Ok(OpCode::Swap) => {
+            simple_instruction(out, "OP_SWAP", offset)
         }
         Ok(OpCode::Print) => {
-            return simple_instruction("OP_PRINT", offset)
+            simple_instruction(out, "OP_PRINT", offset)
         }
         Ok(OpCode::Return) => {
-            return simple_instruction("OP_RETURN", offset)
+            simple_instruction(out, "OP_RETURN", offset)
         }
         Ok(OpCode::Constant) => {
-            return constant_instruction("OP_CONSTANT", chunk, offset)
+            constant_instruction(out, "OP_CONSTANT", chunk, offset)
+        }
+        Ok(OpCode::ConstantLong) => {
+            constant_long_instruction(out, "OP_CONSTANT_LONG", chunk, offset)
+        }
+        Ok(OpCode::SmallInt) => {
+            byte_instruction(out, "OP_SMALL_INT", chunk, offset)
+        }
+        Ok(OpCode::Closure) => {
+            closure_instruction(out, chunk, offset)
+        }
+        Ok(OpCode::GetUpvalue) => {
+            byte_instruction(out, "OP_GET_UPVALUE", chunk, offset)
+        }
+        Ok(OpCode::SetUpvalue) => {
+            byte_instruction(out, "OP_SET_UPVALUE", chunk, offset)
+        }
+        Ok(OpCode::CloseUpvalue) => {
+            simple_instruction(out, "OP_CLOSE_UPVALUE", offset)
+        }
+        Ok(OpCode::Class) => {
+            constant_instruction(out, "OP_CLASS", chunk, offset)
+        }
+        Ok(OpCode::GetProperty) => {
+            constant_instruction(out, "OP_GET_PROPERTY", chunk, offset)
+        }
+        Ok(OpCode::SetProperty) => {
+            constant_instruction(out, "OP_SET_PROPERTY", chunk, offset)
+        }
+        Ok(OpCode::Method) => {
+            constant_instruction(out, "OP_METHOD", chunk, offset)
+        }
+        Ok(OpCode::Invoke) => {
+            invoke_instruction(out, "OP_INVOKE", chunk, offset)
+        }
+        Ok(OpCode::TailInvoke) => {
+            invoke_instruction(out, "OP_TAIL_INVOKE", chunk, offset)
+        }
+        Ok(OpCode::BuildList) => {
+            byte_instruction(out, "OP_BUILD_LIST", chunk, offset)
+        }
+        Ok(OpCode::BuildMap) => {
+            byte_instruction(out, "OP_BUILD_MAP", chunk, offset)
+        }
+        Ok(OpCode::IndexGet) => {
+            simple_instruction(out, "OP_INDEX_GET", offset)
+        }
+        Ok(OpCode::IndexSet) => {
+            simple_instruction(out, "OP_INDEX_SET", offset)
         }
         Ok(OpCode::Negate) => {
-            return simple_instruction("OP_NEGATE", offset)
+            simple_instruction(out, "OP_NEGATE", offset)
         }
         Ok(OpCode::Add) => {
-            return simple_instruction("OP_ADD", offset)
+            simple_instruction(out, "OP_ADD", offset)
         }
         Ok(OpCode::Subtract) => {
-            return simple_instruction("OP_SUBTRACT", offset)
+            simple_instruction(out, "OP_SUBTRACT", offset)
         }
         Ok(OpCode::Multiply) => {
-            return simple_instruction("OP_MULTIPLY", offset)
+            simple_instruction(out, "OP_MULTIPLY", offset)
+        }
+        Ok(OpCode::Modulo) => {
+            simple_instruction(out, "OP_MODULO", offset)
+        }
+        Ok(OpCode::ToString) => {
+            simple_instruction(out, "OP_TO_STRING", offset)
         }
         Ok(OpCode::Divide) => {
-            return simple_instruction("OP_DIVIDE", offset)
+            simple_instruction(out, "OP_DIVIDE", offset)
         }
         Ok(OpCode::Nil) => {
-            return simple_instruction("OP_NIL", offset)
+            simple_instruction(out, "OP_NIL", offset)
         }
         Ok(OpCode::True) => {
-            return simple_instruction("OP_TRUE", offset)
+            simple_instruction(out, "OP_TRUE", offset)
         }
         Ok(OpCode::False) => {
-            return simple_instruction("OP_FALSE", offset)
+            simple_instruction(out, "OP_FALSE", offset)
         }
         Ok(OpCode::Not) => {
-            return simple_instruction("OP_NOT", offset)
+            simple_instruction(out, "OP_NOT", offset)
         }
         Ok(OpCode::Equal) => {
-            return simple_instruction("OP_EQUAL", offset)
+            simple_instruction(out, "OP_EQUAL", offset)
         }
         Ok(OpCode::Greater) => {
-            return simple_instruction("OP_GREATER", offset)
+            simple_instruction(out, "OP_GREATER", offset)
         }
         Ok(OpCode::Less) => {
-            return simple_instruction("OP_LESS", offset)
+            simple_instruction(out, "OP_LESS", offset)
         }
         _ => {
-            print!("Unknown opcode {}\n", instruction);
-            return offset + 1
+            let _ = writeln!(out, "Unknown opcode {}", instruction);
+            offset + 1
         }
     }
 }
 
-#[allow(dead_code)]
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    print!("== {} ==\n", name);
+/// Returns the listing that [`disassemble_chunk`] prints, one line per
+/// instruction with jump targets labeled.
+pub fn disassemble(chunk: &Chunk, name: &str) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "== {} ==", name);
+    let labels = jump_labels(chunk);
     let mut i = 0;
     while i < chunk.code.len() {
-        i = disassemble_instruction(chunk, i);
+        i = disassemble_labeled(&mut out, chunk, i, &labels);
+    }
+    out
+}
+
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+    print!("{}", disassemble(chunk, name));
+}
+
+/// Lists the tokens in `source`, one per line with its line number, type
+/// and text, like the scanner test in clox's chapter 16. Error tokens show
+/// their message instead.
+///
+/// ```
+/// let listing = rustlox::debug::token_listing("var a;\nprint a;");
+/// assert_eq!(listing.lines().next(), Some("   1 Var          'var'"));
+/// assert_eq!(listing.lines().nth(3), Some("   2 Print        'print'"));
+/// ```
+pub fn token_listing(source: &str) -> String {
+    let mut out = String::new();
+    let mut line = -1;
+    for token in scan_tokens(source) {
+        if token.line == line {
+            let _ = write!(out, "   | ");
+        } else {
+            let _ = write!(out, "{:4} ", token.line);
+            line = token.line;
+        }
+        let _ = writeln!(out, "{:12} '{}'", format!("{:?}", token.token_type), token.text());
+    }
+    out
+}
+
+/// The tokens in `source` as JSON, each with its type, text, line and
+/// column.
+#[cfg(feature = "serde")]
+pub fn tokens_json(source: &str) -> serde_json::Value {
+    use serde_json::json;
+
+    let tokens = scan_tokens(source).iter().map(|token| json!({
+        "type": format!("{:?}", token.token_type),
+        "lexeme": token.text(),
+        "line": token.line,
+        "column": token.column,
+    })).collect();
+    serde_json::Value::Array(tokens)
+}
+
+pub fn opcode_name(op: &OpCode) -> &'static str {
+    match op {
+        OpCode::Constant => "OP_CONSTANT",
+        OpCode::Return => "OP_RETURN",
+        OpCode::Negate => "OP_NEGATE",
+        OpCode::Add => "OP_ADD",
+        OpCode::Subtract => "OP_SUBTRACT",
+        OpCode::Multiply => "OP_MULTIPLY",
+        OpCode::Divide => "OP_DIVIDE",
+        OpCode::Modulo => "OP_MODULO",
+        OpCode::ToString => "OP_TO_STRING",
+        OpCode::Nil => "OP_NIL",
+        OpCode::True => "OP_TRUE",
+        OpCode::False => "OP_FALSE",
+        OpCode::Not => "OP_NOT",
+        OpCode::Equal => "OP_EQUAL",
+        OpCode::Greater => "OP_GREATER",
+        OpCode::Less => "OP_LESS",
+        OpCode::Print => "OP_PRINT",
+        OpCode::Pop => "OP_POP",
+        OpCode::PopN => "OP_POP_N",
+        OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+        OpCode::GetGlobal => "OP_GET_GLOBAL",
+        OpCode::SetGlobal => "OP_SET_GLOBAL",
+        OpCode::GetGlobalSlot => "OP_GET_GLOBAL_SLOT",
+        OpCode::SetGlobalSlot => "OP_SET_GLOBAL_SLOT",
+        OpCode::PushHandler => "OP_PUSH_HANDLER",
+        OpCode::PopHandler => "OP_POP_HANDLER",
+        OpCode::Throw => "OP_THROW",
+        OpCode::Yield => "OP_YIELD",
+        OpCode::Import => "OP_IMPORT",
+        OpCode::GetLocal => "OP_GET_LOCAL",
+        OpCode::SetLocal => "OP_SET_LOCAL",
+        OpCode::GetLocalLong => "OP_GET_LOCAL_LONG",
+        OpCode::SetLocalLong => "OP_SET_LOCAL_LONG",
+        OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+        OpCode::Jump => "OP_JUMP",
+        OpCode::Loop => "OP_LOOP",
+        OpCode::Call => "OP_CALL",
+        OpCode::TailCall => "OP_TAIL_CALL",
+        OpCode::IterNext => "OP_ITER_NEXT",
+        OpCode::Dup => "OP_DUP",
+        OpCode::Dup2 => "OP_DUP2",
+        OpCode::Swap => "OP_SWAP",
+        OpCode::SmallInt => "OP_SMALL_INT",
+        OpCode::Closure => "OP_CLOSURE",
+        OpCode::GetUpvalue => "OP_GET_UPVALUE",
+        OpCode::SetUpvalue => "OP_SET_UPVALUE",
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
+        OpCode::Class => "OP_CLASS",
+        OpCode::GetProperty => "OP_GET_PROPERTY",
+        OpCode::SetProperty => "OP_SET_PROPERTY",
+        OpCode::Method => "OP_METHOD",
+        OpCode::Invoke => "OP_INVOKE",
+        OpCode::TailInvoke => "OP_TAIL_INVOKE",
+        OpCode::ConstantLong => "OP_CONSTANT_LONG",
+        OpCode::BuildList => "OP_BUILD_LIST",
+        OpCode::IndexGet => "OP_INDEX_GET",
+        OpCode::IndexSet => "OP_INDEX_SET",
+        OpCode::BuildMap => "OP_BUILD_MAP",
+    }
+}
+
+// Number of operand bytes that follow the instruction `op` at `offset`.
+pub fn operand_len(chunk: &Chunk, offset: usize, op: &OpCode) -> usize {
+    match op {
+        OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal |
+        OpCode::GetLocal | OpCode::SetLocal | OpCode::Call | OpCode::TailCall | OpCode::SmallInt |
+        OpCode::GetUpvalue | OpCode::SetUpvalue | OpCode::Class | OpCode::GetProperty |
+        OpCode::SetProperty | OpCode::Method | OpCode::BuildList |
+        OpCode::BuildMap | OpCode::Import | OpCode::PopN => 1,
+        // The method name constant, then the argument count.
+        OpCode::JumpIfFalse | OpCode::Jump | OpCode::Loop | OpCode::Invoke | OpCode::TailInvoke |
+        OpCode::GetGlobalSlot | OpCode::SetGlobalSlot | OpCode::PushHandler | OpCode::IterNext |
+        OpCode::GetLocalLong | OpCode::SetLocalLong => 2,
+        OpCode::ConstantLong => 3,
+        OpCode::Closure => {
+            // The function constant, then two bytes per captured variable.
+            let function = chunk.constants.values[chunk.code[offset + 1] as usize];
+            1 + 2 * unsafe { (*function.as_function()).upvalue_count }
+        }
+        _ => 0,
+    }
+}
+
+// Change in stack depth caused by an instruction. `operand` is its argument
+// count for OP_CALL and OP_INVOKE, and otherwise its first operand byte.
+fn stack_effect(op: &OpCode, operand: u8) -> isize {
+    match op {
+        OpCode::Constant | OpCode::ConstantLong | OpCode::Nil | OpCode::True | OpCode::False | OpCode::SmallInt |
+        OpCode::GetGlobal | OpCode::GetGlobalSlot | OpCode::GetLocal | OpCode::GetLocalLong | OpCode::Dup | OpCode::Closure |
+        OpCode::GetUpvalue | OpCode::Class | OpCode::Import | OpCode::IterNext => 1,
+        OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Modulo | OpCode::Equal |
+        OpCode::Greater | OpCode::Less | OpCode::Print | OpCode::Pop | OpCode::DefineGlobal |
+        OpCode::Return | OpCode::CloseUpvalue | OpCode::SetProperty | OpCode::Method |
+        OpCode::IndexGet | OpCode::Throw => -1,
+        OpCode::IndexSet => -2,
+        OpCode::Dup2 => 2,
+        OpCode::BuildList => 1 - operand as isize,
+        OpCode::PopN => -(operand as isize),
+        // The operand counts key-value pairs.
+        OpCode::BuildMap => 1 - 2 * operand as isize,
+        OpCode::Call | OpCode::TailCall | OpCode::Invoke | OpCode::TailInvoke => -(operand as isize),
+        _ => 0,
+    }
+}
+
+/// Static measurements of a chunk's code.
+#[derive(Debug, Default)]
+pub struct ChunkStats {
+    /// Number of instructions of each opcode, by name.
+    pub opcode_counts: BTreeMap<&'static str, usize>,
+    pub instructions: usize,
+    pub constants: usize,
+    /// Longest forward or backward jump, in bytes.
+    pub max_jump: usize,
+    /// Deepest stack reached reading the code straight through, ignoring
+    /// jumps, above the function's own slots.
+    pub max_stack: isize,
+}
+
+pub fn chunk_stats(chunk: &Chunk) -> ChunkStats {
+    let mut stats = ChunkStats {
+        constants: chunk.constants.values.len(),
+        ..ChunkStats::default()
+    };
+    let mut depth = 0;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = match OpCode::try_from(chunk.code[offset]) {
+            Ok(op) => op,
+            Err(_) => {
+                offset += 1;
+                continue;
+            }
+        };
+        let len = operand_len(chunk, offset, &op);
+        let operand = match op {
+            OpCode::Invoke | OpCode::TailInvoke => chunk.code[offset + 2],
+            _ if len > 0 => chunk.code[offset + 1],
+            _ => 0,
+        };
+        if matches!(op, OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler | OpCode::IterNext) {
+            let jump = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+            stats.max_jump = stats.max_jump.max(jump);
+        }
+        depth += stack_effect(&op, operand);
+        stats.max_stack = stats.max_stack.max(depth);
+        *stats.opcode_counts.entry(opcode_name(&op)).or_insert(0) += 1;
+        stats.instructions += 1;
+        offset += 1 + len;
+    }
+    stats
+}
+
+/// Calls `visit` with the name and chunk of a function value and of every
+/// function nested in its constants, named as in stack traces. Does nothing
+/// for values that are not functions.
+pub fn visit_functions(function: Value, visit: &mut dyn FnMut(&str, &Chunk)) {
+    if !function.is_function() {
+        return;
+    }
+    let function = unsafe { &*function.as_function() };
+    let name = if function.name.is_null() {
+        "script"
+    } else {
+        unsafe { (*function.name).as_str() }
+    };
+    visit(name, &function.chunk);
+    for constant in &function.chunk.constants.values {
+        visit_functions(*constant, visit);
+    }
+}
+
+pub fn function_stats(function: Value) -> Vec<(String, ChunkStats)> {
+    let mut result = Vec::new();
+    visit_functions(function, &mut |name, chunk| result.push((name.to_string(), chunk_stats(chunk))));
+    result
+}
+
+/// Disassembles `chunk` as JSON for external tools. Each instruction has its
+/// offset, line, opcode name and raw operand bytes, plus the constant it
+/// refers to or the offset it jumps to.
+#[cfg(feature = "serde")]
+pub fn disassemble_chunk_json(chunk: &Chunk, name: &str) -> serde_json::Value {
+    use serde_json::json;
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = match OpCode::try_from(chunk.code[offset]) {
+            Ok(op) => op,
+            Err(_) => {
+                instructions.push(json!({
+                    "offset": offset,
+                    "line": chunk.get_line(offset),
+                    "opcode": null,
+                    "operands": [chunk.code[offset]],
+                }));
+                offset += 1;
+                continue;
+            }
+        };
+        let len = operand_len(chunk, offset, &op);
+        let mut instruction = json!({
+            "offset": offset,
+            "line": chunk.get_line(offset),
+            "opcode": opcode_name(&op),
+            "operands": &chunk.code[offset + 1..offset + 1 + len],
+        });
+        match op {
+            OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal |
+            OpCode::Closure | OpCode::Class | OpCode::GetProperty | OpCode::SetProperty |
+            OpCode::Method | OpCode::Invoke | OpCode::TailInvoke | OpCode::Import => {
+                let constant = chunk.constants.values[chunk.code[offset + 1] as usize];
+                instruction["constant"] = crate::json::to_json(constant)
+                    .unwrap_or_else(|_| json!(format!("{}", constant.display())));
+            }
+            OpCode::ConstantLong => {
+                let constant = chunk.constants.values[long_operand(chunk, offset)];
+                instruction["constant"] = crate::json::to_json(constant)
+                    .unwrap_or_else(|_| json!(format!("{}", constant.display())));
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler | OpCode::IterNext => {
+                instruction["target"] = json!(jump_target(1, chunk, offset).1);
+            }
+            OpCode::Loop => {
+                instruction["target"] = json!(jump_target(-1, chunk, offset).1);
+            }
+            _ => {}
+        }
+        instructions.push(instruction);
+        offset += 1 + len;
     }
+    json!({ "name": name, "instructions": instructions })
 }
@@ -0,0 +1,143 @@
+// Purpose: The interactive debugger run by `rustlox --debug <path>`, built on
+// Vm::debug and the VM's breakpoints.
+
+use crate::error::LoxError;
+use crate::vm::DebugStep;
+use crate::vm::Step;
+use crate::vm::Vm;
+use std::io::BufRead;
+use std::io::Write;
+
+const HELP: &str = "\
+break <line>   stop when the script reaches <line> (b)
+delete <line>  remove the breakpoint on <line> (d)
+step           run to the next line, stepping into calls (s)
+next           run to the next line, stepping over calls (n)
+continue       run to the next breakpoint (c)
+backtrace      print the call stack (bt)
+locals         print the current function's local variables
+stack          print the value stack
+globals        print the global variables, except natives
+quit           stop debugging (q)
+An empty line repeats the last command.";
+
+/// Runs `source` on `vm` one command from `input` at a time, writing
+/// prompts and what they print to `out`. The script's own output goes to
+/// the VM's stdout sink. Returns the script's error, if it fails.
+///
+/// ```
+/// use rustlox::debugger::debug_script;
+/// use rustlox::Vm;
+///
+/// let source = "var a = 1;\nvar b = a + 1;\nprint b;";
+/// let mut input = "break 3\ncontinue\nglobals\ncontinue\n".as_bytes();
+/// let mut out = Vec::new();
+/// debug_script(&mut Vm::new(), source, &mut input, &mut out).unwrap();
+/// let out = String::from_utf8(out).unwrap();
+/// assert!(out.contains("[line 3] in script\n    3 | print b;"));
+/// assert!(out.contains("b = 2"));
+/// assert!(out.ends_with("Script finished.\n"));
+/// ```
+pub fn debug_script(vm: &mut Vm, source: &str, input: &mut dyn BufRead, out: &mut dyn Write) -> Result<(), LoxError> {
+    vm.start(source)?;
+    let lines: Vec<&str> = source.lines().collect();
+    let _ = writeln!(out, "Type 'help' for a list of commands.");
+    print_location(vm, &lines, out);
+    let mut last = String::new();
+    loop {
+        let _ = write!(out, "(lox) ");
+        let _ = out.flush();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(_) => {}
+        }
+        let command = match line.trim() {
+            "" => last.clone(),
+            command => command.to_string(),
+        };
+        last = command.clone();
+        let mut words = command.split_whitespace();
+        let until = match (words.next().unwrap_or(""), words.next()) {
+            ("step" | "s", None) => DebugStep::Step,
+            ("next" | "n", None) => DebugStep::Next,
+            ("continue" | "c", None) => DebugStep::Continue,
+            ("break" | "b", Some(arg)) => {
+                match arg.parse() {
+                    Ok(number) => {
+                        vm.set_breakpoint(number);
+                        let _ = writeln!(out, "Breakpoint at line {}.", number);
+                    }
+                    Err(_) => {
+                        let _ = writeln!(out, "Expect a line number.");
+                    }
+                }
+                continue;
+            }
+            ("delete" | "d", Some(arg)) => {
+                match arg.parse() {
+                    Ok(number) if vm.clear_breakpoint(number) => {}
+                    _ => {
+                        let _ = writeln!(out, "No breakpoint at line {}.", arg);
+                    }
+                }
+                continue;
+            }
+            ("backtrace" | "bt", None) => {
+                for frame in vm.backtrace() {
+                    let _ = writeln!(out, "{}", frame);
+                }
+                continue;
+            }
+            ("locals", None) => {
+                if let Some(frame) = vm.backtrace().first() {
+                    for (name, value) in &frame.locals {
+                        let _ = writeln!(out, "{} = {}", name, value);
+                    }
+                }
+                continue;
+            }
+            ("stack", None) => {
+                for value in vm.stack() {
+                    let _ = write!(out, "[ {} ]", value.display());
+                }
+                let _ = writeln!(out);
+                continue;
+            }
+            ("globals", None) => {
+                for (name, value) in vm.globals() {
+                    if !value.is_native() {
+                        let _ = writeln!(out, "{} = {}", name, value.display());
+                    }
+                }
+                continue;
+            }
+            ("quit" | "q", None) => return Ok(()),
+            ("help" | "h", None) => {
+                let _ = writeln!(out, "{}", HELP);
+                continue;
+            }
+            _ => {
+                let _ = writeln!(out, "Unknown command '{}'. Type 'help' for a list of commands.", command);
+                continue;
+            }
+        };
+        match vm.debug(until)? {
+            Step::Paused => print_location(vm, &lines, out),
+            Step::Done(_) => {
+                let _ = writeln!(out, "Script finished.");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Prints where the script is paused, with the source of that line.
+fn print_location(vm: &Vm, lines: &[&str], out: &mut dyn Write) {
+    if let Some(frame) = vm.backtrace().first() {
+        let _ = writeln!(out, "{}", frame);
+        if let Some(text) = frame.line.checked_sub(1).and_then(|index| lines.get(index)) {
+            let _ = writeln!(out, "{:5} | {}", frame.line, text);
+        }
+    }
+}
@@ -0,0 +1,841 @@
+// Purpose: Rebuilding readable pseudo-Lox from compiled functions.
+
+use crate::chunk::Chunk;
+use crate::chunk::OpCode;
+use crate::debug::opcode_name;
+use crate::debug::operand_len;
+use crate::object::ObjFunction;
+use crate::table::Globals;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Binding strengths, as in the compiler's Precedence, for putting
+// parentheses back where an operand binds more loosely than its operator.
+const ASSIGNMENT: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+const EQUALITY: u8 = 4;
+const COMPARISON: u8 = 5;
+const TERM: u8 = 6;
+const FACTOR: u8 = 7;
+const UNARY: u8 = 8;
+const CALL: u8 = 9;
+const PRIMARY: u8 = 10;
+
+/// Rebuilds Lox source for `function` from its bytecode, for checking what
+/// the compiler made of a script.
+///
+/// Control flow is read back from the jumps the compiler emits for `if`,
+/// `while`, `for`, `try` and the logical operators, and locals are named
+/// from the chunk's debug symbols when it has them. The result reads like
+/// the original but isn't always valid Lox: blocks are flattened, `switch`
+/// comes back as `if` chains, and code the decompiler doesn't recognize is
+/// left as comments naming its instructions.
+///
+/// The script's own function decompiles to its statements; any other
+/// function to a `fun` declaration. Globals are named from `globals`, or
+/// from the function's module if it has one.
+pub fn decompile(function: &ObjFunction, globals: &Globals) -> String {
+    let globals = if function.module.is_null() { globals } else { unsafe { &(*function.module).globals } };
+    let upvalues = (0..function.upvalue_count).map(|i| format!("upvalue{}", i)).collect();
+    let decompiled = decompile_function(function, globals, upvalues);
+    let mut out = String::new();
+    if function.name.is_null() {
+        render(&decompiled.body, 0, &mut out);
+    } else {
+        let name = if decompiled.name == "anonymous" { "" } else { &decompiled.name };
+        let header = format!("fun {}({})", name, decompiled.params.join(", "));
+        render(&[Stmt::Block(vec![(header, decompiled.body)])], 0, &mut out);
+    }
+    out
+}
+
+// A function rebuilt from its chunk.
+#[derive(Clone)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+#[derive(Clone)]
+enum Stmt {
+    Line(String),
+    // Clauses such as `if (x)` and `else`, each with a braced body.
+    Block(Vec<(String, Vec<Stmt>)>),
+}
+
+#[derive(Clone)]
+struct Expr {
+    text: String,
+    precedence: u8,
+    kind: ExprKind,
+}
+
+// What a declaration needs to know about the value it binds.
+#[derive(Clone)]
+enum ExprKind {
+    Plain,
+    // A string literal, or the pieces of an interpolated string, without
+    // the quotes.
+    Str(String),
+    Function(Box<Function>),
+    Class(String),
+    Import(String),
+}
+
+impl Expr {
+    fn new(text: String, precedence: u8) -> Expr {
+        Expr { text, precedence, kind: ExprKind::Plain }
+    }
+
+    // The expression's text, in parentheses if it binds more loosely than
+    // `precedence`.
+    fn wrap(&self, precedence: u8) -> String {
+        if self.precedence < precedence {
+            format!("({})", self.text)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+// A stack slot: a declared local, or a value being worked on.
+#[derive(Clone)]
+enum Slot {
+    Local(String),
+    Temp(Expr),
+}
+
+struct Decompiler<'a> {
+    chunk: &'a Chunk,
+    globals: &'a Globals,
+    upvalues: Vec<String>,
+    initializer: bool,
+    stack: Vec<Slot>,
+    // The locals the debug symbols say start at each offset, by slot.
+    starts: HashMap<usize, Vec<(usize, String)>>,
+    // Locals that have started but whose value isn't on the stack yet.
+    pending: Vec<(usize, String)>,
+    // The offsets of the OP_LOOPs back to each offset.
+    loops: HashMap<usize, Vec<usize>>,
+    // Where continue and break jump to in each enclosing loop.
+    exits: Vec<(usize, usize)>,
+    // The offset of the last local declaration, which a for loop starting
+    // there takes as its initializer.
+    declared: Option<usize>,
+}
+
+fn decompile_function(function: &ObjFunction, globals: &Globals, upvalues: Vec<String>) -> Function {
+    let chunk = &*function.chunk;
+    let name = if function.name.is_null() { "script".to_string() } else { unsafe { (*function.name).as_str() }.to_string() };
+    let mut starts: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+    for local in &chunk.locals {
+        starts.entry(local.start).or_default().push((local.slot as usize, local.name.clone()));
+    }
+    let mut loops: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (offset, op) in instructions(chunk, 0, chunk.code.len()) {
+        if let OpCode::Loop = op {
+            loops.entry(jump_target(chunk, offset, -1)).or_default().push(offset);
+        }
+    }
+    // Slot 0 holds the receiver in methods and the function itself
+    // elsewhere, which code can't name.
+    let mut stack = vec![Slot::Local("this".to_string())];
+    let mut params = Vec::new();
+    for slot in 1..=function.arity as usize {
+        let param = starts.get(&0)
+            .and_then(|locals| locals.iter().find(|(local, _)| *local == slot))
+            .map_or_else(|| format!("local{}", slot), |(_, name)| name.clone());
+        params.push(param.clone());
+        stack.push(Slot::Local(param));
+    }
+    let mut decompiler = Decompiler {
+        chunk,
+        globals,
+        upvalues,
+        initializer: name == "init",
+        stack,
+        starts,
+        pending: Vec::new(),
+        loops,
+        exits: Vec::new(),
+        declared: None,
+    };
+    let body = decompiler.block(0, chunk.code.len());
+    Function { name, params, body }
+}
+
+// The instructions from `start` up to `end`, or the first byte that isn't
+// an opcode.
+fn instructions(chunk: &Chunk, start: usize, end: usize) -> Vec<(usize, OpCode)> {
+    let mut code = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        let Ok(op) = OpCode::try_from(chunk.code[offset]) else {
+            break;
+        };
+        code.push((offset, op));
+        offset += 1 + operand_len(chunk, offset, &op);
+    }
+    code
+}
+
+// Where the jump at `offset` goes, for a forward jump when `sign` is 1 and
+// a loop when it is -1.
+fn jump_target(chunk: &Chunk, offset: usize, sign: isize) -> usize {
+    let distance = (chunk.code[offset + 1] as isize) << 8 | chunk.code[offset + 2] as isize;
+    (offset as isize + 3 + sign * distance) as usize
+}
+
+fn is_op(chunk: &Chunk, offset: usize, op: OpCode) -> bool {
+    chunk.code.get(offset) == Some(&(op as u8))
+}
+
+impl Decompiler<'_> {
+    // Decompiles the statements from `start` up to `end`, leaving any
+    // values they push on the stack.
+    fn block(&mut self, start: usize, end: usize) -> Vec<Stmt> {
+        let mut stmts = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            self.declare_locals(offset, &mut stmts);
+            let last_loop = self.loops.get(&offset).and_then(|loops| loops.iter().max()).copied();
+            if let Some(end_loop) = last_loop.filter(|&end_loop| end_loop < end) {
+                offset = self.loop_statement(offset, end_loop, &mut stmts);
+                continue;
+            }
+            if let Some(next) = self.jump_out(offset, end, &mut stmts) {
+                offset = next;
+                continue;
+            }
+            let Ok(op) = OpCode::try_from(self.chunk.code[offset]) else {
+                stmts.push(Stmt::Line(format!("// {}", self.chunk.code[offset])));
+                break;
+            };
+            offset = self.instruction(offset, op, end, &mut stmts);
+        }
+        stmts
+    }
+
+    // Like block(), but leaves the stack as it found it, for a branch that
+    // may not run.
+    fn branch(&mut self, start: usize, end: usize) -> Vec<Stmt> {
+        let stack = self.stack.clone();
+        let stmts = self.block(start, end);
+        self.stack = stack;
+        let depth = self.stack.len();
+        self.pending.retain(|(slot, _)| *slot < depth);
+        stmts
+    }
+
+    // The single value computed by the code from `start` up to `end`.
+    fn expression(&mut self, start: usize, end: usize) -> Expr {
+        let stack = self.stack.clone();
+        let stmts = self.block(start, end);
+        if stmts.is_empty() && self.stack.len() == stack.len() + 1 {
+            if let Some(Slot::Temp(expr)) = self.stack.pop() {
+                return expr;
+            }
+        }
+        self.stack = stack;
+        Expr::new("?".to_string(), PRIMARY)
+    }
+
+    // Turns the values of locals starting at `offset` into declarations.
+    fn declare_locals(&mut self, offset: usize, stmts: &mut Vec<Stmt>) {
+        if let Some(locals) = self.starts.get(&offset) {
+            self.pending.extend(locals.iter().cloned());
+        }
+        for (slot, name) in std::mem::take(&mut self.pending) {
+            match self.stack.get(slot) {
+                Some(Slot::Temp(expr)) => {
+                    stmts.push(self.declaration("var", &name, expr.clone()));
+                    self.stack[slot] = Slot::Local(name);
+                    self.declared = Some(offset);
+                }
+                Some(Slot::Local(_)) => {}
+                None => self.pending.push((slot, name)),
+            }
+        }
+    }
+
+    // A declaration binding `name` to `value`, in the form it was most
+    // likely written in.
+    fn declaration(&self, keyword: &str, name: &str, value: Expr) -> Stmt {
+        match value.kind {
+            ExprKind::Function(function) if function.name == name => {
+                let header = format!("fun {}({})", name, function.params.join(", "));
+                Stmt::Block(vec![(header, function.body)])
+            }
+            ExprKind::Class(class) if class == name => Stmt::Block(vec![(format!("class {}", name), Vec::new())]),
+            ExprKind::Import(path) => {
+                let stem = std::path::Path::new(&path).file_stem().and_then(|stem| stem.to_str());
+                if stem == Some(name) {
+                    Stmt::Line(format!("import \"{}\";", path))
+                } else {
+                    Stmt::Line(format!("import \"{}\" as {};", path, name))
+                }
+            }
+            _ if value.text == "nil" && keyword == "var" => Stmt::Line(format!("var {};", name)),
+            _ => Stmt::Line(format!("{} {} = {};", keyword, name, value.text)),
+        }
+    }
+
+    // Recognizes the pops and jump of a break or continue at `offset`,
+    // which leave the locals on the stack for the code after them.
+    fn jump_out(&self, offset: usize, end: usize, stmts: &mut Vec<Stmt>) -> Option<usize> {
+        let &(continue_target, break_target) = self.exits.last()?;
+        let mut depth = self.stack.len();
+        for (at, op) in instructions(self.chunk, offset, end) {
+            let next = at + 1 + operand_len(self.chunk, at, &op);
+            let popped = match op {
+                OpCode::Pop | OpCode::CloseUpvalue => 1,
+                OpCode::PopN => self.chunk.code[at + 1] as usize,
+                _ => 0,
+            };
+            // Only locals are discarded on the way out.
+            for _ in 0..popped {
+                depth = depth.checked_sub(1)?;
+                if let Slot::Temp(_) = self.stack[depth] {
+                    return None;
+                }
+            }
+            match op {
+                OpCode::Pop | OpCode::PopN | OpCode::CloseUpvalue | OpCode::PopHandler => {}
+                OpCode::Jump if jump_target(self.chunk, at, 1) == break_target => {
+                    stmts.push(Stmt::Line("break;".to_string()));
+                    return Some(next);
+                }
+                OpCode::Loop if jump_target(self.chunk, at, -1) == continue_target => {
+                    stmts.push(Stmt::Line("continue;".to_string()));
+                    return Some(next);
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    // Decompiles the loop that starts at `start` and whose last OP_LOOP is
+    // at `end_loop`, returning the offset after it.
+    fn loop_statement(&mut self, start: usize, end_loop: usize, stmts: &mut Vec<Stmt>) -> usize {
+        let code = instructions(self.chunk, start, end_loop);
+        // for (var x in collection): OP_ITER_NEXT pushes each item until
+        // it jumps past the loop.
+        if is_op(self.chunk, start, OpCode::IterNext) && self.stack.len() >= 2 {
+            let exit = jump_target(self.chunk, start, 1);
+            let collection = match &self.stack[self.stack.len() - 2] {
+                Slot::Temp(expr) => expr.text.clone(),
+                Slot::Local(name) => name.clone(),
+            };
+            let depth = self.stack.len();
+            self.stack[depth - 2] = Slot::Local(String::new());
+            self.stack[depth - 1] = Slot::Local(String::new());
+            let name = self.started_local(start + 3, depth);
+            let stack = self.stack.clone();
+            self.stack.push(Slot::Local(name.clone()));
+            self.exits.push((start, exit));
+            let body = self.block(start + 3, end_loop);
+            self.exits.pop();
+            self.stack = stack;
+            stmts.push(Stmt::Block(vec![(format!("for (var {} in {})", name, collection), body)]));
+            return exit;
+        }
+        if self.name_locals(stmts) {
+            self.declared = Some(start);
+        }
+        // for (init; condition; increment): the condition jumps over the
+        // increment to the body, which loops back to the increment.
+        let body_jump = code.iter()
+            .find(|&&(at, op)| matches!(op, OpCode::Jump) && jump_target(self.chunk, at, 1) == end_loop + 3)
+            .map(|&(at, _)| at);
+        if let Some(body_jump) = body_jump {
+            let increment_start = body_jump + 3;
+            if let Some(&body_end) = self.loops.get(&increment_start).and_then(|loops| loops.iter().max()) {
+                let condition = if body_jump == start {
+                    None
+                } else if body_jump >= start + 4 && is_op(self.chunk, body_jump - 4, OpCode::JumpIfFalse) {
+                    Some(self.expression(start, body_jump - 4))
+                } else {
+                    return self.endless_loop(start, end_loop, stmts);
+                };
+                let increment = self.expression(increment_start, end_loop - 1);
+                let exit = body_end + 3 + condition.is_some() as usize;
+                let initializer = match (self.declared, stmts.last()) {
+                    (Some(declared), Some(Stmt::Line(line))) if declared == start && line.starts_with("var ") => {
+                        let line = line.trim_end_matches(';').to_string();
+                        stmts.pop();
+                        line
+                    }
+                    _ => String::new(),
+                };
+                self.exits.push((increment_start, exit));
+                let body = self.branch(end_loop + 3, body_end);
+                self.exits.pop();
+                let condition = condition.map_or(String::new(), |condition| condition.text);
+                let header = format!("for ({}; {}; {})", initializer, condition, increment.text).replace("; ;", ";;");
+                stmts.push(Stmt::Block(vec![(header, body)]));
+                return exit;
+            }
+        }
+        // while (condition): the condition jumps past the loop and its
+        // closing pop.
+        let exit_jump = code.iter()
+            .find(|&&(at, op)| matches!(op, OpCode::JumpIfFalse) && jump_target(self.chunk, at, 1) == end_loop + 3)
+            .map(|&(at, _)| at);
+        if let Some(exit_jump) = exit_jump.filter(|&at| is_op(self.chunk, at + 3, OpCode::Pop)) {
+            let condition = self.expression(start, exit_jump);
+            self.exits.push((start, end_loop + 4));
+            let body = self.branch(exit_jump + 4, end_loop);
+            self.exits.pop();
+            stmts.push(Stmt::Block(vec![(format!("while ({})", condition.text), body)]));
+            return end_loop + 4;
+        }
+        self.endless_loop(start, end_loop, stmts)
+    }
+
+    // A loop with no condition, such as `for (;;)`.
+    fn endless_loop(&mut self, start: usize, end_loop: usize, stmts: &mut Vec<Stmt>) -> usize {
+        self.exits.push((start, end_loop + 3));
+        let body = self.branch(start, end_loop);
+        self.exits.pop();
+        stmts.push(Stmt::Block(vec![("while (true)".to_string(), body)]));
+        end_loop + 3
+    }
+
+    // Declares every value on the stack that isn't a named local yet,
+    // returning whether there were any. Between statements, anything on
+    // the stack is a local, even without a debug symbol to name it.
+    fn name_locals(&mut self, stmts: &mut Vec<Stmt>) -> bool {
+        let mut named = false;
+        for slot in 0..self.stack.len() {
+            if let Slot::Temp(_) = self.stack[slot] {
+                self.local(slot, stmts);
+                named = true;
+            }
+        }
+        named
+    }
+
+    // The name of the local starting at `offset` in `slot`, for values the
+    // VM pushes itself.
+    fn started_local(&self, offset: usize, slot: usize) -> String {
+        self.starts.get(&offset)
+            .and_then(|locals| locals.iter().find(|(local, _)| *local == slot))
+            .map_or_else(|| format!("local{}", slot), |(_, name)| name.clone())
+    }
+
+    // The name of the local in `slot`. A value that no debug symbol named,
+    // such as a switch's subject, is declared under a made-up name.
+    fn local(&mut self, slot: usize, stmts: &mut Vec<Stmt>) -> String {
+        match self.stack.get(slot) {
+            Some(Slot::Local(name)) if !name.is_empty() => name.clone(),
+            Some(Slot::Temp(expr)) => {
+                let name = format!("local{}", slot);
+                stmts.push(self.declaration("var", &name, expr.clone()));
+                self.stack[slot] = Slot::Local(name.clone());
+                name
+            }
+            _ => format!("local{}", slot),
+        }
+    }
+
+    fn global(&self, slot: usize) -> String {
+        if slot < self.globals.len() {
+            unsafe { (*self.globals.name(slot)).as_str() }.to_string()
+        } else {
+            format!("global{}", slot)
+        }
+    }
+
+    fn constant(&self, index: usize) -> Value {
+        self.chunk.constants.values[index]
+    }
+
+    fn name(&self, offset: usize) -> String {
+        self.constant(self.chunk.code[offset + 1] as usize).as_str().to_string()
+    }
+
+    fn push(&mut self, expr: Expr) {
+        self.stack.push(Slot::Temp(expr));
+    }
+
+    fn pop(&mut self) -> Expr {
+        match self.stack.pop() {
+            Some(Slot::Temp(expr)) => expr,
+            Some(Slot::Local(name)) => Expr::new(name, PRIMARY),
+            None => Expr::new("?".to_string(), PRIMARY),
+        }
+    }
+
+    fn pop_many(&mut self, count: usize) -> Vec<Expr> {
+        let mut values: Vec<_> = (0..count).map(|_| self.pop()).collect();
+        values.reverse();
+        values
+    }
+
+    fn binary(&mut self, operator: &str, precedence: u8) {
+        let right = self.pop();
+        let left = self.pop();
+        let text = format!("{} {} {}", left.wrap(precedence), operator, right.wrap(precedence + 1));
+        self.push(Expr::new(text, precedence));
+    }
+
+    fn assign(&mut self, target: String) {
+        let value = self.pop();
+        self.push(Expr::new(format!("{} = {}", target, value.text), ASSIGNMENT));
+    }
+
+    fn value(&self, value: Value) -> Expr {
+        if value.is_string() {
+            let text = value.as_str().to_string();
+            Expr { text: format!("\"{}\"", text), precedence: PRIMARY, kind: ExprKind::Str(text) }
+        } else if value.is_number() && value.as_number() < 0.0 {
+            Expr::new(format!("{}", value.display()), UNARY)
+        } else {
+            Expr::new(format!("{}", value.display()), PRIMARY)
+        }
+    }
+
+    // Decompiles the instruction at `offset`, returning where the next one
+    // starts.
+    fn instruction(&mut self, offset: usize, op: OpCode, end: usize, stmts: &mut Vec<Stmt>) -> usize {
+        let chunk = self.chunk;
+        let code = &chunk.code;
+        let mut next = offset + 1 + operand_len(self.chunk, offset, &op);
+        let short = (code[offset + 1..].first().copied().unwrap_or(0) as usize) << 8
+            | code.get(offset + 2).copied().unwrap_or(0) as usize;
+        match op {
+            OpCode::Constant => self.push(self.value(self.constant(code[offset + 1] as usize))),
+            OpCode::ConstantLong => {
+                let index = short << 8 | code[offset + 3] as usize;
+                self.push(self.value(self.constant(index)));
+            }
+            OpCode::Nil => self.push(Expr::new("nil".to_string(), PRIMARY)),
+            OpCode::True => self.push(Expr::new("true".to_string(), PRIMARY)),
+            OpCode::False => self.push(Expr::new("false".to_string(), PRIMARY)),
+            OpCode::SmallInt => self.push(Expr::new(code[offset + 1].to_string(), PRIMARY)),
+            OpCode::Negate | OpCode::Not => {
+                let operand = self.pop();
+                let operator = if matches!(op, OpCode::Negate) { "-" } else { "!" };
+                self.push(Expr::new(format!("{}{}", operator, operand.wrap(UNARY)), UNARY));
+            }
+            OpCode::Add => {
+                let right = self.pop();
+                let left = self.pop();
+                // Interpolated strings compile to a chain of additions.
+                if let (ExprKind::Str(a), ExprKind::Str(b)) = (&left.kind, &right.kind) {
+                    let text = format!("{}{}", a, b);
+                    self.push(Expr { text: format!("\"{}\"", text), precedence: PRIMARY, kind: ExprKind::Str(text) });
+                } else {
+                    self.push(left);
+                    self.push(right);
+                    self.binary("+", TERM);
+                }
+            }
+            OpCode::Subtract => self.binary("-", TERM),
+            OpCode::Multiply => self.binary("*", FACTOR),
+            OpCode::Divide => self.binary("/", FACTOR),
+            OpCode::Modulo => self.binary("%", FACTOR),
+            OpCode::Equal | OpCode::Greater | OpCode::Less => {
+                // !=, <= and >= compile to the opposite test and a not.
+                let negated = is_op(self.chunk, next, OpCode::Not) && next < end;
+                let (operator, precedence) = match (op, negated) {
+                    (OpCode::Equal, false) => ("==", EQUALITY),
+                    (OpCode::Equal, true) => ("!=", EQUALITY),
+                    (OpCode::Greater, false) => (">", COMPARISON),
+                    (OpCode::Greater, true) => ("<=", COMPARISON),
+                    (OpCode::Less, false) => ("<", COMPARISON),
+                    _ => (">=", COMPARISON),
+                };
+                if negated {
+                    next += 1;
+                }
+                self.binary(operator, precedence);
+            }
+            OpCode::ToString => {
+                let value = self.pop();
+                let text = format!("${{{}}}", value.text);
+                self.push(Expr { text: format!("\"{}\"", text), precedence: PRIMARY, kind: ExprKind::Str(text) });
+            }
+            OpCode::Print => {
+                let value = self.pop();
+                stmts.push(Stmt::Line(format!("print {};", value.text)));
+            }
+            OpCode::Throw => {
+                let value = self.pop();
+                stmts.push(Stmt::Line(format!("throw {};", value.text)));
+            }
+            OpCode::Pop => {
+                if let Some(Slot::Temp(expr)) = self.stack.pop() {
+                    // A class declaration ends by popping the class its
+                    // methods were attached to.
+                    let class = format!("class {}", expr.text);
+                    if !matches!(stmts.last(), Some(Stmt::Block(clauses)) if clauses[0].0 == class) {
+                        stmts.push(Stmt::Line(format!("{};", expr.text)));
+                    }
+                }
+            }
+            // Locals going out of scope, some of which may not have been
+            // named yet.
+            OpCode::PopN | OpCode::CloseUpvalue => {
+                let count = if matches!(op, OpCode::PopN) { code[offset + 1] as usize } else { 1 };
+                let depth = self.stack.len().saturating_sub(count);
+                for slot in depth..self.stack.len() {
+                    self.local(slot, stmts);
+                }
+                self.stack.truncate(depth);
+            }
+            OpCode::DefineGlobal => {
+                let name = self.name(offset);
+                let value = self.pop();
+                let constant = self.globals.find(self.constant(code[offset + 1] as usize).as_string())
+                    .is_some_and(|slot| self.globals.is_constant(slot));
+                stmts.push(self.declaration(if constant { "const" } else { "var" }, &name, value));
+            }
+            OpCode::GetGlobal => self.push(Expr::new(self.name(offset), PRIMARY)),
+            OpCode::SetGlobal => self.assign(self.name(offset)),
+            OpCode::GetGlobalSlot => self.push(Expr::new(self.global(short), PRIMARY)),
+            OpCode::SetGlobalSlot => self.assign(self.global(short)),
+            OpCode::GetLocal | OpCode::GetLocalLong => {
+                let slot = if matches!(op, OpCode::GetLocal) { code[offset + 1] as usize } else { short };
+                let name = self.local(slot, stmts);
+                self.push(Expr::new(name, PRIMARY));
+            }
+            OpCode::SetLocal | OpCode::SetLocalLong => {
+                let slot = if matches!(op, OpCode::SetLocal) { code[offset + 1] as usize } else { short };
+                let name = self.local(slot, stmts);
+                self.assign(name);
+            }
+            OpCode::GetUpvalue => {
+                let name = self.upvalue(code[offset + 1] as usize);
+                self.push(Expr::new(name, PRIMARY));
+            }
+            OpCode::SetUpvalue => self.assign(self.upvalue(code[offset + 1] as usize)),
+            OpCode::GetProperty => {
+                let object = self.pop();
+                self.push(Expr::new(format!("{}.{}", object.wrap(CALL), self.name(offset)), CALL));
+            }
+            OpCode::SetProperty => {
+                let value = self.pop();
+                let object = self.pop();
+                self.push(Expr::new(format!("{}.{} = {}", object.wrap(CALL), self.name(offset), value.text), ASSIGNMENT));
+            }
+            OpCode::Call | OpCode::TailCall => {
+                let args = self.pop_many(code[offset + 1] as usize);
+                let callee = self.pop();
+                let args: Vec<_> = args.into_iter().map(|arg| arg.text).collect();
+                self.push(Expr::new(format!("{}({})", callee.wrap(CALL), args.join(", ")), CALL));
+            }
+            OpCode::Invoke | OpCode::TailInvoke => {
+                let args = self.pop_many(code[offset + 2] as usize);
+                let receiver = self.pop();
+                let args: Vec<_> = args.into_iter().map(|arg| arg.text).collect();
+                let text = format!("{}.{}({})", receiver.wrap(CALL), self.name(offset), args.join(", "));
+                self.push(Expr::new(text, CALL));
+            }
+            OpCode::BuildList => {
+                let items: Vec<_> = self.pop_many(code[offset + 1] as usize).into_iter().map(|item| item.text).collect();
+                self.push(Expr::new(format!("[{}]", items.join(", ")), PRIMARY));
+            }
+            OpCode::BuildMap => {
+                let values = self.pop_many(2 * code[offset + 1] as usize);
+                let entries: Vec<_> = values.chunks(2).map(|entry| format!("{}: {}", entry[0].text, entry[1].text)).collect();
+                self.push(Expr::new(format!("{{{}}}", entries.join(", ")), PRIMARY));
+            }
+            OpCode::IndexGet => {
+                let index = self.pop();
+                let object = self.pop();
+                self.push(Expr::new(format!("{}[{}]", object.wrap(CALL), index.text), CALL));
+            }
+            OpCode::IndexSet => {
+                let value = self.pop();
+                let index = self.pop();
+                let object = self.pop();
+                self.push(Expr::new(format!("{}[{}] = {}", object.wrap(CALL), index.text, value.text), ASSIGNMENT));
+            }
+            OpCode::Dup => {
+                let top = self.pop();
+                self.push(top.clone());
+                self.push(top);
+            }
+            OpCode::Dup2 => {
+                let top = self.pop();
+                let below = self.pop();
+                self.push(below.clone());
+                self.push(top.clone());
+                self.push(below);
+                self.push(top);
+            }
+            OpCode::Swap => {
+                let top = self.pop();
+                let below = self.pop();
+                self.push(top);
+                self.push(below);
+            }
+            OpCode::Yield => {
+                let value = self.pop();
+                let text = if value.text == "nil" { "yield".to_string() } else { format!("yield {}", value.text) };
+                self.push(Expr::new(text, ASSIGNMENT));
+            }
+            OpCode::Closure => {
+                let function = unsafe { &*self.constant(code[offset + 1] as usize).as_function() };
+                let mut upvalues = Vec::new();
+                for i in 0..function.upvalue_count {
+                    let (is_local, index) = (code[offset + 2 + 2 * i] == 1, code[offset + 3 + 2 * i] as usize);
+                    upvalues.push(if is_local { self.local(index, stmts) } else { self.upvalue(index) });
+                }
+                let function = decompile_function(function, self.globals, upvalues);
+                let name = if function.name == "anonymous" { " ".to_string() } else { format!(" {}", function.name) };
+                let mut body = String::new();
+                render(&function.body, 0, &mut body);
+                let body: Vec<_> = body.lines().map(str::trim).collect();
+                let text = format!("fun{}({}) {{ {} }}", name, function.params.join(", "), body.join(" "))
+                    .replace("{  }", "{}");
+                // A function called straight away needs parentheses.
+                self.push(Expr { text, precedence: ASSIGNMENT, kind: ExprKind::Function(Box::new(function)) });
+            }
+            OpCode::Class => {
+                let name = self.name(offset);
+                self.push(Expr { text: name.clone(), precedence: PRIMARY, kind: ExprKind::Class(name) });
+            }
+            OpCode::Method => {
+                let method = self.pop();
+                let class = match self.stack.last() {
+                    Some(Slot::Temp(expr)) => expr.text.clone(),
+                    Some(Slot::Local(name)) => name.clone(),
+                    None => String::new(),
+                };
+                let header = format!("class {}", class);
+                let declaration = stmts.iter_mut().rev().find_map(|stmt| match stmt {
+                    Stmt::Block(clauses) if clauses[0].0 == header => Some(&mut clauses[0].1),
+                    _ => None,
+                });
+                match (declaration, method.kind) {
+                    (Some(methods), ExprKind::Function(function)) => {
+                        let header = format!("{}({})", function.name, function.params.join(", "));
+                        methods.push(Stmt::Block(vec![(header, function.body)]));
+                    }
+                    _ => stmts.push(Stmt::Line(format!("// {} {}", opcode_name(&op), self.name(offset)))),
+                }
+            }
+            OpCode::Import => {
+                let path = self.name(offset);
+                self.push(Expr { text: format!("import(\"{}\")", path), precedence: CALL, kind: ExprKind::Import(path) });
+            }
+            OpCode::Return => {
+                let value = self.pop();
+                let implicit = value.text == "nil" || (self.initializer && value.text == "this");
+                if !implicit {
+                    stmts.push(Stmt::Line(format!("return {};", value.text)));
+                } else if next < self.chunk.code.len() {
+                    stmts.push(Stmt::Line("return;".to_string()));
+                }
+            }
+            OpCode::JumpIfFalse => return self.condition(offset, end, stmts),
+            OpCode::PushHandler => return self.try_statement(offset, stmts),
+            _ => stmts.push(Stmt::Line(format!("// {}", opcode_name(&op)))),
+        }
+        next
+    }
+
+    fn upvalue(&self, index: usize) -> String {
+        self.upvalues.get(index).cloned().unwrap_or_else(|| format!("upvalue{}", index))
+    }
+
+    // An `if`, `and` or `or` starting with the OP_JUMP_IF_FALSE at
+    // `offset`. Returns the offset after it.
+    fn condition(&mut self, offset: usize, end: usize, stmts: &mut Vec<Stmt>) -> usize {
+        let chunk = self.chunk;
+        let target = jump_target(chunk, offset, 1);
+        let after = offset + 3;
+        // a or b: a true `a` jumps straight over b.
+        if is_op(chunk, after, OpCode::Jump) && target == after + 3 && is_op(chunk, target, OpCode::Pop) {
+            let exit = jump_target(chunk, after, 1);
+            let left = self.pop();
+            let right = self.expression(target + 1, exit);
+            let text = format!("{} or {}", left.wrap(OR), right.wrap(OR + 1));
+            self.push(Expr::new(text, OR));
+            return exit;
+        }
+        if !is_op(chunk, after, OpCode::Pop) || target > end {
+            stmts.push(Stmt::Line(format!("// {} -> {}", opcode_name(&OpCode::JumpIfFalse), target)));
+            return after;
+        }
+        // if (c) then else other: the then branch ends by jumping over the
+        // else branch, which starts by popping the condition.
+        let last = instructions(chunk, after + 1, target).last().copied();
+        if let Some((else_jump, OpCode::Jump)) = last {
+            let exit = jump_target(chunk, else_jump, 1);
+            if is_op(chunk, target, OpCode::Pop) && exit > target && exit <= end {
+                let condition = self.pop();
+                self.name_locals(stmts);
+                let then = self.branch(after + 1, else_jump);
+                let other = self.branch(target + 1, exit);
+                let mut clauses = vec![(format!("if ({})", condition.text), then)];
+                match other.as_slice() {
+                    [] => {}
+                    // An else branch that is just another if is an else-if.
+                    [Stmt::Block(chain)] if chain[0].0.starts_with("if (") => {
+                        clauses.extend(chain.iter().map(|(header, body)| {
+                            let header = if header.starts_with("if (") { format!("else {}", header) } else { header.clone() };
+                            (header, body.clone())
+                        }));
+                    }
+                    _ => clauses.push(("else".to_string(), other)),
+                }
+                stmts.push(Stmt::Block(clauses));
+                return exit;
+            }
+        }
+        // a and b: a false `a` is the result.
+        let left = self.pop();
+        let right = self.expression(after + 1, target);
+        self.push(Expr::new(format!("{} and {}", left.wrap(AND), right.wrap(AND + 1)), AND));
+        target
+    }
+
+    // try { ... } catch (e) { ... }: the handler pushed at `offset` leads to
+    // the catch clause, which the try block jumps over.
+    fn try_statement(&mut self, offset: usize, stmts: &mut Vec<Stmt>) -> usize {
+        let handler = jump_target(self.chunk, offset, 1);
+        if handler < offset + 7 || !is_op(self.chunk, handler - 4, OpCode::PopHandler) || !is_op(self.chunk, handler - 3, OpCode::Jump) {
+            stmts.push(Stmt::Line(format!("// {} -> {}", opcode_name(&OpCode::PushHandler), handler)));
+            return offset + 3;
+        }
+        let exit = jump_target(self.chunk, handler - 3, 1);
+        self.name_locals(stmts);
+        let body = self.branch(offset + 3, handler - 4);
+        let name = self.started_local(handler, self.stack.len());
+        let stack = self.stack.clone();
+        self.stack.push(Slot::Local(name.clone()));
+        let catch = self.block(handler, exit);
+        self.stack = stack;
+        stmts.push(Stmt::Block(vec![("try".to_string(), body), (format!("catch ({})", name), catch)]));
+        exit
+    }
+}
+
+fn render(stmts: &[Stmt], indent: usize, out: &mut String) {
+    let pad = "  ".repeat(indent);
+    for stmt in stmts {
+        match stmt {
+            Stmt::Line(line) => {
+                let _ = writeln!(out, "{}{}", pad, line);
+            }
+            Stmt::Block(clauses) => {
+                for (i, (header, body)) in clauses.iter().enumerate() {
+                    if i == 0 {
+                        let _ = writeln!(out, "{}{} {{", pad, header);
+                    } else {
+                        let _ = writeln!(out, "{}}} {} {{", pad, header);
+                    }
+                    render(body, indent + 1, out);
+                }
+                let _ = writeln!(out, "{}}}", pad);
+            }
+        }
+    }
+}
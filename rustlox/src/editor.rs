@@ -0,0 +1,454 @@
+// Purpose: Line editing for the interactive REPL.
+//
+// Editor is a small readline: it puts the terminal in raw mode while a
+// line is being typed, so it can handle the arrow keys, the usual Emacs
+// bindings and history itself. Ctrl-C throws away the line instead of
+// killing the process. Raw mode needs termios, declared here for Linux on
+// x86_64 and aarch64, whose layouts match, and for macOS; elsewhere, and
+// when stdin isn't a terminal, lines are read as-is.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The most history lines kept, in memory and in the history file.
+const MAX_HISTORY: usize = 1000;
+
+/// What the user did at a prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// A line, without its line ending.
+    Line(String),
+    /// Ctrl-C: the line typed so far was thrown away.
+    Interrupted,
+    /// Ctrl-D on an empty line, or the end of the input.
+    Eof,
+}
+
+/// Reads REPL input a line at a time.
+pub trait LineReader {
+    /// Shows `prompt` and waits for a line.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input>;
+
+    /// Records a line that was run, for the reader's history.
+    fn add_history(&mut self, _line: &str) {}
+}
+
+/// Reads lines from any reader without editing them, e.g. when the REPL's
+/// input is piped.
+///
+/// ```
+/// use rustlox::editor::{Input, LineReader, PlainReader};
+///
+/// let mut reader = PlainReader::new("print 1;\r\n".as_bytes());
+/// assert_eq!(reader.read_line("> ").unwrap(), Input::Line("print 1;".to_string()));
+/// assert_eq!(reader.read_line("> ").unwrap(), Input::Eof);
+/// ```
+#[derive(Debug)]
+pub struct PlainReader<R> {
+    input: R,
+}
+
+impl<R: BufRead> PlainReader<R> {
+    pub fn new(input: R) -> PlainReader<R> {
+        PlainReader { input }
+    }
+}
+
+impl<R: BufRead> LineReader for PlainReader<R> {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(Input::Eof);
+        }
+        let end = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(end);
+        Ok(Input::Line(line))
+    }
+}
+
+/// Edits lines typed at the terminal, with history.
+///
+/// The history is loaded from and appended to the file given to
+/// [`Editor::with_history_file`], if any.
+#[derive(Debug, Default)]
+pub struct Editor {
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor::default()
+    }
+
+    /// An editor that keeps its history in `path`, which need not exist
+    /// yet.
+    pub fn with_history_file(path: impl Into<PathBuf>) -> Editor {
+        let path = path.into();
+        let mut history: Vec<String> = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(_) => Vec::new(),
+        };
+        if history.len() > MAX_HISTORY {
+            history.drain(..history.len() - MAX_HISTORY);
+            let _ = fs::write(&path, history.iter().map(|line| format!("{}\n", line)).collect::<String>());
+        }
+        Editor { history, history_file: Some(path) }
+    }
+
+    /// `~/.rustlox_history`, if the home directory is known.
+    pub fn default_history_file() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        Some(PathBuf::from(home).join(".rustlox_history"))
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<Input> {
+        let mut stdout = io::stdout();
+        let mut stdin = io::stdin().lock();
+        let mut line = LineState { prompt, chars: Vec::new(), cursor: 0 };
+        // Where we are in the history. The line being typed is kept in
+        // `draft` while older lines are shown.
+        let mut history_index = self.history.len();
+        let mut draft = Vec::new();
+        line.refresh(&mut stdout)?;
+        loop {
+            let Some(key) = read_key(&mut stdin)? else {
+                return Ok(Input::Eof);
+            };
+            match key {
+                Key::Enter => {
+                    write!(stdout, "\r\n")?;
+                    return Ok(Input::Line(line.chars.iter().collect()));
+                }
+                Key::Ctrl('c') => {
+                    write!(stdout, "^C\r\n")?;
+                    return Ok(Input::Interrupted);
+                }
+                Key::Ctrl('d') if line.chars.is_empty() => return Ok(Input::Eof),
+                Key::Ctrl('d') | Key::Delete => {
+                    if line.cursor < line.chars.len() {
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Backspace => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Left | Key::Ctrl('b') => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right | Key::Ctrl('f') => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::Home | Key::Ctrl('a') => line.cursor = 0,
+                Key::End | Key::Ctrl('e') => line.cursor = line.chars.len(),
+                Key::Ctrl('k') => line.chars.truncate(line.cursor),
+                Key::Ctrl('u') => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::Up | Key::Ctrl('p') => {
+                    if history_index > 0 {
+                        if history_index == self.history.len() {
+                            draft = std::mem::take(&mut line.chars);
+                        }
+                        history_index -= 1;
+                        line.set(self.history[history_index].chars().collect());
+                    }
+                }
+                Key::Down | Key::Ctrl('n') => {
+                    if history_index < self.history.len() {
+                        history_index += 1;
+                        let chars = match self.history.get(history_index) {
+                            Some(entry) => entry.chars().collect(),
+                            None => std::mem::take(&mut draft),
+                        };
+                        line.set(chars);
+                    }
+                }
+                Key::Char(c) => {
+                    line.chars.insert(line.cursor, c);
+                    line.cursor += 1;
+                }
+                Key::Ctrl(_) | Key::Unknown => continue,
+            }
+            line.refresh(&mut stdout)?;
+        }
+    }
+}
+
+impl LineReader for Editor {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        if !io::stdin().is_terminal() {
+            return PlainReader::new(io::stdin().lock()).read_line(prompt);
+        }
+        let Ok(_raw) = raw::RawMode::enable() else {
+            return PlainReader::new(io::stdin().lock()).read_line(prompt);
+        };
+        self.edit(prompt)
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+}
+
+// The line being edited, and how to redraw it.
+struct LineState<'a> {
+    prompt: &'a str,
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineState<'_> {
+    fn set(&mut self, chars: Vec<char>) {
+        self.cursor = chars.len();
+        self.chars = chars;
+    }
+
+    // Redraws the prompt and line, then moves the terminal's cursor back
+    // to ours.
+    fn refresh(&self, out: &mut impl Write) -> io::Result<()> {
+        let text: String = self.chars.iter().collect();
+        write!(out, "\r{}{}\x1b[K", self.prompt, text)?;
+        let after = self.chars.len() - self.cursor;
+        if after > 0 {
+            write!(out, "\x1b[{}D", after)?;
+        }
+        out.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+    Char(char),
+    // A control character, by the letter typed with Ctrl.
+    Ctrl(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Reads one key press, decoding UTF-8 and the escape sequences terminals
+// send for the arrow and editing keys. Returns None at the end of input.
+fn read_key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        0x1b => read_escape(input)?,
+        1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0..=31 => Key::Unknown,
+        0x80.. => {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Ok(Some(Key::Unknown)),
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte(input)? {
+                    Some(next) => bytes.push(next),
+                    None => return Ok(None),
+                }
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        }
+        _ => Key::Char(byte as char),
+    };
+    Ok(Some(key))
+}
+
+// Reads the rest of an escape sequence: ESC [ A, ESC O H, ESC [ 3 ~ ...
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    let Some(kind) = read_byte(input)? else {
+        return Ok(Key::Unknown);
+    };
+    if kind != b'[' && kind != b'O' {
+        return Ok(Key::Unknown);
+    }
+    let mut parameter = Vec::new();
+    loop {
+        let Some(byte) = read_byte(input)? else {
+            return Ok(Key::Unknown);
+        };
+        let key = match byte {
+            b'0'..=b'9' | b';' => {
+                parameter.push(byte);
+                continue;
+            }
+            b'A' => Key::Up,
+            b'B' => Key::Down,
+            b'C' => Key::Right,
+            b'D' => Key::Left,
+            b'H' => Key::Home,
+            b'F' => Key::End,
+            b'~' => match parameter.as_slice() {
+                b"1" | b"7" => Key::Home,
+                b"4" | b"8" => Key::End,
+                b"3" => Key::Delete,
+                _ => Key::Unknown,
+            },
+            _ => Key::Unknown,
+        };
+        return Ok(key);
+    }
+}
+
+#[cfg(any(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")), target_os = "macos"))]
+mod raw {
+    use std::ffi::c_int;
+    use std::io;
+
+    // Other architectures, such as powerpc and mips, number the flags and
+    // lay out the struct differently.
+    #[cfg(target_os = "linux")]
+    mod sys {
+        pub type Flag = std::ffi::c_uint;
+
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        pub struct Termios {
+            pub iflag: Flag,
+            pub oflag: Flag,
+            pub cflag: Flag,
+            pub lflag: Flag,
+            pub line: u8,
+            pub cc: [u8; 32],
+            pub ispeed: Flag,
+            pub ospeed: Flag,
+        }
+
+        pub const ISIG: Flag = 0o1;
+        pub const ICANON: Flag = 0o2;
+        pub const ECHO: Flag = 0o10;
+        pub const IEXTEN: Flag = 0o100000;
+        pub const ICRNL: Flag = 0o400;
+        pub const IXON: Flag = 0o2000;
+        pub const VTIME: usize = 5;
+        pub const VMIN: usize = 6;
+    }
+
+    #[cfg(target_os = "macos")]
+    mod sys {
+        pub type Flag = std::ffi::c_ulong;
+
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        pub struct Termios {
+            pub iflag: Flag,
+            pub oflag: Flag,
+            pub cflag: Flag,
+            pub lflag: Flag,
+            pub cc: [u8; 20],
+            pub ispeed: Flag,
+            pub ospeed: Flag,
+        }
+
+        pub const ECHO: Flag = 0x8;
+        pub const ISIG: Flag = 0x80;
+        pub const ICANON: Flag = 0x100;
+        pub const IEXTEN: Flag = 0x400;
+        pub const ICRNL: Flag = 0x100;
+        pub const IXON: Flag = 0x200;
+        pub const VMIN: usize = 16;
+        pub const VTIME: usize = 17;
+    }
+
+    use sys::*;
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, actions: c_int, termios: *const Termios) -> c_int;
+    }
+
+    const STDIN: c_int = 0;
+    // Applied at once, so keys typed while a script ran aren't lost.
+    const TCSANOW: c_int = 0;
+
+    // Keeps the terminal in raw mode until dropped. Output processing is
+    // left on, so "\n" still starts a new line.
+    pub struct RawMode {
+        original: Termios,
+    }
+
+    impl RawMode {
+        pub fn enable() -> io::Result<RawMode> {
+            let mut original = std::mem::MaybeUninit::<Termios>::uninit();
+            if unsafe { tcgetattr(STDIN, original.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = unsafe { original.assume_init() };
+            let mut raw = original;
+            raw.iflag &= !(ICRNL | IXON);
+            raw.lflag &= !(ECHO | ICANON | ISIG | IEXTEN);
+            raw.cc[VMIN] = 1;
+            raw.cc[VTIME] = 0;
+            if unsafe { tcsetattr(STDIN, TCSANOW, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode { original })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { tcsetattr(STDIN, TCSANOW, &self.original) };
+        }
+    }
+}
+
+#[cfg(not(any(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")), target_os = "macos")))]
+mod raw {
+    use std::io;
+
+    pub struct RawMode;
+
+    impl RawMode {
+        pub fn enable() -> io::Result<RawMode> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "raw mode is not supported here"))
+        }
+    }
+}
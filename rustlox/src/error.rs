@@ -0,0 +1,304 @@
+// Purpose: Errors reported to embedders and raised by native functions.
+
+use crate::handle::OwnedValue;
+use crate::native::FromValue;
+use crate::value::Value;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+    /// The script hit one of the VM's resource limits, such as its
+    /// instruction budget or timeout.
+    LimitExceeded,
+    /// The host stopped the script, as with Ctrl-C. See
+    /// [`VmBuilder::interrupt`](crate::vm::VmBuilder::interrupt).
+    Interrupted,
+}
+
+/// Whether a [`Diagnostic`] stops the code from compiling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    /// Reported, but the code still runs unless the VM denies warnings.
+    Warning,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// How the VM writes errors and warnings to its stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorFormat {
+    /// clox's `[line N] Error ...` lines, with a stack trace under runtime
+    /// errors.
+    #[default]
+    Text,
+    /// One JSON object per line, for editors and CI tools. See
+    /// [`Diagnostic::to_json`] and [`LoxError::to_json`].
+    Json,
+}
+
+/// A compile error or warning at a location in the source.
+///
+/// ```
+/// let error = rustlox::interpret("var x = 1;\nprint x +;").unwrap_err();
+/// let diagnostic = &error.diagnostics[0];
+/// assert_eq!((diagnostic.line, diagnostic.column), (2, 10));
+/// assert_eq!(diagnostic.span, Some(20..21));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    /// 1-based column of the offending token, counting characters, or 0 if
+    /// it has no place in the source.
+    pub column: usize,
+    /// Byte range of the offending token in the source, if it has one.
+    pub span: Option<Range<usize>>,
+    /// The report without its line prefix, e.g. `Error at ';': Expect expression.`
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    /// Formats the diagnostic like rustc does, with the offending line of
+    /// `source` below it and carets under the token. Diagnostics without a
+    /// span render as their first line alone.
+    ///
+    /// ```
+    /// let source = "var x = 1;\nprint x +;";
+    /// let error = rustlox::interpret(source).unwrap_err();
+    /// assert_eq!(error.diagnostics[0].render(source), "\
+    /// [line 2] Error at ';': Expect expression.
+    ///   |
+    /// 2 | print x +;
+    ///   |          ^
+    /// ");
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("{}\n", self);
+        let span = match &self.span {
+            Some(span) if self.column > 0 && span.start <= source.len() => span,
+            _ => return out,
+        };
+        let line_start = source[..span.start].rfind('\n').map_or(0, |newline| newline + 1);
+        let line_end = source[span.start..].find('\n').map_or(source.len(), |newline| span.start + newline);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        // Tokens that run onto later lines, like multi-line strings, are
+        // underlined up to the end of their first line.
+        let end = span.end.clamp(span.start, line_start + text.len());
+        let width = source[span.start..end].chars().count().max(1);
+        let gutter = " ".repeat(self.line.to_string().len());
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", self.line, text));
+        out.push_str(&format!("{} | {}{}\n", gutter, " ".repeat(self.column - 1), "^".repeat(width)));
+        out
+    }
+
+    /// The diagnostic as a line of JSON, without the newline. `file` names
+    /// the source it is in, if known.
+    ///
+    /// ```
+    /// let error = rustlox::interpret("print 1 +;").unwrap_err();
+    /// assert_eq!(error.diagnostics[0].to_json(Some("a.lox")), concat!(
+    ///     r#"{"file":"a.lox","line":1,"column":10,"severity":"error","#,
+    ///     r#""message":"Error at ';': Expect expression.","backtrace":[]}"#,
+    /// ));
+    /// ```
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":\"{}\",\"message\":{},\"backtrace\":[]}}",
+            file.map_or("null".to_string(), json_string),
+            self.line,
+            self.column,
+            self.severity.name(),
+            json_string(&self.message),
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+/// One call frame of a runtime error's stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The function's name, or `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+    /// The first and last lines of the function's declaration, or `None`
+    /// for the script and natives.
+    pub span: Option<(usize, usize)>,
+    /// The name of the source the function was compiled from, such as a
+    /// path or "repl", and the text of `line` in it. `None` for natives and
+    /// bytecode files.
+    pub source: Option<(String, String)>,
+    /// Names and printed values of the local variables in scope, when the
+    /// code was compiled with debug symbols.
+    pub locals: Vec<(String, String)>,
+}
+
+/// The alternate form, `{:#}`, also lists the frame's local variables.
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name)?,
+            None => write!(f, "[line {}] in script", self.line)?,
+        }
+        match self.span {
+            Some((start, end)) if start == end => write!(f, " declared on line {}", start)?,
+            Some((start, end)) => write!(f, " declared on lines {}-{}", start, end)?,
+            None => {}
+        }
+        if f.alternate() {
+            for (name, value) in &self.locals {
+                write!(f, "\n    {} = {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TraceFrame {
+    /// The frame as it displays, followed by where it is in its source and
+    /// the line's text, for people reading the trace.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let error = vm.eval_line("fun f() {\n  return -nil;\n}\nf();").unwrap_err();
+    /// assert_eq!(error.trace[0].render(), "\
+    /// [line 2] in f() declared on lines 1-3
+    ///   repl:2 | return -nil;
+    /// ");
+    /// ```
+    pub fn render(&self) -> String {
+        match &self.source {
+            Some((name, text)) => format!("{}\n  {}:{} | {}\n", self, name, self.line, text.trim()),
+            None => format!("{}\n", self),
+        }
+    }
+}
+
+/// An error raised while compiling or running Lox code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoxError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Every error found while compiling; empty for runtime errors.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The call stack at a runtime error, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+    /// The value the script raised with error() or assert(), if any.
+    ///
+    /// ```
+    /// use rustlox::handle::OwnedValue;
+    ///
+    /// let error = rustlox::interpret("error(\"boom\");").unwrap_err();
+    /// assert_eq!(error.message, "boom");
+    /// assert_eq!(error.value, Some(OwnedValue::String("boom".to_string())));
+    /// ```
+    pub value: Option<OwnedValue>,
+}
+
+impl LoxError {
+    pub fn runtime(message: impl Into<String>) -> LoxError {
+        LoxError {
+            kind: ErrorKind::Runtime,
+            message: message.into(),
+            diagnostics: Vec::new(),
+            trace: Vec::new(),
+            value: None,
+        }
+    }
+
+    // A runtime error raised by the script with `value`, which also serves
+    // as the message. The value must be alive, as it is in the native that
+    // raises it.
+    pub(crate) fn raised(value: Value) -> LoxError {
+        LoxError {
+            value: unsafe { OwnedValue::from_value(value) },
+            ..LoxError::runtime(format!("{}", value.display()))
+        }
+    }
+
+    pub fn compile(diagnostics: Vec<Diagnostic>) -> LoxError {
+        let message = diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n");
+        LoxError {
+            kind: ErrorKind::Compile,
+            message,
+            diagnostics,
+            trace: Vec::new(),
+            value: None,
+        }
+    }
+}
+
+impl LoxError {
+    /// A runtime error as a line of JSON in the format of
+    /// [`Diagnostic::to_json`], with its stack trace, innermost frame
+    /// first. Runtime errors have no column. Compile errors are written as
+    /// their diagnostics instead.
+    ///
+    /// ```
+    /// let error = rustlox::interpret("fun f() {\n  -nil;\n}\nf();").unwrap_err();
+    /// assert_eq!(error.to_json(None), concat!(
+    ///     r#"{"file":null,"line":2,"column":null,"severity":"error","#,
+    ///     r#""message":"Operand must be a number.","backtrace":"#,
+    ///     r#"[{"function":"f","line":2,"span":[1,3]},{"function":null,"line":4,"span":null}]}"#,
+    /// ));
+    /// ```
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let backtrace = self.trace.iter().map(|frame| {
+            let function = frame.function.as_deref().map_or("null".to_string(), json_string);
+            let span = frame.span.map_or("null".to_string(), |(start, end)| format!("[{},{}]", start, end));
+            format!("{{\"function\":{},\"line\":{},\"span\":{}}}", function, frame.line, span)
+        }).collect::<Vec<_>>();
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":null,\"severity\":\"error\",\"message\":{},\"backtrace\":[{}]}}",
+            file.map_or("null".to_string(), json_string),
+            self.trace.first().map_or("null".to_string(), |frame| frame.line.to_string()),
+            json_string(&self.message),
+            backtrace.join(","),
+        )
+    }
+}
+
+// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LoxError {}
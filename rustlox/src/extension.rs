@@ -0,0 +1,161 @@
+// Purpose: Native extension modules loaded from shared libraries, enabled
+// with the `extensions` feature.
+//
+// An extension exports a C function named `lox_extension_init` with the
+// signature of ExtensionInit. The VM calls it with an ExtensionRegistrar,
+// and the extension calls `register` once for each native it provides.
+// Only the #[repr(C)] types below cross the library boundary, so extensions
+// can be written in any language and built with any compiler.
+
+use crate::error::LoxError;
+use crate::policy::Capability;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_void;
+use std::ffi::CStr;
+use std::ffi::CString;
+
+/// Incremented whenever the types below change incompatibly.
+pub const ABI_VERSION: u32 = 1;
+
+pub const ENTRY_POINT: &str = "lox_extension_init";
+
+pub const TAG_NIL: u8 = 0;
+pub const TAG_BOOL: u8 = 1;
+pub const TAG_NUMBER: u8 = 2;
+pub const TAG_STRING: u8 = 3;
+
+/// A Lox value passed to or returned from an extension function. Bools are
+/// stored in `number` as 0 or 1. Strings are UTF-8 and are not
+/// NUL-terminated; a returned string must stay valid until the function is
+/// next called.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtensionValue {
+    pub tag: u8,
+    pub number: f64,
+    pub string: *const u8,
+    pub len: usize,
+}
+
+impl ExtensionValue {
+    fn nil() -> ExtensionValue {
+        ExtensionValue { tag: TAG_NIL, number: 0.0, string: std::ptr::null(), len: 0 }
+    }
+}
+
+/// An extension function. It writes its result to `result` and returns
+/// true, or returns false with an error message string in `result`.
+pub type ExtensionFn = extern "C" fn(args: *const ExtensionValue, arg_count: usize, result: *mut ExtensionValue) -> bool;
+
+#[repr(C)]
+pub struct ExtensionRegistrar {
+    pub abi_version: u32,
+    pub context: *mut c_void,
+    pub register: extern "C" fn(context: *mut c_void, name: *const c_char, function: ExtensionFn),
+}
+
+/// Returns false if the extension cannot be loaded, e.g. because it was
+/// built for another ABI version.
+pub type ExtensionInit = extern "C" fn(registrar: *const ExtensionRegistrar) -> bool;
+
+#[cfg_attr(target_os = "linux", link(name = "dl"))]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *const c_char;
+}
+
+const RTLD_NOW: c_int = 2;
+
+fn dl_error() -> String {
+    let message = unsafe { dlerror() };
+    if message.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+}
+
+pub fn define_extension_natives(vm: &mut Vm) {
+    vm.define_gated_native("loadExtension", Capability::Process, Box::new(load_extension_native));
+}
+
+// loadExtension(path) loads a shared library and defines the natives it
+// registers as globals. The library stays loaded until the process exits.
+fn load_extension_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    crate::native::check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Path must be a string."));
+    }
+    let path = args[0].as_str();
+    let c_path = CString::new(path).map_err(|_| LoxError::runtime("Path must not contain NUL."))?;
+    let handle = unsafe { dlopen(c_path.as_ptr(), RTLD_NOW) };
+    if handle.is_null() {
+        return Err(LoxError::runtime(format!("Could not load extension '{}': {}.", path, dl_error())));
+    }
+    let symbol = CString::new(ENTRY_POINT).unwrap();
+    let init = unsafe { dlsym(handle, symbol.as_ptr()) };
+    if init.is_null() {
+        return Err(LoxError::runtime(format!("'{}' does not export {}.", path, ENTRY_POINT)));
+    }
+    let init: ExtensionInit = unsafe { std::mem::transmute::<*mut c_void, ExtensionInit>(init) };
+
+    let registrar = ExtensionRegistrar {
+        abi_version: ABI_VERSION,
+        context: vm as *mut Vm as *mut c_void,
+        register: register_extension_fn,
+    };
+    if !init(&registrar) {
+        return Err(LoxError::runtime(format!("Extension '{}' failed to initialize.", path)));
+    }
+    Ok(Value::nil())
+}
+
+extern "C" fn register_extension_fn(context: *mut c_void, name: *const c_char, function: ExtensionFn) {
+    let vm = unsafe { &mut *(context as *mut Vm) };
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+    vm.define_native(&name, Box::new(move |vm, args| call_extension_fn(vm, function, args)));
+}
+
+fn call_extension_fn(vm: &mut Vm, function: ExtensionFn, args: &[Value]) -> Result<Value, LoxError> {
+    let mut ext_args = Vec::with_capacity(args.len());
+    for arg in args {
+        let mut value = ExtensionValue::nil();
+        if arg.is_bool() {
+            value.tag = TAG_BOOL;
+            value.number = if arg.as_bool() { 1.0 } else { 0.0 };
+        } else if arg.is_number() {
+            value.tag = TAG_NUMBER;
+            value.number = arg.as_number();
+        } else if arg.is_string() {
+            value.tag = TAG_STRING;
+            value.string = arg.as_str().as_ptr();
+            value.len = arg.as_str().len();
+        } else if !arg.is_nil() {
+            return Err(LoxError::runtime(format!("Cannot pass a {} to an extension function.", arg.type_name())));
+        }
+        ext_args.push(value);
+    }
+
+    let mut result = ExtensionValue::nil();
+    let ok = function(ext_args.as_ptr(), ext_args.len(), &mut result);
+    let string = || {
+        let bytes = unsafe { std::slice::from_raw_parts(result.string, result.len) };
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    if !ok {
+        if result.tag == TAG_STRING {
+            return Err(LoxError::runtime(string()));
+        }
+        return Err(LoxError::runtime("Extension function failed."));
+    }
+    match result.tag {
+        TAG_NIL => Ok(Value::nil()),
+        TAG_BOOL => Ok(Value::bool(result.number != 0.0)),
+        TAG_NUMBER => Ok(Value::number(result.number)),
+        TAG_STRING => Ok(vm.take_string(string())),
+        _ => Err(LoxError::runtime("Extension function returned an unknown value type.")),
+    }
+}
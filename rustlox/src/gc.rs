@@ -0,0 +1,372 @@
+// Purpose: Mark-and-sweep garbage collection of the objects in an ObjArray.
+//
+// The VM marks its roots, then the ObjArray traces everything reachable
+// from them and frees the rest. Collections only start between
+// instructions, never while compiling, so objects the compiler or a native
+// is still building can't be freed out from under it. With the `hooks`
+// feature, each collection is reported to the hook set with Vm::on_gc.
+
+use crate::object::Obj;
+use crate::object::ObjArray;
+use crate::object::ObjBoundMethod;
+use crate::object::ObjClass;
+use crate::object::ObjClosure;
+use crate::object::ObjCoroutine;
+use crate::object::ObjFunction;
+use crate::object::ObjInstance;
+use crate::object::ObjList;
+use crate::object::ObjMap;
+use crate::object::ObjModule;
+use crate::object::ObjNative;
+use crate::object::ObjType;
+use crate::object::ObjUpvalue;
+use crate::platform::Platform;
+use crate::table::Globals;
+use crate::table::StringMap;
+use crate::value::Value;
+use crate::vm::Fiber;
+#[cfg(feature = "hooks")]
+use std::fmt;
+
+/// The state of a VM's heap, from [`Vm::gc_stats`](crate::vm::Vm::gc_stats).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcStats {
+    /// Bytes the heap is using, garbage included.
+    pub bytes_allocated: usize,
+    /// Objects on the heap, garbage included.
+    pub objects: usize,
+    /// Collections run so far.
+    pub collections: usize,
+    /// The heap size at which the next collection runs.
+    pub next_gc: usize,
+}
+
+/// Why a collection ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcReason {
+    /// The heap grew past the size set after the last collection.
+    HeapGrowth,
+    /// The heap is over the VM's heap limit.
+    HeapLimit,
+    /// The VM is collecting before every instruction, for testing.
+    Stress,
+    /// The host or a script asked for it.
+    Requested,
+}
+
+/// What one collection did, as passed to the hook set with
+/// [`Vm::on_gc`](crate::vm::Vm::on_gc). Displays as a single line of
+/// `key=value` fields.
+#[cfg(feature = "hooks")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcEvent {
+    pub reason: GcReason,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    /// Seconds the collection took, by the VM's platform clock.
+    pub pause: f64,
+    /// How many objects of each type were freed, for the types with any.
+    pub freed: Vec<(ObjType, usize)>,
+}
+
+#[cfg(feature = "hooks")]
+impl fmt::Display for GcEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gc reason={:?} before={} after={} pause={:.6}", self.reason, self.bytes_before, self.bytes_after, self.pause)?;
+        for (t, count) in &self.freed {
+            write!(f, " {:?}={}", t, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Totals over the collections of a run, built from their [`GcEvent`]s.
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use rustlox::GcReport;
+/// use rustlox::Vm;
+///
+/// let report = Arc::new(Mutex::new(GcReport::default()));
+/// let mut vm = Vm::new();
+/// let recorder = report.clone();
+/// vm.on_gc(move |event| recorder.lock().unwrap().record(event));
+/// vm.interpret("var s; for (var i = 0; i < 10; i = i + 1) s = \"a\" + \"b\"; gc();").unwrap();
+/// let report = report.lock().unwrap();
+/// assert_eq!(report.collections, 1);
+/// assert!(report.bytes_freed > 0);
+/// assert!(report.to_string().starts_with("collections 1\n"));
+/// ```
+#[cfg(feature = "hooks")]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GcReport {
+    pub collections: usize,
+    /// Seconds spent collecting, in all and in the longest collection.
+    pub total_pause: f64,
+    pub longest_pause: f64,
+    pub bytes_freed: usize,
+    /// Objects freed of each type, most first once displayed.
+    pub freed: Vec<(ObjType, usize)>,
+}
+
+#[cfg(feature = "hooks")]
+impl GcReport {
+    pub fn record(&mut self, event: &GcEvent) {
+        self.collections += 1;
+        self.total_pause += event.pause;
+        self.longest_pause = self.longest_pause.max(event.pause);
+        self.bytes_freed += event.bytes_before.saturating_sub(event.bytes_after);
+        for &(t, count) in &event.freed {
+            add_freed(&mut self.freed, t, count);
+        }
+    }
+}
+
+#[cfg(feature = "hooks")]
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "collections {}", self.collections)?;
+        writeln!(f, "pause total {:.6}s, longest {:.6}s", self.total_pause, self.longest_pause)?;
+        writeln!(f, "bytes freed {}", self.bytes_freed)?;
+        let mut freed = self.freed.clone();
+        freed.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        for (t, count) in freed {
+            writeln!(f, "freed {:?} {}", t, count)?;
+        }
+        Ok(())
+    }
+}
+
+// Adds `count` freed objects of type `t` to the tally in `freed`.
+fn add_freed(freed: &mut Vec<(ObjType, usize)>, t: ObjType, count: usize) {
+    match freed.iter_mut().find(|(freed_type, _)| *freed_type == t) {
+        Some((_, total)) => *total += count,
+        None => freed.push((t, count)),
+    }
+}
+
+// After a collection, the next one runs when the heap has grown to this
+// many times the size that survived.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+impl ObjArray {
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if value.is_object() {
+            unsafe { self.mark_object(value.as_object() as *mut Obj) };
+        }
+    }
+
+    /// Marks an object and queues it to have its references traced.
+    ///
+    /// # Safety
+    ///
+    /// `obj` must be null or point to a live object in this ObjArray.
+    pub unsafe fn mark_object(&mut self, obj: *mut Obj) {
+        if obj.is_null() {
+            return;
+        }
+        unsafe {
+            if (*obj).is_marked {
+                return;
+            }
+            (*obj).is_marked = true;
+        }
+        self.gray.push(obj);
+    }
+
+    // Marks the names and values of globals, fields or methods.
+    pub fn mark_table(&mut self, table: &StringMap<Value>) {
+        for (key, value) in table {
+            unsafe { self.mark_object(*key as *mut Obj) };
+            self.mark_value(*value);
+        }
+    }
+
+    // Marks the names of every global, defined or not, since the compiled
+    // code refers to their slots, and the values of the defined ones.
+    pub fn mark_globals(&mut self, globals: &Globals) {
+        for slot in 0..globals.len() {
+            unsafe { self.mark_object(globals.name(slot) as *mut Obj) };
+            if let Some(value) = globals.get(slot) {
+                self.mark_value(value);
+            }
+        }
+    }
+
+    // Marks what a paused coroutine, or one waiting on a coroutine it
+    // resumed, has on its stack and in its frames.
+    pub fn mark_fiber(&mut self, fiber: &Fiber) {
+        for value in &fiber.stack[..fiber.stack_top] {
+            self.mark_value(*value);
+        }
+        for frame in &fiber.frames[..fiber.frame_count] {
+            unsafe { self.mark_object(frame.closure as *mut Obj) };
+        }
+        let mut upvalue = fiber.open_upvalues;
+        while !upvalue.is_null() {
+            unsafe { self.mark_object(upvalue as *mut Obj) };
+            upvalue = unsafe { (*upvalue).next };
+        }
+    }
+
+    // Marks everything reachable from the gray objects. Afterwards, any
+    // object still unmarked is garbage.
+    pub fn trace(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    // Marks everything reachable from the gray objects, then frees every
+    // object left unmarked. `reason` and the platform's clock are for the
+    // GC hook.
+    pub fn collect(&mut self, reason: GcReason, platform: &dyn Platform) {
+        #[cfg(feature = "hooks")]
+        let (start, bytes_before) = (self.on_gc.0.as_ref().map(|_| platform.clock()), self.bytes_allocated);
+        #[cfg(not(feature = "hooks"))]
+        let _ = (reason, platform);
+        self.trace();
+        // The intern table doesn't keep strings alive.
+        self.strings.retain(|string| unsafe { (*string).obj.is_marked });
+        self.close_unreached_coroutines();
+        #[cfg(feature = "hooks")]
+        let mut freed = start.map(|_| Vec::new());
+        #[cfg(not(feature = "hooks"))]
+        let mut freed = None;
+        self.sweep(freed.as_mut());
+        self.collections += 1;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(self.next_gc);
+        #[cfg(feature = "hooks")]
+        if let (Some(hook), Some(start), Some(freed)) = (&mut self.on_gc.0, start, freed) {
+            hook(&GcEvent {
+                reason,
+                bytes_before,
+                bytes_after: self.bytes_allocated,
+                pause: platform.clock() - start,
+                freed,
+            });
+        }
+    }
+
+    fn blacken_object(&mut self, obj: *mut Obj) {
+        unsafe {
+            match (*obj).t {
+                ObjType::String | ObjType::Foreign => {}
+                ObjType::Native => {
+                    self.mark_object((*(obj as *mut ObjNative)).name as *mut Obj);
+                }
+                ObjType::Upvalue => {
+                    // An open upvalue may point into a coroutine that is
+                    // about to be freed, so mark what it points to.
+                    self.mark_value(*(*(obj as *mut ObjUpvalue)).location);
+                }
+                ObjType::Function => {
+                    let function = &*(obj as *mut ObjFunction);
+                    self.mark_object(function.name as *mut Obj);
+                    self.mark_object(function.module as *mut Obj);
+                    for constant in &function.chunk.constants.values {
+                        self.mark_value(*constant);
+                    }
+                }
+                ObjType::Closure => {
+                    let closure = &*(obj as *mut ObjClosure);
+                    self.mark_object(closure.function as *mut Obj);
+                    for upvalue in &closure.upvalues {
+                        self.mark_object(*upvalue as *mut Obj);
+                    }
+                }
+                ObjType::Class => {
+                    let class = &*(obj as *mut ObjClass);
+                    self.mark_object(class.name as *mut Obj);
+                    self.mark_table(&class.methods);
+                }
+                ObjType::Instance => {
+                    let instance = &*(obj as *mut ObjInstance);
+                    self.mark_object(instance.class as *mut Obj);
+                    self.mark_table(&instance.fields);
+                }
+                ObjType::BoundMethod => {
+                    let bound = obj as *mut ObjBoundMethod;
+                    self.mark_value((*bound).receiver);
+                    self.mark_object((*bound).method as *mut Obj);
+                }
+                ObjType::List => {
+                    let list = &*(obj as *mut ObjList);
+                    for item in &list.items {
+                        self.mark_value(*item);
+                    }
+                }
+                ObjType::Map => {
+                    let map = &*(obj as *mut ObjMap);
+                    for (key, value) in &map.entries {
+                        self.mark_value(*key);
+                        self.mark_value(*value);
+                    }
+                }
+                ObjType::Module => {
+                    let module = &*(obj as *mut ObjModule);
+                    self.mark_object(module.name as *mut Obj);
+                    self.mark_globals(&module.globals);
+                }
+                ObjType::Coroutine => {
+                    let coroutine = &*(obj as *mut ObjCoroutine);
+                    self.mark_object(coroutine.resumer as *mut Obj);
+                    self.mark_fiber(&coroutine.fiber);
+                }
+            }
+        }
+    }
+
+    // Closures that outlive a paused coroutine keep the variables they
+    // captured from its stack, so close those upvalues before the stack is
+    // freed with the coroutine.
+    fn close_unreached_coroutines(&mut self) {
+        let mut obj = self.objects;
+        while !obj.is_null() {
+            unsafe {
+                if (*obj).t == ObjType::Coroutine && !(*obj).is_marked {
+                    let fiber = &mut (*(obj as *mut ObjCoroutine)).fiber;
+                    while !fiber.open_upvalues.is_null() {
+                        let upvalue = fiber.open_upvalues;
+                        (*upvalue).closed = *(*upvalue).location;
+                        (*upvalue).location = &mut (*upvalue).closed;
+                        fiber.open_upvalues = (*upvalue).next;
+                    }
+                }
+                obj = (*obj).next;
+            }
+        }
+    }
+
+    // Frees the unmarked objects, counting them by type in `freed` if
+    // given.
+    fn sweep(&mut self, mut freed: Option<&mut Vec<(ObjType, usize)>>) {
+        let mut previous: *mut Obj = std::ptr::null_mut();
+        let mut obj = self.objects;
+        while !obj.is_null() {
+            unsafe {
+                if (*obj).is_marked {
+                    (*obj).is_marked = false;
+                    previous = obj;
+                    obj = (*obj).next;
+                    continue;
+                }
+                let unreached = obj;
+                obj = (*obj).next;
+                if previous.is_null() {
+                    self.objects = obj;
+                } else {
+                    (*previous).next = obj;
+                }
+                if let Some(freed) = freed.as_deref_mut() {
+                    add_freed(freed, (*unreached).t, 1);
+                }
+                self.free_object(unreached);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Purpose: Running a VM on its own thread.
+//
+// A Vm is Send, so it can be moved to another thread, but it is not Sync:
+// only one thread can use it at a time. A VmHandle owns a worker thread that
+// builds and keeps the Vm, and sends it jobs over a channel. The handle
+// itself is Send and Sync, so it can be shared across a thread pool.
+
+use crate::error::LoxError;
+use crate::native::FromValue;
+use crate::native::IntoValue;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce(&mut Vm) + Send>;
+
+/// A Lox value copied out of a VM's heap, so it can cross threads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Any other object, by its printed form, e.g. `<fn add>`.
+    Object(String),
+}
+
+impl FromValue for OwnedValue {
+    const TYPE_NAME: &'static str = "value";
+
+    unsafe fn from_value(value: Value) -> Option<Self> {
+        Some(if value.is_nil() {
+            OwnedValue::Nil
+        } else if value.is_bool() {
+            OwnedValue::Bool(value.as_bool())
+        } else if value.is_number() {
+            OwnedValue::Number(value.as_number())
+        } else if value.is_string() {
+            OwnedValue::String(value.as_str().to_string())
+        } else {
+            OwnedValue::Object(format!("{}", value.display()))
+        })
+    }
+}
+
+impl IntoValue for OwnedValue {
+    fn into_value(self, vm: &mut Vm) -> Value {
+        match self {
+            OwnedValue::Nil | OwnedValue::Object(_) => Value::nil(),
+            OwnedValue::Bool(b) => Value::bool(b),
+            OwnedValue::Number(n) => Value::number(n),
+            OwnedValue::String(s) => vm.take_string(s),
+        }
+    }
+}
+
+/// A VM running on a worker thread.
+///
+/// ```
+/// use rustlox::handle::{OwnedValue, VmHandle};
+/// use rustlox::Vm;
+///
+/// let handle = VmHandle::spawn(Vm::new);
+/// handle.eval("fun double(x) { return x * 2; }").unwrap();
+/// let result = std::thread::scope(|scope| {
+///     scope.spawn(|| handle.call_function("double", vec![OwnedValue::Number(21.0)])).join().unwrap()
+/// });
+/// assert_eq!(result, Ok(OwnedValue::Number(42.0)));
+/// ```
+pub struct VmHandle {
+    jobs: Option<Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl VmHandle {
+    /// Starts a worker thread and builds its VM there with `build`.
+    pub fn spawn<F>(build: F) -> VmHandle
+    where
+        F: FnOnce() -> Vm + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let worker = thread::spawn(move || {
+            let mut vm = build();
+            for job in receiver {
+                job(&mut vm);
+            }
+        });
+        VmHandle { jobs: Some(jobs), worker: Some(worker) }
+    }
+
+    /// Queues `job` to run on the VM and returns a receiver for its result.
+    /// Jobs run one at a time, in the order they were submitted.
+    pub fn submit<R, F>(&self, job: F) -> Receiver<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Vm) -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job: Job = Box::new(move |vm| {
+            let _ = sender.send(job(vm));
+        });
+        self.jobs.as_ref().unwrap().send(job).expect("VM worker thread exited");
+        receiver
+    }
+
+    /// Runs `job` on the VM and waits for its result.
+    pub fn with<R, F>(&self, job: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Vm) -> R + Send + 'static,
+    {
+        self.submit(job).recv().expect("VM worker thread panicked")
+    }
+
+    /// Runs `source` in the VM's session, like [`Vm::eval`].
+    pub fn eval(&self, source: &str) -> Result<OwnedValue, LoxError> {
+        let source = source.to_string();
+        self.with(move |vm| vm.eval(&source).map(owned))
+    }
+
+    /// Calls a global Lox function, like [`Vm::call_function`]. Arguments
+    /// can't be [`OwnedValue::Object`]s.
+    pub fn call_function(&self, name: &str, args: Vec<OwnedValue>) -> Result<OwnedValue, LoxError> {
+        let name = name.to_string();
+        self.with(move |vm| {
+            let args = arguments(vm, args)?;
+            vm.call_function(&name, &args).map(owned)
+        })
+    }
+}
+
+// Only for values the VM has just returned, before anything can collect
+// them.
+pub(crate) fn owned(value: Value) -> OwnedValue {
+    unsafe { OwnedValue::from_value(value) }.unwrap()
+}
+
+// Converts arguments for a call into the VM. An Object only holds its
+// printed form, so it can't be passed back in.
+pub(crate) fn arguments(vm: &mut Vm, args: Vec<OwnedValue>) -> Result<Vec<Value>, LoxError> {
+    args.into_iter().enumerate().map(|(i, arg)| match arg {
+        OwnedValue::Object(text) => Err(LoxError::runtime(format!(
+            "Argument {} is {}, which can't be passed back into the VM.", i + 1, text))),
+        arg => Ok(arg.into_value(vm)),
+    }).collect()
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        // Closing the channel ends the worker's loop.
+        drop(self.jobs.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
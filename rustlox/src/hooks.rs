@@ -0,0 +1,40 @@
+// Purpose: Instrumentation callbacks, enabled with the `hooks` feature.
+//
+// Without the feature the VM has no hook fields and the run loop has no
+// checks for them, so unused hooks cost nothing.
+
+use crate::chunk::Chunk;
+use crate::gc::GcEvent;
+use crate::object::Obj;
+use crate::object::ObjFunction;
+use std::fmt;
+
+/// An optional callback.
+pub struct Hook<F: ?Sized>(pub Option<Box<F>>);
+
+impl<F: ?Sized> Default for Hook<F> {
+    fn default() -> Self {
+        Hook(None)
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Some(<hook>)"),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+pub type InstructionHook = Hook<dyn FnMut(&Chunk, usize) + Send>;
+pub type FunctionHook = Hook<dyn FnMut(&ObjFunction) + Send>;
+pub type AllocHook = Hook<dyn FnMut(&Obj) + Send>;
+pub type GcHook = Hook<dyn FnMut(&GcEvent) + Send>;
+
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub on_instruction: InstructionHook,
+    pub on_call: FunctionHook,
+    pub on_return: FunctionHook,
+}
@@ -0,0 +1,203 @@
+// Purpose: HTTP client natives, enabled with the `http` feature.
+//
+// httpGet(url, headers) and httpPost(url, body, headers) make one HTTP/1.1
+// request each, over a connection that is closed afterwards, and return a
+// map with the response's "status", "headers" and "body". Header names in
+// the response are lowercased. Only plain http:// URLs are supported, since
+// the crate has no TLS implementation. A response may be no bigger than the
+// VM's heap limit, if it has one.
+
+use crate::error::LoxError;
+use crate::native::check_arity;
+use crate::policy::Capability;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Take;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// How long a request may wait on the server for each read or write.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+pub fn define_http_natives(vm: &mut Vm) {
+    // httpGet(url) or httpGet(url, headers).
+    vm.define_gated_native("httpGet", Capability::Net, Box::new(|vm, args| {
+        if args.len() != 1 {
+            check_arity(args, 2)?;
+        }
+        let headers = request_headers(args.get(1).copied())?;
+        let response = request("GET", url(&args[0])?, &headers, None, response_limit(vm))?;
+        Ok(response_value(vm, response))
+    }));
+
+    // httpPost(url, body) or httpPost(url, body, headers).
+    vm.define_gated_native("httpPost", Capability::Net, Box::new(|vm, args| {
+        if args.len() != 2 {
+            check_arity(args, 3)?;
+        }
+        if !args[1].is_string() {
+            return Err(LoxError::runtime("Body must be a string."));
+        }
+        let headers = request_headers(args.get(2).copied())?;
+        let response = request("POST", url(&args[0])?, &headers, Some(args[1].as_str()), response_limit(vm))?;
+        Ok(response_value(vm, response))
+    }));
+}
+
+fn url(value: &Value) -> Result<&str, LoxError> {
+    if !value.is_string() {
+        return Err(LoxError::runtime("URL must be a string."));
+    }
+    // Whitespace would end the request line early, and a line break would
+    // let the URL add lines of its own to the request.
+    if value.as_str().chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(LoxError::runtime("URL can't contain whitespace or control characters."));
+    }
+    Ok(value.as_str())
+}
+
+fn response_limit(vm: &Vm) -> u64 {
+    vm.config().heap_limit.map_or(u64::MAX, |limit| limit as u64)
+}
+
+// The headers to send, from a map of strings to strings, or none for nil.
+fn request_headers(headers: Option<Value>) -> Result<Vec<(String, String)>, LoxError> {
+    let Some(headers) = headers.filter(|headers| !headers.is_nil()) else {
+        return Ok(Vec::new());
+    };
+    if !headers.is_map() {
+        return Err(LoxError::runtime("Headers must be a map."));
+    }
+    let entries = unsafe { &(*headers.as_map()).entries };
+    let mut list = Vec::new();
+    for (name, value) in entries {
+        if !name.is_string() || !value.is_string() {
+            return Err(LoxError::runtime("Header names and values must be strings."));
+        }
+        if [name.as_str(), value.as_str()].iter().any(|text| text.contains(['\r', '\n'])) {
+            return Err(LoxError::runtime("Header names and values can't contain line breaks."));
+        }
+        list.push((name.as_str().to_string(), value.as_str().to_string()));
+    }
+    Ok(list)
+}
+
+fn request(method: &str, url: &str, headers: &[(String, String)], body: Option<&str>, limit: u64) -> Result<Response, LoxError> {
+    let failed = |err: std::io::Error| LoxError::runtime(format!("Request to {} failed: {}.", url, err));
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(LoxError::runtime("Only http:// URLs are supported."));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let mut stream = TcpStream::connect(&address).map_err(failed)?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(failed)?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(failed)?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, authority);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(body.unwrap_or(""));
+    stream.write_all(request.as_bytes()).map_err(failed)?;
+
+    // Reading stops at the limit, which then looks like the end of the
+    // response, so the limit is checked wherever the response ends.
+    let mut reader = BufReader::new(stream.take(limit));
+    let malformed = || LoxError::runtime(format!("Malformed response from {}.", url));
+    let too_large = || LoxError::runtime(format!("Response from {} is too large.", url));
+    // Why the body ended early: the limit, or the server.
+    let cut_short = |reader: &BufReader<Take<TcpStream>>| if reader.get_ref().limit() == 0 { too_large() } else { malformed() };
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(failed)?;
+    let status = line.split(' ').nth(1).and_then(|code| code.parse().ok()).ok_or_else(malformed)?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).map_err(failed)?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).map_err(failed)?;
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            let size = u64::from_str_radix(size, 16).map_err(|_| malformed())?;
+            if size == 0 {
+                break;
+            }
+            let end = (body.len() as u64).checked_add(size).filter(|&end| end <= limit).ok_or_else(too_large)?;
+            read_body(&mut reader, &mut body, size).map_err(failed)?;
+            if (body.len() as u64) < end {
+                return Err(cut_short(&reader));
+            }
+            // Each chunk ends with a line break.
+            reader.read_line(&mut line).map_err(failed)?;
+        }
+    } else if let Some(length) = header("content-length") {
+        let length: u64 = length.parse().map_err(|_| malformed())?;
+        if length > limit {
+            return Err(too_large());
+        }
+        read_body(&mut reader, &mut body, length).map_err(failed)?;
+        if (body.len() as u64) < length {
+            return Err(cut_short(&reader));
+        }
+    } else {
+        reader.read_to_end(&mut body).map_err(failed)?;
+        if reader.get_ref().limit() == 0 {
+            return Err(too_large());
+        }
+    }
+    Ok(Response { status, headers, body })
+}
+
+// Appends up to `size` more bytes to `body`. It grows as they arrive, so a
+// size the server doesn't send can't make it allocate them up front.
+fn read_body(reader: &mut BufReader<Take<TcpStream>>, body: &mut Vec<u8>, size: u64) -> io::Result<usize> {
+    reader.by_ref().take(size).read_to_end(body)
+}
+
+fn response_value(vm: &mut Vm, response: Response) -> Value {
+    // Nothing is collected while a native runs, so the new objects are safe
+    // until the map holds them.
+    let mut headers = HashMap::new();
+    for (name, value) in response.headers {
+        let name = vm.take_string(name);
+        let value = vm.take_string(value);
+        headers.insert(name, value);
+    }
+    let headers = vm.new_map(headers);
+    let body = vm.take_string(String::from_utf8_lossy(&response.body).into_owned());
+    let mut entries = HashMap::new();
+    entries.insert(vm.new_string("status"), Value::number(response.status as f64));
+    entries.insert(vm.new_string("headers"), headers);
+    entries.insert(vm.new_string("body"), body);
+    vm.new_map(entries)
+}
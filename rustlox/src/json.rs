@@ -0,0 +1,113 @@
+// Purpose: Conversions between Lox values and serde data, enabled with the
+// `serde` feature.
+//
+// Nil, booleans, numbers, strings, lists and maps convert. JSON objects only
+// have string keys, so maps with other keys are rejected with an error, as
+// are functions and instances.
+
+use crate::error::LoxError;
+use crate::object::Obj;
+use crate::value::Value;
+use crate::vm::Vm;
+use serde::de::DeserializeOwned;
+use serde::ser::Error;
+use serde::Serialize;
+use serde::Serializer;
+use serde_json::Number;
+use std::collections::HashMap;
+
+pub fn to_json(value: Value) -> Result<serde_json::Value, LoxError> {
+    nested_to_json(value, &mut Vec::new())
+}
+
+// `enclosing` holds the lists and maps being converted, so one that
+// contains itself is an error instead of endless recursion.
+fn nested_to_json(value: Value, enclosing: &mut Vec<*const Obj>) -> Result<serde_json::Value, LoxError> {
+    if value.is_nil() {
+        return Ok(serde_json::Value::Null);
+    }
+    if value.is_bool() {
+        return Ok(serde_json::Value::Bool(value.as_bool()));
+    }
+    if value.is_number() {
+        return Number::from_f64(value.as_number())
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| LoxError::runtime(format!("Cannot convert {} to JSON.", value.as_number())));
+    }
+    if value.is_string() {
+        return Ok(serde_json::Value::String(value.as_str().to_string()));
+    }
+    if value.is_list() || value.is_map() {
+        if enclosing.contains(&value.as_object()) {
+            return Err(LoxError::runtime(format!("Cannot convert a {} that contains itself to JSON.", value.type_name())));
+        }
+        enclosing.push(value.as_object());
+        let json = if value.is_list() {
+            let items = unsafe { &(*value.as_list()).items };
+            serde_json::Value::Array(items.iter().map(|item| nested_to_json(*item, enclosing)).collect::<Result<_, _>>()?)
+        } else {
+            let mut object = serde_json::Map::new();
+            for (key, item) in unsafe { &(*value.as_map()).entries } {
+                if !key.is_string() {
+                    return Err(LoxError::runtime("Cannot convert a map with non-string keys to JSON."));
+                }
+                object.insert(key.as_str().to_string(), nested_to_json(*item, enclosing)?);
+            }
+            serde_json::Value::Object(object)
+        };
+        enclosing.pop();
+        return Ok(json);
+    }
+    Err(LoxError::runtime(format!("Cannot convert a {} to JSON.", value.type_name())))
+}
+
+pub fn from_json(vm: &mut Vm, json: &serde_json::Value) -> Result<Value, LoxError> {
+    match json {
+        serde_json::Value::Null => Ok(Value::nil()),
+        serde_json::Value::Bool(b) => Ok(Value::bool(*b)),
+        serde_json::Value::Number(n) => Ok(Value::number(n.as_f64().unwrap_or(f64::NAN))),
+        serde_json::Value::String(s) => Ok(vm.new_string(s)),
+        serde_json::Value::Array(array) => {
+            let items = array.iter().map(|item| from_json(vm, item)).collect::<Result<_, _>>()?;
+            Ok(vm.new_list(items))
+        }
+        serde_json::Value::Object(object) => {
+            let mut entries = HashMap::new();
+            for (key, item) in object {
+                let key = vm.new_string(key);
+                entries.insert(key, from_json(vm, item)?);
+            }
+            Ok(vm.new_map(entries))
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        to_json(*self).map_err(S::Error::custom)?.serialize(serializer)
+    }
+}
+
+impl Vm {
+    /// Converts any serializable Rust value into a Lox value.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let name = vm.to_value("Lox").unwrap();
+    /// vm.set_global("name", name);
+    /// let greeting = vm.eval_line("\"Hello, \" + name").unwrap();
+    /// let greeting: String = vm.from_value(greeting).unwrap();
+    /// assert_eq!(greeting, "Hello, Lox");
+    /// ```
+    pub fn to_value<T: Serialize + ?Sized>(&mut self, data: &T) -> Result<Value, LoxError> {
+        let json = serde_json::to_value(data).map_err(|err| LoxError::runtime(err.to_string()))?;
+        from_json(self, &json)
+    }
+
+    /// Converts a Lox value into any deserializable Rust type.
+    pub fn from_value<T: DeserializeOwned>(&self, value: Value) -> Result<T, LoxError> {
+        serde_json::from_value(to_json(value)?).map_err(|err| LoxError::runtime(err.to_string()))
+    }
+}
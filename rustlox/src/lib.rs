@@ -0,0 +1,89 @@
+//! rustlox is a bytecode virtual machine for the Lox language from
+//! Crafting Interpreters.
+//!
+//! The simplest way to run a script is [`interpret`]:
+//!
+//! ```
+//! let result = rustlox::interpret("print 1 + 2;");
+//! assert!(result.is_ok());
+//! ```
+//!
+//! To run several scripts against the same heap, create a [`Lox`] and call
+//! [`Lox::eval`] on it, or use a [`Vm`] for full control.
+
+pub(crate) mod arena;
+pub(crate) mod ast;
+pub(crate) mod bytecode;
+pub(crate) mod check;
+pub(crate) mod chunk;
+pub(crate) mod compiler;
+pub mod debug;
+pub mod debugger;
+pub(crate) mod decompile;
+pub mod editor;
+pub mod error;
+#[cfg(all(feature = "extensions", unix))]
+pub(crate) mod extension;
+pub(crate) mod gc;
+pub mod handle;
+#[cfg(feature = "hooks")]
+pub mod hooks;
+#[cfg(feature = "http")]
+pub(crate) mod http;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod lox;
+pub(crate) mod native;
+#[cfg(feature = "net")]
+pub(crate) mod net;
+pub(crate) mod object;
+pub(crate) mod optimize;
+pub mod platform;
+pub mod policy;
+pub mod profile;
+pub mod repl;
+pub mod scanner;
+pub(crate) mod table;
+pub(crate) mod treewalk;
+pub(crate) mod value;
+pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use crate::bytecode::is_bytecode;
+pub use crate::error::ErrorKind;
+pub use crate::error::LoxError;
+#[cfg(feature = "hooks")]
+pub use crate::gc::GcEvent;
+pub use crate::gc::GcReason;
+#[cfg(feature = "hooks")]
+pub use crate::gc::GcReport;
+pub use crate::gc::GcStats;
+pub use crate::handle::OwnedValue;
+pub use crate::lox::Lox;
+pub use crate::native::FromValue;
+pub use crate::native::IntoNative;
+pub use crate::native::IntoNativeResult;
+pub use crate::native::IntoValue;
+pub use crate::native::NativeGroup;
+pub use crate::object::NativeFn;
+pub use crate::value::format_number;
+pub use crate::value::Value;
+pub use crate::value::ValueType;
+pub use crate::vm::interpret;
+pub use crate::vm::InterpretResult;
+pub use crate::vm::Vm;
+
+/// Embeds a precompiled `.loxc` file in the binary, for use with
+/// [`Vm::load_bytecode`]. The path is relative to the calling source file.
+///
+/// ```ignore
+/// let mut vm = rustlox::Vm::new();
+/// vm.load_bytecode(rustlox::include_lox!("scripts/startup.loxc")).unwrap();
+/// ```
+#[macro_export]
+macro_rules! include_lox {
+    ($path:expr) => {
+        include_bytes!($path) as &'static [u8]
+    };
+}
@@ -0,0 +1,136 @@
+// Purpose: The Lox struct, the smallest interface for embedding rustlox.
+//
+// It is a thin wrapper over Vm with just the calls most hosts need: run
+// some code, pass values in and read them back out. Anything more, such as
+// natives, limits or captured output, is on the Vm it wraps.
+
+use crate::error::LoxError;
+use crate::handle::arguments;
+use crate::handle::owned;
+use crate::handle::OwnedValue;
+use crate::native::FromValue;
+use crate::native::IntoValue;
+use crate::vm::CompiledScript;
+use crate::vm::Vm;
+
+/// A Lox interpreter whose globals last from one [`Lox::eval`] to the next.
+///
+/// ```
+/// use rustlox::Lox;
+///
+/// let mut lox = Lox::new();
+/// lox.define_global("name", "world");
+/// lox.eval("fun greet() { return \"hello \" + name; }").unwrap();
+/// lox.eval("var greeting = greet();").unwrap();
+/// assert_eq!(lox.get_global::<String>("greeting").unwrap(), "hello world");
+/// assert!(lox.eval("greet(1);").is_err());
+/// ```
+pub struct Lox {
+    vm: Vm,
+}
+
+impl Lox {
+    /// Creates an interpreter with the default configuration and every
+    /// native function defined.
+    pub fn new() -> Lox {
+        Lox { vm: Vm::new() }
+    }
+
+    /// Runs `source`, returning a copy of what [`Vm::eval`] returns, which
+    /// stays valid after the interpreter is dropped.
+    pub fn eval(&mut self, source: &str) -> Result<OwnedValue, LoxError> {
+        self.vm.eval(source).map(owned)
+    }
+
+    /// Calls the global function `name` with `args`, like
+    /// [`Vm::call_function`], and returns a copy of its result. An
+    /// [`OwnedValue::Object`] only holds the object's printed form, so it
+    /// can't be an argument.
+    ///
+    /// ```
+    /// use rustlox::{Lox, OwnedValue};
+    ///
+    /// let mut lox = Lox::new();
+    /// lox.eval("fun greet(name) { return \"hello \" + name; }").unwrap();
+    /// let greeting = lox.call_function("greet", &[OwnedValue::String("world".to_string())]);
+    /// assert_eq!(greeting.unwrap(), OwnedValue::String("hello world".to_string()));
+    /// assert!(lox.call_function("missing", &[]).is_err());
+    /// let function = OwnedValue::Object("<fn greet>".to_string());
+    /// assert!(lox.call_function("greet", &[function]).is_err());
+    /// ```
+    pub fn call_function(&mut self, name: &str, args: &[OwnedValue]) -> Result<OwnedValue, LoxError> {
+        let args = arguments(&mut self.vm, args.to_vec())?;
+        self.vm.call_function(name, &args).map(owned)
+    }
+
+    /// Defines a global variable, converting `value` from a Rust type.
+    pub fn define_global(&mut self, name: &str, value: impl IntoValue) {
+        self.vm.set_global(name, value);
+    }
+
+    /// Returns a global converted to a Rust type, or `None` if it is
+    /// undefined or has another type.
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Option<T> {
+        self.vm.get_global_as(name)
+    }
+
+    /// Compiles `source` for [`Lox::run`], reusing the compiled code while
+    /// an earlier script with the same source is still held. See
+    /// [`Vm::compile_script`].
+    ///
+    /// ```
+    /// use rustlox::Lox;
+    ///
+    /// let mut lox = Lox::new();
+    /// lox.eval("var count = 0;").unwrap();
+    /// let script = lox.compile("count = count + 1;").unwrap();
+    /// lox.run(&script).unwrap();
+    /// lox.run(&script).unwrap();
+    /// assert_eq!(lox.compile("count = count + 1;").unwrap(), script);
+    /// assert_eq!(lox.get_global::<f64>("count"), Some(2.0));
+    /// ```
+    pub fn compile(&mut self, source: &str) -> Result<CompiledScript, LoxError> {
+        self.vm.compile_script(source)
+    }
+
+    /// Runs a script from [`Lox::compile`], as [`Lox::eval`] would.
+    pub fn run(&mut self, script: &CompiledScript) -> Result<OwnedValue, LoxError> {
+        self.vm.run_script(script).map(owned)
+    }
+
+    /// Brings in changed function declarations from `source` without
+    /// running the rest of it, and returns the names of the functions that
+    /// changed. See [`Vm::reload`].
+    ///
+    /// ```
+    /// use rustlox::Lox;
+    ///
+    /// let mut lox = Lox::new();
+    /// lox.eval("var score = 5;\nfun bonus() { return 1; }").unwrap();
+    /// let changed = lox.reload("var score = 0;\nfun bonus() { return 10; }").unwrap();
+    /// assert_eq!(changed, ["bonus"]);
+    /// lox.eval("score = score + bonus();").unwrap();
+    /// assert_eq!(lox.get_global::<f64>("score"), Some(15.0));
+    /// ```
+    pub fn reload(&mut self, source: &str) -> Result<Vec<String>, LoxError> {
+        self.vm.reload(source)
+    }
+
+    /// The VM underneath, for everything else.
+    pub fn vm(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vm> for Lox {
+    /// Wraps a VM made with [`Vm::builder`], to keep its configuration.
+    fn from(vm: Vm) -> Lox {
+        Lox { vm }
+    }
+}
@@ -1,49 +1,492 @@
-use crate::vm::interpret;
-use crate::vm::InterpretResult;
+use rustlox::debug::disassemble_chunk;
+#[cfg(feature = "serde")]
+use rustlox::debug::disassemble_chunk_json;
+use rustlox::debug::function_stats;
+use rustlox::debug::token_listing;
+#[cfg(feature = "serde")]
+use rustlox::debug::tokens_json;
+use rustlox::debug::visit_functions;
+use rustlox::debugger::debug_script;
+use rustlox::editor::Editor;
+use rustlox::editor::Input;
+use rustlox::editor::LineReader;
+use rustlox::editor::PlainReader;
+use rustlox::error::ErrorFormat;
+use rustlox::LoxError;
+use rustlox::ErrorKind;
+use rustlox::is_bytecode;
+use rustlox::vm::Engine;
+use rustlox::vm::TraceFilter;
+use rustlox::repl::is_incomplete;
+use rustlox::repl::remember_result;
+#[cfg(feature = "net")]
+use rustlox::repl::ReplServer;
+use rustlox::policy::Capability;
+use rustlox::policy::Policy;
+use rustlox::vm::VmBuilder;
+use rustlox::Vm;
 use std::env;
 use std::io;
 use std::fs;
-use std::io::Write;
+use std::io::IsTerminal;
+#[cfg(feature = "net")]
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Instant;
 
-mod chunk;
-mod debug;
-mod value;
-mod vm;
-mod compiler;
-mod object;
-mod scanner;
-
-fn repl() {
+fn repl(options: Options) {
+    // One VM for the whole session, so each line sees the globals defined
+    // by the lines before it.
+    let mut vm = new_vm(options);
+    // Piped input is read as-is and kept out of the history.
+    let mut reader: Box<dyn LineReader> = if !io::stdin().is_terminal() {
+        Box::new(PlainReader::new(io::stdin().lock()))
+    } else if let Some(path) = Editor::default_history_file() {
+        Box::new(Editor::with_history_file(path))
+    } else {
+        Box::new(Editor::new())
+    };
+    // Lines of a statement that isn't finished yet.
+    let mut pending = String::new();
     loop {
-        print!("> ");
-        io::stdout().flush().expect("fail: flush");
-        
-        let mut line = String::new();
-        match io::stdin().read_line(&mut line) {
-            Ok(_) => {},
+        let prompt = if pending.is_empty() { "> " } else { "... " };
+        let line = match reader.read_line(prompt) {
+            Ok(Input::Line(line)) => line,
+            // Ctrl-C abandons the statement being typed.
+            Ok(Input::Interrupted) => {
+                pending.clear();
+                continue;
+            }
+            Ok(Input::Eof) => { println!(); return; }
             Err(_) => { return; }
+        };
+        reader.add_history(&line);
+        pending.push_str(&line);
+        pending.push('\n');
+        if is_incomplete(&pending) {
+            continue;
+        }
+        // A Ctrl-C that came while no script was running isn't meant for
+        // this one.
+        if let Some(flag) = INTERRUPT.get() {
+            flag.store(false, Ordering::Relaxed);
+        }
+        if let Ok(value) = vm.eval_line(&pending) {
+            remember_result(&mut vm, value);
+        }
+        pending.clear();
+    }
+}
+
+// Serves the REPL over TCP. Clients must first send the token in
+// LOX_REPL_TOKEN, which may only be left unset on a loopback address, since
+// anyone who can connect could run code otherwise.
+#[cfg(feature = "net")]
+fn serve_repl(address: &str, options: Options) {
+    let token = env::var("LOX_REPL_TOKEN").ok().filter(|token| !token.is_empty());
+    let loopback = address.to_socket_addrs().is_ok_and(|mut addrs| addrs.all(|addr| addr.ip().is_loopback()));
+    if token.is_none() && !loopback {
+        eprintln!("Set LOX_REPL_TOKEN to serve the REPL on {}, which isn't a loopback address.", address);
+        std::process::exit(64);
+    }
+    let mut server = ReplServer::bind(address, token).expect("fail: listen");
+    eprintln!("Serving REPL on {}", server.local_addr().expect("fail: listen"));
+    server.serve(&mut new_vm(options));
+}
+
+// Flags that apply in every mode: -O turns on the optimizer, --no-io keeps
+// scripts away from files, sockets and the process, --check looks for
+// likely mistakes before running, --deny-warnings makes warnings errors, --max-heap=<bytes> stops scripts whose
+// heap outgrows it even after a collection, --error-format=json writes
+// errors as JSON lines, --coerce lets + and * mix strings and numbers,
+// --outermost-first prints stack traces outermost call first, and
+// --engine=ast runs scripts by walking their syntax trees.
+#[derive(Clone, Copy)]
+struct Options {
+    optimize: bool,
+    no_io: bool,
+    allow_env: bool,
+    allow_process: bool,
+    deny_warnings: bool,
+    check: bool,
+    coerce: bool,
+    outermost_first: bool,
+    max_heap: Option<usize>,
+    error_format: ErrorFormat,
+    engine: Engine,
+}
+
+impl Options {
+    // Compile errors get source snippets when a person is reading them;
+    // piped output keeps clox's one-line format, which test runners compare
+    // against.
+    fn builder(self) -> VmBuilder {
+        let mut policy = Policy::default();
+        if self.allow_env {
+            policy = policy.allow(Capability::EnvVars);
+        }
+        if self.allow_process {
+            policy = policy.allow(Capability::Exec);
+        }
+        if self.no_io {
+            policy = policy.deny(Capability::Fs).deny(Capability::Net).deny(Capability::Process).deny(Capability::Exec)
+                .deny(Capability::EnvVars);
+        }
+        let mut builder = Vm::builder().optimize(self.optimize).policy(policy).snippets(io::stderr().is_terminal())
+            .deny_warnings(self.deny_warnings).check(self.check)
+            .coerce(self.coerce).error_format(self.error_format).engine(self.engine)
+            .outermost_first(self.outermost_first);
+        if let Some(flag) = INTERRUPT.get() {
+            builder = builder.interrupt(flag.clone());
+        }
+        match self.max_heap {
+            Some(bytes) => builder.heap_limit(bytes),
+            None => builder,
+        }
+    }
+}
+
+// Set by Ctrl-C, and cleared by the VM when it stops the running script
+// with an "Interrupted." error. In the REPL that returns to the prompt.
+static INTERRUPT: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+#[cfg(unix)]
+fn handle_interrupts() {
+    use std::ffi::c_int;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+    const SIGINT: c_int = 2;
+
+    // Only touches an atomic, so it's safe to run inside a signal handler.
+    extern "C" fn on_interrupt(_: c_int) {
+        if let Some(flag) = INTERRUPT.get() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    INTERRUPT.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    unsafe { signal(SIGINT, on_interrupt) };
+}
+
+// Elsewhere Ctrl-C keeps ending the process.
+#[cfg(not(unix))]
+fn handle_interrupts() {}
+
+fn new_vm(options: Options) -> Vm {
+    options.builder().build()
+}
+
+fn exit_on_error<T>(result: Result<T, LoxError>) {
+    if let Err(error) = result {
+        match error.kind {
+            ErrorKind::Compile => std::process::exit(65),
+            ErrorKind::Runtime | ErrorKind::LimitExceeded => std::process::exit(70),
+            // What a shell reports for a process that SIGINT ended.
+            ErrorKind::Interrupted => std::process::exit(130),
+        }
+    }
+}
+
+// The directory a script's imports are resolved against: its own.
+fn script_dir(path: &str) -> String {
+    Path::new(path).parent().map_or(String::new(), |dir| dir.to_string_lossy().into_owned())
+}
+
+// Runs a script, or a .loxc file written by --compile.
+fn run_file(builder: VmBuilder, path: String) {
+    let mut vm = builder.import_dir(script_dir(&path)).script_path(path.as_str()).source_name(path.as_str()).build();
+    let contents = fs::read(path).expect("fail: read file");
+    if is_bytecode(&contents) {
+        exit_on_error(vm.load_bytecode(&contents));
+    } else {
+        let source = String::from_utf8(contents).expect("fail: read file");
+        exit_on_error(vm.interpret(&source));
+    }
+}
+
+// Runs a script under the interactive debugger.
+fn debug_file(path: String, options: Options) {
+    let mut vm = options.builder().import_dir(script_dir(&path)).script_path(path.as_str()).build();
+    let source = fs::read_to_string(path).expect("fail: read file");
+    exit_on_error(debug_script(&mut vm, &source, &mut io::stdin().lock(), &mut io::stdout()));
+}
+
+// Runs a script, then prints how often each opcode ran and how long each
+// function took to stderr, even if the script failed.
+fn profile_file(path: String, options: Options) {
+    let mut vm = options.builder().profile(true).import_dir(script_dir(&path)).script_path(path.as_str()).build();
+    let source = fs::read_to_string(path).expect("fail: read file");
+    let result = vm.interpret(&source);
+    if let Some(profile) = vm.profile() {
+        eprint!("\n{}", profile);
+    }
+    exit_on_error(result);
+}
+
+// Runs a script with execution tracing narrowed down by the --trace flags.
+fn trace_file(flags: &[String], path: String, options: Options) {
+    let mut filter = TraceFilter::default();
+    for flag in flags {
+        if let Some(name) = flag.strip_prefix("--trace-filter=function:") {
+            filter.function = Some(name.to_string());
+        } else if let Some(line) = flag.strip_prefix("--trace-start-line=") {
+            filter.start_line = Some(line.parse().expect("fail: parse line"));
+        } else if flag == "--trace-stack-changes" {
+            filter.stack_changes_only = true;
+        } else if flag != "--trace" && flag != "--trace-execution" {
+            return usage();
         }
-        interpret(line);
+    }
+    run_file(options.builder().trace_execution(true).trace_filter(filter), path);
+}
+
+// With `strip`, the output has no debug symbols.
+fn compile_file(path: String, output: String, strip: bool, options: Options) {
+    let source = fs::read_to_string(path).expect("fail: read file");
+    let mut vm = new_vm(options);
+    let result = if strip { vm.compile_bytecode_stripped(&source) } else { vm.compile_bytecode(&source) };
+    match result {
+        Ok(bytecode) => fs::write(output, bytecode).expect("fail: write file"),
+        Err(error) => exit_on_error::<()>(Err(error)),
     }
 }
 
-fn run_file(path: String) {
-    let contents = fs::read_to_string(path).expect("fail: read file");
-    let result = interpret(contents);
-    if result == InterpretResult::CompileError {
-        std::process::exit(65);
+// Prints static metrics for each function in a script without running it.
+fn print_metrics(path: String, options: Options) {
+    let source = fs::read_to_string(path).expect("fail: read file");
+    let mut vm = new_vm(options);
+    let script = match vm.compile(&source) {
+        Ok(script) => script,
+        Err(error) => return exit_on_error::<()>(Err(error)),
+    };
+    for (name, stats) in function_stats(script) {
+        println!("== {} ==", name);
+        println!("instructions: {}", stats.instructions);
+        println!("constants:    {}", stats.constants);
+        println!("max jump:     {}", stats.max_jump);
+        println!("max stack:    {}", stats.max_stack);
+        for (opcode, count) in &stats.opcode_counts {
+            println!("  {:16} {}", opcode, count);
+        }
     }
-    if result == InterpretResult::RuntimeError {
-        std::process::exit(70);
+}
+
+// Prints the bytecode of every function in a script without running it.
+fn print_disassembly(path: String, options: Options) {
+    let source = fs::read_to_string(path).expect("fail: read file");
+    let mut vm = new_vm(options);
+    let script = match vm.compile(&source) {
+        Ok(script) => script,
+        Err(error) => return exit_on_error::<()>(Err(error)),
+    };
+    visit_functions(script, &mut |name, chunk| disassemble_chunk(chunk, name));
+}
+
+// Prints pseudo-Lox rebuilt from the bytecode of a script or a .loxc file,
+// without running it.
+fn print_decompiled(path: String, options: Options) {
+    let contents = fs::read(path).expect("fail: read file");
+    let mut vm = new_vm(options);
+    let script = if is_bytecode(&contents) {
+        vm.read_bytecode(&contents)
+    } else {
+        vm.compile(&String::from_utf8(contents).expect("fail: read file"))
+    };
+    match script.and_then(|script| vm.decompile(script)) {
+        Ok(source) => print!("{}", source),
+        Err(error) => exit_on_error::<()>(Err(error)),
     }
 }
 
+// Prints the tokens the scanner makes from a script, for checking the
+// scanner on its own.
+fn print_tokens(path: String, json: bool) {
+    let source = fs::read_to_string(path).expect("fail: read file");
+    if json {
+        #[cfg(feature = "serde")]
+        println!("{}", tokens_json(&source));
+    } else {
+        print!("{}", token_listing(&source));
+    }
+}
+
+// Runs every .lox script in `dir` on a fresh VM and reports its wall time
+// and instruction count. The scripts' own output is discarded.
+fn run_benchmarks(dir: &str, options: Options) {
+    let mut paths: Vec<_> = fs::read_dir(dir).expect("fail: read dir")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    paths.sort();
+    println!("{:20} {:>10} {:>14}", "benchmark", "seconds", "instructions");
+    for path in paths {
+        let source = fs::read_to_string(&path).expect("fail: read file");
+        let mut vm = new_vm(options);
+        vm.set_stdout(io::sink());
+        let start = Instant::now();
+        let result = vm.interpret(&source);
+        let elapsed = start.elapsed().as_secs_f64();
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("?");
+        match result {
+            Ok(_) => println!("{:20} {:>10.3} {:>14}", name, elapsed, vm.instruction_count()),
+            Err(error) => println!("{:20} failed: {}", name, error.message),
+        }
+    }
+}
+
+// Prints every function in a script as JSON disassembly without running it.
+#[cfg(feature = "serde")]
+fn print_disassembly_json(path: String, options: Options) {
+    let source = fs::read_to_string(path).expect("fail: read file");
+    let mut vm = new_vm(options);
+    let script = match vm.compile(&source) {
+        Ok(script) => script,
+        Err(error) => return exit_on_error::<()>(Err(error)),
+    };
+    let mut chunks = Vec::new();
+    visit_functions(script, &mut |name, chunk| chunks.push(disassemble_chunk_json(chunk, name)));
+    println!("{}", serde_json::Value::Array(chunks));
+}
+
+fn usage() {
+    println!("Usage: rustlox [-O] [--no-io] [--allow-env] [--allow-process] [--check] [--deny-warnings] [--coerce] [--max-heap=<bytes>] [--error-format=json]");
+    println!("               [--outermost-first] [--engine=ast] [path [args...]]");
+    println!("       rustlox --compile [--strip] <path> <output.loxc>");
+    println!("       rustlox --metrics <path>");
+    println!("       rustlox --disassemble <path>");
+    println!("       rustlox tokenize <path>");
+    println!("       rustlox parse <path>");
+    println!("       rustlox decompile <path>");
+    println!("       rustlox bench [dir]");
+    println!("       rustlox --stress-gc <path>");
+    println!("       rustlox --profile <path>");
+    println!("       rustlox --debug <path>");
+    if cfg!(feature = "net") {
+        println!("       rustlox repl --listen <address>  (LOX_REPL_TOKEN is required unless <address> is loopback)");
+    }
+    if cfg!(feature = "serde") {
+        println!("       rustlox --disassemble-json <path>");
+        println!("       rustlox tokenize --json <path>");
+        println!("       rustlox parse --json <path>");
+    }
+    println!("       rustlox --trace[-execution] [--trace-filter=function:<name>] [--trace-start-line=<n>]");
+    println!("               [--trace-stack-changes] <path>");
+    println!("-O folds constants and simplifies the bytecode before running it.");
+    println!("--no-io stops scripts from using files, sockets, environment variables and the process,");
+    println!("  even with --allow-env or --allow-process. Reading stdin is still allowed.");
+    println!("--allow-env lets scripts read and set environment variables with getenv and setenv.");
+    println!("--allow-process lets scripts run shell commands with exec.");
+    println!("--check warns about wrong argument counts, undefined globals and bad constant arithmetic.");
+    println!("--deny-warnings fails to compile scripts with warnings.");
+    println!("--max-heap=<bytes> is a runtime error once the heap is bigger than that after a collection.");
+    println!("--error-format=json writes errors and warnings as JSON lines.");
+    println!("--coerce lets + join strings and numbers and * repeat strings.");
+    println!("--outermost-first prints stack traces from the outermost call to the innermost.");
+    println!("--engine=ast runs scripts with the tree-walking interpreter instead of the bytecode VM.");
+}
+
+const SUBCOMMANDS: [&str; 5] = ["bench", "tokenize", "parse", "decompile", "repl"];
+
+// Options that can go anywhere before the script's path.
+fn is_option(arg: &str) -> bool {
+    arg == "-O" || arg == "--no-io" || arg == "--allow-env" || arg == "--allow-process" || arg == "--deny-warnings" || arg == "--check" || arg == "--coerce"
+        || arg == "--outermost-first"
+        || arg.starts_with("--max-heap=")
+        || arg.starts_with("--error-format=")
+        || arg.starts_with("--engine=")
+}
+
 fn main() {
-    if env::args().len() == 1 {
-        repl();
-    } else if env::args().len() == 2 {
-        run_file(env::args().nth(1).unwrap());
+    handle_interrupts();
+    let mut args: Vec<String> = env::args().collect();
+    // Everything after the path of a script to run is the script's own,
+    // for args(), even if it looks like an option.
+    let script_args = match args.iter().skip(1).position(|arg| !is_option(arg)) {
+        Some(i) if !args[i + 1].starts_with('-') && !SUBCOMMANDS.contains(&args[i + 1].as_str()) => args.split_off(i + 2),
+        _ => Vec::new(),
+    };
+    let mut options = Options {
+        optimize: args.iter().any(|arg| arg == "-O"),
+        no_io: args.iter().any(|arg| arg == "--no-io"),
+        allow_env: args.iter().any(|arg| arg == "--allow-env"),
+        allow_process: args.iter().any(|arg| arg == "--allow-process"),
+        deny_warnings: args.iter().any(|arg| arg == "--deny-warnings"),
+        check: args.iter().any(|arg| arg == "--check"),
+        coerce: args.iter().any(|arg| arg == "--coerce"),
+        outermost_first: args.iter().any(|arg| arg == "--outermost-first"),
+        max_heap: None,
+        error_format: ErrorFormat::Text,
+        engine: Engine::Bytecode,
+    };
+    for arg in &args {
+        if let Some(bytes) = arg.strip_prefix("--max-heap=") {
+            match bytes.parse() {
+                Ok(bytes) => options.max_heap = Some(bytes),
+                Err(_) => return usage(),
+            }
+        }
+        if let Some(format) = arg.strip_prefix("--error-format=") {
+            match format {
+                "text" => options.error_format = ErrorFormat::Text,
+                "json" => options.error_format = ErrorFormat::Json,
+                _ => return usage(),
+            }
+        }
+        if let Some(engine) = arg.strip_prefix("--engine=") {
+            match engine {
+                "bytecode" => options.engine = Engine::Bytecode,
+                "ast" => options.engine = Engine::Ast,
+                _ => return usage(),
+            }
+        }
+    }
+    args.retain(|arg| !is_option(arg));
+    if args.len() == 1 {
+        repl(options);
+    } else if (args.len() == 2 || args.len() == 3) && args[1] == "bench" {
+        run_benchmarks(args.get(2).map_or("benches", |dir| dir.as_str()), options);
+    } else if args.len() == 3 && args[1] == "tokenize" {
+        print_tokens(args[2].clone(), false);
+    } else if cfg!(feature = "serde") && args.len() == 4 && args[1] == "tokenize" && args[2] == "--json" {
+        print_tokens(args[3].clone(), true);
+    } else if args.len() == 3 && args[1] == "parse" {
+        print_disassembly(args[2].clone(), options);
+    } else if cfg!(feature = "serde") && args.len() == 4 && args[1] == "parse" && args[2] == "--json" {
+        #[cfg(feature = "serde")]
+        print_disassembly_json(args[3].clone(), options);
+    } else if args.len() == 3 && args[1] == "decompile" {
+        print_decompiled(args[2].clone(), options);
+    } else if args.len() == 2 {
+        run_file(options.builder().args(&script_args), args[1].clone());
+    } else if args[1].starts_with("--trace") {
+        trace_file(&args[1..args.len() - 1], args[args.len() - 1].clone(), options);
+    } else if args.len() == 4 && args[1] == "--compile" {
+        compile_file(args[2].clone(), args[3].clone(), false, options);
+    } else if args.len() == 5 && args[1] == "--compile" && args[2] == "--strip" {
+        compile_file(args[3].clone(), args[4].clone(), true, options);
+    } else if cfg!(feature = "net") && args.len() == 4 && args[1] == "repl" && args[2] == "--listen" {
+        #[cfg(feature = "net")]
+        serve_repl(&args[3], options);
+    } else if args.len() == 3 && args[1] == "--stress-gc" {
+        run_file(options.builder().stress_gc(true), args[2].clone());
+    } else if args.len() == 3 && args[1] == "--debug" {
+        debug_file(args[2].clone(), options);
+    } else if args.len() == 3 && args[1] == "--profile" {
+        profile_file(args[2].clone(), options);
+    } else if args.len() == 3 && args[1] == "--disassemble" {
+        print_disassembly(args[2].clone(), options);
+    } else if args.len() == 3 && args[1] == "--metrics" {
+        print_metrics(args[2].clone(), options);
+    } else if cfg!(feature = "serde") && args.len() == 3 && args[1] == "--disassemble-json" {
+        #[cfg(feature = "serde")]
+        print_disassembly_json(args[2].clone(), options);
     } else {
-        println!("Usage: rustlox [path]");
+        usage();
     }
 }
@@ -0,0 +1,1263 @@
+// Purpose: Native functions available to Lox programs.
+
+#[cfg(feature = "http")]
+use crate::http::define_http_natives;
+#[cfg(feature = "net")]
+use crate::net::define_net_natives;
+use crate::object::NativeFn;
+use crate::object::Obj;
+use crate::policy::Capability;
+use crate::error::LoxError;
+use crate::value::Value;
+use crate::value::values_equal;
+use crate::vm::checked_index;
+use crate::vm::Vm;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+/// Groups of native functions that can be enabled independently when
+/// building a VM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NativeGroup {
+    /// assert, error, type, hash, repr, inspect, memory, gc and gcStats.
+    Core,
+    /// min, max, clamp, isNaN, isFinite, sqrt, floor, ceil, abs, pow,
+    /// random, numToString and the PI, E, INF and NAN globals.
+    Math,
+    /// format, ord, chr, charAt, len, substr, upper, lower, bytes and utf8.
+    Text,
+    /// push, pop, insert, removeAt, indexOf, sort, map, filter and reduce;
+    /// len also takes a list.
+    List,
+    /// keys, values, has and remove; len also takes a map.
+    Map,
+    /// printf, eprint, eprintln, readLine, readFile, readLines, writeFile
+    /// and exit.
+    Io,
+    /// clock.
+    Time,
+    /// platform, cwd, chdir, pid, args, getenv, setenv and exec.
+    Os,
+    /// TCP sockets, with the `net` feature, and httpGet and httpPost, with
+    /// the `http` feature.
+    Net,
+}
+
+impl NativeGroup {
+    pub const ALL: [NativeGroup; 9] = [
+        NativeGroup::Core,
+        NativeGroup::Math,
+        NativeGroup::Text,
+        NativeGroup::List,
+        NativeGroup::Map,
+        NativeGroup::Io,
+        NativeGroup::Time,
+        NativeGroup::Os,
+        NativeGroup::Net,
+    ];
+}
+
+pub fn define_natives(vm: &mut Vm, groups: &[NativeGroup]) {
+    for group in groups {
+        define_group(vm, *group);
+    }
+}
+
+fn define_group(vm: &mut Vm, group: NativeGroup) {
+    match group {
+        NativeGroup::Core => {
+            vm.define_native("assert", Box::new(assert_native));
+            vm.define_native("error", Box::new(error_native));
+            vm.define_native("type", Box::new(type_native));
+            vm.define_native("funInfo", Box::new(fun_info_native));
+            vm.define_native("decompile", Box::new(decompile_native));
+            vm.define_native("coroutine", Box::new(coroutine_native));
+            if vm.config().allow_eval {
+                vm.define_native("eval", Box::new(eval_native));
+            }
+            vm.define_native("isNil", Box::new(|_vm, args| type_is(args, &["nil"])));
+            vm.define_native("isBool", Box::new(|_vm, args| type_is(args, &["bool"])));
+            vm.define_native("isNumber", Box::new(|_vm, args| type_is(args, &["number"])));
+            vm.define_native("isString", Box::new(|_vm, args| type_is(args, &["string"])));
+            vm.define_native("isFunction", Box::new(|_vm, args| type_is(args, &["function", "native"])));
+            vm.define_native("isClass", Box::new(|_vm, args| type_is(args, &["class"])));
+            vm.define_native("isInstance", Box::new(|_vm, args| type_is(args, &["instance"])));
+            vm.define_native("isList", Box::new(|_vm, args| type_is(args, &["list"])));
+            vm.define_native("isMap", Box::new(|_vm, args| type_is(args, &["map"])));
+            vm.define_native("hash", Box::new(hash_native));
+            vm.define_native("repr", Box::new(repr_native));
+            vm.define_native("inspect", Box::new(repr_native));
+            vm.define_native("memory", Box::new(memory_native));
+            vm.define_native("gc", Box::new(gc_native));
+            vm.define_native("gcStats", Box::new(gc_stats_native));
+        }
+        NativeGroup::Math => {
+            vm.define_native("min", Box::new(min_native));
+            vm.define_native("max", Box::new(max_native));
+            vm.define_native("clamp", Box::new(clamp_native));
+            vm.define_native("isNaN", Box::new(is_nan_native));
+            vm.define_native("isFinite", Box::new(is_finite_native));
+            vm.define_native("sqrt", Box::new(|_vm, args| unary_math(args, f64::sqrt)));
+            vm.define_native("floor", Box::new(|_vm, args| unary_math(args, f64::floor)));
+            vm.define_native("ceil", Box::new(|_vm, args| unary_math(args, f64::ceil)));
+            vm.define_native("abs", Box::new(|_vm, args| unary_math(args, f64::abs)));
+            vm.define_native("pow", Box::new(pow_native));
+            vm.define_native("random", random_native());
+            vm.define_native("numToString", Box::new(num_to_string_native));
+
+            vm.define_global("PI", Value::number(std::f64::consts::PI));
+            vm.define_global("E", Value::number(std::f64::consts::E));
+            vm.define_global("INF", Value::number(f64::INFINITY));
+            vm.define_global("NAN", Value::number(f64::NAN));
+        }
+        NativeGroup::Text => {
+            vm.define_native("format", Box::new(format_native));
+            vm.define_native("ord", Box::new(ord_native));
+            vm.define_native("chr", Box::new(chr_native));
+            vm.define_native("charAt", Box::new(char_at_native));
+            vm.define_native("len", Box::new(len_native));
+            vm.define_native("substr", Box::new(substr_native));
+            vm.define_native("upper", Box::new(upper_native));
+            vm.define_native("lower", Box::new(lower_native));
+            vm.define_native("bytes", Box::new(bytes_native));
+            vm.define_native("utf8", Box::new(utf8_native));
+        }
+        NativeGroup::List => {
+            vm.define_native("push", Box::new(push_native));
+            vm.define_native("pop", Box::new(pop_native));
+            vm.define_native("insert", Box::new(insert_native));
+            vm.define_native("removeAt", Box::new(remove_at_native));
+            vm.define_native("indexOf", Box::new(index_of_native));
+            vm.define_native("sort", Box::new(sort_native));
+            vm.define_native("map", Box::new(map_native));
+            vm.define_native("filter", Box::new(filter_native));
+            vm.define_native("reduce", Box::new(reduce_native));
+            vm.define_native("len", Box::new(len_native));
+        }
+        NativeGroup::Map => {
+            vm.define_native("keys", Box::new(keys_native));
+            vm.define_native("values", Box::new(values_native));
+            vm.define_native("has", Box::new(has_native));
+            vm.define_native("remove", Box::new(remove_native));
+            vm.define_native("len", Box::new(len_native));
+        }
+        NativeGroup::Io => {
+            vm.define_native("println", Box::new(println_native));
+            vm.define_native("printf", Box::new(printf_native));
+            vm.define_native("eprint", Box::new(eprint_native));
+            vm.define_native("eprintln", Box::new(eprintln_native));
+            vm.define_native("readLine", Box::new(read_line_native));
+            vm.define_gated_native("readFile", Capability::Fs, Box::new(read_file_native));
+            // Reading stdin needs no capability, as for readLine(); only a
+            // path needs Fs, which read_lines_native checks.
+            vm.define_native("readLines", Box::new(read_lines_native));
+            vm.define_gated_native("writeFile", Capability::Fs, Box::new(write_file_native));
+            vm.define_gated_native("exit", Capability::Process, Box::new(exit_native));
+        }
+        NativeGroup::Time => {
+            vm.define_gated_native("clock", Capability::Time, Box::new(clock_native));
+        }
+        NativeGroup::Os => {
+            vm.define_gated_native("platform", Capability::Env, Box::new(platform_native));
+            vm.define_gated_native("cwd", Capability::Fs, Box::new(cwd_native));
+            vm.define_gated_native("chdir", Capability::Fs, Box::new(chdir_native));
+            vm.define_gated_native("pid", Capability::Process, Box::new(pid_native));
+            vm.define_native("args", Box::new(args_native));
+            vm.define_gated_native("getenv", Capability::EnvVars, Box::new(getenv_native));
+            vm.define_gated_native("setenv", Capability::EnvVars, Box::new(setenv_native));
+            vm.define_gated_native("exec", Capability::Exec, Box::new(exec_native));
+        }
+        NativeGroup::Net => {
+            #[cfg(feature = "net")]
+            define_net_natives(vm);
+            #[cfg(feature = "http")]
+            define_http_natives(vm);
+        }
+    }
+}
+
+/// Conversion from a Lox value to a Rust argument type for natives
+/// registered with [`Vm::register_fn`].
+pub trait FromValue: Sized {
+    /// The Lox type name used in error messages.
+    const TYPE_NAME: &'static str;
+
+    /// Converts `value`, or returns `None` if it has another type.
+    ///
+    /// # Safety
+    ///
+    /// The value's VM must still exist, and the value must not have been
+    /// collected since the VM handed it out. The arguments of a native are
+    /// safe to convert until it returns.
+    unsafe fn from_value(value: Value) -> Option<Self>;
+}
+
+impl FromValue for f64 {
+    const TYPE_NAME: &'static str = "number";
+
+    unsafe fn from_value(value: Value) -> Option<Self> {
+        value.is_number().then(|| value.as_number())
+    }
+}
+
+impl FromValue for bool {
+    const TYPE_NAME: &'static str = "bool";
+
+    unsafe fn from_value(value: Value) -> Option<Self> {
+        value.is_bool().then(|| value.as_bool())
+    }
+}
+
+impl FromValue for String {
+    const TYPE_NAME: &'static str = "string";
+
+    unsafe fn from_value(value: Value) -> Option<Self> {
+        value.is_string().then(|| value.as_str().to_string())
+    }
+}
+
+impl FromValue for Value {
+    const TYPE_NAME: &'static str = "value";
+
+    unsafe fn from_value(value: Value) -> Option<Self> {
+        Some(value)
+    }
+}
+
+/// Conversion from a native's Rust return type to a Lox value.
+pub trait IntoValue {
+    fn into_value(self, vm: &mut Vm) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _vm: &mut Vm) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _vm: &mut Vm) -> Value {
+        Value::nil()
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _vm: &mut Vm) -> Value {
+        Value::number(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _vm: &mut Vm) -> Value {
+        Value::bool(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, vm: &mut Vm) -> Value {
+        vm.new_string(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, vm: &mut Vm) -> Value {
+        vm.take_string(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, vm: &mut Vm) -> Value {
+        match self {
+            Some(value) => value.into_value(vm),
+            None => Value::nil(),
+        }
+    }
+}
+
+/// Return types a typed native may have: any [`IntoValue`], or a `Result`
+/// of one whose error is raised as a Lox runtime error.
+pub trait IntoNativeResult {
+    fn into_native_result(self, vm: &mut Vm) -> Result<Value, LoxError>;
+}
+
+impl<T: IntoValue> IntoNativeResult for T {
+    fn into_native_result(self, vm: &mut Vm) -> Result<Value, LoxError> {
+        Ok(self.into_value(vm))
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for Result<T, LoxError> {
+    fn into_native_result(self, vm: &mut Vm) -> Result<Value, LoxError> {
+        self.map(|value| value.into_value(vm))
+    }
+}
+
+/// Rust closures that can be registered as natives. Implemented for
+/// closures taking up to four [`FromValue`] arguments; the generated native
+/// checks the argument count and types before calling the closure.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> NativeFn;
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + 'static,
+            R: IntoNativeResult,
+            $($arg: FromValue,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut, unused_assignments)]
+            fn into_native(self) -> NativeFn {
+                Box::new(move |vm, args| {
+                    let arity = <[&str]>::len(&[$(stringify!($arg)),*]);
+                    check_arity(args, arity)?;
+                    let mut position = 0;
+                    $(
+                        let $arg = convert_arg::<$arg>(args[position], position)?;
+                        position += 1;
+                    )*
+                    (self)($($arg),*).into_native_result(vm)
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
+
+fn convert_arg<T: FromValue>(value: Value, position: usize) -> Result<T, LoxError> {
+    // The arguments of a running native are on the stack, so they are alive.
+    unsafe { T::from_value(value) }.ok_or_else(|| LoxError::runtime(format!(
+        "Argument {} must be a {} but got {}.", position + 1, T::TYPE_NAME, value.type_name())))
+}
+
+pub fn check_arity(args: &[Value], arity: usize) -> Result<(), LoxError> {
+    if args.len() != arity {
+        let plural = if arity == 1 { "" } else { "s" };
+        return Err(LoxError::runtime(format!("Expected {} argument{} but got {}.", arity, plural, args.len())));
+    }
+    Ok(())
+}
+
+fn check_numbers(args: &[Value]) -> Result<(), LoxError> {
+    if args.iter().any(|arg| !arg.is_number()) {
+        return Err(LoxError::runtime("Arguments must be numbers."));
+    }
+    Ok(())
+}
+
+fn clock_native(vm: &mut Vm, _args: &[Value]) -> Result<Value, LoxError> {
+    Ok(Value::number(vm.platform().clock()))
+}
+
+// assert(condition, message) raises a runtime error when the condition is
+// falsey. The message is optional.
+fn assert_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(LoxError::runtime(format!("Expected 1 or 2 arguments but got {}.", args.len())));
+    }
+    if !args[0].is_falsey() {
+        return Ok(Value::nil());
+    }
+    if args.len() == 2 {
+        let mut error = LoxError::raised(args[1]);
+        error.message = format!("Assertion failed: {}", error.message);
+        return Err(error);
+    }
+    Err(LoxError::runtime("Assertion failed."))
+}
+
+// error(value) raises a runtime error carrying the value, reported with the
+// same traceback as errors raised by the VM itself. A catch clause receives
+// an Error whose message is the value.
+fn error_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    vm.set_raised(args[0]);
+    Err(LoxError::raised(args[0]))
+}
+
+// format(template, args...) replaces each `{}` in the template with the next
+// argument, or `{N}` with the Nth argument. A `:.P` suffix such as `{:.2}`
+// prints a number with P digits after the decimal point. `{{` and `}}` are
+// literal braces.
+fn format_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    let result = format_args(args)?;
+    Ok(vm.take_string(result))
+}
+
+// println(values...) writes its arguments the way print does, separated by
+// spaces, then a newline. Unlike the print statement it is an expression
+// and takes any number of values.
+fn println_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    let line = args.iter().map(|value| format!("{}", value.display())).collect::<Vec<_>>().join(" ");
+    let _ = writeln!(vm.stdout(), "{}", line);
+    Ok(Value::nil())
+}
+
+// printf(template, args...) writes the formatted string without a trailing
+// newline.
+fn printf_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    let result = format_args(args)?;
+    let _ = write!(vm.stdout(), "{}", result);
+    Ok(Value::nil())
+}
+
+// eprint(value) and eprintln(value) write to stderr so scripts can keep
+// diagnostics separate from the data they print to stdout.
+fn eprint_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    let _ = write!(vm.stderr(), "{}", args[0].display());
+    Ok(Value::nil())
+}
+
+fn eprintln_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if args.len() > 1 {
+        return Err(LoxError::runtime(format!("Expected 0 or 1 arguments but got {}.", args.len())));
+    }
+    if let Some(value) = args.first() {
+        let _ = write!(vm.stderr(), "{}", value.display());
+    }
+    let _ = writeln!(vm.stderr());
+    Ok(Value::nil())
+}
+
+// readLine() returns the next line of stdin, or nil at the end of it.
+fn read_line_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    // Prompts printed without a newline should show before the script waits.
+    let _ = vm.stdout().flush();
+    let line = vm.platform().read_line()
+        .map_err(|err| LoxError::runtime(format!("Could not read a line: {}.", err)))?;
+    Ok(match line {
+        Some(line) => vm.take_string(line),
+        None => Value::nil(),
+    })
+}
+
+fn read_file_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Path must be a string."));
+    }
+    let contents = vm.platform().read_file(args[0].as_str())
+        .map_err(|err| LoxError::runtime(format!("Could not read file '{}': {}.", args[0].as_str(), err)))?;
+    Ok(vm.take_string(contents))
+}
+
+// readLines(path) returns a list of the file's lines, without their line
+// endings. readLines() reads the rest of standard input instead, so that a
+// script can loop over the lines piped to it.
+fn read_lines_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if !args.is_empty() {
+        check_arity(args, 1)?;
+    }
+    let mut lines = Vec::new();
+    match args.first() {
+        Some(path) if !path.is_string() => return Err(LoxError::runtime("Path must be a string.")),
+        Some(_) if !vm.config().policy.allows(Capability::Fs) => {
+            return Err(LoxError::runtime(format!("Capability '{}' is not allowed.", Capability::Fs.name())));
+        }
+        Some(path) => {
+            let contents = vm.platform().read_file(path.as_str())
+                .map_err(|err| LoxError::runtime(format!("Could not read file '{}': {}.", path.as_str(), err)))?;
+            for line in contents.lines() {
+                lines.push(vm.new_string(line));
+            }
+        }
+        None => {
+            let _ = vm.stdout().flush();
+            while let Some(line) = vm.platform().read_line()
+                .map_err(|err| LoxError::runtime(format!("Could not read a line: {}.", err)))?
+            {
+                lines.push(vm.take_string(line));
+            }
+        }
+    }
+    Ok(vm.new_list(lines))
+}
+
+// writeFile(path, contents) replaces the file's contents, creating it if
+// needed.
+fn write_file_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Path must be a string."));
+    }
+    if !args[1].is_string() {
+        return Err(LoxError::runtime("Contents must be a string."));
+    }
+    vm.platform().write_file(args[0].as_str(), args[1].as_str())
+        .map_err(|err| LoxError::runtime(format!("Could not write file '{}': {}.", args[0].as_str(), err)))?;
+    Ok(Value::nil())
+}
+
+// exit(code) ends the process, after writing out anything the script has
+// printed.
+fn exit_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_number() {
+        return Err(LoxError::runtime("Exit code must be an integer."));
+    }
+    let code = args[0].as_number();
+    if code.fract() != 0.0 || code < i32::MIN as f64 || code > i32::MAX as f64 {
+        return Err(LoxError::runtime("Exit code must be an integer."));
+    }
+    let _ = vm.stdout().flush();
+    let _ = vm.stderr().flush();
+    vm.platform().exit(code as i32)
+        .map_err(|err| LoxError::runtime(format!("Could not exit: {}.", err)))?;
+    Ok(Value::nil())
+}
+
+// The most digits {:.N} may ask for, so a format string can't make the host
+// allocate without limit.
+const MAX_PRECISION: usize = 100;
+
+fn format_args(args: &[Value]) -> Result<String, LoxError> {
+    if args.is_empty() || !args[0].is_string() {
+        return Err(LoxError::runtime("First argument must be a format string."));
+    }
+    let template = args[0].as_str();
+    let values = &args[1..];
+
+    let mut result = String::new();
+    let mut next = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '}' {
+            if chars.next_if_eq(&'}').is_none() {
+                return Err(LoxError::runtime("Unmatched '}' in format string."));
+            }
+            result.push('}');
+            continue;
+        }
+        if c != '{' {
+            result.push(c);
+            continue;
+        }
+        if chars.next_if_eq(&'{').is_some() {
+            result.push('{');
+            continue;
+        }
+
+        let mut spec = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => spec.push(c),
+                None => return Err(LoxError::runtime("Unterminated placeholder in format string.")),
+            }
+        }
+        let (position, precision) = match spec.split_once(':') {
+            Some((position, precision)) => (position, Some(precision)),
+            None => (spec.as_str(), None),
+        };
+
+        let index = if position.is_empty() {
+            next += 1;
+            next - 1
+        } else {
+            position.parse::<usize>()
+                .map_err(|_| LoxError::runtime(format!("Invalid placeholder '{{{}}}'.", spec)))?
+        };
+        let value = match values.get(index) {
+            Some(value) => value,
+            None => return Err(LoxError::runtime(format!("Missing argument for placeholder {}.", index))),
+        };
+
+        match precision {
+            None => result.push_str(&format!("{}", value.display())),
+            Some(precision) => {
+                let digits = precision.strip_prefix('.')
+                    .filter(|digits| !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()))
+                    .ok_or_else(|| LoxError::runtime(format!("Invalid placeholder '{{{}}}'.", spec)))?;
+                let digits = digits.parse::<usize>().ok()
+                    .filter(|&digits| digits <= MAX_PRECISION)
+                    .ok_or_else(|| LoxError::runtime("Precision too large."))?;
+                if !value.is_number() {
+                    return Err(LoxError::runtime("Precision can only be applied to numbers."));
+                }
+                result.push_str(&format!("{:.*}", digits, value.as_number()));
+            }
+        }
+    }
+    Ok(result)
+}
+
+// ord(s) returns the Unicode code point of a one-character string.
+// bytes(s) returns a list of the numbers of s's UTF-8 bytes.
+fn bytes_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string."));
+    }
+    let bytes = args[0].as_str().bytes().map(|byte| Value::number(byte as f64)).collect();
+    Ok(vm.new_list(bytes))
+}
+
+// utf8(list) decodes a list of byte numbers, as bytes() returns, into a
+// string.
+fn utf8_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("Argument must be a list."));
+    }
+    let items = unsafe { &(*args[0].as_list()).items };
+    let mut bytes = Vec::with_capacity(items.len());
+    for item in items {
+        let n = if item.is_number() { item.as_number() } else { f64::NAN };
+        if n.fract() != 0.0 || !(0.0..=255.0).contains(&n) {
+            return Err(LoxError::runtime("Items must be integers from 0 to 255."));
+        }
+        bytes.push(n as u8);
+    }
+    match String::from_utf8(bytes) {
+        Ok(string) => Ok(vm.take_string(string)),
+        Err(err) => Err(LoxError::runtime(format!("Invalid UTF-8 at byte {}.", err.utf8_error().valid_up_to()))),
+    }
+}
+
+fn ord_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string."));
+    }
+    let mut chars = args[0].as_str().chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Value::number(c as u32 as f64)),
+        _ => Err(LoxError::runtime("Argument must be a single character.")),
+    }
+}
+
+// chr(n) returns the one-character string for the code point n.
+fn chr_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_number() {
+        return Err(LoxError::runtime("Argument must be a number."));
+    }
+    let n = args[0].as_number();
+    let c = if n.fract() == 0.0 && n >= 0.0 && n <= u32::MAX as f64 {
+        char::from_u32(n as u32)
+    } else {
+        None
+    };
+    match c {
+        Some(c) => Ok(vm.new_string(c.encode_utf8(&mut [0; 4]))),
+        None => Err(LoxError::runtime(format!("{} is not a valid code point.", n))),
+    }
+}
+
+// charAt(s, i) returns the character at index i, counting characters rather
+// than UTF-8 bytes.
+fn char_at_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_string() || !args[1].is_number() {
+        return Err(LoxError::runtime("Arguments must be a string and a number."));
+    }
+    let index = args[1].as_number();
+    if index.fract() != 0.0 || index < 0.0 {
+        return Err(LoxError::runtime("Index must be a non-negative integer."));
+    }
+    match args[0].as_str().chars().nth(index as usize) {
+        Some(c) => Ok(vm.new_string(c.encode_utf8(&mut [0; 4]))),
+        None => Err(LoxError::runtime(format!("String index {} out of bounds.", index))),
+    }
+}
+
+// len(s) returns the number of characters in a string, the number of items
+// in a list, or the number of entries in a map.
+fn len_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if args[0].is_list() {
+        return Ok(Value::number(unsafe { (*args[0].as_list()).items.len() } as f64));
+    }
+    if args[0].is_map() {
+        return Ok(Value::number(unsafe { (*args[0].as_map()).entries.len() } as f64));
+    }
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string, a list or a map."));
+    }
+    Ok(Value::number(args[0].as_str().chars().count() as f64))
+}
+
+// push(list, value) appends value to the end of list.
+fn push_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    unsafe { (*args[0].as_list()).items.push(args[1]) };
+    Ok(Value::nil())
+}
+
+// pop(list) removes and returns the last item of list.
+fn pop_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("Argument must be a list."));
+    }
+    match unsafe { (*args[0].as_list()).items.pop() } {
+        Some(item) => Ok(item),
+        None => Err(LoxError::runtime("Can't pop from an empty list.")),
+    }
+}
+
+// insert(list, index, value) puts value at index, moving the items from
+// there on up by one. The index may be the list's length, to append.
+fn insert_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 3)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let items = unsafe { &mut (*args[0].as_list()).items };
+    let index = checked_index(args[1], items.len() + 1).map_err(LoxError::runtime)?;
+    items.insert(index, args[2]);
+    Ok(Value::nil())
+}
+
+// removeAt(list, index) removes and returns the item at index, moving the
+// items after it down by one.
+fn remove_at_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let items = unsafe { &mut (*args[0].as_list()).items };
+    let index = checked_index(args[1], items.len()).map_err(LoxError::runtime)?;
+    Ok(items.remove(index))
+}
+
+// indexOf(list, value) returns the index of the first item == value, or -1.
+fn index_of_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let items = unsafe { &(*args[0].as_list()).items };
+    match items.iter().position(|&item| values_equal(item, args[1])) {
+        Some(index) => Ok(Value::number(index as f64)),
+        None => Ok(Value::number(-1.0)),
+    }
+}
+
+// sort(list, cmp) sorts list in place, keeping equal items in order.
+// cmp(a, b) returns a negative number if a goes before b, a positive one if
+// after, and 0 if either will do. Without cmp, the list must hold only
+// numbers or only strings, which sort in ascending order. It is an error for
+// cmp to add items to the list or remove them.
+fn sort_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if args.len() != 1 {
+        check_arity(args, 2)?;
+    }
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let list = args[0].as_list();
+    let cmp = args.get(1).copied();
+    if cmp.is_none() {
+        let items = unsafe { &(*list).items };
+        if !items.iter().all(Value::is_number) && !items.iter().all(Value::is_string) {
+            return Err(LoxError::runtime("A list sorted without a comparison function must hold only numbers or only strings."));
+        }
+    }
+    // The items are sorted in a list of their own, which the comparison
+    // function can't reach, and which keeps them alive if it changes the
+    // list being sorted.
+    let items = unsafe { (*list).items.clone() };
+    let len = items.len();
+    let sorted = vm.new_list(items);
+    let sorted = vm.holding(sorted, |vm| {
+        let items = unsafe { &mut (*sorted.as_list()).items };
+        merge_sort(items, |a, b| match cmp {
+            Some(cmp) => {
+                let order = vm.apply(cmp, &[a, b])?;
+                if !order.is_number() {
+                    return Err(LoxError::runtime("Comparison function must return a number."));
+                }
+                Ok(order.as_number() > 0.0)
+            }
+            None if a.is_number() => Ok(a.as_number() > b.as_number()),
+            None => Ok(a.as_str() > b.as_str()),
+        })?;
+        Ok(std::mem::take(items))
+    })?;
+    // Writing the sorted items back would lose what cmp added.
+    if unsafe { (*list).items.len() } != len {
+        return Err(LoxError::runtime("List modified during sort."));
+    }
+    unsafe { (*list).items = sorted };
+    Ok(Value::nil())
+}
+
+// A stable bottom-up merge sort that stops at the first error from
+// `after(a, b)`, which says whether a must go after b. Unlike slice::sort_by
+// it doesn't need the order to be consistent, since comparison functions
+// from scripts may not be.
+fn merge_sort(items: &mut Vec<Value>, mut after: impl FnMut(Value, Value) -> Result<bool, LoxError>) -> Result<(), LoxError> {
+    let mut merged = Vec::with_capacity(items.len());
+    let mut width = 1;
+    while width < items.len() {
+        for start in (0..items.len()).step_by(2 * width) {
+            let middle = (start + width).min(items.len());
+            let end = (start + 2 * width).min(items.len());
+            let (mut left, mut right) = (start, middle);
+            while left < middle && right < end {
+                if after(items[left], items[right])? {
+                    merged.push(items[right]);
+                    right += 1;
+                } else {
+                    merged.push(items[left]);
+                    left += 1;
+                }
+            }
+            merged.extend_from_slice(&items[left..middle]);
+            merged.extend_from_slice(&items[right..end]);
+        }
+        std::mem::swap(items, &mut merged);
+        merged.clear();
+        width *= 2;
+    }
+    Ok(())
+}
+
+// The item at `index`, read afresh since calls back into Lox may have
+// changed the list.
+fn list_item(list: Value, index: usize) -> Option<Value> {
+    let items = unsafe { &(*list.as_list()).items };
+    items.get(index).copied()
+}
+
+// map(list, fn) returns a new list of fn(item) for each item of list.
+fn map_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let result = vm.new_list(Vec::new());
+    vm.holding(result, |vm| {
+        let mut index = 0;
+        while let Some(item) = list_item(args[0], index) {
+            let mapped = vm.apply(args[1], &[item])?;
+            unsafe { (*result.as_list()).items.push(mapped) };
+            index += 1;
+        }
+        Ok(result)
+    })
+}
+
+// filter(list, fn) returns a new list of the items of list for which
+// fn(item) is truthy.
+fn filter_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let result = vm.new_list(Vec::new());
+    vm.holding(result, |vm| {
+        let mut index = 0;
+        while let Some(item) = list_item(args[0], index) {
+            if !vm.apply(args[1], &[item])?.is_falsey() {
+                unsafe { (*result.as_list()).items.push(item) };
+            }
+            index += 1;
+        }
+        Ok(result)
+    })
+}
+
+// reduce(list, fn, init) calls fn(total, item) for each item of list,
+// starting with init as the total, and returns the last total.
+fn reduce_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 3)?;
+    if !args[0].is_list() {
+        return Err(LoxError::runtime("First argument must be a list."));
+    }
+    let mut total = args[2];
+    let mut index = 0;
+    while let Some(item) = list_item(args[0], index) {
+        // The total is safe from the collector while it is an argument.
+        total = vm.apply(args[1], &[total, item])?;
+        index += 1;
+    }
+    Ok(total)
+}
+
+// substr(s, start, len) returns up to len characters of s from index start.
+// It stops early at the end of the string.
+fn substr_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 3)?;
+    if !args[0].is_string() || !args[1].is_number() || !args[2].is_number() {
+        return Err(LoxError::runtime("Arguments must be a string and two numbers."));
+    }
+    let start = args[1].as_number();
+    let len = args[2].as_number();
+    if start.fract() != 0.0 || start < 0.0 || len.fract() != 0.0 || len < 0.0 {
+        return Err(LoxError::runtime("Start and length must be non-negative integers."));
+    }
+    let s = args[0].as_str();
+    if start as usize > s.chars().count() {
+        return Err(LoxError::runtime(format!("String index {} out of bounds.", start)));
+    }
+    let result: String = s.chars().skip(start as usize).take(len as usize).collect();
+    Ok(vm.take_string(result))
+}
+
+fn upper_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string."));
+    }
+    let result = args[0].as_str().to_uppercase();
+    Ok(vm.take_string(result))
+}
+
+fn lower_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string."));
+    }
+    let result = args[0].as_str().to_lowercase();
+    Ok(vm.take_string(result))
+}
+
+// keys(map) and values(map) return new lists, in no particular order.
+fn keys_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_map() {
+        return Err(LoxError::runtime("Argument must be a map."));
+    }
+    let keys = unsafe { (*args[0].as_map()).entries.keys().copied().collect() };
+    Ok(vm.new_list(keys))
+}
+
+fn values_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_map() {
+        return Err(LoxError::runtime("Argument must be a map."));
+    }
+    let values = unsafe { (*args[0].as_map()).entries.values().copied().collect() };
+    Ok(vm.new_list(values))
+}
+
+// has(map, key) returns whether map has an entry for key.
+fn has_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_map() {
+        return Err(LoxError::runtime("First argument must be a map."));
+    }
+    Ok(Value::bool(unsafe { (*args[0].as_map()).entries.contains_key(&args[1]) }))
+}
+
+// remove(map, key) removes key's entry and returns its value, or nil if
+// there was none.
+fn remove_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_map() {
+        return Err(LoxError::runtime("First argument must be a map."));
+    }
+    let removed = unsafe { (*args[0].as_map()).entries.remove(&args[1]) };
+    Ok(removed.unwrap_or(Value::nil()))
+}
+
+// type(value) returns the name of the value's runtime type, or for an
+// instance the name of its class.
+fn type_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if args[0].is_instance() {
+        let class = unsafe { (*args[0].as_instance()).class };
+        return Ok(Value::object(unsafe { (*class).name } as *const Obj));
+    }
+    Ok(vm.new_string(args[0].type_name()))
+}
+
+// isNumber(value), isString(value) and the other predicates check a value's
+// type() against `names`. isFunction is true for natives too.
+fn type_is(args: &[Value], names: &[&str]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    Ok(Value::bool(names.contains(&args[0].type_name())))
+}
+
+// eval(source) runs a string of Lox code with the caller's globals and
+// returns the value of its trailing expression statement, or nil.
+fn eval_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Argument must be a string."));
+    }
+    let source = args[0].as_str().to_string();
+    vm.eval_in_caller(&source)
+}
+
+// coroutine(f) makes a coroutine that runs f when it is first resumed.
+fn coroutine_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    let f = args[0];
+    if !(f.is_function() || f.is_closure() || f.is_bound_method()) {
+        return Err(LoxError::runtime("Argument must be a function."));
+    }
+    Ok(vm.new_coroutine(f))
+}
+
+// funInfo(f) describes a function, closure or bound method as a map with
+// its "name", "arity", and the "line" and "endLine" of its declaration.
+// Natives have no declaration, so their arity and lines are nil.
+fn fun_info_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    let f = args[0];
+    let function = if f.is_function() {
+        f.as_function()
+    } else if f.is_closure() {
+        unsafe { (*f.as_closure()).function }
+    } else if f.is_bound_method() {
+        unsafe { (*(*f.as_bound_method()).method).function }
+    } else if f.is_native() {
+        std::ptr::null()
+    } else {
+        return Err(LoxError::runtime("Argument must be a function."));
+    };
+    let (name, arity, line, end_line) = if function.is_null() {
+        let name = unsafe { (*(*f.as_native()).name).as_str() }.to_string();
+        (name, Value::nil(), Value::nil(), Value::nil())
+    } else {
+        let function = unsafe { &*function };
+        let name = unsafe { (*function.name).as_str() }.to_string();
+        let arity = Value::number(function.arity as f64);
+        (name, arity, Value::number(function.line as f64), Value::number(function.end_line as f64))
+    };
+    let mut entries = HashMap::new();
+    entries.insert(vm.new_string("name"), vm.take_string(name));
+    entries.insert(vm.new_string("arity"), arity);
+    entries.insert(vm.new_string("line"), line);
+    entries.insert(vm.new_string("endLine"), end_line);
+    Ok(vm.new_map(entries))
+}
+
+// decompile(f) is pseudo-Lox source rebuilt from f's bytecode.
+fn decompile_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    let source = vm.decompile(args[0])?;
+    Ok(vm.take_string(source))
+}
+
+// hash(value) exposes the VM's value hash, the one map keys use. It is
+// stable within a run, and keys that find the same map entry hash the same.
+// Like map keys, lists and maps hash by identity, so two that are == by
+// their contents can still hash differently.
+fn hash_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    Ok(Value::number(args[0].hash() as f64))
+}
+
+// memory() is the number of bytes the heap is using, garbage included.
+fn memory_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    Ok(Value::number(vm.bytes_allocated() as f64))
+}
+
+// gc() collects garbage now, rather than when the heap next grows.
+fn gc_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    vm.collect_garbage();
+    Ok(Value::nil())
+}
+
+// gcStats() returns a map of the heap's "bytes" and "objects", garbage
+// included, and the "collections" run so far.
+fn gc_stats_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    let stats = vm.gc_stats();
+    let mut entries = HashMap::new();
+    for (name, count) in [("bytes", stats.bytes_allocated), ("objects", stats.objects), ("collections", stats.collections)] {
+        entries.insert(vm.new_string(name), Value::number(count as f64));
+    }
+    Ok(vm.new_map(entries))
+}
+
+// min(a, b, ...) and max(a, b, ...) take one or more numbers.
+fn min_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if args.is_empty() {
+        return Err(LoxError::runtime("Expected at least 1 argument but got 0."));
+    }
+    check_numbers(args)?;
+    Ok(Value::number(extreme(args, f64::INFINITY, f64::min)))
+}
+
+fn max_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    if args.is_empty() {
+        return Err(LoxError::runtime("Expected at least 1 argument but got 0."));
+    }
+    check_numbers(args)?;
+    Ok(Value::number(extreme(args, f64::NEG_INFINITY, f64::max)))
+}
+
+// Folds the arguments with f64::min or f64::max, which on their own skip
+// NaN. Here any NaN argument makes the result NaN.
+fn extreme(args: &[Value], init: f64, pick: fn(f64, f64) -> f64) -> f64 {
+    args.iter().map(|arg| arg.as_number()).fold(init, |best, number| {
+        if best.is_nan() || number.is_nan() {
+            f64::NAN
+        } else {
+            pick(best, number)
+        }
+    })
+}
+
+// clamp(x, lo, hi) limits x to the range [lo, hi].
+fn clamp_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 3)?;
+    check_numbers(args)?;
+    let (x, lo, hi) = (args[0].as_number(), args[1].as_number(), args[2].as_number());
+    if lo.is_nan() || hi.is_nan() || lo > hi {
+        return Err(LoxError::runtime("Lower bound must not exceed upper bound."));
+    }
+    Ok(Value::number(x.clamp(lo, hi)))
+}
+
+fn unary_math(args: &[Value], function: fn(f64) -> f64) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    check_numbers(args)?;
+    Ok(Value::number(function(args[0].as_number())))
+}
+
+fn pow_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    check_numbers(args)?;
+    Ok(Value::number(args[0].as_number().powf(args[1].as_number())))
+}
+
+// random() returns a number in [0, 1) from a xorshift generator. Each VM
+// gets its own, seeded by its platform.
+fn random_native() -> NativeFn {
+    // Zero until the first call seeds it from the platform.
+    let state = Cell::new(0u64);
+    Box::new(move |vm, args| {
+        check_arity(args, 0)?;
+        let mut x = state.get();
+        if x == 0 {
+            x = vm.platform().random_seed() | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        Ok(Value::number((x >> 11) as f64 / (1u64 << 53) as f64))
+    })
+}
+
+// numToString(n, precision) formats n with exactly `precision` digits after
+// the decimal point.
+fn num_to_string_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    check_numbers(args)?;
+    let precision = args[1].as_number();
+    if precision.fract() != 0.0 || !(0.0..=100.0).contains(&precision) {
+        return Err(LoxError::runtime("Precision must be an integer from 0 to 100."));
+    }
+    let text = format!("{:.*}", precision as usize, args[0].as_number());
+    Ok(vm.take_string(text))
+}
+
+fn is_nan_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    check_numbers(args)?;
+    Ok(Value::bool(args[0].as_number().is_nan()))
+}
+
+fn is_finite_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    check_numbers(args)?;
+    Ok(Value::bool(args[0].as_number().is_finite()))
+}
+
+// repr(value), also available as inspect(value), returns the value's
+// developer-facing representation.
+fn repr_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    let repr = args[0].repr();
+    Ok(vm.take_string(repr))
+}
+
+// platform() returns the operating system name, e.g. "linux" or "macos".
+fn platform_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    let name = vm.platform().os_name().to_string();
+    Ok(vm.take_string(name))
+}
+
+fn cwd_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    let cwd = vm.platform().current_dir()
+        .map_err(|err| LoxError::runtime(format!("Could not get working directory: {}.", err)))?;
+    Ok(vm.take_string(cwd))
+}
+
+fn chdir_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Path must be a string."));
+    }
+    vm.platform().set_current_dir(args[0].as_str())
+        .map_err(|err| LoxError::runtime(format!("Could not change directory to '{}': {}.", args[0].as_str(), err)))?;
+    Ok(Value::nil())
+}
+
+// pid() returns nil on platforms without processes.
+fn pid_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    Ok(vm.platform().process_id().map_or(Value::nil(), |pid| Value::number(pid as f64)))
+}
+
+// getenv(name) returns the environment variable's value, or nil if it
+// isn't set.
+fn getenv_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Name must be a string."));
+    }
+    match vm.platform().env_var(args[0].as_str()) {
+        Some(value) => Ok(vm.take_string(value)),
+        None => Ok(Value::nil()),
+    }
+}
+
+fn setenv_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 2)?;
+    if !args[0].is_string() || !args[1].is_string() {
+        return Err(LoxError::runtime("Name and value must be strings."));
+    }
+    vm.platform().set_env_var(args[0].as_str(), args[1].as_str())
+        .map_err(|err| LoxError::runtime(format!("Could not set '{}': {}.", args[0].as_str(), err)))?;
+    Ok(Value::nil())
+}
+
+// exec(command) runs a shell command and returns a map with its "status",
+// which is nil if a signal ended it, and its "stdout" and "stderr".
+fn exec_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 1)?;
+    if !args[0].is_string() {
+        return Err(LoxError::runtime("Command must be a string."));
+    }
+    let output = vm.platform().exec(args[0].as_str())
+        .map_err(|err| LoxError::runtime(format!("Could not run '{}': {}.", args[0].as_str(), err)))?;
+    let mut entries = HashMap::new();
+    let status = output.status.map_or(Value::nil(), |status| Value::number(status as f64));
+    entries.insert(vm.new_string("status"), status);
+    let stdout = vm.take_string(output.stdout);
+    entries.insert(vm.new_string("stdout"), stdout);
+    let stderr = vm.take_string(output.stderr);
+    entries.insert(vm.new_string("stderr"), stderr);
+    Ok(vm.new_map(entries))
+}
+
+// args() returns a new list of the script's command-line arguments, as
+// strings, each time it is called.
+fn args_native(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    check_arity(args, 0)?;
+    let items = vm.config().args.clone().into_iter().map(|arg| vm.take_string(arg)).collect();
+    Ok(vm.new_list(items))
+}
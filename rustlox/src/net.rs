@@ -0,0 +1,157 @@
+// Purpose: TCP socket natives, enabled with the `net` feature.
+//
+// Sockets are referred to from Lox by a numeric handle returned from
+// tcpConnect(), tcpListen() and tcpAccept(). Listeners bind to the loopback
+// address unless the script names another host.
+
+use crate::error::LoxError;
+use crate::native::check_arity;
+use crate::policy::Capability;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+enum Socket {
+    Stream(BufReader<TcpStream>),
+    Listener(TcpListener),
+}
+
+#[derive(Default)]
+struct Sockets {
+    sockets: HashMap<u32, Socket>,
+    next_handle: u32,
+}
+
+impl Sockets {
+    fn insert(&mut self, socket: Socket) -> Value {
+        self.next_handle += 1;
+        self.sockets.insert(self.next_handle, socket);
+        Value::number(self.next_handle as f64)
+    }
+
+    fn get(&mut self, handle: Value) -> Result<&mut Socket, LoxError> {
+        let key = key(handle)?;
+        self.sockets.get_mut(&key).ok_or_else(|| LoxError::runtime("Invalid socket handle."))
+    }
+
+    fn stream(&mut self, handle: Value) -> Result<&mut BufReader<TcpStream>, LoxError> {
+        match self.get(handle)? {
+            Socket::Stream(stream) => Ok(stream),
+            Socket::Listener(_) => Err(LoxError::runtime("Socket is a listener, not a connection.")),
+        }
+    }
+}
+
+// The key of a handle, which has to be a whole number, so that 1.9 isn't
+// taken for socket 1.
+fn key(handle: Value) -> Result<u32, LoxError> {
+    let n = handle.as_number();
+    if !handle.is_number() || n.fract() != 0.0 || !(0.0..=u32::MAX as f64).contains(&n) {
+        return Err(LoxError::runtime("Socket handle must be a non-negative integer."));
+    }
+    Ok(n as u32)
+}
+
+pub fn define_net_natives(vm: &mut Vm) {
+    let sockets = Arc::new(Mutex::new(Sockets::default()));
+
+    let s = sockets.clone();
+    vm.define_gated_native("tcpConnect", Capability::Net, Box::new(move |_, args| {
+        check_arity(args, 2)?;
+        if !args[0].is_string() || !args[1].is_number() {
+            return Err(LoxError::runtime("Arguments must be a host string and a port number."));
+        }
+        let address = format!("{}:{}", args[0].as_str(), args[1].as_number());
+        let stream = TcpStream::connect(&address)
+            .map_err(|err| LoxError::runtime(format!("Could not connect to {}: {}.", address, err)))?;
+        Ok(s.lock().unwrap().insert(Socket::Stream(BufReader::new(stream))))
+    }));
+
+    // tcpListen(port) listens on 127.0.0.1; tcpListen(port, host) on host,
+    // such as "0.0.0.0" for every interface.
+    let s = sockets.clone();
+    vm.define_gated_native("tcpListen", Capability::Net, Box::new(move |_, args| {
+        if args.len() != 1 {
+            check_arity(args, 2)?;
+        }
+        if !args[0].is_number() {
+            return Err(LoxError::runtime("Port must be a number."));
+        }
+        let host = match args.get(1) {
+            None => "127.0.0.1",
+            Some(host) if host.is_string() => host.as_str(),
+            Some(_) => return Err(LoxError::runtime("Host must be a string.")),
+        };
+        let address = format!("{}:{}", host, args[0].as_number());
+        let listener = TcpListener::bind(&address)
+            .map_err(|err| LoxError::runtime(format!("Could not listen on {}: {}.", address, err)))?;
+        Ok(s.lock().unwrap().insert(Socket::Listener(listener)))
+    }));
+
+    let s = sockets.clone();
+    vm.define_gated_native("tcpAccept", Capability::Net, Box::new(move |_, args| {
+        check_arity(args, 1)?;
+        let mut sockets = s.lock().unwrap();
+        let stream = match sockets.get(args[0])? {
+            Socket::Listener(listener) => listener.accept()
+                .map_err(|err| LoxError::runtime(format!("Accept failed: {}.", err)))?.0,
+            Socket::Stream(_) => return Err(LoxError::runtime("Socket is a connection, not a listener.")),
+        };
+        Ok(sockets.insert(Socket::Stream(BufReader::new(stream))))
+    }));
+
+    // tcpRead(handle) returns the next chunk of data, or nil at end of stream.
+    let s = sockets.clone();
+    vm.define_gated_native("tcpRead", Capability::Net, Box::new(move |vm, args| {
+        check_arity(args, 1)?;
+        let mut buffer = [0; 4096];
+        let n = s.lock().unwrap().stream(args[0])?.read(&mut buffer)
+            .map_err(|err| LoxError::runtime(format!("Read failed: {}.", err)))?;
+        if n == 0 {
+            return Ok(Value::nil());
+        }
+        Ok(vm.new_string(&String::from_utf8_lossy(&buffer[..n])))
+    }));
+
+    // tcpReadLine(handle) returns the next line including its newline, or nil
+    // at end of stream.
+    let s = sockets.clone();
+    vm.define_gated_native("tcpReadLine", Capability::Net, Box::new(move |vm, args| {
+        check_arity(args, 1)?;
+        let mut line = String::new();
+        let n = s.lock().unwrap().stream(args[0])?.read_line(&mut line)
+            .map_err(|err| LoxError::runtime(format!("Read failed: {}.", err)))?;
+        if n == 0 {
+            return Ok(Value::nil());
+        }
+        Ok(vm.take_string(line))
+    }));
+
+    let s = sockets.clone();
+    vm.define_gated_native("tcpWrite", Capability::Net, Box::new(move |_, args| {
+        check_arity(args, 2)?;
+        if !args[1].is_string() {
+            return Err(LoxError::runtime("Data must be a string."));
+        }
+        let data = args[1].as_str().as_bytes();
+        s.lock().unwrap().stream(args[0])?.get_mut().write_all(data)
+            .map_err(|err| LoxError::runtime(format!("Write failed: {}.", err)))?;
+        Ok(Value::number(data.len() as f64))
+    }));
+
+    let s = sockets;
+    vm.define_gated_native("tcpClose", Capability::Net, Box::new(move |_, args| {
+        check_arity(args, 1)?;
+        let key = key(args[0])?;
+        s.lock().unwrap().sockets.remove(&key).ok_or_else(|| LoxError::runtime("Invalid socket handle."))?;
+        Ok(Value::nil())
+    }));
+}
@@ -4,27 +4,66 @@ use std::fmt::Formatter;
 use std::fmt::Result;
 use std::fmt::Debug;
 use std::alloc::Layout;
+use std::cell::Cell;
+use std::any::Any;
+use std::mem::size_of;
 use std::collections::HashMap;
 use std::str;
 use std::slice;
-use std::rc::Rc;
+use std::sync::Arc;
+use crate::arena::Arena;
 use crate::chunk::Chunk;
+use crate::error::LoxError;
+use crate::policy::Capability;
+use crate::table::Globals;
+use crate::table::StringMap;
+use crate::table::StringTable;
+#[cfg(feature = "hooks")]
+use crate::hooks::AllocHook;
+#[cfg(feature = "hooks")]
+use crate::hooks::GcHook;
 use crate::value::Value;
+use crate::vm::CallFrame;
+use crate::vm::Fiber;
+use crate::vm::Vm;
 
 #[repr(C)]
 pub struct Obj {
     pub t: ObjType,
+    // Set while the garbage collector finds the object reachable.
+    pub is_marked: bool,
     pub next: *mut Obj,
 }
 
-pub fn obj_fmt(obj: *const Obj, f: &mut Formatter) -> Result {
+thread_local! {
+    // How many lists and maps are being printed around the current one.
+    static PRINT_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Lists and maps nested deeper than this print as `[...]` and `{...}`,
+// which also stops cycles through more than one of them.
+const MAX_PRINT_DEPTH: usize = 64;
+
+// Runs `print` one level deeper, or returns None past the limit.
+fn nested_print(print: impl FnOnce() -> Result) -> Option<Result> {
+    let depth = PRINT_DEPTH.with(Cell::get);
+    if depth == MAX_PRINT_DEPTH {
+        return None;
+    }
+    PRINT_DEPTH.with(|cell| cell.set(depth + 1));
+    let result = print();
+    PRINT_DEPTH.with(|cell| cell.set(depth));
+    Some(result)
+}
+
+pub(crate) fn obj_fmt(obj: *const Obj, f: &mut Formatter) -> Result {
     unsafe {
         match (*obj).t {
             ObjType::String => {
                 let sp = obj as *const ObjString;
                 let slice = slice::from_raw_parts((*sp).chars, (*sp).len);
                 let s = str::from_utf8_unchecked(slice);
-                return write!(f, "{}", s);
+                write!(f, "{}", s)
             }
             ObjType::Function => {
                 let fp = obj as *const ObjFunction;
@@ -33,10 +72,77 @@ pub fn obj_fmt(obj: *const Obj, f: &mut Formatter) -> Result {
                 }
                 let slice = slice::from_raw_parts((*(*fp).name).chars, (*(*fp).name).len);
                 let s = str::from_utf8_unchecked(slice);
-                return write!(f, "<fn {}>", s);
+                write!(f, "<fn {}>", s)
             }
             ObjType::Native => {
-                return write!(f, "<native fn>");
+                let np = obj as *const ObjNative;
+                write!(f, "<native fn {}>", (*(*np).name).as_str())
+            }
+            ObjType::Closure => {
+                let cp = obj as *const ObjClosure;
+                obj_fmt((*cp).function as *const Obj, f)
+            }
+            ObjType::Upvalue => {
+                write!(f, "upvalue")
+            }
+            ObjType::Foreign => {
+                let fp = obj as *const ObjForeign;
+                write!(f, "<{}>", (*fp).type_name)
+            }
+            ObjType::Class => {
+                let cp = obj as *const ObjClass;
+                write!(f, "{}", (*(*cp).name).as_str())
+            }
+            ObjType::Instance => {
+                let ip = obj as *const ObjInstance;
+                write!(f, "{} instance", (*(*(*ip).class).name).as_str())
+            }
+            ObjType::BoundMethod => {
+                let bp = obj as *const ObjBoundMethod;
+                obj_fmt((*bp).method as *const Obj, f)
+            }
+            ObjType::List => {
+                let lp = obj as *const ObjList;
+                nested_print(|| {
+                    write!(f, "[")?;
+                    for (i, item) in (*lp).items.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        // Don't recurse forever into a list that holds itself.
+                        if std::ptr::eq(item.as_object(), obj) && item.is_object() {
+                            write!(f, "[...]")?;
+                        } else {
+                            write!(f, "{}", item.repr())?;
+                        }
+                    }
+                    write!(f, "]")
+                }).unwrap_or_else(|| write!(f, "[...]"))
+            }
+            ObjType::Map => {
+                let mp = obj as *const ObjMap;
+                nested_print(|| {
+                    write!(f, "{{")?;
+                    for (i, (key, value)) in (*mp).entries.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        // Don't recurse forever into a map that holds itself.
+                        if std::ptr::eq(value.as_object(), obj) && value.is_object() {
+                            write!(f, "{}: {{...}}", key.repr())?;
+                        } else {
+                            write!(f, "{}: {}", key.repr(), value.repr())?;
+                        }
+                    }
+                    write!(f, "}}")
+                }).unwrap_or_else(|| write!(f, "{{...}}"))
+            }
+            ObjType::Module => {
+                let mp = obj as *const ObjModule;
+                write!(f, "<module {}>", (*(*mp).name).as_str())
+            }
+            ObjType::Coroutine => {
+                write!(f, "<coroutine>")
             }
         }
     }
@@ -48,6 +154,16 @@ pub enum ObjType {
     String,
     Function,
     Native,
+    Foreign,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    List,
+    Map,
+    Module,
+    Coroutine,
 }
 
 #[repr(C)]
@@ -55,13 +171,24 @@ pub struct ObjString {
     pub obj: Obj,
     pub len: usize,
     pub chars: *const u8,
+    pub hash: u32,
+}
+
+// FNV-1a, the same string hash clox uses.
+pub fn hash_string(s: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in s.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
 }
 
 impl ObjString {
     pub fn as_str(&self) -> &str {
         unsafe {
             let slice = std::slice::from_raw_parts(self.chars, self.len);
-            return std::str::from_utf8(slice).unwrap();
+            std::str::from_utf8(slice).unwrap()
         }
     }
 }
@@ -70,29 +197,191 @@ impl ObjString {
 pub struct ObjFunction {
     pub obj: Obj,
     pub arity: u8,
-    pub chunk: Rc<Chunk>,
+    pub upvalue_count: usize,
+    pub chunk: Arc<Chunk>,
+    pub name: *const ObjString,
+    // The module whose globals the function uses, or null for the VM's
+    // own.
+    pub module: *mut ObjModule,
+    // The most locals in scope at once, counting slot 0, or 0 if it
+    // declares none. The frame needs that many stack slots below its
+    // temporaries.
+    pub max_locals: usize,
+    // The lines the declaration starts and ends on, from the function's
+    // name (or `fun`) to its closing brace. Both are 0 for the script.
+    pub line: usize,
+    pub end_line: usize,
+}
+
+// A function together with the variables it captured. Every Lox function
+// runs as a closure; the VM wraps bare functions when it calls them.
+#[repr(C)]
+pub struct ObjClosure {
+    pub obj: Obj,
+    pub function: *const ObjFunction,
+    pub upvalues: Vec<*mut ObjUpvalue>,
+}
+
+// A captured variable. While the variable is still on the stack, `location`
+// points at its slot; when it goes out of scope the value moves into
+// `closed` and `location` points there instead.
+#[repr(C)]
+pub struct ObjUpvalue {
+    pub obj: Obj,
+    pub location: *mut Value,
+    pub closed: Value,
+    // Next open upvalue, further down the stack.
+    pub next: *mut ObjUpvalue,
+}
+
+// Methods and fields are keyed by interned strings, like the VM's globals,
+// so equal names are the same key.
+#[repr(C)]
+pub struct ObjClass {
+    pub obj: Obj,
+    pub name: *const ObjString,
+    pub methods: StringMap<Value>,
+}
+
+#[repr(C)]
+pub struct ObjInstance {
+    pub obj: Obj,
+    pub class: *const ObjClass,
+    pub fields: StringMap<Value>,
+}
+
+// A method read off an instance, remembering the instance to use as `this`
+// when it is called later.
+#[repr(C)]
+pub struct ObjBoundMethod {
+    pub obj: Obj,
+    pub receiver: Value,
+    pub method: *const ObjClosure,
+}
+
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+// Keys may be any value except NaN. Strings are interned, so equal strings
+// are the same key.
+#[repr(C)]
+pub struct ObjMap {
+    pub obj: Obj,
+    pub entries: HashMap<Value, Value>,
+}
+
+// A file loaded by an import statement. Its code runs against its own
+// globals, which importers read as properties of the module.
+#[repr(C)]
+pub struct ObjModule {
+    pub obj: Obj,
+    // The path the module was loaded from.
     pub name: *const ObjString,
+    pub globals: Globals,
+    // False while the module's code is still running, so that importing it
+    // again from there is caught as a cycle.
+    pub loaded: bool,
 }
 
-pub type NativeFn = Box<dyn Fn(usize, &[Value]) -> Value>;
+// A function running on its own stack, paused at each yield until it is
+// resumed. The VM's stack, frames and try blocks are always those of the
+// code running; a coroutine keeps its own in its fiber while it isn't, and
+// the fiber holds its resumer's while it is.
+#[repr(C)]
+pub struct ObjCoroutine {
+    pub obj: Obj,
+    pub state: CoroutineState,
+    pub fiber: Fiber,
+    // While it runs, the coroutine that resumed it, or null for the script.
+    pub resumer: *mut ObjCoroutine,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CoroutineState {
+    // Not started yet, or paused at a yield.
+    Suspended,
+    Running,
+    // Returned, or stopped by an error it didn't catch.
+    Done,
+}
+
+// Natives receive the VM so they can allocate objects, and return an error
+// message to raise a runtime error at the call site.
+pub type NativeFn = Box<dyn Fn(&mut Vm, &[Value]) -> std::result::Result<Value, LoxError> + Send>;
 
 #[repr(C)]
 pub struct ObjNative {
     pub obj: Obj,
+    pub name: *const ObjString,
     pub function: NativeFn,
+    // Checked against the VM's policy on each call.
+    pub capability: Option<Capability>,
+}
+
+// Called with a foreign object's data when the object is freed, by the
+// collector or when the VM is dropped.
+pub type Finalizer = Box<dyn FnOnce(Box<dyn Any + Send>) + Send>;
+
+// Host data handed to scripts as an opaque value. Lox code can only pass it
+// around; natives get the data back with Value::foreign_ref(). When the
+// object is freed the data goes to the finalizer if there is one, and is
+// dropped otherwise.
+#[repr(C)]
+pub struct ObjForeign {
+    pub obj: Obj,
+    pub type_name: &'static str,
+    pub data: Box<dyn Any + Send>,
+    pub finalizer: Option<Finalizer>,
+}
+
+// The stack and frames of a fiber never grow, so their size is counted once
+// when its coroutine is made.
+fn fiber_size(fiber: &Fiber) -> usize {
+    fiber.stack.len() * size_of::<Value>() + fiber.frames.len() * size_of::<CallFrame>()
 }
 
+// Heap size at which the first collection runs.
+const FIRST_GC: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct ObjArray {
     pub objects: *mut Obj,
-    pub strings: HashMap<&'static str, *const ObjString>,
+    pub bytes_allocated: usize,
+    // Objects allocated and not yet freed, garbage included.
+    pub object_count: usize,
+    // Collect garbage once bytes_allocated passes this.
+    pub next_gc: usize,
+    // Collections run so far.
+    pub collections: usize,
+    pub strings: StringTable,
+    // Where the objects themselves live.
+    pub arena: Arena,
+    // Objects marked reachable whose references are not yet marked.
+    pub gray: Vec<*mut Obj>,
+    #[cfg(feature = "hooks")]
+    pub on_alloc: AllocHook,
+    #[cfg(feature = "hooks")]
+    pub on_gc: GcHook,
 }
 
 impl ObjArray {
     pub fn default() -> ObjArray {
         ObjArray {
             objects: std::ptr::null_mut(),
-            strings: HashMap::new(),
+            bytes_allocated: 0,
+            object_count: 0,
+            next_gc: FIRST_GC,
+            collections: 0,
+            strings: StringTable::default(),
+            arena: Arena::default(),
+            gray: Vec::new(),
+            #[cfg(feature = "hooks")]
+            on_alloc: AllocHook::default(),
+            #[cfg(feature = "hooks")]
+            on_gc: GcHook::default(),
         }
     }
 
@@ -108,73 +397,390 @@ impl ObjArray {
         self.objects = std::ptr::null_mut();
     }
 
-    pub fn free_object(&mut self, obj: *mut Obj) {
+    pub(crate) fn free_object(&mut self, obj: *mut Obj) {
+        self.object_count -= 1;
         unsafe {
             match (*obj).t {
                 ObjType::String => {
                     let sp = obj as *mut ObjString;
                     let heap_chars_layout = Layout::array::<u8>((*sp).len + 1).unwrap();
                     std::alloc::dealloc((*sp).chars as *mut u8, heap_chars_layout);
-                    std::alloc::dealloc(sp as *mut u8, Layout::new::<ObjString>());
+                    self.arena.free(sp);
+                    self.bytes_allocated -= heap_chars_layout.size() + size_of::<ObjString>();
                 }
                 ObjType::Function => {
                     let fp = obj as *mut ObjFunction;
-                    drop(&(*fp).chunk);
-                    std::alloc::dealloc(fp as *mut u8, Layout::new::<ObjFunction>());
+                    std::ptr::drop_in_place(fp);
+                    self.arena.free(fp);
+                    self.bytes_allocated -= size_of::<ObjFunction>();
                 }
                 ObjType::Native => {
                     let fp = obj as *mut ObjNative;
-                    std::alloc::dealloc(fp as *mut u8, Layout::new::<ObjNative>());
+                    std::ptr::drop_in_place(fp);
+                    self.arena.free(fp);
+                    self.bytes_allocated -= size_of::<ObjNative>();
+                }
+                ObjType::Closure => {
+                    let cp = obj as *mut ObjClosure;
+                    let upvalues_size = (*cp).upvalues.capacity() * size_of::<*mut ObjUpvalue>();
+                    std::ptr::drop_in_place(cp);
+                    self.arena.free(cp);
+                    self.bytes_allocated -= size_of::<ObjClosure>() + upvalues_size;
+                }
+                ObjType::Upvalue => {
+                    let up = obj as *mut ObjUpvalue;
+                    self.arena.free(up);
+                    self.bytes_allocated -= size_of::<ObjUpvalue>();
+                }
+                ObjType::Foreign => {
+                    let fp = obj as *mut ObjForeign;
+                    let foreign = fp.read();
+                    self.arena.free(fp);
+                    if let Some(finalizer) = foreign.finalizer {
+                        finalizer(foreign.data);
+                    }
+                    self.bytes_allocated -= size_of::<ObjForeign>();
+                }
+                ObjType::Class => {
+                    let cp = obj as *mut ObjClass;
+                    std::ptr::drop_in_place(cp);
+                    self.arena.free(cp);
+                    self.bytes_allocated -= size_of::<ObjClass>();
+                }
+                ObjType::Instance => {
+                    let ip = obj as *mut ObjInstance;
+                    std::ptr::drop_in_place(ip);
+                    self.arena.free(ip);
+                    self.bytes_allocated -= size_of::<ObjInstance>();
+                }
+                ObjType::BoundMethod => {
+                    let bp = obj as *mut ObjBoundMethod;
+                    self.arena.free(bp);
+                    self.bytes_allocated -= size_of::<ObjBoundMethod>();
+                }
+                ObjType::List => {
+                    let lp = obj as *mut ObjList;
+                    std::ptr::drop_in_place(lp);
+                    self.arena.free(lp);
+                    self.bytes_allocated -= size_of::<ObjList>();
+                }
+                ObjType::Map => {
+                    let mp = obj as *mut ObjMap;
+                    std::ptr::drop_in_place(mp);
+                    self.arena.free(mp);
+                    self.bytes_allocated -= size_of::<ObjMap>();
+                }
+                ObjType::Module => {
+                    let mp = obj as *mut ObjModule;
+                    std::ptr::drop_in_place(mp);
+                    self.arena.free(mp);
+                    self.bytes_allocated -= size_of::<ObjModule>();
+                }
+                ObjType::Coroutine => {
+                    let cp = obj as *mut ObjCoroutine;
+                    let fiber_size = fiber_size(&(*cp).fiber);
+                    std::ptr::drop_in_place(cp);
+                    self.arena.free(cp);
+                    self.bytes_allocated -= size_of::<ObjCoroutine>() + fiber_size;
                 }
             }
         }
     }
 
-    pub fn write(&mut self, obj: *mut Obj) {
+    pub(crate) fn write(&mut self, obj: *mut Obj) {
+        self.object_count += 1;
         unsafe {
             (*obj).next = self.objects;
             self.objects = obj;
         }
+        #[cfg(feature = "hooks")]
+        if let Some(hook) = &mut self.on_alloc.0 {
+            hook(unsafe { &*obj });
+        }
     }
 
-    pub fn new_native(&mut self, function: NativeFn) -> *mut ObjNative {
-        let layout = Layout::new::<ObjNative>();
-        let ptr = unsafe { std::alloc::alloc(layout) } as *mut ObjNative;
-        if ptr.is_null() {
-            panic!("allocate native: out of memory");
-        }
+    pub fn new_native(&mut self, name: *const ObjString, function: NativeFn, capability: Option<Capability>) -> *mut ObjNative {
+        let ptr = self.arena.alloc::<ObjNative>();
+        self.bytes_allocated += size_of::<ObjNative>();
         unsafe {
             ptr.write(ObjNative {
-                obj: Obj { t: ObjType::Native, next: std::ptr::null_mut() },
+                obj: Obj { t: ObjType::Native, is_marked: false, next: std::ptr::null_mut() },
+                name,
                 function: Box::new(function),
+                capability,
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_foreign(
+        &mut self,
+        type_name: &'static str,
+        data: Box<dyn Any + Send>,
+        finalizer: Option<Finalizer>,
+    ) -> *mut ObjForeign {
+        let ptr = self.arena.alloc::<ObjForeign>();
+        self.bytes_allocated += size_of::<ObjForeign>();
+        unsafe {
+            ptr.write(ObjForeign {
+                obj: Obj { t: ObjType::Foreign, is_marked: false, next: std::ptr::null_mut() },
+                type_name,
+                data,
+                finalizer,
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_closure(&mut self, function: &ObjFunction) -> *mut ObjClosure {
+        let ptr = self.arena.alloc::<ObjClosure>();
+        let upvalues = Vec::with_capacity(function.upvalue_count);
+        self.bytes_allocated += size_of::<ObjClosure>() + upvalues.capacity() * size_of::<*mut ObjUpvalue>();
+        unsafe {
+            ptr.write(ObjClosure {
+                obj: Obj { t: ObjType::Closure, is_marked: false, next: std::ptr::null_mut() },
+                function: function as *const ObjFunction,
+                upvalues,
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_upvalue(&mut self, slot: *mut Value) -> *mut ObjUpvalue {
+        let ptr = self.arena.alloc::<ObjUpvalue>();
+        self.bytes_allocated += size_of::<ObjUpvalue>();
+        unsafe {
+            ptr.write(ObjUpvalue {
+                obj: Obj { t: ObjType::Upvalue, is_marked: false, next: std::ptr::null_mut() },
+                location: slot,
+                closed: Value::nil(),
+                next: std::ptr::null_mut(),
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_class(&mut self, name: *const ObjString) -> *mut ObjClass {
+        let ptr = self.arena.alloc::<ObjClass>();
+        self.bytes_allocated += size_of::<ObjClass>();
+        unsafe {
+            ptr.write(ObjClass {
+                obj: Obj { t: ObjType::Class, is_marked: false, next: std::ptr::null_mut() },
+                name,
+                methods: StringMap::default(),
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_instance(&mut self, class: *const ObjClass) -> *mut ObjInstance {
+        let ptr = self.arena.alloc::<ObjInstance>();
+        self.bytes_allocated += size_of::<ObjInstance>();
+        unsafe {
+            ptr.write(ObjInstance {
+                obj: Obj { t: ObjType::Instance, is_marked: false, next: std::ptr::null_mut() },
+                class,
+                fields: StringMap::default(),
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_bound_method(&mut self, receiver: Value, method: *const ObjClosure) -> *mut ObjBoundMethod {
+        let ptr = self.arena.alloc::<ObjBoundMethod>();
+        self.bytes_allocated += size_of::<ObjBoundMethod>();
+        unsafe {
+            ptr.write(ObjBoundMethod {
+                obj: Obj { t: ObjType::BoundMethod, is_marked: false, next: std::ptr::null_mut() },
+                receiver,
+                method,
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> *mut ObjList {
+        let ptr = self.arena.alloc::<ObjList>();
+        self.bytes_allocated += size_of::<ObjList>();
+        unsafe {
+            ptr.write(ObjList {
+                obj: Obj { t: ObjType::List, is_marked: false, next: std::ptr::null_mut() },
+                items,
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_map(&mut self, entries: HashMap<Value, Value>) -> *mut ObjMap {
+        let ptr = self.arena.alloc::<ObjMap>();
+        self.bytes_allocated += size_of::<ObjMap>();
+        unsafe {
+            ptr.write(ObjMap {
+                obj: Obj { t: ObjType::Map, is_marked: false, next: std::ptr::null_mut() },
+                entries,
             });
         }
         self.write(ptr as *mut Obj);
-        return ptr;
+        ptr
     }
 
-    pub fn new_function(&mut self, chunk: Rc<Chunk>) -> *mut ObjFunction {
-        let layout = Layout::new::<ObjFunction>();
-        let ptr = unsafe { std::alloc::alloc(layout) } as *mut ObjFunction;
-        if ptr.is_null() {
-            panic!("allocate function: out of memory");
+    pub fn new_module(&mut self, name: *const ObjString) -> *mut ObjModule {
+        let ptr = self.arena.alloc::<ObjModule>();
+        self.bytes_allocated += size_of::<ObjModule>();
+        unsafe {
+            ptr.write(ObjModule {
+                obj: Obj { t: ObjType::Module, is_marked: false, next: std::ptr::null_mut() },
+                name,
+                globals: Globals::default(),
+                loaded: false,
+            });
         }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_coroutine(&mut self, fiber: Fiber) -> *mut ObjCoroutine {
+        let ptr = self.arena.alloc::<ObjCoroutine>();
+        self.bytes_allocated += size_of::<ObjCoroutine>() + fiber_size(&fiber);
+        unsafe {
+            ptr.write(ObjCoroutine {
+                obj: Obj { t: ObjType::Coroutine, is_marked: false, next: std::ptr::null_mut() },
+                state: CoroutineState::Suspended,
+                fiber,
+                resumer: std::ptr::null_mut(),
+            });
+        }
+        self.write(ptr as *mut Obj);
+        ptr
+    }
+
+    pub fn new_function(&mut self, chunk: Chunk) -> *mut ObjFunction {
+        let ptr = self.arena.alloc::<ObjFunction>();
+        self.bytes_allocated += size_of::<ObjFunction>();
         unsafe {
             ptr.write(ObjFunction {
-                obj: Obj { t: ObjType::Function, next: std::ptr::null_mut() },
+                obj: Obj { t: ObjType::Function, is_marked: false, next: std::ptr::null_mut() },
                 arity: 0,
-                chunk: chunk,
+                upvalue_count: 0,
+                chunk: chunk.into_shared(),
                 name: std::ptr::null_mut(),
+                module: std::ptr::null_mut(),
+                max_locals: 0,
+                line: 0,
+                end_line: 0,
             });
         }
         self.write(ptr as *mut Obj);
-        return ptr;
+        ptr
     }
     
+    /// Copies `value` and the mutable objects it refers to: lists, maps,
+    /// instances, bound methods, and closures with the variables they
+    /// captured. Strings, functions, classes, natives, modules, coroutines
+    /// and foreign objects are immutable or can't be copied, so the copy
+    /// shares them.
+    ///
+    /// `copies` maps each object already copied to its copy. Passing the
+    /// same map for several values keeps objects they share, and cycles,
+    /// shared in the copies.
+    pub fn deep_clone(&mut self, value: Value, copies: &mut HashMap<*const Obj, Value>) -> Value {
+        if !value.is_object() {
+            return value;
+        }
+        let obj = value.as_object();
+        if let Some(&copy) = copies.get(&obj) {
+            return copy;
+        }
+        unsafe {
+            match (*obj).t {
+                ObjType::List => {
+                    let copy = self.new_list(Vec::new());
+                    copies.insert(obj, Value::object(copy as *const Obj));
+                    let items = (*value.as_list()).items.clone();
+                    (*copy).items = items.into_iter().map(|item| self.deep_clone(item, copies)).collect();
+                }
+                ObjType::Map => {
+                    let copy = self.new_map(HashMap::new());
+                    copies.insert(obj, Value::object(copy as *const Obj));
+                    let entries: Vec<(Value, Value)> = (*value.as_map()).entries.iter().map(|(&k, &v)| (k, v)).collect();
+                    for (key, entry) in entries {
+                        let key = self.deep_clone(key, copies);
+                        let entry = self.deep_clone(entry, copies);
+                        (*copy).entries.insert(key, entry);
+                    }
+                }
+                ObjType::Instance => {
+                    let instance = value.as_instance();
+                    let copy = self.new_instance((*instance).class);
+                    copies.insert(obj, Value::object(copy as *const Obj));
+                    let fields: Vec<(*const ObjString, Value)> = (*instance).fields.iter().map(|(&k, &v)| (k, v)).collect();
+                    for (name, field) in fields {
+                        let field = self.deep_clone(field, copies);
+                        (*copy).fields.insert(name, field);
+                    }
+                }
+                ObjType::Closure => {
+                    let closure = value.as_closure();
+                    let copy = self.new_closure(&*(*closure).function);
+                    copies.insert(obj, Value::object(copy as *const Obj));
+                    for &upvalue in &(*closure).upvalues {
+                        let upvalue = self.deep_clone_upvalue(upvalue, copies);
+                        (*copy).upvalues.push(upvalue);
+                    }
+                }
+                ObjType::Upvalue => {
+                    let upvalue = self.deep_clone_upvalue(obj as *mut ObjUpvalue, copies);
+                    return Value::object(upvalue as *const Obj);
+                }
+                ObjType::BoundMethod => {
+                    let bound = value.as_bound_method();
+                    let receiver = self.deep_clone((*bound).receiver, copies);
+                    let method = self.deep_clone(Value::object((*bound).method as *const Obj), copies);
+                    let copy = self.new_bound_method(receiver, method.as_closure());
+                    copies.insert(obj, Value::object(copy as *const Obj));
+                }
+                ObjType::String | ObjType::Function | ObjType::Class | ObjType::Native |
+                ObjType::Module | ObjType::Coroutine | ObjType::Foreign => return value,
+            }
+        }
+        copies[&obj]
+    }
+
+    // An upvalue still open refers to a stack slot, which is shared rather
+    // than copied.
+    fn deep_clone_upvalue(&mut self, upvalue: *mut ObjUpvalue, copies: &mut HashMap<*const Obj, Value>) -> *mut ObjUpvalue {
+        let obj = upvalue as *const Obj;
+        if let Some(copy) = copies.get(&obj) {
+            return copy.as_object() as *mut ObjUpvalue;
+        }
+        unsafe {
+            if !std::ptr::eq((*upvalue).location, &(*upvalue).closed) {
+                return upvalue;
+            }
+            let copy = self.new_upvalue(std::ptr::null_mut());
+            (*copy).location = &mut (*copy).closed;
+            copies.insert(obj, Value::object(copy as *const Obj));
+            (*copy).closed = self.deep_clone((*upvalue).closed, copies);
+            copy
+        }
+    }
+
+    /// Returns the interned string with the text `s`, if there is one.
+    pub fn find_string(&self, s: &str) -> Option<*const ObjString> {
+        self.strings.find(s, hash_string(s))
+    }
+
     pub fn copy_string(&mut self, s: &str) -> *const ObjString {
-        let interned = self.strings.get(s);
-        if interned.is_some() {
-            return (*interned.unwrap()) as *const ObjString;
+        if let Some(interned) = self.find_string(s) {
+            return interned;
         }
         
         let len = s.len();
@@ -183,24 +789,39 @@ impl ObjArray {
         if heap_chars_ptr.is_null() {
             panic!("allocate string: out of memory");
         }
+        self.bytes_allocated += heap_chars_layout.size();
         unsafe {
             std::ptr::copy(s.as_ptr(), heap_chars_ptr, len);
             heap_chars_ptr.add(len).write(0);
         }
-        return self.allocate_string(heap_chars_ptr, len);
+        self.allocate_string(heap_chars_ptr, len)
     }
     
-    fn allocate_string(&mut self, chars: *const u8, len: usize) -> *const ObjString {
-        let layout = Layout::new::<ObjString>();
-        let ptr = unsafe { std::alloc::alloc(layout) } as *mut ObjString;
-        if ptr.is_null() {
-            panic!("allocate string: out of memory");
+    /// Like copy_string, but adopts the buffer of `s` rather than copying it
+    /// when the text isn't interned yet.
+    pub fn take_string(&mut self, mut s: String) -> *const ObjString {
+        if let Some(interned) = self.find_string(&s) {
+            return interned;
         }
+
+        // Strings are freed as len + 1 bytes, so add the terminator and trim
+        // the buffer to exactly that size.
+        let len = s.len();
+        s.push('\0');
+        let heap_chars_ptr = Box::into_raw(s.into_boxed_str()) as *mut u8;
+        self.bytes_allocated += len + 1;
+        self.allocate_string(heap_chars_ptr, len)
+    }
+
+    fn allocate_string(&mut self, chars: *const u8, len: usize) -> *const ObjString {
+        let ptr = self.arena.alloc::<ObjString>();
+        self.bytes_allocated += size_of::<ObjString>();
         unsafe {
             ptr.write(ObjString {
-                obj: Obj { t: ObjType::String, next: std::ptr::null_mut() },
-                len: len,
-                chars: chars,
+                obj: Obj { t: ObjType::String, is_marked: false, next: std::ptr::null_mut() },
+                len,
+                chars,
+                hash: 0,
             });
         }
         self.write(ptr as *mut Obj);
@@ -209,9 +830,10 @@ impl ObjArray {
         unsafe {
             let slice = std::slice::from_raw_parts(chars, len);
             let s = std::str::from_utf8(slice).unwrap();
-            self.strings.insert(&s, result);
+            (*ptr).hash = hash_string(s);
+            self.strings.insert(result);
         }
-        return ptr;
+        ptr
     }
 
 }
@@ -0,0 +1,394 @@
+// Purpose: Peephole optimization of compiled bytecode.
+//
+// A chunk is decoded into a list of instructions whose jumps point at other
+// instructions rather than at byte offsets, rewritten until no rule
+// applies, and encoded again. The rules:
+//
+// - Arithmetic and comparisons on constant operands are folded into one
+//   constant, as is negating a number constant and Not on any constant.
+// - Not Not after an instruction that produces a bool is removed.
+// - Jumps to the next instruction are removed.
+//
+// Nothing is rewritten across an instruction that a jump lands on or where
+// a local variable's range starts or ends, so control flow and debug
+// symbols survive. Constants that folding leaves unused stay in the table.
+
+use crate::chunk::Chunk;
+use crate::chunk::LocalSymbol;
+use crate::chunk::OpCode;
+use crate::debug::operand_len;
+use crate::object::ObjFunction;
+use crate::value::Value;
+
+struct Instruction {
+    op: OpCode,
+    operands: Vec<u8>,
+    line: i32,
+    // The index of the instruction a jump or loop goes to.
+    target: usize,
+    // The value of a constant that folding produced. It is added to the
+    // constant table when the chunk is encoded.
+    folded: Option<Value>,
+    // Whether a jump lands here or a local variable's range starts or ends
+    // here.
+    boundary: bool,
+}
+
+/// Returns an optimized copy of `chunk`. Functions nested in its constants
+/// are left as they are; see [`optimize_function`].
+///
+/// ```
+/// use rustlox::Vm;
+/// use rustlox::debug::disassemble;
+/// use rustlox::debug::visit_functions;
+///
+/// let mut vm = Vm::builder().optimize(true).build();
+/// let script = vm.compile("print -(1 + 2) * 4 < 0 == !nil;").unwrap();
+/// let mut listing = String::new();
+/// visit_functions(script, &mut |name, chunk| listing.push_str(&disassemble(chunk, name)));
+/// assert_eq!(listing, "\
+/// == script ==
+/// 0000    1 OP_TRUE
+/// 0001    | OP_PRINT
+/// 0002    | OP_NIL
+/// 0003    | OP_RETURN
+/// ");
+/// ```
+pub fn optimize(chunk: &Chunk) -> Chunk {
+    let (mut code, mut locals) = match decode(chunk) {
+        Some(decoded) => decoded,
+        None => return copy(chunk),
+    };
+    while rewrite(chunk, &mut code, &mut locals) {}
+    encode(chunk, &code, &locals)
+}
+
+/// Optimizes a compiled function and every function nested in its
+/// constants, in place. VMs built with [`VmBuilder::optimize`] do this to
+/// everything they compile.
+///
+/// [`VmBuilder::optimize`]: crate::vm::VmBuilder::optimize
+///
+/// ```
+/// use rustlox::Vm;
+/// use rustlox::debug::disassemble;
+/// use rustlox::debug::visit_functions;
+///
+/// let mut vm = Vm::builder().optimize(true).build();
+/// let script = vm.compile("fun less(a, b) { return !!(a < b); }").unwrap();
+/// let mut listing = String::new();
+/// visit_functions(script, &mut |name, chunk| listing.push_str(&disassemble(chunk, name)));
+/// assert_eq!(listing, "\
+/// == script ==
+/// 0000    1 OP_CLOSURE          1 <fn less>
+/// 0002    | OP_DEFINE_GLOBAL    0 'less'
+/// 0004    | OP_NIL
+/// 0005    | OP_RETURN
+/// == less ==
+/// 0000    1 OP_GET_LOCAL        1 'a'
+/// 0002    | OP_GET_LOCAL        2 'b'
+/// 0004    | OP_LESS
+/// 0005    | OP_RETURN
+/// 0006    | OP_NIL
+/// 0007    | OP_RETURN
+/// ");
+/// ```
+pub fn optimize_function(function: Value) {
+    if !function.is_function() {
+        return;
+    }
+    let function = unsafe { &mut *(function.as_function() as *mut ObjFunction) };
+    function.chunk = optimize(&function.chunk).into_shared();
+    for constant in &function.chunk.constants.values {
+        optimize_function(*constant);
+    }
+}
+
+fn copy(chunk: &Chunk) -> Chunk {
+    let mut result = Chunk {
+        code: chunk.code.clone(),
+        lines: chunk.lines.clone(),
+        locals: chunk.locals.clone(),
+        source: chunk.source.clone(),
+        ..Chunk::default()
+    };
+    result.constants.values = chunk.constants.values.clone();
+    result
+}
+
+// The start and end of each local variable's range, as instruction indices.
+type Ranges = Vec<(usize, usize)>;
+
+// Splits the chunk into instructions, and its local variable ranges into
+// instruction indices. Returns None for code that doesn't decode.
+fn decode(chunk: &Chunk) -> Option<(Vec<Instruction>, Ranges)> {
+    let mut code = Vec::new();
+    // The index of the first instruction at or after each offset.
+    let mut indices = Vec::with_capacity(chunk.code.len() + 1);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::try_from(chunk.code[offset]).ok()?;
+        let len = operand_len(chunk, offset, &op);
+        let operands = chunk.code.get(offset + 1..offset + 1 + len)?.to_vec();
+        let target = match op {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler | OpCode::IterNext => {
+                offset + 3 + jump_distance(&operands)
+            }
+            OpCode::Loop => (offset + 3).checked_sub(jump_distance(&operands))?,
+            _ => 0,
+        };
+        indices.resize(offset + 1 + len, code.len());
+        code.push(Instruction { op, operands, line: chunk.get_line(offset), target, folded: None, boundary: false });
+        offset += 1 + len;
+    }
+    indices.push(code.len());
+    for instruction in &mut code {
+        if is_jump(&instruction.op) {
+            instruction.target = *indices.get(instruction.target)?;
+        }
+    }
+    let locals = chunk.locals.iter()
+        .map(|local| Some((*indices.get(local.start)?, *indices.get(local.end)?)))
+        .collect::<Option<Ranges>>()?;
+    Some((code, locals))
+}
+
+fn is_jump(op: &OpCode) -> bool {
+    matches!(op, OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler | OpCode::IterNext)
+}
+
+fn jump_distance(operands: &[u8]) -> usize {
+    (operands[0] as usize) << 8 | operands[1] as usize
+}
+
+// One pass of the rules over the code. Returns whether anything changed.
+fn rewrite(chunk: &Chunk, code: &mut Vec<Instruction>, locals: &mut [(usize, usize)]) -> bool {
+    let mut boundaries = vec![false; code.len() + 1];
+    for instruction in code.iter() {
+        if is_jump(&instruction.op) {
+            boundaries[instruction.target] = true;
+        }
+    }
+    for &(start, end) in locals.iter() {
+        boundaries[start] = true;
+        boundaries[end] = true;
+    }
+
+    let mut result: Vec<Instruction> = Vec::with_capacity(code.len());
+    // Where each instruction ended up. One that was removed maps to the
+    // instruction that took its place.
+    let mut moved = Vec::with_capacity(code.len() + 1);
+    let mut changed = false;
+    for (i, mut instruction) in code.drain(..).enumerate() {
+        moved.push(result.len());
+        instruction.boundary = boundaries[i];
+        let jumps_to_next = matches!(instruction.op, OpCode::Jump | OpCode::JumpIfFalse)
+            && instruction.target == i + 1;
+        if jumps_to_next {
+            changed = true;
+            continue;
+        }
+        result.push(instruction);
+        while simplify(chunk, &mut result) {
+            changed = true;
+        }
+    }
+    moved.push(result.len());
+
+    let last = result.len();
+    for instruction in &mut result {
+        if is_jump(&instruction.op) {
+            instruction.target = moved[instruction.target].min(last);
+        }
+    }
+    for (start, end) in locals.iter_mut() {
+        *start = moved[*start].min(last);
+        *end = moved[*end].min(last);
+    }
+    *code = result;
+    changed
+}
+
+// Applies the first rule that matches the instructions at the end of
+// `code`. Returns whether one did.
+fn simplify(chunk: &Chunk, code: &mut Vec<Instruction>) -> bool {
+    let n = code.len();
+    if n < 2 || code[n - 1].boundary {
+        return false;
+    }
+    let op = &code[n - 1].op;
+
+    if let Some(a) = constant(chunk, &code[n - 2]) {
+        let folded = match op {
+            OpCode::Negate if a.is_number() => Some(Value::number(-a.as_number())),
+            OpCode::Not => Some(Value::bool(a.is_falsey())),
+            _ => None,
+        };
+        if let Some(value) = folded {
+            code.pop();
+            code[n - 2].folded = Some(value);
+            return true;
+        }
+    }
+
+    if n >= 3 && !code[n - 2].boundary {
+        if let (Some(a), Some(b)) = (constant(chunk, &code[n - 3]), constant(chunk, &code[n - 2])) {
+            if let Some(value) = fold_binary(op, a, b) {
+                code.truncate(n - 2);
+                code[n - 3].folded = Some(value);
+                return true;
+            }
+        }
+    }
+
+    // !!x is x when x is already a bool.
+    if n >= 3 && matches!(op, OpCode::Not) && matches!(code[n - 2].op, OpCode::Not)
+        && !code[n - 2].boundary && produces_bool(&code[n - 3]) {
+        code.truncate(n - 2);
+        return true;
+    }
+    false
+}
+
+fn fold_binary(op: &OpCode, a: Value, b: Value) -> Option<Value> {
+    if let OpCode::Equal = op {
+        return Some(Value::bool(a.equals(b)));
+    }
+    if !a.is_number() || !b.is_number() {
+        return None;
+    }
+    let (a, b) = (a.as_number(), b.as_number());
+    match op {
+        OpCode::Add => Some(Value::number(a + b)),
+        OpCode::Subtract => Some(Value::number(a - b)),
+        OpCode::Multiply => Some(Value::number(a * b)),
+        OpCode::Divide => Some(Value::number(a / b)),
+        OpCode::Modulo => Some(Value::number(a % b)),
+        OpCode::Greater => Some(Value::bool(a > b)),
+        OpCode::Less => Some(Value::bool(a < b)),
+        _ => None,
+    }
+}
+
+// The value an instruction pushes, if it always pushes the same one.
+fn constant(chunk: &Chunk, instruction: &Instruction) -> Option<Value> {
+    if instruction.folded.is_some() {
+        return instruction.folded;
+    }
+    match instruction.op {
+        OpCode::Constant => Some(chunk.constants.values[instruction.operands[0] as usize]),
+        OpCode::ConstantLong => {
+            let index = (instruction.operands[0] as usize) << 16
+                | (instruction.operands[1] as usize) << 8
+                | instruction.operands[2] as usize;
+            Some(chunk.constants.values[index])
+        }
+        OpCode::SmallInt => Some(Value::number(instruction.operands[0] as f64)),
+        OpCode::Nil => Some(Value::nil()),
+        OpCode::True => Some(Value::bool(true)),
+        OpCode::False => Some(Value::bool(false)),
+        _ => None,
+    }
+}
+
+fn produces_bool(instruction: &Instruction) -> bool {
+    match instruction.folded {
+        Some(value) => value.is_bool(),
+        None => matches!(instruction.op,
+            OpCode::Not | OpCode::Equal | OpCode::Greater | OpCode::Less | OpCode::True | OpCode::False),
+    }
+}
+
+fn encode(original: &Chunk, code: &[Instruction], locals: &[(usize, usize)]) -> Chunk {
+    let mut chunk = Chunk { source: original.source.clone(), ..Chunk::default() };
+    chunk.constants.values = original.constants.values.clone();
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    for instruction in code {
+        offsets.push(chunk.code.len());
+        match instruction.folded {
+            Some(value) => write_constant(&mut chunk, value, instruction.line),
+            None => {
+                chunk.write_chunk(instruction.op as u8, instruction.line);
+                for &byte in &instruction.operands {
+                    chunk.write_chunk(byte, instruction.line);
+                }
+            }
+        }
+    }
+    offsets.push(chunk.code.len());
+
+    // The code only got shorter, so every jump still fits in 16 bits.
+    for (i, instruction) in code.iter().enumerate() {
+        if !is_jump(&instruction.op) {
+            continue;
+        }
+        let offset = offsets[i];
+        let target = offsets[instruction.target];
+        let distance = match instruction.op {
+            OpCode::Loop => offset + 3 - target,
+            _ => target - (offset + 3),
+        };
+        chunk.code[offset + 1] = (distance >> 8) as u8;
+        chunk.code[offset + 2] = distance as u8;
+    }
+
+    chunk.locals = original.locals.iter().zip(locals)
+        .map(|(local, &(start, end))| LocalSymbol {
+            start: offsets[start],
+            end: offsets[end],
+            ..local.clone()
+        })
+        .collect();
+    chunk
+}
+
+// Writes the shortest instruction that pushes `value`, the way the compiler
+// would: OP_SMALL_INT for small integers, otherwise a constant that reuses
+// an equal number already in the table.
+fn write_constant(chunk: &mut Chunk, value: Value, line: i32) {
+    if value.is_bool() {
+        let op = if value.as_bool() { OpCode::True } else { OpCode::False };
+        chunk.emit_op(op, line);
+        return;
+    }
+    let n = value.as_number();
+    if n.fract() == 0.0 && (0.0..=255.0).contains(&n) && n.is_sign_positive() {
+        chunk.emit_op_u8(OpCode::SmallInt, n as u8, line);
+        return;
+    }
+    let existing = chunk.constants.values.iter()
+        .position(|constant| constant.is_number() && constant.as_number().to_bits() == n.to_bits());
+    let index = existing.unwrap_or_else(|| chunk.add_constant(value));
+    if index <= u8::MAX as usize {
+        chunk.emit_op_u8(OpCode::Constant, index as u8, line);
+    } else {
+        chunk.emit_op_u24(OpCode::ConstantLong, index as u32, line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::disassemble;
+
+    // Jumps to the next instruction are removed, and the jumps around them
+    // are shortened.
+    #[test]
+    fn jumps_to_the_next_instruction_are_removed() {
+        let mut chunk = Chunk::default();
+        for byte in [OpCode::True as u8, OpCode::JumpIfFalse as u8, 0, 4,
+                     OpCode::Jump as u8, 0, 0, OpCode::Pop as u8, OpCode::Nil as u8,
+                     OpCode::Return as u8] {
+            chunk.write_chunk(byte, 1);
+        }
+        assert_eq!(disassemble(&optimize(&chunk), "script"), "\
+== script ==
+0000    1 OP_TRUE
+0001    | OP_JUMP_IF_FALSE   +1 -> L1
+0004    | OP_POP
+L1:
+0005    | OP_NIL
+0006    | OP_RETURN
+");
+    }
+}
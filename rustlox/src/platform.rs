@@ -0,0 +1,206 @@
+// Purpose: Host services used by the VM and its natives.
+//
+// The clock, random seed, file access, process information, environment
+// variables and commands all go through a Platform
+// so the VM can run where std's implementations are missing or unwanted,
+// such as embedded and wasm32-unknown-unknown targets. Output goes through
+// the VM's stdout and stderr sinks instead.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::time::Instant;
+
+pub trait Platform: fmt::Debug + Send {
+    /// Seconds since a fixed point, used by clock() and timeouts.
+    fn clock(&self) -> f64;
+
+    /// Seeds random(), which asks once, on its first call.
+    fn random_seed(&self) -> u64;
+
+    /// The operating system's name, as returned by platform().
+    fn os_name(&self) -> &str;
+
+    fn read_file(&self, path: &str) -> Result<String, String>;
+
+    /// The absolute form of `path` with symbolic links resolved, used to
+    /// keep imports inside the import directory. Platforms without links
+    /// can keep the default, which leaves the path as it is.
+    fn canonicalize(&self, path: &str) -> Result<String, String> {
+        Ok(path.to_string())
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> Result<(), String>;
+
+    /// The next line of standard input without its line ending, or None at
+    /// the end of the input.
+    fn read_line(&self) -> Result<Option<String>, String>;
+
+    fn current_dir(&self) -> Result<String, String>;
+
+    fn set_current_dir(&self, path: &str) -> Result<(), String>;
+
+    /// The process id, if there is one.
+    fn process_id(&self) -> Option<u32>;
+
+    /// Ends the process with `code`. Only returns if it can't.
+    fn exit(&self, code: i32) -> Result<(), String>;
+
+    /// The environment variable `name`, if it is set.
+    fn env_var(&self, name: &str) -> Option<String>;
+
+    /// Sets the environment variable `name`. Implementations that change
+    /// the process's environment are only sound while no other thread reads
+    /// it; see [`Capability::EnvVars`].
+    ///
+    /// [`Capability::EnvVars`]: crate::policy::Capability::EnvVars
+    fn set_env_var(&self, name: &str, value: &str) -> Result<(), String>;
+
+    /// Runs `command` with the system shell and waits for it to finish.
+    fn exec(&self, command: &str) -> Result<CommandOutput, String>;
+}
+
+/// What a command run by [`Platform::exec`] wrote, and how it ended.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    /// The exit status, or None if the command was killed by a signal.
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The platform backed by the standard library, used by default.
+#[derive(Debug)]
+pub struct StdPlatform {
+    start: Instant,
+    // Set by deterministic(): the seed, how far the clock moves on each
+    // reading, and its last reading.
+    replay: Option<(u64, f64, Cell<f64>)>,
+}
+
+impl Default for StdPlatform {
+    fn default() -> Self {
+        StdPlatform { start: Instant::now(), replay: None }
+    }
+}
+
+impl StdPlatform {
+    /// The standard library's platform with a clock and random numbers
+    /// that are the same on every run, for tests and for replaying a run
+    /// while debugging. random() is seeded with `seed`, and clock() starts
+    /// at 0 and moves forward `tick` seconds each time it is read.
+    ///
+    /// ```
+    /// use rustlox::platform::StdPlatform;
+    /// use rustlox::Vm;
+    ///
+    /// let run = || {
+    ///     let mut vm = Vm::builder().platform(StdPlatform::deterministic(42, 0.5)).build();
+    ///     vm.interpret_captured("print random(); print clock(); print clock();").1
+    /// };
+    /// let first = run();
+    /// assert!(first.ends_with("0.5\n1\n"));
+    /// assert_eq!(run(), first);
+    /// ```
+    pub fn deterministic(seed: u64, tick: f64) -> StdPlatform {
+        StdPlatform { start: Instant::now(), replay: Some((seed, tick, Cell::new(0.0))) }
+    }
+}
+
+impl Platform for StdPlatform {
+    fn clock(&self) -> f64 {
+        match &self.replay {
+            Some((_, tick, now)) => {
+                now.set(now.get() + tick);
+                now.get()
+            }
+            None => self.start.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn random_seed(&self) -> u64 {
+        match &self.replay {
+            Some((seed, _, _)) => *seed,
+            None => RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    fn os_name(&self) -> &str {
+        std::env::consts::OS
+    }
+
+    fn read_file(&self, path: &str) -> Result<String, String> {
+        std::fs::read_to_string(path).map_err(|err| err.to_string())
+    }
+
+    fn canonicalize(&self, path: &str) -> Result<String, String> {
+        std::fs::canonicalize(path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|err| err.to_string())
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> Result<(), String> {
+        std::fs::write(path, contents).map_err(|err| err.to_string())
+    }
+
+    fn read_line(&self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                let end = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(end);
+                Ok(Some(line))
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn current_dir(&self) -> Result<String, String> {
+        std::env::current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .map_err(|err| err.to_string())
+    }
+
+    fn set_current_dir(&self, path: &str) -> Result<(), String> {
+        std::env::set_current_dir(path).map_err(|err| err.to_string())
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        Some(std::process::id())
+    }
+
+    fn exit(&self, code: i32) -> Result<(), String> {
+        std::process::exit(code)
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    fn set_env_var(&self, name: &str, value: &str) -> Result<(), String> {
+        // set_var panics on names and values the OS can't take.
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err("invalid name".to_string());
+        }
+        if value.contains('\0') {
+            return Err("invalid value".to_string());
+        }
+        // Not thread-safe: see Capability::EnvVars, which guards setenv.
+        std::env::set_var(name, value);
+        Ok(())
+    }
+
+    fn exec(&self, command: &str) -> Result<CommandOutput, String> {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        let output = std::process::Command::new(shell).args([flag, command]).output()
+            .map_err(|err| err.to_string())?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
@@ -0,0 +1,118 @@
+// Purpose: Which host capabilities scripts on a VM may use.
+
+/// A host resource that natives can reach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    /// The file system, including the working directory.
+    Fs,
+    Net,
+    /// The host process: its id and native extensions.
+    Process,
+    /// Facts about the host environment, such as the operating system.
+    Env,
+    Time,
+    /// Reading and setting environment variables.
+    ///
+    /// Only allow this while no other thread can touch the environment.
+    /// With [`StdPlatform`], `setenv` calls [`std::env::set_var`], which
+    /// races with any other thread reading the environment, and on glibc
+    /// that is undefined behaviour, so a VM with this capability must not
+    /// run beside other threads, such as VMs on [`VmHandle`] workers.
+    ///
+    /// [`StdPlatform`]: crate::platform::StdPlatform
+    /// [`VmHandle`]: crate::handle::VmHandle
+    EnvVars,
+    /// Running other programs.
+    Exec,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Fs,
+        Capability::Net,
+        Capability::Process,
+        Capability::Env,
+        Capability::Time,
+        Capability::EnvVars,
+        Capability::Exec,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Fs => "fs",
+            Capability::Net => "net",
+            Capability::Process => "process",
+            Capability::Env => "env",
+            Capability::Time => "time",
+            Capability::EnvVars => "env-vars",
+            Capability::Exec => "exec",
+        }
+    }
+}
+
+/// The capabilities allowed on a VM.
+///
+/// Natives that need a capability the policy denies are not defined, and
+/// fail if called after the policy is tightened with [`Vm::set_policy`].
+///
+/// ```
+/// use rustlox::policy::{Capability, Policy};
+/// use rustlox::Vm;
+///
+/// let mut vm = Vm::builder().policy(Policy::pure().allow(Capability::Time)).build();
+/// assert!(vm.interpret("clock();").is_ok());
+/// assert!(vm.interpret("cwd();").is_err());
+/// ```
+///
+/// [`Vm::set_policy`]: crate::Vm::set_policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    allowed: Vec<Capability>,
+}
+
+/// The default policy allows everything but [`Capability::EnvVars`], which
+/// is only sound in a single-threaded host, and [`Capability::Exec`]. Scripts
+/// only get them when the host allows them.
+///
+/// ```
+/// use rustlox::policy::{Capability, Policy};
+/// use rustlox::Vm;
+///
+/// assert!(Vm::new().interpret("getenv(\"HOME\");").is_err());
+/// let mut vm = Vm::builder().policy(Policy::default().allow(Capability::EnvVars)).build();
+/// let (_, stdout, _) = vm.interpret_captured("setenv(\"RUSTLOX_POLICY\", \"on\"); print getenv(\"RUSTLOX_POLICY\");");
+/// assert_eq!(stdout, "on\n");
+/// ```
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::all().deny(Capability::EnvVars).deny(Capability::Exec)
+    }
+}
+
+impl Policy {
+    /// Allows every capability.
+    pub fn all() -> Policy {
+        Policy { allowed: Capability::ALL.to_vec() }
+    }
+
+    /// Allows nothing beyond pure computation and printing.
+    pub fn pure() -> Policy {
+        Policy { allowed: Vec::new() }
+    }
+
+    pub fn allow(mut self, capability: Capability) -> Policy {
+        if !self.allows(capability) {
+            self.allowed.push(capability);
+        }
+        self
+    }
+
+    pub fn deny(mut self, capability: Capability) -> Policy {
+        self.allowed.retain(|c| *c != capability);
+        self
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+}
@@ -0,0 +1,159 @@
+// Purpose: Counts the opcodes a VM executes and times the functions it
+// calls, for `rustlox --profile` and VmBuilder::profile.
+
+use crate::chunk::OpCode;
+use crate::debug::opcode_name;
+use crate::object::ObjFunction;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Calls and time spent in one function, in seconds of the VM's platform
+/// clock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    /// The function's name, or `script` for top-level code.
+    pub name: String,
+    /// The line the function's code starts on.
+    pub line: usize,
+    pub calls: u64,
+    /// Time spent running the function's own code.
+    pub self_time: f64,
+    /// Time from entering the function to leaving it, including the
+    /// functions it calls. Recursive calls are only counted once.
+    pub total_time: f64,
+}
+
+// A call that hasn't returned yet.
+#[derive(Debug)]
+struct Active {
+    function: usize,
+    start: f64,
+    // Time spent in the functions it called.
+    children: f64,
+}
+
+/// What a VM built with [`VmBuilder::profile`](crate::vm::VmBuilder::profile)
+/// has executed so far. `{}` formats it as a table of the most executed
+/// opcodes and the functions that took the most time.
+///
+/// ```
+/// use rustlox::Vm;
+///
+/// let mut vm = Vm::builder().profile(true).build();
+/// vm.interpret("fun f(n) { return n + 1; } for (var i = 0; i < 10; i = i + 1) f(i);").unwrap();
+/// let profile = vm.profile().unwrap();
+/// let f = profile.functions().into_iter().find(|function| function.name == "f").unwrap();
+/// assert_eq!(f.calls, 10);
+/// assert_eq!(profile.opcode_count("OP_CALL"), 10);
+/// ```
+#[derive(Debug)]
+pub struct Profile {
+    opcodes: [u64; 256],
+    functions: Vec<FunctionProfile>,
+    indices: HashMap<*const ObjFunction, usize>,
+    active: Vec<Active>,
+    // How many calls of each function are active, so recursion isn't
+    // counted twice in its total time.
+    depths: Vec<usize>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            opcodes: [0; 256],
+            functions: Vec::new(),
+            indices: HashMap::new(),
+            active: Vec::new(),
+            depths: Vec::new(),
+        }
+    }
+}
+
+impl Profile {
+    /// The number of times the opcode named `name`, e.g. `OP_ADD`, ran.
+    pub fn opcode_count(&self, name: &str) -> u64 {
+        self.opcode_counts().into_iter()
+            .find(|(opcode, _)| *opcode == name)
+            .map_or(0, |(_, count)| count)
+    }
+
+    /// Every opcode that ran, most executed first.
+    pub fn opcode_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<_> = self.opcodes.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(byte, count)| Some((opcode_name(&OpCode::try_from(byte as u8).ok()?), *count)))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Every function called, the one with the most self time first.
+    pub fn functions(&self) -> Vec<&FunctionProfile> {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.self_time.total_cmp(&a.self_time));
+        functions
+    }
+
+    pub(crate) fn count(&mut self, byte: u8) {
+        self.opcodes[byte as usize] += 1;
+    }
+
+    pub(crate) fn enter(&mut self, function: *const ObjFunction, now: f64) {
+        let index = match self.indices.get(&function) {
+            Some(&index) => index,
+            None => {
+                let index = self.functions.len();
+                let function_ref = unsafe { &*function };
+                let name = if function_ref.name.is_null() {
+                    "script".to_string()
+                } else {
+                    unsafe { (*function_ref.name).as_str() }.to_string()
+                };
+                let line = if function_ref.chunk.lines.is_empty() { 0 } else { function_ref.chunk.get_line(0) as usize };
+                self.functions.push(FunctionProfile { name, line, calls: 0, self_time: 0.0, total_time: 0.0 });
+                self.depths.push(0);
+                self.indices.insert(function, index);
+                index
+            }
+        };
+        self.functions[index].calls += 1;
+        self.depths[index] += 1;
+        self.active.push(Active { function: index, start: now, children: 0.0 });
+    }
+
+    // Ends the calls of every frame above the first `frame_count`, which
+    // have returned or been unwound by an error.
+    pub(crate) fn exit_to(&mut self, frame_count: usize, now: f64) {
+        while self.active.len() > frame_count {
+            let call = self.active.pop().unwrap();
+            let elapsed = now - call.start;
+            let function = &mut self.functions[call.function];
+            function.self_time += elapsed - call.children;
+            self.depths[call.function] -= 1;
+            if self.depths[call.function] == 0 {
+                function.total_time += elapsed;
+            }
+            if let Some(caller) = self.active.last_mut() {
+                caller.children += elapsed;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts = self.opcode_counts();
+        let total: u64 = counts.iter().map(|(_, count)| count).sum();
+        writeln!(f, "{:24} {:>14} {:>7}", "opcode", "count", "%")?;
+        for (name, count) in &counts {
+            writeln!(f, "{:24} {:>14} {:>6.1}%", name, count, 100.0 * *count as f64 / total as f64)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:24} {:>14} {:>12} {:>12}", "function", "calls", "self (s)", "total (s)")?;
+        for function in self.functions() {
+            let name = format!("{} (line {})", function.name, function.line);
+            writeln!(f, "{:24} {:>14} {:>12.6} {:>12.6}", name, function.calls, function.self_time, function.total_time)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+// Purpose: Interactive sessions, run locally or served over TCP.
+//
+// A ReplServer (with the `net` feature) lets someone inspect a VM embedded
+// in a long-running host, e.g. a game or a server, from another terminal
+// with a line-based client such as `nc`. The host calls poll() from its
+// own loop, so scripts only ever run on the host's thread.
+//
+// Protocol: if the server has a token, the client's first line must be
+// that token. Every line after that runs like a line typed at the local
+// REPL, with its output and errors sent back to the client, followed by a
+// "> " prompt, or a "... " prompt while the input so far is incomplete.
+
+use crate::scanner::new_scanner;
+use crate::scanner::TokenType;
+use crate::value::Value;
+use crate::vm::Vm;
+
+/// Whether REPL input needs more lines before it can run: it has unclosed
+/// parentheses, brackets or braces, or ends inside a string.
+///
+/// ```
+/// use rustlox::repl::is_incomplete;
+///
+/// assert!(is_incomplete("fun f() {\n  print 1;\n"));
+/// assert!(!is_incomplete("fun f() {\n  print 1;\n}\n"));
+/// assert!(is_incomplete("print \"two\n"));
+/// assert!(is_incomplete("/* a comment\n"));
+/// assert!(is_incomplete("print \"${f(\n"));
+/// assert!(!is_incomplete("print \"${a}, ${b}\";\n"));
+/// ```
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = new_scanner(source.to_string());
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+            TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => depth -= 1,
+            // The part of a string after an interpolated expression starts
+            // with the '}' that closes it.
+            TokenType::Interpolation if token.text().starts_with('"') => depth += 1,
+            TokenType::String if token.text().starts_with('}') => depth -= 1,
+            TokenType::Error if token.text() == "Unterminated string." => return true,
+            TokenType::Error if token.text() == "Unterminated block comment." => return true,
+            TokenType::EOF => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+// Binds a REPL expression's value to `_`, shifting earlier results to `_2`
+// and `_3`. Like Python, nil results are not remembered.
+pub fn remember_result(vm: &mut Vm, value: Value) {
+    if value.is_nil() {
+        return;
+    }
+    if let Some(previous) = vm.global("_2") {
+        vm.set_global("_3", previous);
+    }
+    if let Some(previous) = vm.global("_") {
+        vm.set_global("_2", previous);
+    }
+    vm.set_global("_", value);
+}
+
+#[cfg(feature = "net")]
+pub use server::ReplServer;
+
+#[cfg(feature = "net")]
+mod server {
+    use super::is_incomplete;
+    use super::remember_result;
+    use crate::vm::Vm;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const PROMPT: &[u8] = b"> ";
+    const CONTINUATION_PROMPT: &[u8] = b"... ";
+    // How much a client may send before it has authenticated, so that one
+    // that never sends a newline can't make the buffer grow without bound.
+    const MAX_UNAUTHENTICATED: usize = 1024;
+    // The longest line, or statement split over lines, a client may send
+    // once it has.
+    const MAX_LINE: usize = 64 * 1024;
+    // How long a write to a client may wait for it to read, so that one
+    // that stops reading can't hang the host.
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Client {
+        stream: TcpStream,
+        // Bytes received but not yet run, up to the next newline.
+        buffer: Vec<u8>,
+        // Lines of an incomplete statement, waiting for the rest.
+        pending: String,
+        authenticated: bool,
+        closed: bool,
+    }
+
+    /// Serves interactive sessions on a VM over TCP.
+    ///
+    /// ```no_run
+    /// use rustlox::repl::ReplServer;
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let mut server = ReplServer::bind("127.0.0.1:7007", Some("secret".to_string())).unwrap();
+    /// loop {
+    ///     // ... the host's own work ...
+    ///     server.poll(&mut vm);
+    /// }
+    /// ```
+    #[derive(Debug)]
+    pub struct ReplServer {
+        listener: TcpListener,
+        token: Option<String>,
+        clients: Vec<Client>,
+    }
+
+    impl std::fmt::Debug for Client {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.debug_struct("Client")
+                .field("peer", &self.stream.peer_addr().ok())
+                .field("authenticated", &self.authenticated)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl ReplServer {
+        /// Listens on `address`. Clients must send `token` before anything
+        /// else; without one, anyone who can connect can run code in the
+        /// host, so only use `None` on a loopback address.
+        pub fn bind(address: &str, token: Option<String>) -> io::Result<ReplServer> {
+            let listener = TcpListener::bind(address)?;
+            listener.set_nonblocking(true)?;
+            Ok(ReplServer { listener, token, clients: Vec::new() })
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.listener.local_addr()
+        }
+
+        /// Accepts new clients and runs every complete line they have sent,
+        /// without waiting for more input.
+        pub fn poll(&mut self, vm: &mut Vm) {
+            while let Ok((stream, _)) = self.listener.accept() {
+                if let Some(client) = self.connect(stream) {
+                    self.clients.push(client);
+                }
+            }
+            for client in &mut self.clients {
+                client.receive();
+                while let Some(line) = client.next_line() {
+                    if client.authenticated {
+                        client.run(vm, &line);
+                    } else {
+                        client.authenticate(self.token.as_deref(), &line);
+                    }
+                }
+            }
+            self.clients.retain(|client| !client.closed);
+        }
+
+        /// Polls forever, for a process that only serves the REPL.
+        pub fn serve(&mut self, vm: &mut Vm) -> ! {
+            loop {
+                self.poll(vm);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn connect(&self, mut stream: TcpStream) -> Option<Client> {
+            stream.set_nonblocking(true).ok()?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok()?;
+            let authenticated = self.token.is_none();
+            if authenticated {
+                stream.write_all(PROMPT).ok()?;
+            }
+            Some(Client { stream, buffer: Vec::new(), pending: String::new(), authenticated, closed: false })
+        }
+    }
+
+    impl Client {
+        fn receive(&mut self) {
+            let mut chunk = [0; 4096];
+            loop {
+                match self.stream.read(&mut chunk) {
+                    Ok(0) => {
+                        self.closed = true;
+                        return;
+                    }
+                    Ok(n) => {
+                        self.buffer.extend_from_slice(&chunk[..n]);
+                        // The rest waits until next_line() has taken what
+                        // it can.
+                        if self.buffer.len() >= self.max_line() {
+                            return;
+                        }
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                    Err(_) => {
+                        self.closed = true;
+                        return;
+                    }
+                }
+            }
+        }
+
+        fn max_line(&self) -> usize {
+            if self.authenticated {
+                MAX_LINE - self.pending.len()
+            } else {
+                MAX_UNAUTHENTICATED
+            }
+        }
+
+        fn next_line(&mut self) -> Option<String> {
+            let end = self.buffer.iter().position(|&byte| byte == b'\n');
+            if end.unwrap_or(self.buffer.len()) >= self.max_line() {
+                if self.authenticated {
+                    self.refuse(b"Line too long.\n");
+                } else {
+                    self.fail_authentication();
+                }
+                return None;
+            }
+            let end = end?;
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            Some(String::from_utf8_lossy(&line).into_owned())
+        }
+
+        fn authenticate(&mut self, token: Option<&str>, line: &str) {
+            if token.is_some_and(|token| tokens_match(line.trim_end().as_bytes(), token.as_bytes())) {
+                self.authenticated = true;
+                let _ = self.stream.write_all(PROMPT);
+            } else {
+                self.fail_authentication();
+            }
+        }
+
+        fn fail_authentication(&mut self) {
+            self.refuse(b"Authentication failed.\n");
+        }
+
+        // Sends `message` and closes the connection. What the client has
+        // already sent is read first, up to a limit, so that closing doesn't
+        // reset the connection before the message arrives.
+        fn refuse(&mut self, message: &[u8]) {
+            let _ = self.stream.write_all(message);
+            let _ = io::copy(&mut (&self.stream).take(MAX_LINE as u64), &mut io::sink());
+            self.buffer.clear();
+            self.pending.clear();
+            self.closed = true;
+        }
+
+        // Runs `line`, together with any incomplete lines before it, with
+        // the VM's output going to this client.
+        fn run(&mut self, vm: &mut Vm, line: &str) {
+            self.pending.push_str(line);
+            if is_incomplete(&self.pending) {
+                if self.stream.write_all(CONTINUATION_PROMPT).is_err() {
+                    self.closed = true;
+                }
+                return;
+            }
+            let source = std::mem::take(&mut self.pending);
+            let failed = Arc::new(AtomicBool::new(false));
+            let (stdout, stderr) = match (Output::new(&self.stream, &failed), Output::new(&self.stream, &failed)) {
+                (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+                _ => {
+                    self.closed = true;
+                    return;
+                }
+            };
+            // Writes block, up to the timeout, so that long output isn't cut
+            // short.
+            let _ = self.stream.set_nonblocking(false);
+            let host_stdout = vm.set_stdout(stdout);
+            let host_stderr = vm.set_stderr(stderr);
+            if let Ok(value) = vm.eval_line(&source) {
+                remember_result(vm, value);
+            }
+            vm.set_stdout(host_stdout);
+            vm.set_stderr(host_stderr);
+            if failed.load(Ordering::Relaxed) || self.stream.write_all(PROMPT).is_err() {
+                self.closed = true;
+            }
+            let _ = self.stream.set_nonblocking(true);
+        }
+    }
+
+    // A client's stdout or stderr. After one write fails, or times out,
+    // the rest fail at once, so a script that prints a lot to a client that
+    // stopped reading waits for one timeout rather than one per print.
+    struct Output {
+        stream: TcpStream,
+        failed: Arc<AtomicBool>,
+    }
+
+    impl Output {
+        fn new(stream: &TcpStream, failed: &Arc<AtomicBool>) -> io::Result<Output> {
+            Ok(Output { stream: stream.try_clone()?, failed: failed.clone() })
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failed.load(Ordering::Relaxed) {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            self.stream.write(buf).inspect_err(|_| self.failed.store(true, Ordering::Relaxed))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    // Compares every byte of the token however early `given` differs from
+    // it, so the time taken doesn't tell a client how much it got right.
+    fn tokens_match(given: &[u8], token: &[u8]) -> bool {
+        let mut difference = given.len() ^ token.len();
+        for (i, &byte) in token.iter().enumerate() {
+            difference |= usize::from(byte ^ given.get(i).copied().unwrap_or(0));
+        }
+        difference == 0
+    }
+}
@@ -1,85 +1,126 @@
 // Purpose: Scanner for the Lox language.
 
+use std::ops::Range;
+use std::rc::Rc;
+
 pub struct Scanner {
-    source: String,
+    source: Rc<str>,
     start: usize,
     current: usize,
     line: i32,
+    // Offset where the current line begins, for columns.
+    line_start: usize,
+    // Column of the token being scanned.
+    column: usize,
+    // For each "${" whose expression is still being scanned, the number of
+    // its braces that are open. The '}' that closes it resumes the string.
+    interpolations: Vec<usize>,
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
 #[repr(u8)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen, RightParen, LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftBracket, RightBracket, Colon, Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    Percent,
     
     // One or two character tokens.
     Bang, BangEqual,
+    MinusEqual, PlusEqual, SlashEqual, StarEqual, PercentEqual,
     Equal, EqualEqual,
     Greater, GreaterEqual,
     Less, LessEqual,
     
-    // Literals.
-    Identifier, String, Number,
+    // Literals. A string with interpolations is scanned as an Interpolation
+    // token for each part that ends in "${", then the expression's tokens,
+    // and finally a String token for the part after the last '}'.
+    Identifier, String, Interpolation, Number,
     
     // Keywords.
-    And, Class, Else, False, Fun, For, If, Nil, Or,
-    Print, Return, Super, This, True, Var, While,
+    And, Break, Case, Catch, Class, Const, Continue, Default, Else, False, Fun, For, If, Import, Nil,
+    Or, Print, Return, Super, Switch, This, Throw, True, Try, Var, While, Yield,
     
-    Error, EOF,
-}
-
-impl Default for TokenType {
-    fn default() -> Self { TokenType::EOF }
+    Error,
+    #[default]
+    EOF,
 }
 
-#[derive(Debug, Copy, Clone)]
+// A token's text is `source[range]`. Tokens share the scanner's source;
+// error tokens instead carry their message as their source.
+#[derive(Debug, Clone, Default)]
 pub struct Token {
     pub token_type: TokenType,
-    pub start: *const u8,
-    pub length: usize,
+    pub source: Rc<str>,
+    pub range: Range<usize>,
     pub line: i32,
-}
-
-static EMPTY_STRING: &str = "";
-
-impl Default for Token {
-    fn default() -> Self {
-        return Token{
-            token_type: TokenType::EOF,
-            start: EMPTY_STRING.as_ptr(),
-            length: 0,
-            line: 0,
-        }
-    }
+    // 1-based, counting characters. 0 for tokens made up by the compiler.
+    pub column: usize,
 }
 
 impl Token {
     pub fn text(&self) -> &str {
-        unsafe {
-            let slice = std::slice::from_raw_parts(self.start, self.length);
-            return std::str::from_utf8(slice).unwrap();
-        }
+        &self.source[self.range.clone()]
     }
 }
 
 pub fn new_scanner(source: String) -> Scanner {
-    return Scanner{
-        source: source,
+    Scanner{
+        source: Rc::from(source),
         current: 0,
         start: 0,
         line: 1,
+        line_start: 0,
+        column: 1,
+        interpolations: Vec::new(),
+    }
+}
+
+/// Scans all of `source`, up to and including the EOF token.
+///
+/// ```
+/// use rustlox::scanner::{scan_tokens, TokenType};
+///
+/// let tokens = scan_tokens("print 1;");
+/// let types: Vec<TokenType> = tokens.iter().map(|token| token.token_type).collect();
+/// assert_eq!(types, [TokenType::Print, TokenType::Number, TokenType::Semicolon, TokenType::EOF]);
+/// assert_eq!(tokens[1].text(), "1");
+/// ```
+pub fn scan_tokens(source: &str) -> Vec<Token> {
+    let mut scanner = new_scanner(source.to_string());
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        let done = token.token_type == TokenType::EOF;
+        tokens.push(token);
+        if done {
+            return tokens;
+        }
     }
 }
 
 const UNEXPECTED_CHAR: &str = "Unexpected character.";
 
 impl Scanner {
+    // Byte range of `token` in the source, or None for error tokens and
+    // tokens the compiler made up.
+    pub fn span(&self, token: &Token) -> Option<Range<usize>> {
+        if Rc::ptr_eq(&token.source, &self.source) {
+            return Some(token.range.clone());
+        }
+        None
+    }
+
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        let skipped = self.skip_whitespace();
         self.start = self.current;
+        self.column = self.source[self.line_start..self.start].chars().count() + 1;
         
+        if let Err(line) = skipped {
+            let mut token = self.error_token("Unterminated block comment.");
+            token.line = line;
+            return token;
+        }
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
         }
@@ -91,41 +132,62 @@ impl Scanner {
         if self.is_digit(c) {
             return self.number();
         }
-        return match c {
+        match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(open) = self.interpolations.last_mut() {
+                    *open += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+                        self.string()
+                    }
+                    Some(open) => {
+                        *open -= 1;
+                        self.make_token(TokenType::RightBrace)
+                    }
+                    None => self.make_token(TokenType::RightBrace),
+                }
+            }
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ':' => self.make_token(TokenType::Colon),
             ';' => self.make_token(TokenType::Semicolon),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
+            '-' => self.make_compound_token(TokenType::Minus, TokenType::MinusEqual),
+            '+' => self.make_compound_token(TokenType::Plus, TokenType::PlusEqual),
+            '/' => self.make_compound_token(TokenType::Slash, TokenType::SlashEqual),
+            '*' => self.make_compound_token(TokenType::Star, TokenType::StarEqual),
+            '%' => self.make_compound_token(TokenType::Percent, TokenType::PercentEqual),
             '!' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::BangEqual);
                 } 
-                return self.make_token(TokenType::Bang);
+                self.make_token(TokenType::Bang)
             },
             '=' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::EqualEqual);
                 }
-                return self.make_token(TokenType::Equal);
+                self.make_token(TokenType::Equal)
             },
             '<' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::LessEqual);
                 }
-                return self.make_token(TokenType::Less);
+                self.make_token(TokenType::Less)
             },
             '>' => {
                 if self.match_char('=') {
                     return self.make_token(TokenType::GreaterEqual);
                 }
-                return self.make_token(TokenType::Greater);
+                self.make_token(TokenType::Greater)
             },
             '"' => self.string(),
             _ => self.error_token(UNEXPECTED_CHAR),
@@ -134,8 +196,15 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
+            }
             if self.peek() == '\n' {
                 self.line += 1;
+                self.line_start = self.current + 1;
             }
             self.advance();
         }
@@ -145,40 +214,62 @@ impl Scanner {
         }
 
         self.advance();
-        return self.make_token(TokenType::String);
+        self.make_token(TokenType::String)
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        return (c >= 'a' && c <= 'z') ||
-               (c >= 'A' && c <= 'Z') ||
-                c == '_';
+        c.is_ascii_lowercase() ||
+               c.is_ascii_uppercase() ||
+                c == '_'
     }
 
     fn identifier(&mut self) -> Token {
         while self.is_alpha(self.peek()) || self.is_digit(self.peek()) {
             self.advance();
         }
-        return self.make_token(self.identifier_type());
+        self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
-        return match self.source.as_bytes()[self.start] as char {
+        match self.source.as_bytes()[self.start] as char {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'c' => {
+                if self.current - self.start <= 1 {
+                    return TokenType::Identifier;
+                }
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'a' => self.check_keyword_pair(2, "se", TokenType::Case, "tch", TokenType::Catch),
+                    'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                    'o' => self.check_keyword_pair(2, "nst", TokenType::Const, "ntinue", TokenType::Continue),
+                    _ => TokenType::Identifier,
+                }
+            },
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'd' => self.check_keyword(1, 6, "efault", TokenType::Default),
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' => self.check_keyword_pair(1, "f", TokenType::If, "mport", TokenType::Import),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
             'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            's' => {
+                if self.current - self.start <= 1 {
+                    return TokenType::Identifier;
+                }
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'u' => self.check_keyword(2, 3, "per", TokenType::Super),
+                    'w' => self.check_keyword(2, 4, "itch", TokenType::Switch),
+                    _ => TokenType::Identifier,
+                }
+            },
             'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
             'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            'y' => self.check_keyword(1, 4, "ield", TokenType::Yield),
             'f' => {
                 if self.current - self.start <= 1 {
                     return TokenType::Identifier;
                 }
-                return match self.source.as_bytes()[self.start + 1] as char {
+                match self.source.as_bytes()[self.start + 1] as char {
                     'a' => self.check_keyword(2, 3, "lse", TokenType::False),
                     'o' => self.check_keyword(2, 1, "r", TokenType::For),
                     'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
@@ -189,9 +280,9 @@ impl Scanner {
                 if self.current - self.start <= 1 {
                     return TokenType::Identifier;
                 }
-                return match self.source.as_bytes()[self.start + 1] as char {
-                    'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                    'r' => self.check_keyword(2, 2, "ue", TokenType::True),
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'h' => self.check_keyword_pair(2, "is", TokenType::This, "row", TokenType::Throw),
+                    'r' => self.check_keyword_pair(2, "ue", TokenType::True, "y", TokenType::Try),
                     _ => TokenType::Identifier,
                 }
             },
@@ -199,39 +290,81 @@ impl Scanner {
         }
     }
 
+    // An operator, or its compound assignment form when followed by '='.
+    fn make_compound_token(&mut self, operator: TokenType, assignment: TokenType) -> Token {
+        if self.match_char('=') {
+            return self.make_token(assignment);
+        }
+        self.make_token(operator)
+    }
+
+    // Tells apart two keywords that share their first `start` characters.
+    fn check_keyword_pair(&self, start: usize, first: &str, first_type: TokenType, second: &str, second_type: TokenType) -> TokenType {
+        match self.check_keyword(start, first.len(), first, first_type) {
+            TokenType::Identifier => self.check_keyword(start, second.len(), second, second_type),
+            token_type => token_type,
+        }
+    }
+
     fn check_keyword(&self, start: usize, length: usize, rest: &str, token_type: TokenType) -> TokenType {
         if (self.current - self.start == start + length) &&
             (&self.source[self.start + start..self.start + start + length] == rest) {
             return token_type;
         }
-        return TokenType::Identifier;
+        TokenType::Identifier
     }
 
     fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        c.is_ascii_digit()
     }
 
     fn number(&mut self) -> Token {
-        while self.is_digit(self.peek()) {
-            self.advance();
-        }
+        self.digits();
 
         if self.peek() == '.' && self.is_digit(self.peek_next()) {
             self.advance();
-            while self.is_digit(self.peek()) {
+            self.digits();
+        }
+
+        // An exponent, as in 1e9 or 2.5e-3.
+        if self.peek() == 'e' || self.peek() == 'E' {
+            let signed = self.peek_next() == '+' || self.peek_next() == '-';
+            let first_digit = if signed { self.peek_at(2) } else { self.peek_next() };
+            if self.is_digit(first_digit) {
                 self.advance();
+                if signed {
+                    self.advance();
+                }
+                self.digits();
             }
         }
 
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
+    // Consumes a run of digits, which may be split up by underscores between
+    // them, as in 1_000_000.
+    fn digits(&mut self) {
+        while self.is_digit(self.peek()) || (self.peek() == '_' && self.is_digit(self.peek_next())) {
+            self.advance();
+        }
+    }
+
+    // Steps over a whole character, so that tokens never end inside a
+    // multi-byte one.
     fn advance(&mut self) -> char {
-        self.current += 1;
-        return self.source.as_bytes()[self.current - 1] as char;
+        let byte = self.source.as_bytes()[self.current];
+        if byte.is_ascii() {
+            self.current += 1;
+            return byte as char;
+        }
+        let c = self.source[self.current..].chars().next().unwrap();
+        self.current += c.len_utf8();
+        c
     }
 
-    fn skip_whitespace(&mut self) {
+    // Fails with the line of a block comment that the source ends inside.
+    fn skip_whitespace(&mut self) -> Result<(), i32> {
         loop {
             let c = self.peek();
             match c {
@@ -241,21 +374,52 @@ impl Scanner {
                 '\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.current;
                 },
                 '/' => {
                     if self.peek_next() == '/' {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
+                    } else if self.peek_next() == '*' {
+                        let line = self.line;
+                        if !self.block_comment() {
+                            return Err(line);
+                        }
                     } else {
-                        return;
+                        return Ok(());
                     }
                 },
-                _ => return,
+                _ => return Ok(()),
             }
         }
     }
 
+    // Skips a /* */ comment, including any comments nested inside it.
+    // Returns false if the source ends before it is closed.
+    fn block_comment(&mut self) -> bool {
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return false;
+            }
+            if self.peek() == '/' && self.peek_next() == '*' {
+                self.advance();
+                depth += 1;
+            } else if self.peek() == '*' && self.peek_next() == '/' {
+                self.advance();
+                depth -= 1;
+            } else if self.peek() == '\n' {
+                self.line += 1;
+                self.line_start = self.current + 1;
+            }
+            self.advance();
+        }
+        true
+    }
+
     fn match_char(&mut self, expected: char) -> bool {
         if self.is_at_end() {
             return false;
@@ -264,43 +428,49 @@ impl Scanner {
             return false;
         }
         self.current += 1;
-        return true;
+        true
     }
 
     fn peek(&self) -> char {
         if self.is_at_end() {
             return '\0';
         }
-        return self.source.as_bytes()[self.current] as char;
+        self.source.as_bytes()[self.current] as char
     }
 
     fn peek_next(&self) -> char {
         if  self.current + 1 >= self.source.len() {
             return '\0';
         }
-        return self.source.as_bytes()[self.current + 1] as char;
+        self.source.as_bytes()[self.current + 1] as char
+    }
+
+    // The character `offset` places ahead of the current one.
+    fn peek_at(&self, offset: usize) -> char {
+        self.source.as_bytes().get(self.current + offset).map_or('\0', |&byte| byte as char)
     }
 
     fn is_at_end(&self) -> bool {
-        return self.current >= self.source.len();
+        self.current >= self.source.len()
     }
 
     fn make_token(&self, token_type: TokenType) -> Token {
-        let slice = &self.source[self.start..self.current];
-        return Token{
-            token_type: token_type,
-            start: slice.as_ptr(),
-            length: slice.len(),
+        Token{
+            token_type,
+            source: self.source.clone(),
+            range: self.start..self.current,
             line: self.line,
+            column: self.column,
         }
     }
 
     fn error_token(&self, message: &str) -> Token {
-        return Token{
+        Token{
             token_type: TokenType::Error,
-            start: message.as_ptr(),
-            length: message.len(),
+            source: Rc::from(message),
+            range: 0..message.len(),
             line: self.line,
+            column: self.column,
         }
     }
 }
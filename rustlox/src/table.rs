@@ -0,0 +1,167 @@
+// Purpose: The intern table that makes equal strings the same ObjString,
+// and the table of global variables.
+//
+// Strings are bucketed by their FNV-1a hash, and a lookup compares the
+// text of each string in the bucket. Keys are plain hashes, so nothing in
+// the table borrows from a string the garbage collector might free.
+// Methods and fields are then keyed by the interned ObjString pointers
+// themselves, and globals are numbered by them. Those maps hash the
+// pointer's address rather than running SipHash over it.
+
+use crate::object::ObjString;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::hash::Hasher;
+
+#[derive(Debug, Default)]
+pub struct StringTable {
+    buckets: HashMap<u32, Vec<*const ObjString>>,
+}
+
+impl StringTable {
+    /// Returns the interned string with the text `s`, whose hash is `hash`.
+    pub fn find(&self, s: &str, hash: u32) -> Option<*const ObjString> {
+        let bucket = self.buckets.get(&hash)?;
+        bucket.iter().copied().find(|&string| unsafe { (*string).as_str() } == s)
+    }
+
+    /// Adds a string that isn't interned yet.
+    pub(crate) fn insert(&mut self, string: *const ObjString) {
+        let hash = unsafe { (*string).hash };
+        self.buckets.entry(hash).or_default().push(string);
+    }
+
+    /// Keeps only the strings for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(*const ObjString) -> bool) {
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(|&string| keep(string));
+            !bucket.is_empty()
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+    }
+}
+
+/// A map keyed by interned strings. Equal names are the same pointer, so
+/// the key's address is all there is to hash.
+pub type StringMap<V> = HashMap<*const ObjString, V, BuildHasherDefault<AddressHasher>>;
+
+/// Hashes a pointer by its address. Objects are at least 8-byte aligned, so
+/// the low bits carry nothing and the address is spread by a multiply.
+#[derive(Default)]
+pub struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_usize(&mut self, address: usize) {
+        self.0 = ((address >> 3) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// The VM's global variables, stored in slots so compiled code can read and
+/// write them by index instead of hashing the name on every access.
+///
+/// The compiler gives a name its slot the first time it sees it, defined or
+/// not, so a function can refer to a global that is declared after it. The
+/// slot stays undefined until the declaration runs.
+#[derive(Debug, Default)]
+pub struct Globals {
+    slots: StringMap<usize>,
+    names: Vec<*const ObjString>,
+    // None while the variable is undefined.
+    values: Vec<Option<Value>>,
+    // Whether each global was last declared with `const`.
+    constants: Vec<bool>,
+}
+
+impl Globals {
+    /// Returns the slot of the global named `name`, adding an undefined one
+    /// if the name doesn't have one yet.
+    pub fn slot(&mut self, name: *const ObjString) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            return slot;
+        }
+        let slot = self.names.len();
+        self.slots.insert(name, slot);
+        self.names.push(name);
+        self.values.push(None);
+        self.constants.push(false);
+        slot
+    }
+
+    pub fn find(&self, name: *const ObjString) -> Option<usize> {
+        self.slots.get(&name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> *const ObjString {
+        self.names[slot]
+    }
+
+    /// Returns the value in `slot`, or None if it is undefined.
+    pub fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    /// Assigns to a defined global. Returns false, changing nothing, if it
+    /// is undefined.
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(global) => {
+                *global = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the global in `slot` undefined again, keeping its slot.
+    pub fn undefine(&mut self, slot: usize) {
+        self.values[slot] = None;
+    }
+
+    pub fn is_constant(&self, slot: usize) -> bool {
+        self.constants[slot]
+    }
+
+    /// Marks the global in `slot` as declared with `const` or not. The
+    /// compiler sets this when it sees the declaration, and the VM refuses
+    /// to assign to constants.
+    pub fn set_constant(&mut self, slot: usize, constant: bool) {
+        self.constants[slot] = constant;
+    }
+
+    pub fn define(&mut self, name: *const ObjString, value: Value) {
+        let slot = self.slot(name);
+        self.values[slot] = Some(value);
+    }
+
+    /// The number of slots, including undefined ones.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The names and values of the defined globals, in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (*const ObjString, Value)> + '_ {
+        self.names.iter().zip(&self.values)
+            .filter_map(|(&name, value)| value.map(|value| (name, value)))
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.names.clear();
+        self.values.clear();
+        self.constants.clear();
+    }
+}
@@ -0,0 +1,620 @@
+// Purpose: A tree-walking engine, which runs the syntax trees from ast.rs
+// instead of compiling them to bytecode.
+//
+// It is a simpler picture of what the VM does, and the two can be run
+// against each other to check one with the other. It shares the VM's heap,
+// globals, natives and output, so its values are the VM's own objects and
+// print the same. Each function it creates is an ObjFunction whose syntax
+// tree the VM keeps in a side table; its bytecode only raises an error, so
+// natives that call back into Lox can't call these functions. Every local
+// variable lives in an upvalue of its own, which closures capture by
+// sharing it.
+//
+// The frames, and the values that expressions hold while evaluating their
+// other operands, are kept in the VM for the collector to find. Garbage is
+// collected between statements.
+
+use crate::ast;
+use crate::ast::Expr;
+use crate::ast::FunctionKind;
+use crate::ast::Literal;
+use crate::ast::Stmt;
+use crate::ast::Variable;
+use crate::chunk::Chunk;
+use crate::chunk::OpCode;
+use crate::compiler::ScriptKind;
+use crate::error::ErrorKind;
+use crate::error::LoxError;
+use crate::error::TraceFrame;
+use crate::gc::GcReason;
+use crate::object::Obj;
+use crate::object::ObjArray;
+use crate::object::ObjClosure;
+use crate::object::ObjFunction;
+use crate::object::ObjUpvalue;
+use crate::scanner::TokenType;
+use crate::value::Value;
+use crate::value::values_equal;
+use crate::vm::Vm;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+impl Vm {
+    /// Parses `source` and runs it by walking its syntax tree, rather than
+    /// compiling it to bytecode. [`Vm::interpret`] does this when the
+    /// engine is [`Engine::Ast`](crate::vm::Engine::Ast).
+    ///
+    /// The engine supports the language of the book: everything but
+    /// inheritance, which rustlox doesn't have. Extensions such as lists,
+    /// string interpolation and try blocks are compile errors. Output and
+    /// errors are otherwise the same as the VM's, stack traces included,
+    /// except that there are no warnings and calls in return statements
+    /// aren't tail calls. Of the limits, only the maximum call depth and
+    /// interrupts apply.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret_ast("fun add(a, b) { return a + b; }").unwrap();
+    /// vm.collect_garbage();
+    /// vm.interpret_ast("var sum = add(1, 2);").unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("sum").unwrap(), 3.0);
+    ///
+    /// let error = vm.interpret_ast("var list = [];").unwrap_err();
+    /// assert_eq!(error.diagnostics[0].message, "Error at '[': Not supported by the AST engine.");
+    /// ```
+    pub fn interpret_ast(&mut self, source: &str) -> Result<Value, LoxError> {
+        let program = match ast::parse(source) {
+            Ok(program) => program,
+            Err(diagnostics) => {
+                self.report_diagnostics(source, std::ptr::null_mut(), ScriptKind::File, &diagnostics);
+                return Err(LoxError::compile(diagnostics));
+            }
+        };
+        let name = self.config().source_name.clone().unwrap_or_else(|| "script".to_string());
+        let base = self.ast_roots.frames.len();
+        let temporaries = self.ast_roots.temporaries.len();
+        let mut walker = Walker {
+            vm: self,
+            name,
+            source,
+            functions: HashMap::new(),
+            base,
+        };
+        walker.push_frame(std::ptr::null(), vec![Value::nil()]);
+        let result = program.iter().try_for_each(|stmt| walker.execute(stmt));
+        self.ast_roots.frames.truncate(base);
+        self.ast_roots.temporaries.truncate(temporaries);
+        match result {
+            Ok(()) | Err(Unwind::Return(_)) => Ok(Value::nil()),
+            Err(Unwind::Error(error)) => {
+                self.report_error(&error);
+                Err(error)
+            }
+        }
+    }
+}
+
+struct Walker<'a> {
+    vm: &'a mut Vm,
+    // The source's name and text, for stack traces.
+    name: String,
+    source: &'a str,
+    // The function object made for each syntax tree function so far. The
+    // collector may have freed some of them since.
+    functions: HashMap<*const ast::Function, *const ObjFunction>,
+    // The first of the VM's walker frames that belongs to this run.
+    base: usize,
+}
+
+/// The parts of running tree-walkers that the collector has to mark.
+#[derive(Default)]
+pub(crate) struct Roots {
+    frames: Vec<Frame>,
+    // Values that expressions are holding on to while they evaluate their
+    // other operands, such as a call's callee and arguments.
+    temporaries: Vec<Value>,
+}
+
+impl Roots {
+    pub(crate) fn mark(&self, obj_array: &mut ObjArray) {
+        for frame in &self.frames {
+            unsafe { obj_array.mark_object(frame.closure as *mut Obj) };
+            for &slot in &frame.slots {
+                unsafe { obj_array.mark_object(slot as *mut Obj) };
+            }
+        }
+        for &value in &self.temporaries {
+            obj_array.mark_value(value);
+        }
+    }
+}
+
+struct Frame {
+    // The closure running, or null for the script.
+    closure: *const ObjClosure,
+    // The upvalue holding each local slot's value.
+    slots: Vec<*mut ObjUpvalue>,
+    // The line of the call the frame is making, for stack traces.
+    line: usize,
+}
+
+// What stops a statement early: a return statement, unwinding to the call,
+// or an error, unwinding all the way out.
+enum Unwind {
+    Return(Value),
+    Error(LoxError),
+}
+
+type Exec = Result<(), Unwind>;
+type Eval = Result<Value, Unwind>;
+
+impl Walker<'_> {
+    fn frame(&mut self) -> &mut Frame {
+        self.vm.ast_roots.frames.last_mut().unwrap()
+    }
+
+    fn frames(&self) -> &[Frame] {
+        &self.vm.ast_roots.frames[self.base..]
+    }
+
+    fn push_frame(&mut self, closure: *const ObjClosure, values: Vec<Value>) {
+        let slots = values.into_iter().map(|value| self.new_slot(value)).collect();
+        self.vm.ast_roots.frames.push(Frame { closure, slots, line: 0 });
+    }
+
+    // Keeps `value` from being collected until the end of the enclosing
+    // holding() call.
+    fn keep(&mut self, value: Value) {
+        self.vm.ast_roots.temporaries.push(value);
+    }
+
+    // Runs `body`, then lets go of the values it kept.
+    fn holding<T>(&mut self, body: impl FnOnce(&mut Self) -> Result<T, Unwind>) -> Result<T, Unwind> {
+        let temporaries = self.vm.ast_roots.temporaries.len();
+        let result = body(self);
+        self.vm.ast_roots.temporaries.truncate(temporaries);
+        result
+    }
+
+    // A closed upvalue holding `value`.
+    fn new_slot(&mut self, value: Value) -> *mut ObjUpvalue {
+        let upvalue = self.vm.obj_array.new_upvalue(std::ptr::null_mut());
+        unsafe {
+            (*upvalue).closed = value;
+            (*upvalue).location = &mut (*upvalue).closed;
+        }
+        upvalue
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> Exec {
+        // Everything live is in a frame or a temporary between statements.
+        if self.vm.config().stress_gc {
+            self.vm.collect_garbage_for(GcReason::Stress);
+        } else if self.vm.obj_array.should_collect() {
+            self.vm.collect_garbage_for(GcReason::HeapGrowth);
+        }
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+            }
+            Stmt::Print(expr) => {
+                let value = self.evaluate(expr)?;
+                let _ = writeln!(self.vm.stdout(), "{}", value.display());
+            }
+            Stmt::Var { variable, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::nil(),
+                };
+                self.define(variable, value);
+            }
+            Stmt::Block(statements) => {
+                let slots = self.frame().slots.len();
+                let result = statements.iter().try_for_each(|stmt| self.execute(stmt));
+                self.frame().slots.truncate(slots);
+                result?;
+            }
+            Stmt::If { condition, then_branch, else_branch } => {
+                if !self.evaluate(condition)?.is_falsey() {
+                    self.execute(then_branch)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)?;
+                }
+            }
+            Stmt::While { condition, body } => {
+                while !self.evaluate(condition)?.is_falsey() {
+                    self.execute(body)?;
+                    if self.vm.take_interrupt() {
+                        return Err(self.interrupted());
+                    }
+                }
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::nil(),
+                };
+                return Err(Unwind::Return(value));
+            }
+            Stmt::Function { variable, function } => {
+                // Define the variable first, in case the function captures
+                // it to call itself.
+                self.define(variable, Value::nil());
+                let closure = self.closure(function);
+                self.assign_declared(variable, closure);
+            }
+            Stmt::Class { variable, name, methods } => {
+                let name = self.vm.obj_array.copy_string(name);
+                let class = self.vm.obj_array.new_class(name);
+                self.define(variable, Value::object(class as *const Obj));
+                for method in methods {
+                    let name = self.vm.obj_array.copy_string(&method.name);
+                    let closure = self.closure(method);
+                    unsafe { (*class).methods.insert(name, closure) };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Eval {
+        match expr {
+            Expr::Literal(literal) => Ok(match literal {
+                Literal::Nil => Value::nil(),
+                Literal::Bool(value) => Value::bool(*value),
+                Literal::Number(value) => Value::number(*value),
+                Literal::String(value) => self.vm.new_string(value),
+            }),
+            Expr::Variable { variable, line } => self.get(variable, *line),
+            Expr::Assign { variable, value, line } => {
+                let value = self.evaluate(value)?;
+                self.set(variable, value, *line)?;
+                Ok(value)
+            }
+            Expr::Unary { operator, operand, line } => {
+                let operand = self.evaluate(operand)?;
+                match operator {
+                    TokenType::Minus if operand.is_number() => Ok(Value::number(-operand.as_number())),
+                    TokenType::Minus => Err(self.error(*line, "Operand must be a number.")),
+                    _ => Ok(Value::bool(operand.is_falsey())),
+                }
+            }
+            Expr::Binary { left, operator, right, line } => self.holding(|walker| {
+                let left = walker.evaluate(left)?;
+                walker.keep(left);
+                let right = walker.evaluate(right)?;
+                walker.binary(left, *operator, right, *line)
+            }),
+            Expr::Logical { left, operator, right } => {
+                let left = self.evaluate(left)?;
+                let short_circuits = match operator {
+                    TokenType::And => left.is_falsey(),
+                    _ => !left.is_falsey(),
+                };
+                if short_circuits {
+                    return Ok(left);
+                }
+                self.evaluate(right)
+            }
+            Expr::Call { callee, arguments, line } => self.holding(|walker| {
+                let callee = walker.evaluate(callee)?;
+                walker.keep(callee);
+                let arguments = walker.arguments(arguments)?;
+                walker.call_value(callee, arguments, *line)
+            }),
+            Expr::Invoke { object, name, arguments, line } => self.holding(|walker| {
+                let receiver = walker.evaluate(object)?;
+                walker.keep(receiver);
+                let arguments = walker.arguments(arguments)?;
+                if !receiver.is_instance() {
+                    return Err(walker.error(*line, "Only instances have methods."));
+                }
+                let instance = receiver.as_instance();
+                let key = walker.vm.obj_array.copy_string(name);
+                // A field holding a function shadows a method.
+                if let Some(&field) = unsafe { (*instance).fields.get(&key) } {
+                    return walker.call_value(field, arguments, *line);
+                }
+                match unsafe { (*(*instance).class).methods.get(&key) } {
+                    Some(&method) => walker.call_closure(method.as_closure(), receiver, arguments, *line),
+                    None => Err(walker.error(*line, format!("Undefined property '{}'.", name))),
+                }
+            }),
+            Expr::Get { object, name, line } => {
+                let object = self.evaluate(object)?;
+                if !object.is_instance() {
+                    return Err(self.error(*line, "Only instances have properties."));
+                }
+                let instance = object.as_instance();
+                let key = self.vm.obj_array.copy_string(name);
+                if let Some(&field) = unsafe { (*instance).fields.get(&key) } {
+                    return Ok(field);
+                }
+                match unsafe { (*(*instance).class).methods.get(&key) } {
+                    Some(&method) => {
+                        let bound = self.vm.obj_array.new_bound_method(object, method.as_closure());
+                        Ok(Value::object(bound as *const Obj))
+                    }
+                    None => Err(self.error(*line, format!("Undefined property '{}'.", name))),
+                }
+            }
+            Expr::Set { object, name, value, line } => self.holding(|walker| {
+                let object = walker.evaluate(object)?;
+                walker.keep(object);
+                let value = walker.evaluate(value)?;
+                if !object.is_instance() {
+                    return Err(walker.error(*line, "Only instances have fields."));
+                }
+                let key = walker.vm.obj_array.copy_string(name);
+                unsafe { (*object.as_instance()).fields.insert(key, value) };
+                Ok(value)
+            }),
+        }
+    }
+
+    // Evaluates and keeps the arguments of a call.
+    fn arguments(&mut self, arguments: &[Expr]) -> Result<Vec<Value>, Unwind> {
+        arguments.iter().map(|argument| {
+            let value = self.evaluate(argument)?;
+            self.keep(value);
+            Ok(value)
+        }).collect()
+    }
+
+    fn binary(&mut self, a: Value, operator: TokenType, b: Value, line: usize) -> Eval {
+        match operator {
+            TokenType::EqualEqual => return Ok(Value::bool(values_equal(a, b))),
+            TokenType::BangEqual => return Ok(Value::bool(!values_equal(a, b))),
+            TokenType::Plus if a.is_string() && b.is_string() => {
+                return Ok(self.vm.take_string(format!("{}{}", a.as_str(), b.as_str())));
+            }
+            TokenType::Plus if !(a.is_number() && b.is_number()) => {
+                return Err(self.error(line, "Operands must be two numbers or two strings."));
+            }
+            _ if !(a.is_number() && b.is_number()) => {
+                return Err(self.error(line, "Operands must be numbers."));
+            }
+            _ => {}
+        }
+        let (a, b) = (a.as_number(), b.as_number());
+        Ok(match operator {
+            TokenType::Plus => Value::number(a + b),
+            TokenType::Minus => Value::number(a - b),
+            TokenType::Star => Value::number(a * b),
+            TokenType::Slash => Value::number(a / b),
+            TokenType::Greater => Value::bool(a > b),
+            TokenType::Less => Value::bool(a < b),
+            // The compiler turns these into the negated opposite
+            // comparison, so they are true when either side is NaN.
+            TokenType::GreaterEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Less)),
+            TokenType::LessEqual => Value::bool(a.partial_cmp(&b) != Some(Ordering::Greater)),
+            _ => unreachable!(),
+        })
+    }
+
+    // Variables
+
+    fn define(&mut self, variable: &Variable, value: Value) {
+        match variable {
+            Variable::Global(name) => {
+                let name = self.vm.obj_array.copy_string(name);
+                let slot = self.vm.globals.slot(name);
+                self.vm.globals.set_constant(slot, false);
+                self.vm.globals.define(name, value);
+            }
+            // Locals are declared in slot order.
+            _ => {
+                let slot = self.new_slot(value);
+                self.frame().slots.push(slot);
+            }
+        }
+    }
+
+    // Sets a variable just defined, without the checks of an assignment.
+    fn assign_declared(&mut self, variable: &Variable, value: Value) {
+        match variable {
+            Variable::Global(name) => {
+                let name = self.vm.obj_array.copy_string(name);
+                self.vm.globals.define(name, value);
+            }
+            _ => unsafe { *(*self.slot(variable)).location = value },
+        }
+    }
+
+    // The upvalue holding a local or captured variable.
+    fn slot(&mut self, variable: &Variable) -> *mut ObjUpvalue {
+        let frame = self.frame();
+        match variable {
+            Variable::Local(slot) => frame.slots[*slot],
+            Variable::Upvalue(index) => unsafe { (&(*frame.closure).upvalues)[*index] },
+            Variable::Global(_) => unreachable!(),
+        }
+    }
+
+    fn get(&mut self, variable: &Variable, line: usize) -> Eval {
+        let Variable::Global(name) = variable else {
+            return Ok(unsafe { *(*self.slot(variable)).location });
+        };
+        let key = self.vm.obj_array.copy_string(name);
+        match self.vm.globals.find(key).and_then(|slot| self.vm.globals.get(slot)) {
+            Some(value) => Ok(value),
+            None => Err(self.error(line, format!("Undefined variable '{}'.", name))),
+        }
+    }
+
+    fn set(&mut self, variable: &Variable, value: Value, line: usize) -> Exec {
+        let Variable::Global(name) = variable else {
+            unsafe { *(*self.slot(variable)).location = value };
+            return Ok(());
+        };
+        let key = self.vm.obj_array.copy_string(name);
+        let slot = self.vm.globals.find(key);
+        if slot.is_some_and(|slot| self.vm.globals.is_constant(slot)) {
+            return Err(self.error(line, format!("Cannot assign to constant '{}'.", name)));
+        }
+        if !slot.is_some_and(|slot| self.vm.globals.set(slot, value)) {
+            return Err(self.error(line, format!("Undefined variable '{}'.", name)));
+        }
+        Ok(())
+    }
+
+    // Functions
+
+    fn closure(&mut self, function: &Arc<ast::Function>) -> Value {
+        let object = self.function_object(function);
+        let closure = self.vm.obj_array.new_closure(unsafe { &*object });
+        for capture in &function.captures {
+            let upvalue = if capture.is_local {
+                self.frame().slots[capture.index]
+            } else {
+                self.slot(&Variable::Upvalue(capture.index))
+            };
+            unsafe { (*closure).upvalues.push(upvalue) };
+        }
+        Value::object(closure as *const Obj)
+    }
+
+    // The function object standing for `function`, made the first time it
+    // is needed. Its bytecode raises an error if the VM ever calls it.
+    fn function_object(&mut self, function: &Arc<ast::Function>) -> *const ObjFunction {
+        if let Some(&object) = self.functions.get(&Arc::as_ptr(function)) {
+            // Unless it has been collected, and its memory perhaps reused.
+            if self.vm.ast_functions.get(&object).is_some_and(|tree| Arc::ptr_eq(tree, function)) {
+                return object;
+            }
+        }
+        let mut chunk = Chunk::default();
+        let message = self.vm.new_string("Functions run by the AST engine can't be called from bytecode.");
+        let constant = chunk.add_constant(message) as u8;
+        chunk.emit_op_u8(OpCode::Constant, constant, function.line as i32);
+        chunk.emit_op(OpCode::Throw, function.line as i32);
+
+        let object = self.vm.obj_array.new_function(chunk);
+        unsafe {
+            (*object).arity = function.arity as u8;
+            (*object).upvalue_count = function.captures.len();
+            (*object).name = self.vm.obj_array.copy_string(&function.name);
+            (*object).line = function.line;
+            (*object).end_line = function.end_line;
+        }
+        self.vm.ast_functions.insert(object, function.clone());
+        self.functions.insert(Arc::as_ptr(function), object);
+        object
+    }
+
+    fn call_value(&mut self, callee: Value, arguments: Vec<Value>, line: usize) -> Eval {
+        if callee.is_closure() {
+            return self.call_closure(callee.as_closure(), callee, arguments, line);
+        }
+        if callee.is_bound_method() {
+            let bound = callee.as_bound_method();
+            let (receiver, method) = unsafe { ((*bound).receiver, (*bound).method) };
+            return self.call_closure(method, receiver, arguments, line);
+        }
+        if callee.is_class() {
+            let class = callee.as_class();
+            let instance = Value::object(self.vm.obj_array.new_instance(class) as *const Obj);
+            let init = self.vm.obj_array.copy_string("init");
+            match unsafe { (*class).methods.get(&init) } {
+                Some(&initializer) => {
+                    self.call_closure(initializer.as_closure(), instance, arguments, line)?;
+                }
+                None if !arguments.is_empty() => {
+                    let name = unsafe { (*(*class).name).as_str() };
+                    let message = format!("Expected 0 arguments but got {} in call to '{}'.", arguments.len(), name);
+                    return Err(self.error(line, message));
+                }
+                None => {}
+            }
+            return Ok(instance);
+        }
+        if callee.is_native() {
+            let native = callee.as_native();
+            if let Some(capability) = unsafe { (*native).capability } {
+                if !self.vm.config().policy.allows(capability) {
+                    let message = format!("Capability '{}' is not allowed.", capability.name());
+                    return Err(self.error(line, message));
+                }
+            }
+            return unsafe { ((*native).function)(self.vm, &arguments) }.map_err(|mut error| {
+                if error.trace.is_empty() {
+                    // Natives report the line they were called from.
+                    let name = unsafe { (*(*native).name).as_str() }.to_string();
+                    error.trace = self.trace(line);
+                    error.trace.insert(0, TraceFrame { function: Some(name), line, span: None, source: None, locals: Vec::new() });
+                }
+                Unwind::Error(error)
+            });
+        }
+        Err(self.error(line, "Can only call functions and classes."))
+    }
+
+    // Calls a closure, with `receiver` in slot 0.
+    fn call_closure(&mut self, closure: *const ObjClosure, receiver: Value, arguments: Vec<Value>, line: usize) -> Eval {
+        let object = unsafe { (*closure).function };
+        let Some(function) = self.vm.ast_functions.get(&object).cloned() else {
+            return Err(self.error(line, "Can only call functions and classes."));
+        };
+        if arguments.len() != function.arity {
+            let message = format!("Expected {} arguments but got {} in call to '{}'.", function.arity, arguments.len(), function.name);
+            return Err(self.error(line, message));
+        }
+        if self.frames().len() == self.vm.config().max_frames {
+            let Unwind::Error(mut error) = self.error(line, "Stack overflow.") else { unreachable!() };
+            error.kind = ErrorKind::LimitExceeded;
+            return Err(Unwind::Error(error));
+        }
+        if self.vm.take_interrupt() {
+            return Err(self.interrupted());
+        }
+
+        self.frame().line = line;
+        let mut values = vec![receiver];
+        values.extend(arguments);
+        self.push_frame(closure, values);
+        let result = function.body.iter().try_for_each(|stmt| self.execute(stmt));
+        let this = unsafe { *(*self.frame().slots[0]).location };
+        self.vm.ast_roots.frames.pop();
+
+        match result {
+            _ if function.kind == FunctionKind::Initializer && !matches!(result, Err(Unwind::Error(_))) => Ok(this),
+            Ok(()) => Ok(Value::nil()),
+            Err(Unwind::Return(value)) => Ok(value),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Errors
+
+    fn error(&self, line: usize, message: impl Into<String>) -> Unwind {
+        let mut error = LoxError::runtime(message);
+        error.trace = self.trace(line);
+        Unwind::Error(error)
+    }
+
+    fn interrupted(&self) -> Unwind {
+        let line = self.frames().last().map_or(0, |frame| frame.line);
+        let Unwind::Error(mut error) = self.error(line, "Interrupted.") else { unreachable!() };
+        error.kind = ErrorKind::Interrupted;
+        Unwind::Error(error)
+    }
+
+    // The stack trace at `line` of the running function, innermost frame
+    // first.
+    fn trace(&self, line: usize) -> Vec<TraceFrame> {
+        self.frames().iter().rev().enumerate().map(|(depth, frame)| {
+            let line = if depth == 0 { line } else { frame.line };
+            let function = (!frame.closure.is_null()).then(|| unsafe { &*(*frame.closure).function });
+            TraceFrame {
+                function: function.map(|function| unsafe { (*function.name).as_str() }.to_string()),
+                line,
+                span: function.map(|function| (function.line, function.end_line)),
+                source: Some((self.name.clone(), self.source.lines().nth(line.wrapping_sub(1)).unwrap_or_default().to_string())),
+                locals: Vec::new(),
+            }
+        }).collect()
+    }
+}
@@ -1,12 +1,27 @@
 // Purpose: In-memory bytecode representation.
 
+use std::fmt;
 use std::fmt::Formatter;
 use std::fmt::Result;
 use std::fmt::Debug;
+use std::any::Any;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::collections::HashSet;
+#[cfg(not(feature = "nan-boxing"))]
 use std::ptr;
 use crate::object::ObjType;
 use crate::object::Obj;
+use crate::object::ObjBoundMethod;
+use crate::object::ObjClass;
+use crate::object::ObjClosure;
+use crate::object::ObjCoroutine;
+use crate::object::ObjForeign;
 use crate::object::ObjFunction;
+use crate::object::ObjInstance;
+use crate::object::ObjList;
+use crate::object::ObjMap;
+use crate::object::ObjModule;
 use crate::object::ObjNative;
 use crate::object::ObjString;
 use crate::object::obj_fmt;
@@ -20,37 +35,174 @@ pub enum ValueType {
     Obj,
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 #[derive(Copy, Clone)]
 pub struct Value {
-    pub t: ValueType,
-    pub as_: As,
+    t: ValueType,
+    as_: As,
 }
 
+#[cfg(not(feature = "nan-boxing"))]
 #[derive(Copy, Clone)]
 #[repr(C)]
-pub union As {
-    pub boolean: bool,
-    pub number: f64,
-    pub obj: *const Obj,
+pub(crate) union As {
+    boolean: bool,
+    number: f64,
+    obj: *const Obj,
 }
 
+// With the `nan-boxing` feature a value is 8 bytes, like clox's
+// NAN_BOXING. Numbers are stored as themselves. Everything else is a quiet
+// NaN that no arithmetic produces: objects have the sign bit set and a
+// 48-bit pointer in the low bits, and nil, false and true are tags 1 to 3.
+#[cfg(feature = "nan-boxing")]
+#[derive(Copy, Clone)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000000000000000;
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc000000000000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+
+// Objects show only their address: a Value can outlive the VM that owns
+// its object, so a public trait can't follow the pointer. Lox's own
+// formatting, as print does it, is display().
 impl Debug for Value {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        match self.t {
+        match self.value_type() {
+            ValueType::Obj => write!(f, "<object {:p}>", self.as_object()),
+            _ => write!(f, "{}", self.display()),
+        }
+    }
+}
+
+/// A value formatted the way print writes it. See [`Value::display`].
+pub(crate) struct Display(Value);
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let value = self.0;
+        match value.value_type() {
             ValueType::Bool => {
-                if self.as_bool() {
-                    return write!(f, "true");
+                if value.as_bool() {
+                    write!(f, "true")
                 } else {
-                    return write!(f, "false");
+                    write!(f, "false")
                 }
             }
             ValueType::Nil => write!(f, "nil"),
-            ValueType::Number => write!(f, "{}", self.as_number()),
-            ValueType::Obj => obj_fmt(self.as_object(), f),
+            ValueType::Number => write!(f, "{}", format_number(value.as_number())),
+            ValueType::Obj => obj_fmt(value.as_object(), f),
         }
     }
 }
 
+/// Formats a number the way print does: integers without a fraction, other
+/// numbers with the fewest digits that read back as the same number, and
+/// very large or small ones in exponent form. NaN and the infinities print
+/// as clox prints them.
+///
+/// ```
+/// use rustlox::format_number;
+///
+/// assert_eq!(format_number(1.0), "1");
+/// assert_eq!(format_number(0.1 + 0.2), "0.30000000000000004");
+/// assert_eq!(format_number(1e21), "1e+21");
+/// assert_eq!(format_number(-1.5e-7), "-1.5e-7");
+/// assert_eq!(format_number(f64::NAN), "nan");
+/// assert_eq!(format_number(f64::NEG_INFINITY), "-inf");
+/// ```
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    // The same cutoffs as JavaScript, so numbers up to 2^53 stay readable.
+    let magnitude = n.abs();
+    if magnitude.is_infinite() || magnitude == 0.0 || (1e-6..1e21).contains(&magnitude) {
+        return format!("{}", n);
+    }
+    let exponent = format!("{:e}", n);
+    match exponent.split_once("e") {
+        Some((mantissa, power)) if !power.starts_with('-') => format!("{}e+{}", mantissa, power),
+        _ => exponent,
+    }
+}
+
+// Whether `a == b` in Lox. Lists are equal if their items are, in order,
+// and maps if they have the same keys with equal values. Other values
+// compare as equals() does, which is by identity for objects, and which map
+// keys still use.
+pub(crate) fn values_equal(a: Value, b: Value) -> bool {
+    // Pairs of collections still to compare, and every pair queued so far.
+    // A pair met again is already being checked, which is what ends the
+    // comparison of collections that contain themselves.
+    let mut pending = vec![(a, b)];
+    let mut seen = HashSet::new();
+    while let Some((a, b)) = pending.pop() {
+        if a.equals(b) {
+            continue;
+        }
+        let lists = a.is_list() && b.is_list();
+        let maps = a.is_map() && b.is_map();
+        if !(lists || maps) {
+            return false;
+        }
+        if !seen.insert((a.as_object(), b.as_object())) {
+            continue;
+        }
+        unsafe {
+            if lists {
+                let (a, b) = (&(*a.as_list()).items, &(*b.as_list()).items);
+                if a.len() != b.len() {
+                    return false;
+                }
+                pending.extend(a.iter().copied().zip(b.iter().copied()));
+            } else {
+                let (a, b) = (&(*a.as_map()).entries, &(*b.as_map()).entries);
+                if a.len() != b.len() {
+                    return false;
+                }
+                for (key, &value) in a {
+                    match b.get(key) {
+                        Some(&other) => pending.push((value, other)),
+                        None => return false,
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+// Lets values key a map. NaN isn't equal to itself, so maps refuse it as a
+// key rather than break Eq.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.equals(*other)
+    }
+}
+
+impl Eq for Value {}
+
+// Objects hash by identity, which agrees with equals() because strings are
+// interned, so hashing never reads the heap.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if self.is_object() {
+            state.write_usize(self.as_object() as usize);
+        } else {
+            state.write_u32(Value::hash(self));
+        }
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
     pub fn number(value: f64) -> Value {
         Value {
@@ -60,32 +212,32 @@ impl Value {
     }
 
     pub fn bool(value: bool) -> Value {
+        // Every byte of the union is set, so reading it as a number is
+        // defined even for a bool.
+        let mut as_ = As{number: 0.0};
+        as_.boolean = value;
         Value {
             t: ValueType::Bool,
-            as_: As{boolean: value},
+            as_,
         }
     }
 
     pub fn nil() -> Value {
         Value {
             t: ValueType::Nil,
-            as_: As{boolean: false},
+            as_: As{number: 0.0},
         }
     }
 
-    pub fn object(value: *const Obj) -> Value {
+    pub(crate) fn object(value: *const Obj) -> Value {
         Value {
             t: ValueType::Obj,
             as_: As{obj: value},
         }
     }
-    
-    pub fn print(&self) {
-        print!("{:?}", self);
-    }
 
-    pub fn is_falsey(&self) -> bool {
-        self.is_nil() || (self.is_bool() && !self.as_bool())
+    pub fn value_type(&self) -> ValueType {
+        self.t
     }
 
     pub fn equals(&self, other: Value) -> bool {
@@ -97,10 +249,12 @@ impl Value {
             ValueType::Bool => self.as_bool() == other.as_bool(),
             ValueType::Nil => true,
             ValueType::Number => self.as_number() == other.as_number(),
+            // Strings are interned, so equal strings are the same object and
+            // identity is the right comparison for every object type.
             ValueType::Obj => ptr::eq(self.as_object(), other.as_object()),
         }
     }
-    
+
     pub fn is_bool(&self) -> bool {
         self.t == ValueType::Bool
     }
@@ -117,64 +271,307 @@ impl Value {
         self.t == ValueType::Obj
     }
 
-    pub fn is_string(&self) -> bool {
+    pub fn as_bool(&self) -> bool {
+        // Only a bool's byte is known to be a valid bool.
+        self.t == ValueType::Bool && unsafe {
+            self.as_.boolean
+        }
+    }
+    
+    pub fn as_number(&self) -> f64 {
+        unsafe {
+            self.as_.number
+        }
+    }
+
+    pub(crate) fn as_object(&self) -> *const Obj {
+        unsafe {
+            self.as_.obj
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub fn number(value: f64) -> Value {
+        // Every NaN becomes the canonical one, so no NaN payload can look
+        // like a tag.
+        if value.is_nan() {
+            return Value(f64::NAN.to_bits());
+        }
+        Value(value.to_bits())
+    }
+
+    pub fn bool(value: bool) -> Value {
+        Value(QNAN | if value { TAG_TRUE } else { TAG_FALSE })
+    }
+
+    pub fn nil() -> Value {
+        Value(QNAN | TAG_NIL)
+    }
+
+    pub(crate) fn object(value: *const Obj) -> Value {
+        Value(SIGN_BIT | QNAN | value as usize as u64)
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        if self.is_number() {
+            ValueType::Number
+        } else if self.is_object() {
+            ValueType::Obj
+        } else if self.is_nil() {
+            ValueType::Nil
+        } else {
+            ValueType::Bool
+        }
+    }
+
+    pub fn equals(&self, other: Value) -> bool {
+        // Numbers compare as floats so that NaN != NaN and 0 == -0. Every
+        // other value has exactly one encoding.
+        if self.is_number() && other.is_number() {
+            return self.as_number() == other.as_number();
+        }
+        self.0 == other.0
+    }
+
+    pub fn is_bool(&self) -> bool {
+        (self.0 | 1) == (QNAN | TAG_TRUE)
+    }
+    
+    pub fn is_nil(&self) -> bool {
+        self.0 == (QNAN | TAG_NIL)
+    }
+    
+    pub fn is_number(&self) -> bool {
+        (self.0 & QNAN) != QNAN
+    }
+
+    pub fn is_object(&self) -> bool {
+        (self.0 & (SIGN_BIT | QNAN)) == (SIGN_BIT | QNAN)
+    }
+
+    pub fn as_bool(&self) -> bool {
+        self.0 == (QNAN | TAG_TRUE)
+    }
+    
+    pub fn as_number(&self) -> f64 {
+        f64::from_bits(self.0)
+    }
+
+    pub(crate) fn as_object(&self) -> *const Obj {
+        (self.0 & !(SIGN_BIT | QNAN)) as usize as *const Obj
+    }
+}
+
+// Most of what follows reads the object a value points to, so it is
+// crate-private or unsafe: only the crate knows the object is still alive.
+// Outside it a Value is opaque apart from its type and any bool or number
+// it holds.
+impl Value {
+    pub(crate) fn display(&self) -> Display {
+        Display(*self)
+    }
+
+    // Developer-facing representation: unlike print, strings are quoted and
+    // escaped so "1" and 1 can be told apart.
+    pub(crate) fn repr(&self) -> String {
+        if self.is_string() {
+            return format!("\"{}\"", self.as_str().escape_debug());
+        }
+        self.display().to_string()
+    }
+
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self.value_type() {
+            ValueType::Bool => "bool",
+            ValueType::Nil => "nil",
+            ValueType::Number => "number",
+            ValueType::Obj => unsafe {
+                match (*self.as_object()).t {
+                    ObjType::String => "string",
+                    ObjType::Function | ObjType::Closure | ObjType::BoundMethod => "function",
+                    ObjType::Native => "native",
+                    ObjType::Upvalue => "upvalue",
+                    ObjType::Foreign => (*(self.as_object() as *const ObjForeign)).type_name,
+                    ObjType::Class => "class",
+                    ObjType::Instance => "instance",
+                    ObjType::List => "list",
+                    ObjType::Map => "map",
+                    ObjType::Module => "module",
+                    ObjType::Coroutine => "coroutine",
+                }
+            },
+        }
+    }
+
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || (self.is_bool() && !self.as_bool())
+    }
+
+    // Hash consistent with equals(): strings hash by content (they are
+    // interned, so equal strings share a hash), other objects by identity.
+    pub(crate) fn hash(&self) -> u32 {
+        match self.value_type() {
+            ValueType::Bool => if self.as_bool() { 3 } else { 5 },
+            ValueType::Nil => 7,
+            ValueType::Number => {
+                // 0.0 and -0.0 are equal, so they must hash the same.
+                let n = self.as_number();
+                let bits = if n == 0.0 { 0 } else { n.to_bits() };
+                (bits ^ (bits >> 32)) as u32
+            }
+            ValueType::Obj => {
+                if self.is_string() {
+                    return unsafe { (*self.as_string()).hash };
+                }
+                let address = self.as_object() as usize as u64;
+                (address ^ (address >> 32)) as u32
+            }
+        }
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
         unsafe {
             self.is_object() && (*self.as_object()).t == ObjType::String
         }
     }
 
-    pub fn is_function(&self) -> bool {
+    pub(crate) fn is_function(&self) -> bool {
         unsafe {
             self.is_object() && (*self.as_object()).t == ObjType::Function
         }
     }
 
-    pub fn is_native(&self) -> bool {
+    pub(crate) fn is_closure(&self) -> bool {
         unsafe {
-            self.is_object() && (*self.as_object()).t == ObjType::Native
+            self.is_object() && (*self.as_object()).t == ObjType::Closure
         }
     }
-    
-    pub fn as_bool(&self) -> bool {
+
+    pub(crate) fn is_class(&self) -> bool {
         unsafe {
-            self.as_.boolean
+            self.is_object() && (*self.as_object()).t == ObjType::Class
         }
     }
-    
-    pub fn as_number(&self) -> f64 {
+
+    pub(crate) fn is_instance(&self) -> bool {
         unsafe {
-            self.as_.number
+            self.is_object() && (*self.as_object()).t == ObjType::Instance
         }
     }
 
-    pub fn as_object(&self) -> *const Obj {
+    pub(crate) fn is_bound_method(&self) -> bool {
         unsafe {
-            self.as_.obj
+            self.is_object() && (*self.as_object()).t == ObjType::BoundMethod
+        }
+    }
+
+    pub(crate) fn is_list(&self) -> bool {
+        unsafe {
+            self.is_object() && (*self.as_object()).t == ObjType::List
         }
     }
 
-    pub fn as_string(&self) -> *const ObjString {
+    pub(crate) fn is_map(&self) -> bool {
         unsafe {
-            self.as_.obj as *const ObjString
+            self.is_object() && (*self.as_object()).t == ObjType::Map
         }
     }
 
-    pub fn as_function(&self) -> *const ObjFunction {
+    pub(crate) fn is_module(&self) -> bool {
         unsafe {
-            self.as_.obj as *const ObjFunction
+            self.is_object() && (*self.as_object()).t == ObjType::Module
         }
     }
 
-    pub fn as_native(&self) -> *const ObjNative {
+    pub(crate) fn is_coroutine(&self) -> bool {
         unsafe {
-            self.as_.obj as *const ObjNative
+            self.is_object() && (*self.as_object()).t == ObjType::Coroutine
+        }
+    }
+
+    pub(crate) fn is_native(&self) -> bool {
+        unsafe {
+            self.is_object() && (*self.as_object()).t == ObjType::Native
+        }
+    }
+
+    pub(crate) fn is_foreign(&self) -> bool {
+        unsafe {
+            self.is_object() && (*self.as_object()).t == ObjType::Foreign
+        }
+    }
+    
+    pub(crate) fn as_string(&self) -> *const ObjString {
+        self.as_object() as *const ObjString
+    }
+
+    pub(crate) fn as_function(&self) -> *const ObjFunction {
+        self.as_object() as *const ObjFunction
+    }
+
+    pub(crate) fn as_closure(&self) -> *const ObjClosure {
+        self.as_object() as *const ObjClosure
+    }
+
+    pub(crate) fn as_class(&self) -> *mut ObjClass {
+        self.as_object() as *mut ObjClass
+    }
+
+    pub(crate) fn as_instance(&self) -> *mut ObjInstance {
+        self.as_object() as *mut ObjInstance
+    }
+
+    pub(crate) fn as_bound_method(&self) -> *const ObjBoundMethod {
+        self.as_object() as *const ObjBoundMethod
+    }
+
+    pub(crate) fn as_list(&self) -> *mut ObjList {
+        self.as_object() as *mut ObjList
+    }
+
+    pub(crate) fn as_map(&self) -> *mut ObjMap {
+        self.as_object() as *mut ObjMap
+    }
+
+    pub(crate) fn as_module(&self) -> *mut ObjModule {
+        self.as_object() as *mut ObjModule
+    }
+
+    pub(crate) fn as_coroutine(&self) -> *mut ObjCoroutine {
+        self.as_object() as *mut ObjCoroutine
+    }
+
+    pub(crate) fn as_native(&self) -> *const ObjNative {
+        self.as_object() as *const ObjNative
+    }
+
+    pub(crate) fn as_foreign(&self) -> *mut ObjForeign {
+        self.as_object() as *mut ObjForeign
+    }
+
+    /// Returns the host data of a foreign value if it holds a `T`. Data that
+    /// natives need to modify should be wrapped in a `Cell` or `RefCell`.
+    ///
+    /// # Safety
+    ///
+    /// The value's VM must still exist, and the value must not have been
+    /// collected since the VM handed it out. The arguments of a native are
+    /// safe to read until it returns.
+    pub unsafe fn foreign_ref<T: Any>(&self) -> Option<&T> {
+        if !self.is_foreign() {
+            return None;
         }
+        (*self.as_foreign()).data.downcast_ref::<T>()
     }
 
-    pub fn as_str(&self) -> &str {
+    // The text of a string. Panics if the value is not one.
+    pub(crate) fn as_str(&self) -> &str {
+        assert!(self.is_string(), "Value is not a string.");
         unsafe {
             let obj_string = self.as_string();
-            return (*obj_string).as_str();
+            (*obj_string).as_str()
         }
     }
 }
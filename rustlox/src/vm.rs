@@ -1,274 +1,3170 @@
 // Purpose: Lox Virtual Machine
 
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use crate::ast;
+use crate::treewalk;
+use crate::bytecode::read_bytecode;
+use crate::bytecode::write_bytecode;
+use crate::check::check_script;
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
+use crate::chunk::Source;
 use crate::value::Value;
-use crate::debug::disassemble_instruction;
+use crate::value::values_equal;
+use crate::debug::operand_len;
+use crate::decompile::decompile;
+use crate::debug::write_instruction;
 use crate::compiler::compile;
+use crate::compiler::ScriptKind;
 use crate::object::Obj;
+use crate::object::Finalizer;
 use crate::object::ObjArray;
+use crate::object::ObjClass;
+use crate::object::ObjClosure;
+use crate::object::ObjCoroutine;
+use crate::object::CoroutineState;
 use crate::object::ObjFunction;
+use crate::object::ObjModule;
+use crate::object::ObjString;
+use crate::table::Globals;
+use crate::table::StringMap;
+use crate::optimize::optimize_function;
+use crate::object::ObjUpvalue;
 use crate::object::NativeFn;
-use std::rc::Rc;
-use std::time::Instant;
+use crate::error::Diagnostic;
+use crate::error::ErrorFormat;
+use crate::error::ErrorKind;
+use crate::error::LoxError;
+use crate::error::TraceFrame;
+#[cfg(feature = "hooks")]
+use crate::gc::GcEvent;
+use crate::gc::GcReason;
+use crate::gc::GcStats;
+use crate::handle::OwnedValue;
+#[cfg(feature = "hooks")]
+use crate::hooks::Hook;
+#[cfg(feature = "hooks")]
+use crate::hooks::Hooks;
+use crate::native::check_arity;
+use crate::native::define_natives;
+use crate::native::FromValue;
+use crate::native::IntoNative;
+use crate::native::IntoValue;
+use crate::native::NativeGroup;
+use crate::platform::Platform;
+use crate::policy::Capability;
+use crate::policy::Policy;
+use crate::profile::Profile;
+use crate::platform::StdPlatform;
+use std::any::Any;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-const DEBUG: bool = false;
 const UINT8_COUNT: usize = 256;
 const FRAMES_MAX: usize = 64;
 const STACK_MAX: usize = FRAMES_MAX * UINT8_COUNT;
+/// The smallest stack a VM is built with: a native's name and value are on
+/// the stack while it is defined.
+pub const MIN_STACK_SIZE: usize = 2;
 
-#[derive(Debug)]
-pub struct VM<'a> {
-    stack: [Value; STACK_MAX],
+// The clock is only read every this many instructions when a timeout is set.
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// Per-instance VM settings, set through [`VmBuilder`].
+///
+/// Scripts that exceed `max_frames`, `stack_size`, `heap_limit`,
+/// `instruction_budget` or `timeout` are stopped with an
+/// [`ErrorKind::LimitExceeded`] error.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of value slots in the VM stack.
+    pub stack_size: usize,
+    /// Maximum call depth, which bounds recursion.
+    pub max_frames: usize,
+    /// Bytes of reachable heap objects a script may hold before it is
+    /// stopped.
+    pub heap_limit: Option<usize>,
+    /// Instructions a single `interpret` call may execute before it is
+    /// stopped.
+    pub instruction_budget: Option<u64>,
+    /// Wall-clock time a single `interpret` call may run before it is
+    /// stopped.
+    pub timeout: Option<Duration>,
+    /// Native function groups defined as globals.
+    pub natives: Vec<NativeGroup>,
+    /// Host capabilities that natives may use.
+    pub policy: Policy,
+    /// Print the stack and each instruction to the stdout sink as it
+    /// executes.
+    pub trace_execution: bool,
+    /// Limits which instructions `trace_execution` prints.
+    pub trace_filter: TraceFilter,
+    /// Disassemble each function after it is compiled.
+    pub print_code: bool,
+    /// Fold constants and simplify compiled code before running it.
+    pub optimize: bool,
+    /// Write compile errors with the offending source line and carets
+    /// under the token, like rustc, instead of one line each, and stack
+    /// traces with each frame's source name and line.
+    pub snippets: bool,
+    /// Whether errors and warnings are written as text or JSON lines.
+    pub error_format: ErrorFormat,
+    /// Print stack traces from the outermost call to the innermost, ending
+    /// at the line that failed, instead of innermost first as clox does.
+    pub outermost_first: bool,
+    /// The script's file name, which JSON errors give as their file and
+    /// stack traces name, or "script" if unset. Errors in imported modules
+    /// give the module's path instead.
+    pub source_name: Option<String>,
+    /// Fail to compile code that has warnings, such as unused locals.
+    pub deny_warnings: bool,
+    /// Look for likely mistakes in compiled code, such as calls with the
+    /// wrong number of arguments, and report them as warnings.
+    pub check: bool,
+    /// Count executed opcodes and time each function; see [`Vm::profile`].
+    pub profile: bool,
+    /// Let `+` join a string and a number, converting the number as print
+    /// does, and let `*` repeat a string a whole number of times. Off by
+    /// default, as in the book.
+    pub coerce: bool,
+    /// Directory that imports in scripts are resolved against, instead of
+    /// the current one. Imports in a module are resolved against the
+    /// module's own directory.
+    pub import_dir: Option<String>,
+    /// The path of the script being run, so that a module importing it
+    /// back is reported as an import cycle rather than running it again.
+    pub script_path: Option<String>,
+    /// The script's command-line arguments, which args() returns.
+    pub args: Vec<String>,
+    /// Collect garbage before every instruction, to shake out objects the
+    /// VM forgets to keep reachable.
+    pub stress_gc: bool,
+    /// Define eval(), which compiles and runs a string of Lox code. On by
+    /// default; turn it off to keep scripts to the code they were given.
+    pub allow_eval: bool,
+    /// Define loadExtension(), which runs native code from shared libraries.
+    #[cfg(all(feature = "extensions", unix))]
+    pub allow_extensions: bool,
+    /// Which engine `interpret` runs scripts with.
+    pub engine: Engine,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            stack_size: STACK_MAX,
+            max_frames: FRAMES_MAX,
+            heap_limit: None,
+            instruction_budget: None,
+            timeout: None,
+            natives: NativeGroup::ALL.to_vec(),
+            policy: Policy::default(),
+            trace_execution: false,
+            trace_filter: TraceFilter::default(),
+            print_code: false,
+            optimize: false,
+            snippets: false,
+            error_format: ErrorFormat::Text,
+            outermost_first: false,
+            source_name: None,
+            deny_warnings: false,
+            check: false,
+            profile: false,
+            coerce: false,
+            import_dir: None,
+            script_path: None,
+            args: Vec::new(),
+            stress_gc: false,
+            allow_eval: true,
+            #[cfg(all(feature = "extensions", unix))]
+            allow_extensions: false,
+            engine: Engine::default(),
+        }
+    }
+}
+
+/// How [`Vm::interpret`] runs a script.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Engine {
+    /// Compile it to bytecode and run that.
+    #[default]
+    Bytecode,
+    /// Parse it into a syntax tree and walk the tree; see
+    /// [`Vm::interpret_ast`].
+    Ast,
+}
+
+/// Narrows execution tracing down to the part of a program of interest.
+/// The default traces every instruction.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions in functions with this name. The top-level
+    /// code is named `script`.
+    pub function: Option<String>,
+    /// Start tracing the first time execution reaches this line.
+    pub start_line: Option<usize>,
+    /// Only print the stack when it differs from the last one printed.
+    pub stack_changes_only: bool,
+}
+
+/// Builds a [`Vm`] with non-default settings.
+///
+/// ```
+/// use rustlox::Vm;
+/// use rustlox::NativeGroup;
+///
+/// let mut vm = Vm::builder()
+///     .max_frames(16)
+///     .instruction_budget(10_000)
+///     .natives(&[NativeGroup::Core, NativeGroup::Math])
+///     .build();
+/// vm.interpret("print max(1, 2);").unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    config: Config,
+    platform: Option<Box<dyn Platform>>,
+    interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl VmBuilder {
+    /// Sets the number of value slots in the stack. Sizes below
+    /// [`MIN_STACK_SIZE`], which defining the natives needs, are raised to
+    /// it.
+    ///
+    /// ```
+    /// use rustlox::vm::MIN_STACK_SIZE;
+    /// use rustlox::Vm;
+    ///
+    /// let vm = Vm::builder().stack_size(0).max_frames(0).build();
+    /// assert_eq!(vm.config().stack_size, MIN_STACK_SIZE);
+    /// assert_eq!(vm.config().max_frames, 1);
+    /// ```
+    pub fn stack_size(mut self, slots: usize) -> Self {
+        self.config.stack_size = slots;
+        self
+    }
+
+    /// Sets the maximum call depth. The script itself takes one frame, so
+    /// 0 is raised to 1.
+    pub fn max_frames(mut self, frames: usize) -> Self {
+        self.config.max_frames = frames;
+        self
+    }
+
+    pub fn heap_limit(mut self, bytes: usize) -> Self {
+        self.config.heap_limit = Some(bytes);
+        self
+    }
+
+    pub fn instruction_budget(mut self, instructions: u64) -> Self {
+        self.config.instruction_budget = Some(instructions);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Replaces the standard library's clock, file access and process
+    /// information, e.g. on targets that don't have them.
+    pub fn platform(mut self, platform: impl Platform + 'static) -> Self {
+        self.platform = Some(Box::new(platform));
+        self
+    }
+
+    /// Stops the running script with an [`ErrorKind::Interrupted`] error
+    /// soon after `flag` is set, e.g. from a Ctrl-C handler or another
+    /// thread. The VM clears the flag when it stops. Scripts can't catch
+    /// the error.
+    ///
+    /// ```
+    /// use rustlox::ErrorKind;
+    /// use rustlox::Vm;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let flag = Arc::new(AtomicBool::new(false));
+    /// let mut vm = Vm::builder().interrupt(flag.clone()).build();
+    /// let stopper = std::thread::spawn(move || {
+    ///     std::thread::sleep(std::time::Duration::from_millis(10));
+    ///     flag.store(true, Ordering::Relaxed);
+    /// });
+    /// let error = vm.interpret("try { while (true) {} } catch (e) {}").unwrap_err();
+    /// assert_eq!(error.kind, ErrorKind::Interrupted);
+    /// assert_eq!(error.message, "Interrupted.");
+    /// stopper.join().unwrap();
+    /// ```
+    pub fn interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    pub fn natives(mut self, groups: &[NativeGroup]) -> Self {
+        self.config.natives = groups.to_vec();
+        self
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.config.policy = policy;
+        self
+    }
+
+    pub fn trace_execution(mut self, enabled: bool) -> Self {
+        self.config.trace_execution = enabled;
+        self
+    }
+
+    pub fn trace_filter(mut self, filter: TraceFilter) -> Self {
+        self.config.trace_filter = filter;
+        self
+    }
+
+    pub fn print_code(mut self, enabled: bool) -> Self {
+        self.config.print_code = enabled;
+        self
+    }
+
+    pub fn optimize(mut self, enabled: bool) -> Self {
+        self.config.optimize = enabled;
+        self
+    }
+
+    pub fn snippets(mut self, enabled: bool) -> Self {
+        self.config.snippets = enabled;
+        self
+    }
+
+    /// Writes errors and warnings as JSON lines, or as text.
+    ///
+    /// ```
+    /// use rustlox::error::ErrorFormat;
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::builder().error_format(ErrorFormat::Json).source_name("main.lox").build();
+    /// let (_, _, stderr) = vm.interpret_captured("print x;");
+    /// assert!(stderr.starts_with(r#"{"file":"main.lox","line":1,"column":null,"#));
+    /// ```
+    pub fn error_format(mut self, format: ErrorFormat) -> Self {
+        self.config.error_format = format;
+        self
+    }
+
+    /// Prints stack traces outermost call first, rather than innermost.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::builder().outermost_first(true).build();
+    /// let (_, _, stderr) = vm.interpret_captured("fun f() { -nil; }\nf();");
+    /// assert_eq!(stderr, "Operand must be a number.\n[line 2] in script\n[line 1] in f() declared on line 1\n");
+    /// ```
+    pub fn outermost_first(mut self, enabled: bool) -> Self {
+        self.config.outermost_first = enabled;
+        self
+    }
+
+    pub fn source_name(mut self, name: impl Into<String>) -> Self {
+        self.config.source_name = Some(name.into());
+        self
+    }
+
+    /// Fails compilation on warnings, which are otherwise only reported.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::builder().deny_warnings(true).build();
+    /// let error = vm.interpret("fun f() { var x = 1; }").unwrap_err();
+    /// assert_eq!(error.message, "[line 1] Warning at 'x': Local variable is never read.");
+    /// ```
+    pub fn deny_warnings(mut self, enabled: bool) -> Self {
+        self.config.deny_warnings = enabled;
+        self
+    }
+
+    /// Runs the checks in [`check_script`] on code before it runs.
+    ///
+    /// [`check_script`]: crate::check::check_script
+    pub fn check(mut self, enabled: bool) -> Self {
+        self.config.check = enabled;
+        self
+    }
+
+    /// Lets `+` and `*` mix strings and numbers; see [`Config::coerce`].
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::builder().coerce(true).build();
+    /// vm.interpret("var line = \"x = \" + 4 + \", \" + \"ab\" * 3;").unwrap();
+    /// assert_eq!(vm.get_global_as::<String>("line").unwrap(), "x = 4, ababab");
+    /// assert!(Vm::new().interpret("print \"ab\" * 3;").is_err());
+    /// ```
+    pub fn coerce(mut self, enabled: bool) -> Self {
+        self.config.coerce = enabled;
+        self
+    }
+
+    pub fn import_dir(mut self, dir: impl Into<String>) -> Self {
+        self.config.import_dir = Some(dir.into());
+        self
+    }
+
+    pub fn script_path(mut self, path: impl Into<String>) -> Self {
+        self.config.script_path = Some(path.into());
+        self
+    }
+
+    /// Sets the arguments that args() gives the script.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::builder().args(&["in.txt", "-v"]).build();
+    /// let (_, stdout, _) = vm.interpret_captured("print args();");
+    /// assert_eq!(stdout, "[\"in.txt\", \"-v\"]\n");
+    /// ```
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> Self {
+        self.config.args = args.iter().map(|arg| arg.as_ref().to_string()).collect();
+        self
+    }
+
+    pub fn profile(mut self, enabled: bool) -> Self {
+        self.config.profile = enabled;
+        self
+    }
+
+    pub fn stress_gc(mut self, enabled: bool) -> Self {
+        self.config.stress_gc = enabled;
+        self
+    }
+
+    /// Whether scripts get eval(). Sandboxes that only run the code they
+    /// were handed should turn it off.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// assert_eq!(vm.eval_line("eval(\"1 + 2\")").unwrap().as_number(), 3.0);
+    /// let mut vm = Vm::builder().allow_eval(false).build();
+    /// assert!(vm.eval("eval(\"1 + 2\");").is_err());
+    /// ```
+    pub fn allow_eval(mut self, enabled: bool) -> Self {
+        self.config.allow_eval = enabled;
+        self
+    }
+
+    /// Lets scripts load native extensions. Only enable this for trusted
+    /// scripts: an extension can do anything the host process can.
+    #[cfg(all(feature = "extensions", unix))]
+    pub fn allow_extensions(mut self, enabled: bool) -> Self {
+        self.config.allow_extensions = enabled;
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.config.engine = engine;
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let mut config = self.config;
+        config.stack_size = config.stack_size.max(MIN_STACK_SIZE);
+        config.max_frames = config.max_frames.max(1);
+        let platform = self.platform.unwrap_or_else(|| Box::new(StdPlatform::default()));
+        let mut vm = Vm {
+            stack: vec![Value::nil(); config.stack_size],
+            stack_top: 0,
+            obj_array: ObjArray::default(),
+            globals: Globals::default(),
+            init_string: std::ptr::null(),
+            error_class: std::ptr::null(),
+            frames: vec![CallFrame::default(); config.max_frames],
+            frame_count: 0,
+            instruction_count: 0,
+            deadline: None,
+            platform,
+            interrupt: self.interrupt,
+            last_error: None,
+            handlers: Vec::new(),
+            thrown: None,
+            raised: None,
+            open_upvalues: std::ptr::null_mut(),
+            coroutine: std::ptr::null_mut(),
+            pause_at: None,
+            trace_started: false,
+            traced_stack: Vec::new(),
+            profile: config.profile.then(Box::default),
+            breakpoints: BTreeSet::new(),
+            debug_stop: None,
+            modules: HashMap::new(),
+            #[cfg(feature = "hooks")]
+            hooks: Hooks::default(),
+            snapshots: Vec::new(),
+            scripts: Vec::new(),
+            ast_functions: HashMap::new(),
+            ast_roots: treewalk::Roots::default(),
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            config,
+        };
+        vm.init_string = vm.obj_array.copy_string("init");
+        let error_name = vm.obj_array.copy_string("Error");
+        vm.error_class = vm.obj_array.new_class(error_name);
+        let groups = vm.config.natives.clone();
+        define_natives(&mut vm, &groups);
+        #[cfg(all(feature = "extensions", unix))]
+        if vm.config.allow_extensions {
+            crate::extension::define_extension_natives(&mut vm);
+        }
+        vm
+    }
+}
+
+/// A Lox virtual machine.
+///
+/// The VM owns every object allocated while compiling and running scripts,
+/// and frees them when it is dropped.
+///
+/// A VM can be moved to another thread, and VMs share nothing, so several
+/// can run scripts in parallel:
+///
+/// ```
+/// use rustlox::Vm;
+/// use std::thread;
+///
+/// let workers: Vec<_> = (1..=4)
+///     .map(|n| {
+///         let mut vm = Vm::new();
+///         vm.interpret(&format!("var n = {};", n)).unwrap();
+///         thread::spawn(move || {
+///             vm.interpret("var total = 0; for (var i = 0; i < 1000; i = i + 1) total = total + n;").unwrap();
+///             vm.get_global_as::<f64>("total").unwrap()
+///         })
+///     })
+///     .collect();
+/// let totals: Vec<f64> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+/// assert_eq!(totals, [1000.0, 2000.0, 3000.0, 4000.0]);
+/// ```
+pub struct Vm {
+    stack: Vec<Value>,
     stack_top: usize,
-    obj_array: &'a mut ObjArray,
-    globals: HashMap<&'static str, Value>,
-    frames: [CallFrame; FRAMES_MAX],
+    pub(crate) obj_array: ObjArray,
+    pub(crate) globals: Globals,
+    // The interned name of initializers, kept to look them up quickly.
+    init_string: *const ObjString,
+    // The class of the Error instances catch clauses receive.
+    error_class: *const ObjClass,
+    frames: Vec<CallFrame>,
     frame_count: usize,
+    instruction_count: u64,
+    deadline: Option<f64>,
+    platform: Box<dyn Platform>,
+    // Set from outside to stop the running script.
+    interrupt: Option<Arc<AtomicBool>>,
+    last_error: Option<LoxError>,
+    // The try blocks being run, innermost last.
+    handlers: Vec<Handler>,
+    // The value raised by a throw statement, until a catch clause
+    // receives it.
+    thrown: Option<Value>,
+    // The value raised by error(), until a catch clause receives it as the
+    // message of an Error.
+    raised: Option<Value>,
+    // Upvalues still pointing into the stack, highest slot first.
+    open_upvalues: *mut ObjUpvalue,
+    // The coroutine running, or null for the script. The fields above
+    // belong to it.
+    coroutine: *mut ObjCoroutine,
+    // While stepping, the instruction count at which the top-level run
+    // loop returns to the host.
+    pause_at: Option<u64>,
+    // Whether the trace filter's start line has been reached.
+    trace_started: bool,
+    // The stack as last printed by the trace.
+    traced_stack: Vec<Value>,
+    profile: Option<Box<Profile>>,
+    // Lines that Vm::debug stops at, and where the current call stops.
+    breakpoints: BTreeSet<usize>,
+    debug_stop: Option<DebugStop>,
+    // Every module imported so far, by the normalized path it was loaded
+    // from, so that each file only runs once.
+    modules: HashMap<String, *mut ObjModule>,
+    #[cfg(feature = "hooks")]
+    hooks: Hooks,
+    // The snapshots taken so far. Live ones keep what they saved from
+    // being collected.
+    snapshots: Vec<Weak<Saved>>,
+    // The scripts compiled by Vm::compile_script. Live ones keep their
+    // functions from being collected, and are handed out again when the
+    // same source is compiled.
+    scripts: Vec<Weak<Compiled>>,
+    // The syntax trees of the functions the tree-walking engine has
+    // created, which it looks up when they are called. An entry goes when
+    // the collector frees its function.
+    pub(crate) ast_functions: HashMap<*const ObjFunction, Arc<ast::Function>>,
+    // The frames and temporaries of the tree-walking engine while it runs.
+    pub(crate) ast_roots: treewalk::Roots,
+    stdout: Box<dyn Write + Send>,
+    stderr: Box<dyn Write + Send>,
+    config: Config,
+}
+
+/// The global variables of a VM at one point, taken by [`Vm::snapshot`]
+/// and put back by [`Vm::restore`].
+///
+/// A snapshot holds deep copies of the globals' values in the VM's heap,
+/// so it can only be restored into the VM that took it, and is of no use
+/// once that VM is dropped.
+#[derive(Clone)]
+pub struct Snapshot {
+    saved: Arc<Saved>,
+}
+
+// Only the number of globals is shown, since the values may belong to a VM
+// that has been dropped.
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("globals", &self.saved.globals.len())
+            .finish()
+    }
+}
+
+struct Saved {
+    // Each global's value, if it was defined, and whether it was constant.
+    globals: StringMap<(Option<Value>, bool)>,
+}
+
+// SAFETY: a Saved is never changed after it is made, and the objects it
+// points to are only read by the VM that took it, through Vm::restore and
+// the collector, so a snapshot can go wherever its VM goes.
+unsafe impl Send for Saved {}
+unsafe impl Sync for Saved {}
+
+/// A script compiled by [`Vm::compile_script`], which
+/// [`Vm::run_script`] can run any number of times without compiling it
+/// again.
+///
+/// Like a [`Snapshot`], it can only be run by the VM that compiled it.
+/// Two `CompiledScript`s are equal if they share the same compiled code.
+#[derive(Clone)]
+pub struct CompiledScript {
+    compiled: Arc<Compiled>,
+}
+
+impl CompiledScript {
+    /// The name stack traces give the script.
+    pub fn name(&self) -> &str {
+        &self.compiled.name
+    }
+}
+
+impl PartialEq for CompiledScript {
+    fn eq(&self, other: &CompiledScript) -> bool {
+        Arc::ptr_eq(&self.compiled, &other.compiled)
+    }
+}
+
+impl fmt::Debug for CompiledScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompiledScript")
+            .field("name", &self.compiled.name)
+            .field("hash", &self.compiled.hash)
+            .finish()
+    }
+}
+
+struct Compiled {
+    // A hash of the name and source, checked before comparing them.
+    hash: u64,
+    name: String,
+    source: String,
+    function: Value,
+}
+
+// SAFETY: as with Saved, the function is only used by the VM that
+// compiled it, through Vm::run_script and the collector.
+unsafe impl Send for Compiled {}
+unsafe impl Sync for Compiled {}
+
+fn source_hash(name: &str, source: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    source.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl fmt::Debug for Vm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Vm")
+            .field("stack_top", &self.stack_top)
+            .field("frame_count", &self.frame_count)
+            .field("globals", &self.globals.iter()
+                .map(|(name, value)| (unsafe { (*name).as_str() }, value))
+                .collect::<HashMap<_, _>>())
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct CallFrame {
-    pub function: *const ObjFunction,
+pub(crate) struct CallFrame {
+    pub closure: *const ObjClosure,
     pub ip: usize,
     pub stack_top: usize,
+    // The closure's chunk, cached so that reading an instruction doesn't go
+    // through the closure and its function.
+    chunk: *const Chunk,
 }
 
 impl CallFrame {
+    pub fn function(&self) -> *const ObjFunction {
+        unsafe { (*self.closure).function }
+    }
+
     pub fn chunk(&self) -> &Chunk {
-        unsafe { &(*(*self.function).chunk) }
+        unsafe { &*self.chunk }
     }
 }
 
 impl Default for CallFrame {
     fn default() -> CallFrame {
         CallFrame {
-            function: std::ptr::null(),
+            closure: std::ptr::null(),
             ip: 0,
             stack_top: 0,
+            chunk: std::ptr::null(),
+        }
+    }
+}
+
+// A try block being run: where its catch clause starts, and the call and
+// stack depths to unwind to when an error reaches it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Handler {
+    frame_count: usize,
+    stack_top: usize,
+    catch_ip: usize,
+}
+
+/// The stack, call frames and try blocks of a coroutine. The VM runs on
+/// its own copies of these; resuming a coroutine swaps its fiber in, and
+/// yielding or returning swaps it back out.
+pub(crate) struct Fiber {
+    pub(crate) stack: Vec<Value>,
+    pub(crate) stack_top: usize,
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) frame_count: usize,
+    pub(crate) handlers: Vec<Handler>,
+    // Upvalues still pointing into the stack, highest slot first.
+    pub(crate) open_upvalues: *mut ObjUpvalue,
+}
+
+// An in-memory sink whose contents stay readable after it is handed to the VM.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+
+    // Returns the contents and empties the sink.
+    #[cfg(feature = "wasm")]
+    pub(crate) fn take(&self) -> String {
+        let contents = self.contents();
+        self.0.lock().unwrap().clear();
+        contents
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The outcome of running a script.
+#[derive(Debug, PartialEq)]
+pub enum InterpretResult {
+    Ok,
+    CompileError,
+    RuntimeError,
+}
+
+/// Progress reported by [`Vm::step`].
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    /// The instruction limit was reached; call `step` or `resume` again.
+    Paused,
+    /// The script returned this value.
+    Done(Value),
+}
+
+/// How far [`Vm::debug`] runs before pausing again. Each moves on from
+/// the current line; a line is reached when execution enters it from a
+/// different line or call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugStep {
+    /// To the next line, stepping into calls.
+    Step,
+    /// To the next line in the current function or one of its callers,
+    /// stepping over calls.
+    Next,
+    /// To the next line with a breakpoint.
+    Continue,
+}
+
+/// A snapshot of one call frame, from [`Vm::current_frames`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    /// The function's name, or `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+    /// The offset of the next instruction in the function's chunk.
+    pub ip: usize,
+    /// The index in [`Vm::stack_values`] of the frame's slot zero.
+    pub stack_base: usize,
+}
+
+// Where Vm::debug stops.
+#[derive(Debug, Clone, Copy)]
+struct DebugStop {
+    until: DebugStep,
+    // The call depth it started at, for DebugStep::Next.
+    frame_count: usize,
+    // The call depth and line of the last instruction run.
+    location: (usize, usize),
+}
+
+// Removes `.` and resolves `..` in `path` without touching the file
+// system, so the paths of imports compare equal whenever they name the
+// same file the same way.
+fn normalize_path(path: &Path) -> String {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if resolved.file_name().is_some() => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved.to_string_lossy().into_owned()
+}
+
+/// Compiles and runs `source` in a fresh VM.
+pub fn interpret(source: &str) -> Result<Value, LoxError> {
+    Vm::new().interpret(source)
+}
+
+// SAFETY: every object a VM points to was allocated by its ObjArray and is
+// reached only through the VM, and the host-supplied parts (natives, foreign
+// data, hooks, output sinks and the platform) are all required to be Send.
+// Chunks and their sources are shared through Arc, so a hook that keeps a
+// clone of one doesn't race with the VM on its count after the VM moves.
+// Values and chunks handed out by a VM are still tied to it and must not be
+// used from another thread while it runs.
+unsafe impl Send for Vm {}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.globals.clear();
+        self.obj_array.free_objects();
+    }
+}
+
+impl Vm {
+    /// Creates a VM with the default configuration and every native
+    /// function group defined.
+    pub fn new() -> Vm {
+        Vm::builder().build()
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replaces the capability policy. Natives that were defined under the
+    /// old policy fail if they need a capability the new one denies.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.config.policy = policy;
+    }
+
+    /// Replaces the instruction budget, or removes it with `None`. Like the
+    /// timeout, it applies from the next call that runs code.
+    ///
+    /// ```
+    /// use rustlox::ErrorKind;
+    /// use rustlox::Vm;
+    /// use std::time::Duration;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.set_instruction_budget(Some(1_000));
+    /// let error = vm.interpret("while (true) {}").unwrap_err();
+    /// assert_eq!(error.kind, ErrorKind::LimitExceeded);
+    /// assert_eq!(error.message, "Instruction budget exhausted.");
+    ///
+    /// vm.set_instruction_budget(None);
+    /// vm.set_timeout(Some(Duration::from_millis(10)));
+    /// let error = vm.interpret("while (true) {}").unwrap_err();
+    /// assert_eq!(error.message, "Timeout exceeded.");
+    /// ```
+    pub fn set_instruction_budget(&mut self, instructions: Option<u64>) {
+        self.config.instruction_budget = instructions;
+    }
+
+    /// Replaces the timeout, or removes it with `None`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.config.timeout = timeout;
+    }
+
+    pub fn platform(&self) -> &dyn Platform {
+        &*self.platform
+    }
+
+    /// Instructions run by the latest call from the host, or so far by the
+    /// script set up with [`Vm::start`].
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("var x = 1 + 2;").unwrap();
+    /// assert_eq!(vm.instruction_count(), 6);
+    /// ```
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Bytes held by live objects and any garbage not yet collected. This
+    /// is what [`VmBuilder::heap_limit`] is checked against.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let before = vm.bytes_allocated();
+    /// vm.interpret("var s = \"a\" + \"b\";").unwrap();
+    /// assert!(vm.bytes_allocated() > before);
+    /// ```
+    pub fn bytes_allocated(&self) -> usize {
+        self.obj_array.bytes_allocated
+    }
+
+    /// The heap's size, object count and collections so far.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("for (var i = 0; i < 10; i = i + 1) { var s = \"a\" + \"b\"; }").unwrap();
+    /// let before = vm.gc_stats();
+    /// vm.collect_garbage();
+    /// let after = vm.gc_stats();
+    /// assert_eq!(after.collections, before.collections + 1);
+    /// assert!(after.objects < before.objects);
+    /// assert!(after.bytes_allocated < before.bytes_allocated);
+    /// ```
+    pub fn gc_stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.obj_array.bytes_allocated,
+            objects: self.obj_array.object_count,
+            collections: self.obj_array.collections,
+            next_gc: self.obj_array.next_gc,
+        }
+    }
+
+    /// What the VM has executed, if it was built with
+    /// [`VmBuilder::profile`]. Calls still running aren't timed yet.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+
+    /// Redirects the output of `print` statements, the printing natives and
+    /// `trace_execution`, returning the previous sink.
+    ///
+    /// Any `Write` works, so output can be handed to a callback:
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    /// use std::io::{self, Write};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// struct Callback<F: FnMut(&str)>(F);
+    ///
+    /// impl<F: FnMut(&str)> Write for Callback<F> {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    ///         (self.0)(&String::from_utf8_lossy(buf));
+    ///         Ok(buf.len())
+    ///     }
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let printed = Arc::new(Mutex::new(String::new()));
+    /// let sink = printed.clone();
+    /// let mut vm = Vm::new();
+    /// vm.set_stdout(Callback(move |text: &str| sink.lock().unwrap().push_str(text)));
+    /// vm.interpret("print \"hi\"; printf(\"{}!\", 1);").unwrap();
+    /// assert_eq!(*printed.lock().unwrap(), "hi\n1!");
+    /// ```
+    pub fn set_stdout(&mut self, sink: impl Write + Send + 'static) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.stdout, Box::new(sink))
+    }
+
+    /// Redirects compile errors, runtime errors and stack traces, returning
+    /// the previous sink.
+    pub fn set_stderr(&mut self, sink: impl Write + Send + 'static) -> Box<dyn Write + Send> {
+        std::mem::replace(&mut self.stderr, Box::new(sink))
+    }
+
+    pub fn stdout(&mut self) -> &mut dyn Write {
+        &mut *self.stdout
+    }
+
+    pub fn stderr(&mut self) -> &mut dyn Write {
+        &mut *self.stderr
+    }
+
+    /// Calls `hook` with the chunk and offset of each instruction before it
+    /// executes.
+    #[cfg(feature = "hooks")]
+    pub fn on_instruction(&mut self, hook: impl FnMut(&Chunk, usize) + Send + 'static) {
+        self.hooks.on_instruction = Hook(Some(Box::new(hook)));
+    }
+
+    /// Calls `hook` each time a Lox function is entered.
+    #[cfg(feature = "hooks")]
+    pub fn on_call(&mut self, hook: impl FnMut(&ObjFunction) + Send + 'static) {
+        self.hooks.on_call = Hook(Some(Box::new(hook)));
+    }
+
+    /// Calls `hook` each time a Lox function returns.
+    #[cfg(feature = "hooks")]
+    pub fn on_return(&mut self, hook: impl FnMut(&ObjFunction) + Send + 'static) {
+        self.hooks.on_return = Hook(Some(Box::new(hook)));
+    }
+
+    /// Calls `hook` after each garbage collection with what it did. See
+    /// [`GcReport`](crate::gc::GcReport) for totals over a run.
+    #[cfg(feature = "hooks")]
+    pub fn on_gc(&mut self, hook: impl FnMut(&GcEvent) + Send + 'static) {
+        self.obj_array.on_gc = Hook(Some(Box::new(hook)));
+    }
+
+    /// Calls `hook` with each object the VM allocates, including those
+    /// created while compiling.
+    #[cfg(feature = "hooks")]
+    pub fn on_alloc(&mut self, hook: impl FnMut(&Obj) + Send + 'static) {
+        self.obj_array.on_alloc = Hook(Some(Box::new(hook)));
+    }
+
+    /// Compiles and runs `source` as a top-level script, returning the
+    /// script's result.
+    ///
+    /// Errors are reported to the VM's stderr sink as they happen, and are
+    /// also returned with their diagnostics or stack trace.
+    ///
+    /// ```
+    /// use rustlox::error::ErrorKind;
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let error = vm.interpret("fun f() { return -nil; }\nf();").unwrap_err();
+    /// assert_eq!(error.kind, ErrorKind::Runtime);
+    /// assert_eq!(error.message, "Operand must be a number.");
+    /// assert_eq!(error.trace[0].function.as_deref(), Some("f"));
+    /// assert_eq!(error.trace[1].line, 2);
+    /// ```
+    pub fn interpret(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.stack_top = 0;
+        self.frame_count = 0;
+        self.handlers.clear();
+        self.profile_exit();
+        if self.config.engine == Engine::Ast {
+            return self.interpret_ast(source);
+        }
+        self.eval(source)
+    }
+
+    /// Compiles and runs `source` in the current session.
+    ///
+    /// Globals, interned strings and natives carry over from earlier calls,
+    /// and every object compiled or allocated by them stays alive until the
+    /// VM is dropped, so functions defined by one call can be used by the
+    /// next. Unlike [`Vm::interpret`], `eval` leaves the stack alone and may
+    /// be called from inside a native function.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.eval("var greeting = \"hello\";").unwrap();
+    /// vm.eval("fun greet(name) { return greeting + \" \" + name; }").unwrap();
+    /// let result = vm.eval("var message = greet(\"world\");");
+    /// assert!(result.is_ok());
+    /// assert_eq!(vm.get_global_as::<String>("message").unwrap(), "hello world");
+    /// ```
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        let func = self.compile(source)?;
+        self.apply(func, &[])
+    }
+
+    /// Like [`Vm::eval`], but stack traces name the source `name`, so
+    /// errors in code from different places can be told apart.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// let error = vm.eval_named("config", "var x = 1;\nx();").unwrap_err();
+    /// let (name, text) = error.trace[0].source.clone().unwrap();
+    /// assert_eq!((name.as_str(), error.trace[0].line, text.as_str()), ("config", 2, "x();"));
+    /// ```
+    pub fn eval_named(&mut self, name: &str, source: &str) -> Result<Value, LoxError> {
+        let func = self.compile_named(name, source)?;
+        self.apply(func, &[])
+    }
+
+    /// Compiles `source` to the `.loxc` format read by [`Vm::load_bytecode`].
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let bytecode = Vm::new().compile_bytecode("var answer = 6 * 7;").unwrap();
+    /// let mut vm = Vm::new();
+    /// vm.load_bytecode(&bytecode).unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("answer"), Some(42.0));
+    /// ```
+    pub fn compile_bytecode(&mut self, source: &str) -> Result<Vec<u8>, LoxError> {
+        let func = self.compile(source)?;
+        Ok(write_bytecode(func.as_function(), &self.globals, false))
+    }
+
+    /// Like [`Vm::compile_bytecode`], but leaves out the local variable
+    /// names that stack traces use, for smaller files.
+    pub fn compile_bytecode_stripped(&mut self, source: &str) -> Result<Vec<u8>, LoxError> {
+        let func = self.compile(source)?;
+        Ok(write_bytecode(func.as_function(), &self.globals, true))
+    }
+
+    /// Compiles `source` without running it and returns the function for
+    /// its top-level code.
+    pub fn compile(&mut self, source: &str) -> Result<Value, LoxError> {
+        let name = self.config.source_name.clone().unwrap_or_else(|| "script".to_string());
+        self.compile_named(&name, source)
+    }
+
+    /// Like [`Vm::compile`], but stack traces name the source `name`
+    /// instead of the [`Config::source_name`].
+    pub fn compile_named(&mut self, name: &str, source: &str) -> Result<Value, LoxError> {
+        self.compile_in(name, source, std::ptr::null_mut(), ScriptKind::File)
+    }
+
+    /// Compiles `source` for [`Vm::run_script`], reusing the compiled code
+    /// if the same source is still held by an earlier [`CompiledScript`].
+    ///
+    /// Scripts are matched by a hash of their text and
+    /// [`Config::source_name`], so a loop that compiles its script each
+    /// time round only scans and compiles it again when it changes.
+    /// Imported files are read when the script runs, not here.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.eval("var count;").unwrap();
+    /// let script = vm.compile_script("count = (count or 0) + 1;").unwrap();
+    /// vm.run_script(&script).unwrap();
+    /// assert_eq!(vm.compile_script("count = (count or 0) + 1;").unwrap(), script);
+    /// vm.run_script(&script).unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("count"), Some(2.0));
+    /// assert_ne!(vm.compile_script("count = 0;").unwrap(), script);
+    /// ```
+    pub fn compile_script(&mut self, source: &str) -> Result<CompiledScript, LoxError> {
+        let name = self.config.source_name.clone().unwrap_or_else(|| "script".to_string());
+        let hash = source_hash(&name, source);
+        self.scripts.retain(|compiled| compiled.strong_count() > 0);
+        let cached = self.scripts.iter()
+            .filter_map(Weak::upgrade)
+            .find(|compiled| compiled.hash == hash && compiled.name == name && compiled.source == source);
+        if let Some(compiled) = cached {
+            return Ok(CompiledScript { compiled });
+        }
+        let function = self.compile_named(&name, source)?;
+        let compiled = Arc::new(Compiled { hash, name, source: source.to_string(), function });
+        self.scripts.push(Arc::downgrade(&compiled));
+        Ok(CompiledScript { compiled })
+    }
+
+    /// Runs a script from [`Vm::compile_script`] in the current session,
+    /// as [`Vm::eval`] would.
+    ///
+    /// Fails, running nothing, if `script` was compiled by another VM.
+    pub fn run_script(&mut self, script: &CompiledScript) -> Result<Value, LoxError> {
+        let ours = self.scripts.iter().any(|compiled| std::ptr::eq(compiled.as_ptr(), Arc::as_ptr(&script.compiled)));
+        if !ours {
+            return Err(LoxError::runtime("Script was compiled by a different VM."));
+        }
+        self.apply(script.compiled.function, &[])
+    }
+
+    /// Compiles `source` and brings in the functions it declares at top
+    /// level without running the rest of it, so global variables keep
+    /// their values. Returns the names of the functions that changed, in
+    /// the order they are declared.
+    ///
+    /// A function whose declaration changed has its body swapped in
+    /// place, so callbacks the host or the script already hold run the
+    /// new code too. Functions the VM doesn't have yet are defined, and
+    /// globals that hold anything other than a function are left alone.
+    ///
+    /// Fails, changing nothing, if a script is running or paused.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.eval("var hits = 0;\nfun hit() { hits = hits + 1; }\nvar callback = hit;").unwrap();
+    /// vm.eval("callback();").unwrap();
+    ///
+    /// let changed = vm.reload("var hits = 0;\nfun hit() { hits = hits + 10; }\nvar callback = hit;").unwrap();
+    /// assert_eq!(changed, ["hit"]);
+    /// vm.eval("callback();").unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("hits"), Some(11.0));
+    /// ```
+    pub fn reload(&mut self, source: &str) -> Result<Vec<String>, LoxError> {
+        if self.frame_count > 0 {
+            return Err(LoxError::runtime("Can't reload while a script is running."));
+        }
+        let script = self.compile_script(source)?;
+        let chunk = unsafe { &(*script.compiled.function.as_function()).chunk };
+        let mut changed = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let Ok(op) = OpCode::try_from(chunk.code[offset]) else {
+                break;
+            };
+            let next = offset + 1 + operand_len(chunk, offset, &op);
+            // A top-level `fun` compiles to a closure that is stored
+            // straight into its global. It captures nothing, since it can
+            // only see globals.
+            if matches!(op, OpCode::Closure) && chunk.code.get(next) == Some(&(OpCode::DefineGlobal as u8)) {
+                let function = chunk.constants.values[chunk.code[offset + 1] as usize].as_function();
+                let name = chunk.constants.values[chunk.code[next + 1] as usize].as_string();
+                let current = self.globals.find(name).and_then(|slot| self.globals.get(slot));
+                match current {
+                    Some(value) if value.is_closure() => {
+                        let closure = value.as_closure() as *mut ObjClosure;
+                        let old = unsafe { (*closure).function };
+                        if unsafe { (*closure).upvalues.is_empty() && declaration_changed(&*old, &*function) } {
+                            unsafe { (*closure).function = function };
+                            changed.push(unsafe { (*name).as_str() }.to_string());
+                        }
+                    }
+                    Some(_) => {}
+                    None => {
+                        let closure = self.obj_array.new_closure(unsafe { &*function });
+                        self.globals.define(name, Value::object(closure as *const Obj));
+                        changed.push(unsafe { (*name).as_str() }.to_string());
+                    }
+                }
+            }
+            offset = next;
+        }
+        Ok(changed)
+    }
+
+    // Compiles `source` against the globals of `module`, or the VM's own
+    // if it is null. `name` is the source's name in stack traces.
+    fn compile_in(&mut self, name: &str, source: &str, module: *mut ObjModule, kind: ScriptKind) -> Result<Value, LoxError> {
+        let print_code = self.config.print_code;
+        let globals = if module.is_null() { &mut self.globals } else { unsafe { &mut (*module).globals } };
+        let func = match compile(source.to_string(), &mut self.obj_array, globals, module, print_code, kind) {
+            Ok((func, mut warnings)) => {
+                if self.config.check {
+                    let globals = if module.is_null() { &self.globals } else { unsafe { &(*module).globals } };
+                    warnings.extend(check_script(Value::object(func as *const Obj), globals, self.config.coerce));
+                    warnings.sort_by_key(|warning| warning.line);
+                }
+                self.report_diagnostics(source, module, kind, &warnings);
+                if self.config.deny_warnings && !warnings.is_empty() {
+                    return Err(LoxError::compile(warnings));
+                }
+                func
+            }
+            Err(diagnostics) => {
+                self.report_diagnostics(source, module, kind, &diagnostics);
+                return Err(LoxError::compile(diagnostics));
+            }
+        };
+        let script = Value::object(func as *const Obj);
+        set_source(script, &Arc::new(Source { name: name.to_string(), text: source.to_string() }));
+        if self.config.optimize {
+            optimize_function(script);
+        }
+        Ok(script)
+    }
+
+    pub(crate) fn report_diagnostics(&mut self, source: &str, module: *mut ObjModule, kind: ScriptKind, diagnostics: &[Diagnostic]) {
+        // eval() raises its compile errors for the script to catch.
+        if kind == ScriptKind::Eval {
+            return;
+        }
+        for diagnostic in diagnostics {
+            if self.config.error_format == ErrorFormat::Json {
+                let file = if module.is_null() {
+                    self.config.source_name.as_deref()
+                } else {
+                    Some(unsafe { (*(*module).name).as_str() })
+                };
+                let _ = writeln!(self.stderr, "{}", diagnostic.to_json(file));
+            } else if self.config.snippets {
+                let _ = write!(self.stderr, "{}", diagnostic.render(source));
+            } else {
+                let _ = writeln!(self.stderr, "{}", diagnostic);
+            }
+        }
+    }
+
+    /// Runs a line of REPL input like [`Vm::eval`]. If the line ends with an
+    /// expression statement, that expression's value is returned instead of
+    /// nil. The line may end with an expression and no `;`, which also
+    /// prints its value.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// assert_eq!(vm.eval_line("var x = 20; x + 1;").unwrap().as_number(), 21.0);
+    /// assert!(vm.eval_line("print x;").unwrap().is_nil());
+    /// assert_eq!(vm.eval_line("x * 2").unwrap().as_number(), 40.0);
+    /// assert!(vm.eval("x * 2").is_err());
+    /// ```
+    pub fn eval_line(&mut self, source: &str) -> Result<Value, LoxError> {
+        let func = self.compile_in("repl", source, std::ptr::null_mut(), ScriptKind::Repl)?;
+        self.apply(func, &[])
+    }
+
+    /// Runs a script compiled by [`Vm::compile_bytecode`] in the current
+    /// session, skipping compilation.
+    ///
+    /// The file's structure is checked, but its instructions are not, so
+    /// only load bytecode from a trusted source. Globals are matched by
+    /// name, so the loading VM may number its globals differently from the
+    /// one that compiled the file.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    /// use rustlox::NativeGroup;
+    ///
+    /// let bytecode = Vm::new().compile_bytecode("var answer = max(x, 5) * 7;").unwrap();
+    /// let mut vm = Vm::builder().natives(&[NativeGroup::Math]).build();
+    /// vm.set_global("x", 6.0);
+    /// vm.load_bytecode(&bytecode).unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("answer"), Some(42.0));
+    /// ```
+    pub fn load_bytecode(&mut self, bytes: &[u8]) -> Result<Value, LoxError> {
+        let func = self.read_bytecode(bytes)?;
+        self.apply(func, &[])
+    }
+
+    /// Reads a script compiled by [`Vm::compile_bytecode`] without running
+    /// it and returns the function for its top-level code, as
+    /// [`Vm::compile`] does for source.
+    pub fn read_bytecode(&mut self, bytes: &[u8]) -> Result<Value, LoxError> {
+        let func = read_bytecode(bytes, &mut self.obj_array, &mut self.globals).map_err(|error| {
+            let _ = writeln!(self.stderr, "{}", error);
+            error
+        })?;
+        Ok(Value::object(func as *const Obj))
+    }
+
+    /// Rebuilds pseudo-Lox source for a function, closure or bound method
+    /// from its bytecode, as described at [`crate::decompile::decompile`]. A script's
+    /// top-level function comes back as its statements.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.eval("fun countdown(n) { while (n > 0) { print n; n = n - 1; } }").unwrap();
+    /// let countdown = vm.eval_line("countdown").unwrap();
+    /// assert_eq!(vm.decompile(countdown).unwrap(), "\
+    /// fun countdown(n) {
+    ///   while (n > 0) {
+    ///     print n;
+    ///     n = n - 1;
+    ///   }
+    /// }
+    /// ");
+    /// ```
+    pub fn decompile(&self, function: Value) -> Result<String, LoxError> {
+        let function = if function.is_function() {
+            function.as_function()
+        } else if function.is_closure() {
+            unsafe { (*function.as_closure()).function }
+        } else if function.is_bound_method() {
+            unsafe { (*(*function.as_bound_method()).method).function }
+        } else {
+            return Err(LoxError::runtime("Can only decompile Lox functions."));
+        };
+        Ok(decompile(unsafe { &*function }, &self.globals))
+    }
+
+    /// Runs `source` like [`Vm::interpret`] and returns what it wrote to
+    /// stdout and stderr instead of passing it on to the VM's sinks.
+    ///
+    /// ```
+    /// use rustlox::{InterpretResult, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// let (result, stdout, stderr) = vm.interpret_captured("print 1 + 2; -nil;");
+    /// assert_eq!(result, InterpretResult::RuntimeError);
+    /// assert_eq!(stdout, "3\n");
+    /// assert!(stderr.starts_with("Operand must be a number."));
+    /// ```
+    pub fn interpret_captured(&mut self, source: &str) -> (InterpretResult, String, String) {
+        let out = Capture::default();
+        let err = Capture::default();
+        let stdout = std::mem::replace(&mut self.stdout, Box::new(out.clone()));
+        let stderr = std::mem::replace(&mut self.stderr, Box::new(err.clone()));
+        let result = match self.interpret(source) {
+            Ok(_) => InterpretResult::Ok,
+            Err(error) if error.kind == ErrorKind::Compile => InterpretResult::CompileError,
+            Err(_) => InterpretResult::RuntimeError,
+        };
+        self.stdout = stdout;
+        self.stderr = stderr;
+        (result, out.contents(), err.contents())
+    }
+
+    /// Calls the global function `name` with `args` and returns its result.
+    ///
+    /// ```
+    /// use rustlox::{Value, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("fun add(a, b) { return a + b; }").unwrap();
+    /// let sum = vm.call_function("add", &[Value::number(1.0), Value::number(2.0)]);
+    /// assert_eq!(sum.unwrap().as_number(), 3.0);
+    /// ```
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, LoxError> {
+        match self.global(name) {
+            Some(callee) => self.apply(callee, args),
+            None => Err(LoxError::runtime(format!("Undefined function '{}'.", name))),
+        }
+    }
+
+    /// Calls a Lox function or native with `args` and runs it to completion.
+    /// Natives may use this to call back into Lox code.
+    pub fn apply(&mut self, callee: Value, args: &[Value]) -> Result<Value, LoxError> {
+        let stack_top = self.stack_top;
+        let frame_count = self.frame_count;
+        if stack_top + args.len() + 1 > self.stack.len() {
+            return Err(LoxError::runtime("Stack overflow."));
+        }
+        let caller = match frame_count {
+            0 => CallFrame::default(),
+            n => self.frames[n - 1],
+        };
+        // Limits apply to each call made from the host, not to calls that
+        // natives make back into the VM.
+        if frame_count == 0 {
+            self.reset_limits();
+        }
+
+        self.push(callee);
+        for arg in args {
+            self.push(*arg);
+        }
+        let mut ok = self.call_value(&caller, callee, args.len());
+        // A Lox function pushed a new frame; run until it returns.
+        if ok && self.frame_count > frame_count {
+            ok = self.run(frame_count) == InterpretResult::Ok;
+        }
+        if !ok {
+            self.close_upvalues(stack_top);
+            self.stack_top = stack_top;
+            self.frame_count = frame_count;
+            self.discard_handlers(frame_count);
+            self.profile_exit();
+            let error = self.last_error.take().unwrap_or_else(|| LoxError::runtime("Call failed."));
+            // A native calling back into Lox gets the error to handle or
+            // pass on; only the host sees it reported.
+            if frame_count == 0 {
+                self.report_error(&error);
+                self.thrown = None;
+                self.raised = None;
+            }
+            return Err(error);
+        }
+        Ok(self.pop())
+    }
+
+    // Keeps `value` from being collected while `body` runs, such as a list
+    // that a native fills with the results of calls back into Lox.
+    pub(crate) fn holding<T>(&mut self, value: Value, body: impl FnOnce(&mut Vm) -> Result<T, LoxError>) -> Result<T, LoxError> {
+        if self.stack_top == self.stack.len() {
+            return Err(LoxError::runtime("Stack overflow."));
+        }
+        self.push(value);
+        let result = body(self);
+        self.pop();
+        result
+    }
+
+    /// Compiles `source` and sets it up to run as a top-level script,
+    /// without running any of it. Drive it with [`Vm::step`] or
+    /// [`Vm::resume`], e.g. to interleave a long script with a host's event
+    /// loop.
+    ///
+    /// The instruction budget and timeout cover the whole script, so time
+    /// spent by the host between steps counts towards the timeout.
+    ///
+    /// ```
+    /// use rustlox::vm::Step;
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.start("var sum = 0; for (var i = 0; i < 100; i = i + 1) sum = sum + i;").unwrap();
+    /// let mut steps = 0;
+    /// while let Step::Paused = vm.step(50).unwrap() {
+    ///     steps += 1;
+    /// }
+    /// assert!(steps > 1);
+    /// assert_eq!(vm.get_global_as::<f64>("sum"), Some(4950.0));
+    /// ```
+    pub fn start(&mut self, source: &str) -> Result<(), LoxError> {
+        self.stack_top = 0;
+        self.frame_count = 0;
+        self.handlers.clear();
+        self.profile_exit();
+        let script = self.compile(source)?;
+        self.reset_limits();
+        self.push(script);
+        if !self.call_value(&CallFrame::default(), script, 0) {
+            return Err(self.abort());
+        }
+        Ok(())
+    }
+
+    /// Runs at most `max_instructions` more instructions of the script set
+    /// up by [`Vm::start`].
+    pub fn step(&mut self, max_instructions: u64) -> Result<Step, LoxError> {
+        if self.frame_count == 0 {
+            return Err(LoxError::runtime("No script is running."));
+        }
+        self.pause_at = Some(self.instruction_count + max_instructions);
+        let result = self.run(0);
+        self.pause_at = None;
+        if result != InterpretResult::Ok {
+            return Err(self.abort());
+        }
+        if self.frame_count > 0 {
+            return Ok(Step::Paused);
+        }
+        Ok(Step::Done(self.pop()))
+    }
+
+    /// Runs the script set up by [`Vm::start`] to completion.
+    pub fn resume(&mut self) -> Result<Value, LoxError> {
+        if self.frame_count == 0 {
+            return Err(LoxError::runtime("No script is running."));
+        }
+        if self.run(0) != InterpretResult::Ok {
+            return Err(self.abort());
+        }
+        Ok(self.pop())
+    }
+
+    /// Runs the script set up by [`Vm::start`] until it reaches the line
+    /// `until` asks for, like a debugger.
+    ///
+    /// ```
+    /// use rustlox::vm::{DebugStep, Step};
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.start("var a = 1;\nvar b = 2;\nvar c = a + b;\nprint c;").unwrap();
+    /// vm.set_breakpoint(3);
+    /// assert!(matches!(vm.debug(DebugStep::Continue).unwrap(), Step::Paused));
+    /// assert_eq!(vm.backtrace()[0].line, 3);
+    /// vm.debug(DebugStep::Next).unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("c"), Some(3.0));
+    /// assert!(matches!(vm.debug(DebugStep::Continue).unwrap(), Step::Done(_)));
+    /// ```
+    pub fn debug(&mut self, until: DebugStep) -> Result<Step, LoxError> {
+        if self.frame_count == 0 {
+            return Err(LoxError::runtime("No script is running."));
+        }
+        let frame = self.frames[self.frame_count - 1];
+        let line = frame.chunk().get_line(frame.ip) as usize;
+        self.debug_stop = Some(DebugStop { until, frame_count: self.frame_count, location: (self.frame_count, line) });
+        let result = self.run(0);
+        self.debug_stop = None;
+        if result != InterpretResult::Ok {
+            return Err(self.abort());
+        }
+        if self.frame_count > 0 {
+            return Ok(Step::Paused);
+        }
+        Ok(Step::Done(self.pop()))
+    }
+
+    /// Makes [`Vm::debug`] stop when it reaches `line`.
+    pub fn set_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    /// Removes the breakpoint on `line`, returning whether there was one.
+    pub fn clear_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// The call stack of a paused script, innermost first. The innermost
+    /// frame is at the line about to run.
+    pub fn backtrace(&self) -> Vec<TraceFrame> {
+        (0..self.frame_count).rev()
+            .map(|i| {
+                let mut frame = self.frames[i];
+                // Frames below the top were saved after reading their call
+                // instruction, which trace_frame() expects.
+                if i == self.frame_count - 1 {
+                    frame.ip += 1;
+                }
+                self.trace_frame(&frame)
+            })
+            .collect()
+    }
+
+    /// The values on the stack, bottom first.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack[..self.stack_top]
+    }
+
+    /// Every global variable's name and value, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<_> = self.globals.iter()
+            .map(|(name, value)| (unsafe { (*name).as_str() }.to_string(), value))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    /// Owned copies of the values on the stack, bottom first. Objects other
+    /// than strings are printed, so the snapshot stays valid after the VM
+    /// moves on or collects garbage.
+    ///
+    /// ```
+    /// use rustlox::handle::OwnedValue;
+    /// use rustlox::vm::{DebugStep, Step};
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.start("fun add(a, b) {\n  return a + b;\n}\nvar sum = add(1, 2);").unwrap();
+    /// vm.set_breakpoint(2);
+    /// assert!(matches!(vm.debug(DebugStep::Continue).unwrap(), Step::Paused));
+    ///
+    /// let frames = vm.current_frames();
+    /// assert_eq!(frames[0].function.as_deref(), Some("add"));
+    /// assert_eq!(frames[0].line, 2);
+    /// assert_eq!(frames[1].function, None);
+    /// let stack = vm.stack_values();
+    /// assert_eq!(stack[frames[0].stack_base + 1..], [OwnedValue::Number(1.0), OwnedValue::Number(2.0)]);
+    /// assert!(vm.global_names().contains(&"add".to_string()));
+    /// ```
+    pub fn stack_values(&self) -> Vec<OwnedValue> {
+        self.stack().iter()
+            .map(|&value| unsafe { OwnedValue::from_value(value) }.unwrap_or(OwnedValue::Nil))
+            .collect()
+    }
+
+    /// The names of every global variable, sorted.
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.globals.iter()
+            .map(|(name, _)| unsafe { (*name).as_str() }.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The call frames of a paused script, innermost first, with where each
+    /// is in its chunk and on the stack.
+    pub fn current_frames(&self) -> Vec<FrameInfo> {
+        let trace = self.backtrace();
+        (0..self.frame_count).rev()
+            .zip(trace)
+            .map(|(i, trace)| FrameInfo {
+                function: trace.function,
+                line: trace.line,
+                ip: self.frames[i].ip,
+                stack_base: self.frames[i].stack_top,
+            })
+            .collect()
+    }
+
+    fn reset_limits(&mut self) {
+        self.instruction_count = 0;
+        self.deadline = self.config.timeout.map(|timeout| self.platform.clock() + timeout.as_secs_f64());
+    }
+
+    // Unwinds a failed top-level script and returns its error.
+    fn abort(&mut self) -> LoxError {
+        self.close_upvalues(0);
+        self.stack_top = 0;
+        self.frame_count = 0;
+        self.handlers.clear();
+        self.thrown = None;
+        self.raised = None;
+        self.profile_exit();
+        let error = self.last_error.take().unwrap_or_else(|| LoxError::runtime("Call failed."));
+        self.report_error(&error);
+        error
+    }
+
+    // Prints an error that no catch clause handled, with its stack trace.
+    pub(crate) fn report_error(&mut self, error: &LoxError) {
+        if self.config.error_format == ErrorFormat::Json {
+            let _ = writeln!(self.stderr, "{}", error.to_json(self.config.source_name.as_deref()));
+            return;
+        }
+        let _ = writeln!(self.stderr, "{}", error.message);
+        let mut frames: Vec<&TraceFrame> = error.trace.iter().collect();
+        if self.config.outermost_first {
+            frames.reverse();
+        }
+        for frame in frames {
+            if self.config.snippets {
+                let _ = write!(self.stderr, "{}", frame.render());
+            } else {
+                let _ = writeln!(self.stderr, "{}", frame);
+            }
+        }
+    }
+
+    // Ends the profile's timing of calls whose frames have returned or been
+    // unwound.
+    fn profile_exit(&mut self) {
+        if let Some(profile) = &mut self.profile {
+            profile.exit_to(self.frame_count, self.platform.clock());
+        }
+    }
+
+    // Drops the handlers of try blocks in frames above the first
+    // `frame_count`, which have returned or been unwound.
+    fn discard_handlers(&mut self, frame_count: usize) {
+        while let Some(handler) = self.handlers.last() {
+            if handler.frame_count <= frame_count {
+                break;
+            }
+            self.handlers.pop();
+        }
+    }
+
+    // Sends the error raised by the last instruction to the innermost try
+    // block started since `base_frame`, unwinding the stack to it and
+    // pushing the thrown value, or an Error describing it, for the catch
+    // clause. Returns false if there
+    // is no such block, or the error is a resource limit, which scripts
+    // can't catch.
+    fn catch_error(&mut self, base_frame: usize) -> bool {
+        let handler = match self.handlers.last() {
+            Some(handler) if handler.frame_count > base_frame => *handler,
+            _ => return false,
+        };
+        let error = match self.last_error.take() {
+            Some(error) if error.kind == ErrorKind::Runtime => error,
+            other => {
+                self.last_error = other;
+                return false;
+            }
+        };
+        self.handlers.pop();
+        let value = match self.thrown.take() {
+            Some(value) => value,
+            None => self.error_instance(&error),
+        };
+        self.close_upvalues(handler.stack_top);
+        self.frame_count = handler.frame_count;
+        self.profile_exit();
+        self.stack_top = handler.stack_top;
+        self.frames[self.frame_count - 1].ip = handler.catch_ip;
+        self.push(value);
+        true
+    }
+
+    // Returns the upvalue for the variable in stack slot `slot`, reusing
+    // the open one if another closure already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> *mut ObjUpvalue {
+        let local = &mut self.stack[slot] as *mut Value;
+        let mut prev: *mut ObjUpvalue = std::ptr::null_mut();
+        let mut upvalue = self.open_upvalues;
+        unsafe {
+            while !upvalue.is_null() && (*upvalue).location > local {
+                prev = upvalue;
+                upvalue = (*upvalue).next;
+            }
+            if !upvalue.is_null() && (*upvalue).location == local {
+                return upvalue;
+            }
+        }
+
+        let created = self.obj_array.new_upvalue(local);
+        unsafe {
+            (*created).next = upvalue;
+            if prev.is_null() {
+                self.open_upvalues = created;
+            } else {
+                (*prev).next = created;
+            }
+        }
+        created
+    }
+
+    // Moves every captured variable at or above stack slot `last` off the
+    // stack and into its upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        let last = &mut self.stack[last] as *mut Value;
+        unsafe {
+            while !self.open_upvalues.is_null() && (*self.open_upvalues).location >= last {
+                let upvalue = self.open_upvalues;
+                (*upvalue).closed = *(*upvalue).location;
+                (*upvalue).location = &mut (*upvalue).closed;
+                self.open_upvalues = (*upvalue).next;
+            }
+        }
+    }
+
+    /// Frees every object that scripts can no longer reach. This runs
+    /// automatically as the heap grows.
+    ///
+    /// Objects are reachable from the stack, globals and the functions
+    /// being run. A host holding a [`Value`] must keep it in a global, or
+    /// on the stack as an argument, for it to survive.
+    pub fn collect_garbage(&mut self) {
+        self.collect_garbage_for(GcReason::Requested);
+    }
+
+    pub(crate) fn collect_garbage_for(&mut self, reason: GcReason) {
+        for value in &self.stack[..self.stack_top] {
+            self.obj_array.mark_value(*value);
+        }
+        for frame in &self.frames[..self.frame_count] {
+            unsafe { self.obj_array.mark_object(frame.closure as *mut Obj) };
+        }
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() {
+            unsafe { self.obj_array.mark_object(upvalue as *mut Obj) };
+            upvalue = unsafe { (*upvalue).next };
+        }
+        unsafe { self.obj_array.mark_object(self.coroutine as *mut Obj) };
+        self.obj_array.mark_globals(&self.globals);
+        for module in self.modules.values() {
+            unsafe { self.obj_array.mark_object(*module as *mut Obj) };
+        }
+        unsafe { self.obj_array.mark_object(self.init_string as *mut Obj) };
+        unsafe { self.obj_array.mark_object(self.error_class as *mut Obj) };
+        self.ast_roots.mark(&mut self.obj_array);
+        if let Some(thrown) = self.thrown {
+            self.obj_array.mark_value(thrown);
+        }
+        if let Some(raised) = self.raised {
+            self.obj_array.mark_value(raised);
+        }
+        self.scripts.retain(|compiled| compiled.strong_count() > 0);
+        for compiled in self.scripts.iter().filter_map(Weak::upgrade) {
+            self.obj_array.mark_value(compiled.function);
+        }
+        self.snapshots.retain(|saved| saved.strong_count() > 0);
+        for saved in self.snapshots.iter().filter_map(Weak::upgrade) {
+            for (&name, &(value, _)) in &saved.globals {
+                unsafe { self.obj_array.mark_object(name as *mut Obj) };
+                if let Some(value) = value {
+                    self.obj_array.mark_value(value);
+                }
+            }
+        }
+        self.obj_array.trace();
+        // The syntax tree table doesn't keep functions alive.
+        self.ast_functions.retain(|&function, _| unsafe { (*function).obj.is_marked });
+        self.obj_array.collect(reason, &*self.platform);
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack[self.stack_top] = value;
+        self.stack_top += 1;
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance]
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack_top -= 1;
+        self.stack[self.stack_top]
+    }
+    
+    fn read_byte(&mut self, frame: &mut CallFrame) -> u8 {
+        let byte = frame.chunk().code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+    
+    fn read_short(&mut self, frame: &mut CallFrame) -> u16 {
+        let chunk = frame.chunk();
+        let short = (chunk.code[frame.ip] as u16) << 8 | chunk.code[frame.ip + 1] as u16;
+        frame.ip += 2;
+        short
+    }
+
+    fn read_constant(&mut self, frame: &mut CallFrame) -> Value {
+        let byte = self.read_byte(frame) as usize;
+        frame.chunk().constants.values[byte]
+    }
+
+    fn undefined_global(&mut self, frame: &CallFrame, slot: usize) {
+        let name = unsafe { (*self.frame_globals(frame).name(slot)).as_str() };
+        let message = format!("Undefined variable '{}'.", name);
+        self.runtime_error(frame, &message);
+    }
+
+    // The globals that the frame's code uses: its module's, or the VM's own.
+    fn frame_globals(&mut self, frame: &CallFrame) -> &mut Globals {
+        let module = unsafe { (*frame.function()).module };
+        if module.is_null() {
+            &mut self.globals
+        } else {
+            unsafe { &mut (*module).globals }
+        }
+    }
+
+    fn runtime_error(&mut self, frame: &CallFrame, message: &str) {
+        // The run loop works on a copy of the current frame, so store it back
+        // before walking the call stack.
+        if self.frame_count > 0 {
+            self.frames[self.frame_count - 1] = *frame;
+        }
+        let mut error = LoxError::runtime(message);
+        for i in (0..self.frame_count).rev() {
+            error.trace.push(self.trace_frame(&self.frames[i]));
+        }
+        self.last_error = Some(error);
+    }
+
+    fn limit_exceeded(&mut self, frame: &CallFrame, message: &str) {
+        self.runtime_error(frame, message);
+        if let Some(error) = &mut self.last_error {
+            error.kind = ErrorKind::LimitExceeded;
+        }
+    }
+
+    // Whether Vm::debug should stop before the instruction at `frame.ip`.
+    fn should_pause(&mut self, frame: &CallFrame) -> bool {
+        let stop = match &mut self.debug_stop {
+            Some(stop) => stop,
+            None => return false,
+        };
+        let location = (self.frame_count, frame.chunk().get_line(frame.ip) as usize);
+        if location == stop.location {
+            return false;
+        }
+        stop.location = location;
+        match stop.until {
+            DebugStep::Step => true,
+            DebugStep::Next => location.0 <= stop.frame_count,
+            DebugStep::Continue => self.breakpoints.contains(&location.1),
+        }
+    }
+
+    fn should_trace(&mut self, frame: &CallFrame) -> bool {
+        let filter = &self.config.trace_filter;
+        if let Some(start_line) = filter.start_line {
+            if !self.trace_started {
+                if frame.chunk().get_line(frame.ip) as usize != start_line {
+                    return false;
+                }
+                self.trace_started = true;
+            }
+        }
+        if let Some(name) = &filter.function {
+            let function = unsafe { (*frame.function()).name };
+            let current = if function.is_null() { "script" } else { unsafe { (*function).as_str() } };
+            if current != name {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn trace_instruction(&mut self, frame: &CallFrame) {
+        let mut out = String::new();
+        let stack = &self.stack[..self.stack_top];
+        let changed = stack.len() != self.traced_stack.len() ||
+            stack.iter().zip(&self.traced_stack).any(|(a, b)| !a.equals(*b));
+        if changed || !self.config.trace_filter.stack_changes_only {
+            out.push_str("          ");
+            for value in stack {
+                out.push_str(&format!("[ {} ]", value.display()));
+            }
+            out.push('\n');
+        }
+        if changed {
+            self.traced_stack = stack.to_vec();
+        }
+
+        write_instruction(&mut out, frame.chunk(), frame.ip);
+        let _ = self.stdout.write_all(out.as_bytes());
+    }
+
+    fn trace_frame(&self, frame: &CallFrame) -> TraceFrame {
+        let function = unsafe { &*frame.function() };
+        let instruction = frame.ip - 1;
+        let line = frame.chunk().get_line(instruction) as usize;
+        TraceFrame {
+            function: if function.name.is_null() {
+                None
+            } else {
+                Some(unsafe { (*function.name).as_str() }.to_string())
+            },
+            line,
+            source: frame.chunk().source.as_ref()
+                .map(|source| (source.name.clone(), source.line(line).unwrap_or_default().to_string())),
+            span: if function.name.is_null() { None } else { Some((function.line, function.end_line)) },
+            locals: frame.chunk().locals_at(instruction)
+                .map(|local| (local.name.clone(), format!("{}", self.stack[frame.stack_top + local.slot as usize].display())))
+                .collect(),
+        }
+    }
+
+    fn concatenate(&mut self) {
+        let bv = self.pop();
+        let av = self.pop();
+        let b = bv.as_str();
+        let a = av.as_str();
+
+        let mut result = String::with_capacity(a.len() + b.len() + 1);
+        result.push_str(a);
+        result.push_str(b);
+
+        let val = self.obj_array.take_string(result);
+        self.push(Value::object(val as *const Obj));
+    }
+
+    // Whether the top two values are a string and a number, in either
+    // order, which `+` and `*` accept in coerce mode.
+    fn mixes_string_and_number(&self) -> bool {
+        let (a, b) = (self.peek(1), self.peek(0));
+        a.is_string() && b.is_number() || a.is_number() && b.is_string()
+    }
+
+    // Replaces numbers among the top two values with strings, the way
+    // print shows them.
+    fn stringify_numbers(&mut self) {
+        for distance in 0..2 {
+            let value = self.peek(distance);
+            if value.is_number() {
+                let string = self.take_string(format!("{}", value.display()));
+                self.stack[self.stack_top - 1 - distance] = string;
+            }
+        }
+    }
+
+    // Pops a string and a repeat count, in either order, and pushes the
+    // string repeated that many times.
+    fn repeat(&mut self) -> Result<(), &'static str> {
+        let (string, count) = match (self.peek(1), self.peek(0)) {
+            (a, b) if a.is_string() => (a, b.as_number()),
+            (a, b) => (b, a.as_number()),
+        };
+        if count < 0.0 || count.fract() != 0.0 {
+            return Err("Repeat count must be a non-negative integer.");
+        }
+        let text = string.as_str();
+        let len = text.len() as f64 * count;
+        let mut repeated = String::new();
+        let too_long = self.config.heap_limit.is_some_and(|limit| len > limit as f64)
+            || len > usize::MAX as f64
+            || repeated.try_reserve_exact(len as usize).is_err();
+        if too_long {
+            return Err("Repeated string is too long.");
+        }
+        while repeated.len() < len as usize {
+            repeated.push_str(text);
+        }
+        self.pop();
+        self.pop();
+        let string = self.take_string(repeated);
+        self.push(string);
+        Ok(())
+    }
+
+    // Makes a coroutine that will call `callee` when it is first resumed,
+    // with its own stack and frames as large as the VM's.
+    pub(crate) fn new_coroutine(&mut self, callee: Value) -> Value {
+        let mut fiber = Fiber {
+            stack: vec![Value::nil(); self.config.stack_size],
+            stack_top: 0,
+            frames: vec![CallFrame::default(); self.config.max_frames],
+            frame_count: 0,
+            handlers: Vec::new(),
+            open_upvalues: std::ptr::null_mut(),
+        };
+        fiber.stack[0] = callee;
+        fiber.stack_top = 1;
+        let coroutine = self.obj_array.new_coroutine(fiber);
+        Value::object(coroutine as *const Obj)
+    }
+
+    // Swaps the running stack, frames and try blocks with `fiber`'s. Only
+    // the vectors' buffers are swapped, so open upvalues still point into
+    // the right stack.
+    fn swap_fiber(&mut self, fiber: &mut Fiber) {
+        std::mem::swap(&mut self.stack, &mut fiber.stack);
+        std::mem::swap(&mut self.stack_top, &mut fiber.stack_top);
+        std::mem::swap(&mut self.frames, &mut fiber.frames);
+        std::mem::swap(&mut self.frame_count, &mut fiber.frame_count);
+        std::mem::swap(&mut self.handlers, &mut fiber.handlers);
+        std::mem::swap(&mut self.open_upvalues, &mut fiber.open_upvalues);
+    }
+
+    // Calls the coroutine method `name`: resume(value) or done().
+    fn invoke_coroutine(&mut self, frame: &CallFrame, coroutine: *mut ObjCoroutine, name: &str, arg_count: usize) -> bool {
+        match name {
+            "resume" if arg_count <= 1 => self.resume_coroutine(frame, coroutine, arg_count),
+            "resume" => {
+                let message = format!("Expected 0 or 1 arguments but got {}.", arg_count);
+                self.runtime_error(frame, &message);
+                false
+            }
+            "done" if arg_count == 0 => {
+                let done = unsafe { (*coroutine).state } == CoroutineState::Done;
+                self.pop();
+                self.push(Value::bool(done));
+                true
+            }
+            "done" => {
+                let message = format!("Expected 0 arguments but got {}.", arg_count);
+                self.runtime_error(frame, &message);
+                false
+            }
+            _ => {
+                let message = format!("Undefined property '{}'.", name);
+                self.runtime_error(frame, &message);
+                false
+            }
+        }
+    }
+
+    // Runs `coroutine` until it yields or returns, and replaces the
+    // receiver and argument of the resume() call with the value it yielded
+    // or returned. The first resume passes its argument to the coroutine's
+    // function; later ones make it the value of the paused yield.
+    fn resume_coroutine(&mut self, frame: &CallFrame, coroutine: *mut ObjCoroutine, arg_count: usize) -> bool {
+        match unsafe { (*coroutine).state } {
+            CoroutineState::Suspended => {}
+            CoroutineState::Running => {
+                self.runtime_error(frame, "Can't resume a running coroutine.");
+                return false;
+            }
+            CoroutineState::Done => {
+                self.runtime_error(frame, "Can't resume a finished coroutine.");
+                return false;
+            }
+        }
+        let mut depth = 0;
+        let mut resumer = self.coroutine;
+        while !resumer.is_null() {
+            depth += 1;
+            resumer = unsafe { (*resumer).resumer };
+        }
+        if depth >= self.config.max_frames {
+            self.runtime_error(frame, "Stack overflow.");
+            return false;
+        }
+        let arg = if arg_count == 1 { self.peek(0) } else { Value::nil() };
+
+        // The receiver and argument stay on the resumer's stack until the
+        // coroutine is done with them, which keeps them from being
+        // collected.
+        unsafe {
+            (*coroutine).state = CoroutineState::Running;
+            (*coroutine).resumer = self.coroutine;
+            self.swap_fiber(&mut (*coroutine).fiber);
+        }
+        self.coroutine = coroutine;
+        let ok = if self.frame_count == 0 {
+            let callee = self.stack[0];
+            if arg_count == 1 {
+                self.push(arg);
+            }
+            self.call_value(&CallFrame::default(), callee, arg_count)
+                && (self.frame_count == 0 || self.run(0) == InterpretResult::Ok)
+        } else {
+            self.push(arg);
+            self.run(0) == InterpretResult::Ok
+        };
+        let result = if ok { self.pop() } else { Value::nil() };
+        let finished = !ok || self.frame_count == 0;
+        if finished {
+            // Closures made inside keep the variables they captured.
+            self.close_upvalues(0);
+            self.stack_top = 0;
+            self.frame_count = 0;
+            self.handlers.clear();
+        }
+        unsafe {
+            self.swap_fiber(&mut (*coroutine).fiber);
+            self.coroutine = (*coroutine).resumer;
+            (*coroutine).resumer = std::ptr::null_mut();
+            (*coroutine).state = if finished { CoroutineState::Done } else { CoroutineState::Suspended };
+        }
+
+        if !ok {
+            // The trace so far runs through the coroutine's frames; carry
+            // it on through the resumer's.
+            if let Some(mut error) = self.last_error.take() {
+                for i in (0..self.frame_count).rev() {
+                    error.trace.push(self.trace_frame(&self.frames[i]));
+                }
+                self.last_error = Some(error);
+            }
+            return false;
+        }
+        self.stack_top -= arg_count + 1;
+        self.push(result);
+        true
+    }
+
+    // Makes `value` the message of the Error a catch clause receives for
+    // the error the running native is about to return.
+    pub(crate) fn set_raised(&mut self, value: Value) {
+        self.raised = Some(value);
+    }
+
+    // The message of `value` if it is an Error.
+    fn error_message(&self, value: Value) -> Option<Value> {
+        if !value.is_instance() {
+            return None;
+        }
+        let instance = value.as_instance();
+        unsafe {
+            if (*instance).class != self.error_class {
+                return None;
+            }
+            let name = self.obj_array.find_string("message")?;
+            (*instance).fields.get(&name).copied()
+        }
+    }
+
+    // The Error a catch clause receives for `error`: an instance with the
+    // error's `message`, the `line` it was raised on, and the `stack` of
+    // calls it was raised in, innermost first, as maps with the `function`
+    // (nil for the script) and its `line`.
+    fn error_instance(&mut self, error: &LoxError) -> Value {
+        // Nothing is collected before the instance is on the stack, so the
+        // values made here are safe until it holds them.
+        let message = match self.raised.take() {
+            Some(value) => value,
+            None => self.new_string(&error.message),
+        };
+        let line = error.trace.first().map_or(Value::nil(), |frame| Value::number(frame.line as f64));
+        let mut stack = Vec::new();
+        for frame in &error.trace {
+            let function = match &frame.function {
+                Some(name) => self.new_string(name),
+                None => Value::nil(),
+            };
+            let mut entries = HashMap::new();
+            entries.insert(self.new_string("function"), function);
+            entries.insert(self.new_string("line"), Value::number(frame.line as f64));
+            stack.push(self.new_map(entries));
+        }
+        let stack = self.new_list(stack);
+        let instance = self.obj_array.new_instance(self.error_class);
+        for (name, value) in [("message", message), ("line", line), ("stack", stack)] {
+            let name = self.obj_array.copy_string(name);
+            unsafe { (*instance).fields.insert(name, value) };
+        }
+        Value::object(instance as *const Obj)
+    }
+
+    // Compiles and runs `source` for eval(), against the globals of the
+    // code that called it. The first compile error is raised as a runtime
+    // error.
+    pub(crate) fn eval_in_caller(&mut self, source: &str) -> Result<Value, LoxError> {
+        let module = match self.frame_count {
+            0 => std::ptr::null_mut(),
+            count => unsafe { (*self.frames[count - 1].function()).module },
+        };
+        let script = match self.compile_in("eval", source, module, ScriptKind::Eval) {
+            Ok(script) => script,
+            Err(error) => {
+                let first = error.message.lines().next().unwrap_or_default().to_string();
+                return Err(LoxError::runtime(format!("Could not compile eval source: {}", first)));
+            }
+        };
+        self.apply(script, &[])
+    }
+
+    pub fn new_string(&mut self, s: &str) -> Value {
+        let val = self.obj_array.copy_string(s);
+        Value::object(val as *const Obj)
+    }
+
+    /// Like new_string, but takes ownership of `s` to avoid copying it.
+    pub fn take_string(&mut self, s: String) -> Value {
+        let val = self.obj_array.take_string(s);
+        Value::object(val as *const Obj)
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        let val = self.obj_array.new_list(items);
+        Value::object(val as *const Obj)
+    }
+
+    pub fn new_map(&mut self, entries: HashMap<Value, Value>) -> Value {
+        let val = self.obj_array.new_map(entries);
+        Value::object(val as *const Obj)
+    }
+
+    /// Wraps host data in an opaque value that scripts can hold and pass
+    /// back to natives. `type_name` is what `type()` reports for it.
+    ///
+    /// ```
+    /// use rustlox::{LoxError, Value, Vm};
+    /// use std::cell::Cell;
+    ///
+    /// struct Counter(Cell<u32>);
+    ///
+    /// let mut vm = Vm::new();
+    /// let counter = vm.new_foreign("Counter", Counter(Cell::new(0)));
+    /// vm.set_global("counter", counter);
+    /// vm.define_native("bump", Box::new(|_, args| {
+    ///     let counter = unsafe { args[0].foreign_ref::<Counter>() }
+    ///         .ok_or_else(|| LoxError::runtime("Expected a Counter."))?;
+    ///     counter.0.set(counter.0.get() + 1);
+    ///     Ok(Value::number(counter.0.get() as f64))
+    /// }));
+    /// vm.interpret("bump(counter); bump(counter); print type(counter);").unwrap();
+    /// // `counter` is still a global, so it hasn't been collected.
+    /// assert_eq!(unsafe { counter.foreign_ref::<Counter>() }.unwrap().0.get(), 2);
+    /// ```
+    pub fn new_foreign<T: Any + Send>(&mut self, type_name: &'static str, data: T) -> Value {
+        let val = self.obj_array.new_foreign(type_name, Box::new(data), None);
+        Value::object(val as *const Obj)
+    }
+
+    /// Like [`Vm::new_foreign`], but when the value is collected, or the VM
+    /// is dropped, `finalizer` gets the data back instead of it being
+    /// dropped. Use it to release what the data stands for on the host side.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// struct Entity(u32);
+    ///
+    /// let despawned = Arc::new(Mutex::new(Vec::new()));
+    /// let mut vm = Vm::new();
+    /// let sink = despawned.clone();
+    /// let player = vm.new_foreign_with_finalizer("Entity", Entity(7), move |entity: Entity| {
+    ///     sink.lock().unwrap().push(entity.0);
+    /// });
+    /// vm.set_global("player", player);
+    /// vm.collect_garbage();
+    /// assert!(despawned.lock().unwrap().is_empty());
+    /// vm.interpret("player = nil;").unwrap();
+    /// vm.collect_garbage();
+    /// assert_eq!(*despawned.lock().unwrap(), [7]);
+    /// ```
+    pub fn new_foreign_with_finalizer<T: Any + Send>(
+        &mut self,
+        type_name: &'static str,
+        data: T,
+        finalizer: impl FnOnce(T) + Send + 'static,
+    ) -> Value {
+        let finalizer: Finalizer = Box::new(move |data| {
+            if let Ok(data) = data.downcast::<T>() {
+                finalizer(*data);
+            }
+        });
+        let val = self.obj_array.new_foreign(type_name, Box::new(data), Some(finalizer));
+        Value::object(val as *const Obj)
+    }
+
+    fn call(&mut self, orig_frame: &CallFrame, callee: *const ObjClosure, arg_count: usize) -> bool {
+        let function = unsafe { (*callee).function };
+        let arity = unsafe { (*function).arity };
+        if arg_count != arity as usize {
+            let name = unsafe { (*function).name };
+            let name = if name.is_null() { "script" } else { unsafe { (*name).as_str() } };
+            let message = format!("Expected {} arguments but got {} in call to '{}'.", arity, arg_count, name);
+            self.runtime_error(orig_frame, &message);
+            return false;
+        }
+        // Refuse calls that could run off the end of the stack.
+        if self.frame_count == self.frames.len() ||
+            self.stack_top + frame_slots(function) > self.stack.len() {
+            self.limit_exceeded(orig_frame, "Stack overflow.");
+            return false;
+        }
+        
+        let frame = &mut self.frames[self.frame_count];
+        frame.closure = callee;
+        frame.chunk = unsafe { Arc::as_ptr(&(*function).chunk) };
+        frame.ip = 0;
+        frame.stack_top = self.stack_top - arg_count - 1;
+
+        self.frame_count += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.enter(function, self.platform.clock());
+        }
+        #[cfg(feature = "hooks")]
+        if let Some(hook) = &mut self.hooks.on_call.0 {
+            hook(unsafe { &*function });
+        }
+        true
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let name = self.obj_array.copy_string(name);
+        self.globals.define(name, value);
+    }
+
+    /// Sets a global variable visible to scripts run on this VM.
+    ///
+    /// ```
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.set_global("name", "world");
+    /// vm.set_global("count", 3.0);
+    /// vm.interpret("var greeting = \"hello \" + name; var twice = count * 2;").unwrap();
+    /// assert_eq!(vm.get_global_as::<String>("greeting").unwrap(), "hello world");
+    /// assert_eq!(vm.get_global_as::<f64>("twice"), Some(6.0));
+    /// ```
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        let value = value.into_value(self);
+        self.define_global(name, value);
+    }
+
+    /// Returns a copy of a global variable, if it is defined. The copy is
+    /// owned, so it stays valid after the VM is dropped.
+    ///
+    /// ```
+    /// use rustlox::handle::OwnedValue;
+    /// use rustlox::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("var name = \"lox\"; fun f() {}").unwrap();
+    /// let name = vm.get_global("name");
+    /// let f = vm.get_global("f");
+    /// drop(vm);
+    /// assert_eq!(name, Some(OwnedValue::String("lox".to_string())));
+    /// assert_eq!(f, Some(OwnedValue::Object("<fn f>".to_string())));
+    /// ```
+    pub fn get_global(&self, name: &str) -> Option<OwnedValue> {
+        self.get_global_as(name)
+    }
+
+    /// Returns a global converted to a Rust type, copying strings out of the
+    /// heap. Returns `None` if the global is undefined or has another type.
+    pub fn get_global_as<T: FromValue>(&self, name: &str) -> Option<T> {
+        // Globals are roots, so the value is alive.
+        self.global(name).and_then(|value| unsafe { T::from_value(value) })
+    }
+
+    pub(crate) fn global(&self, name: &str) -> Option<Value> {
+        let name = self.obj_array.find_string(name)?;
+        self.globals.get(self.globals.find(name)?)
+    }
+
+    /// Copies `value` and the lists, maps, instances and closures it refers
+    /// to, so that changing the copy leaves the original alone. Strings,
+    /// functions and classes are shared.
+    ///
+    /// ```
+    /// use rustlox::{OwnedValue, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("var a = [1, [2]];").unwrap();
+    /// let a = vm.eval_line("a").unwrap();
+    /// let copy = vm.deep_clone(a);
+    /// vm.set_global("b", copy);
+    /// vm.interpret("b[1][0] = 3;").unwrap();
+    /// assert_eq!(vm.get_global("a"), Some(OwnedValue::Object("[1, [2]]".to_string())));
+    /// assert_eq!(vm.get_global("b"), Some(OwnedValue::Object("[1, [3]]".to_string())));
+    /// ```
+    pub fn deep_clone(&mut self, value: Value) -> Value {
+        self.obj_array.deep_clone(value, &mut HashMap::new())
+    }
+
+    /// Saves the values of every global variable, deep-copying them as
+    /// [`Vm::deep_clone`] does, for [`Vm::restore`] to put back later.
+    ///
+    /// ```
+    /// use rustlox::{OwnedValue, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret("var score = 10; var seen = [\"start\"];").unwrap();
+    /// let checkpoint = vm.snapshot();
+    /// vm.interpret("score = 0; push(seen, \"trap\"); var lives = 2;").unwrap();
+    /// vm.restore(&checkpoint).unwrap();
+    /// assert_eq!(vm.get_global_as::<f64>("score"), Some(10.0));
+    /// assert_eq!(vm.get_global("seen"), Some(OwnedValue::Object("[\"start\"]".to_string())));
+    /// assert!(vm.get_global("lives").is_none());
+    /// ```
+    pub fn snapshot(&mut self) -> Snapshot {
+        let mut copies = HashMap::new();
+        let mut globals = StringMap::default();
+        for slot in 0..self.globals.len() {
+            let value = self.globals.get(slot).map(|value| self.obj_array.deep_clone(value, &mut copies));
+            globals.insert(self.globals.name(slot), (value, self.globals.is_constant(slot)));
+        }
+        let saved = Arc::new(Saved { globals });
+        self.snapshots.push(Arc::downgrade(&saved));
+        Snapshot { saved }
+    }
+
+    /// Sets every global variable back to its value when `snapshot` was
+    /// taken. Globals defined since then become undefined. The snapshot
+    /// is copied again, so it can be restored any number of times.
+    ///
+    /// Fails, changing nothing, if `snapshot` was taken by another VM.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), LoxError> {
+        let ours = self.snapshots.iter().any(|saved| std::ptr::eq(saved.as_ptr(), Arc::as_ptr(&snapshot.saved)));
+        if !ours {
+            return Err(LoxError::runtime("Snapshot was taken by a different VM."));
+        }
+        let mut copies = HashMap::new();
+        for slot in 0..self.globals.len() {
+            match snapshot.saved.globals.get(&self.globals.name(slot)) {
+                Some(&(Some(value), constant)) => {
+                    let value = self.obj_array.deep_clone(value, &mut copies);
+                    self.globals.define(self.globals.name(slot), value);
+                    self.globals.set_constant(slot, constant);
+                }
+                Some(&(None, constant)) => {
+                    self.globals.undefine(slot);
+                    self.globals.set_constant(slot, constant);
+                }
+                None => {
+                    self.globals.undefine(slot);
+                    self.globals.set_constant(slot, false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers a Rust closure as a global native function, converting its
+    /// arguments and return value between Lox and Rust types.
+    ///
+    /// ```
+    /// use rustlox::{LoxError, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.register_fn("sqrt", |x: f64| x.sqrt());
+    /// vm.register_fn("greet", |name: String| format!("Hello, {}!", name));
+    /// vm.register_fn("half", |x: f64| {
+    ///     if x.fract() != 0.0 {
+    ///         return Err(LoxError::runtime("Expected an integer."));
+    ///     }
+    ///     Ok(x / 2.0)
+    /// });
+    /// vm.interpret("print greet(\"Lox\"); print sqrt(16) + half(4);").unwrap();
+    /// ```
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        self.define_native(name, function.into_native());
+    }
+
+    /// Registers a native that takes Lox values as they are and must be
+    /// called with exactly `arity` arguments. Return an error to raise a
+    /// runtime error in the calling script.
+    ///
+    /// ```
+    /// use rustlox::{LoxError, Value, Vm};
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.register_native("first", 2, |_, args| {
+    ///     if args[0].is_nil() {
+    ///         return Err(LoxError::runtime("First argument is nil."));
+    ///     }
+    ///     Ok(args[0])
+    /// });
+    /// assert!(vm.interpret("print first(1, \"a\");").is_ok());
+    /// assert!(vm.interpret("first(1);").is_err());
+    /// assert!(vm.interpret("first(nil, 2);").is_err());
+    /// ```
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Vm, &[Value]) -> Result<Value, LoxError> + Send + 'static,
+    ) {
+        self.define_native(name, Box::new(move |vm, args| {
+            check_arity(args, arity)?;
+            function(vm, args)
+        }));
+    }
+
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        self.define_native_with(name, None, function);
+    }
+
+    /// Defines a native that needs `capability`, unless the policy denies
+    /// it.
+    pub fn define_gated_native(&mut self, name: &str, capability: Capability, function: NativeFn) {
+        if self.config.policy.allows(capability) {
+            self.define_native_with(name, Some(capability), function);
+        }
+    }
+
+    fn define_native_with(&mut self, name: &str, capability: Option<Capability>, function: NativeFn) {
+        let val = self.obj_array.copy_string(name);
+        self.push(Value::object(val as *const Obj));
+        let native = self.obj_array.new_native(val, function, capability);
+        self.push(Value::object(native as *const Obj));
+        
+        self.globals.define(self.peek(1).as_string(), self.peek(0));
+        self.pop();
+        self.pop();
+    }
+
+    fn call_value(&mut self, frame: &CallFrame, callee: Value, arg_count: usize) -> bool {
+        if callee.is_closure() {
+            return self.call(frame, callee.as_closure(), arg_count);
+        }
+        if callee.is_bound_method() {
+            let bound = callee.as_bound_method();
+            self.stack[self.stack_top - arg_count - 1] = unsafe { (*bound).receiver };
+            return self.call(frame, unsafe { (*bound).method }, arg_count);
+        }
+        if callee.is_class() {
+            let class = callee.as_class();
+            let instance = self.obj_array.new_instance(class);
+            self.stack[self.stack_top - arg_count - 1] = Value::object(instance as *const Obj);
+            if let Some(initializer) = unsafe { (*class).methods.get(&self.init_string).copied() } {
+                return self.call(frame, initializer.as_closure(), arg_count);
+            }
+            if arg_count != 0 {
+                let name = unsafe { (*(*class).name).as_str() };
+                let message = format!("Expected 0 arguments but got {} in call to '{}'.", arg_count, name);
+                self.runtime_error(frame, &message);
+                return false;
+            }
+            return true;
+        }
+        // Compiled scripts and functions from the host have no upvalues;
+        // wrap them so that every frame runs a closure.
+        if callee.is_function() && unsafe { (*callee.as_function()).upvalue_count } == 0 {
+            let closure = self.obj_array.new_closure(unsafe { &*callee.as_function() });
+            self.stack[self.stack_top - arg_count - 1] = Value::object(closure as *const Obj);
+            return self.call(frame, closure, arg_count);
+        }
+        if callee.is_native() {
+            let native = callee.as_native();
+            if let Some(capability) = unsafe { (*native).capability } {
+                if !self.config.policy.allows(capability) {
+                    let message = format!("Capability '{}' is not allowed.", capability.name());
+                    self.runtime_error(frame, &message);
+                    return false;
+                }
+            }
+            let args = self.stack[self.stack_top - arg_count..self.stack_top].to_vec();
+            let result = unsafe { ((*native).function)(self, &args) };
+            let thrown = self.thrown.take();
+            let raised = self.raised.take();
+            match result {
+                Ok(value) => {
+                    self.stack_top -= arg_count + 1;
+                    self.push(value);
+                    return true;
+                }
+                Err(error) => {
+                    if error.trace.is_empty() {
+                        self.runtime_error(frame, &error.message);
+                        if let Some(last_error) = &mut self.last_error {
+                            last_error.value = error.value;
+                            // Natives have no chunk, so their frame reports
+                            // the line they were called from.
+                            let line = last_error.trace.first().map_or(0, |caller| caller.line);
+                            let name = unsafe { (*(*native).name).as_str() }.to_string();
+                            last_error.trace.insert(0, TraceFrame { function: Some(name), line, span: None, source: None, locals: Vec::new() });
+                        }
+                    } else {
+                        // It came from Lox code the native called, and its
+                        // trace already runs through this frame.
+                        self.last_error = Some(error);
+                    }
+                    self.thrown = thrown;
+                    self.raised = raised;
+                    return false;
+                }
+            }
+        }
+
+        self.runtime_error(frame, "Can only call functions and classes.");
+        false
+    }
+
+    // Loads the module at `path` and pushes it. The module's code runs the
+    // first time it is imported; later imports get the same module.
+    fn import(&mut self, frame: &CallFrame, path: &str) -> bool {
+        if !self.config.policy.allows(Capability::Fs) {
+            let message = format!("Capability '{}' is not allowed.", Capability::Fs.name());
+            self.runtime_error(frame, &message);
+            return false;
+        }
+        let resolved = match self.module_path(frame, path) {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                let message = format!("Could not import '{}': {}.", path, error);
+                self.runtime_error(frame, &message);
+                return false;
+            }
+        };
+        // The script itself is still being imported until it finishes.
+        let script = self.config.script_path.as_deref().map(|script| normalize_path(Path::new(script)));
+        if script.as_ref() == Some(&resolved) {
+            let message = format!("Import cycle: '{}' is still being imported.", path);
+            self.runtime_error(frame, &message);
+            return false;
+        }
+        if let Some(&module) = self.modules.get(&resolved) {
+            if unsafe { !(*module).loaded } {
+                let message = format!("Import cycle: '{}' is still being imported.", path);
+                self.runtime_error(frame, &message);
+                return false;
+            }
+            self.push(Value::object(module as *const Obj));
+            return true;
+        }
+        let source = match self.platform.read_file(&resolved) {
+            Ok(source) => source,
+            Err(error) => {
+                let message = format!("Could not import '{}': {}.", path, error);
+                self.runtime_error(frame, &message);
+                return false;
+            }
+        };
+
+        let name = self.obj_array.copy_string(&resolved);
+        let module = self.obj_array.new_module(name);
+        self.modules.insert(resolved.clone(), module);
+        // A module sees the natives, but not its importer's globals.
+        for (name, value) in self.globals.iter() {
+            if value.is_native() {
+                unsafe { (*module).globals.define(name, value) };
+            }
+        }
+        let result = match self.compile_in(&resolved, &source, module, ScriptKind::File) {
+            Ok(script) => self.apply(script, &[]),
+            Err(_) => Err(LoxError::runtime(format!("Could not compile '{}'.", path))),
+        };
+        match result {
+            Ok(_) => {
+                unsafe { (*module).loaded = true };
+                self.push(Value::object(module as *const Obj));
+                true
+            }
+            Err(error) => {
+                // Forget the module so that importing it again retries.
+                self.modules.remove(&resolved);
+                if error.trace.is_empty() {
+                    self.runtime_error(frame, &error.message);
+                } else {
+                    self.last_error = Some(error);
+                }
+                false
+            }
+        }
+    }
+
+    // Resolves an import's path against the directory of the module doing
+    // the import, or the import directory for scripts. The path must be
+    // relative and stay inside the import directory, so that import can't
+    // read any file the process can.
+    fn module_path(&self, frame: &CallFrame, path: &str) -> Result<String, String> {
+        if matches!(Path::new(path).components().next(), Some(Component::Prefix(_) | Component::RootDir)) {
+            return Err("Path is absolute".to_string());
+        }
+        let root = normalize_path(Path::new(self.config.import_dir.as_deref().unwrap_or("")));
+        let module = unsafe { (*frame.function()).module };
+        let dir = if module.is_null() {
+            PathBuf::from(&root)
+        } else {
+            let name = unsafe { (*(*module).name).as_str() };
+            Path::new(name).parent().map(Path::to_path_buf).unwrap_or_default()
+        };
+        // Normalize, so that one file reached by different paths is
+        // imported once.
+        let resolved = normalize_path(&dir.join(path));
+        let outside = Path::new(&resolved).strip_prefix(&root)
+            .map_or(true, |rest| rest.components().next() == Some(Component::ParentDir));
+        if outside {
+            return Err("Path leaves the import directory".to_string());
         }
+        // Links could still lead out of it.
+        let real_root = self.platform.canonicalize(if root.is_empty() { "." } else { &root })?;
+        let real = self.platform.canonicalize(&resolved)?;
+        if !Path::new(&real).starts_with(&real_root) {
+            return Err("Path leaves the import directory".to_string());
+        }
+        Ok(resolved)
     }
-}
-
-#[derive(Debug, PartialEq)]
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
-}
 
-pub fn interpret(source: String) -> InterpretResult {
-    let mut obj_array = ObjArray::default();
-    let chunk = Rc::new(Chunk::default());
-    let func = compile(source, chunk, &mut obj_array);
-    if func.is_none() {
-        return InterpretResult::CompileError;
+    // The module's global `name`, or None after reporting it undefined.
+    fn module_variable(&mut self, frame: &CallFrame, module: *const ObjModule, name: *const ObjString) -> Option<Value> {
+        let globals = unsafe { &(*module).globals };
+        let value = globals.find(name).and_then(|slot| globals.get(slot));
+        if value.is_none() {
+            let message = format!("Module has no variable '{}'.", unsafe { (*name).as_str() });
+            self.runtime_error(frame, &message);
+        }
+        value
     }
 
-    let mut vm = VM {
-        stack: [Value::number(0.0); STACK_MAX],
-        stack_top: 0,
-        obj_array: &mut obj_array,
-        globals: HashMap::new(),
-        frames: std::array::from_fn(|_| CallFrame::default()),
-        frame_count: 0,
-    };
-    vm.define_native("clock", new_clock_native());
-    vm.push(Value::object(func.unwrap() as *const Obj));
-    vm.call(&CallFrame::default(), func.unwrap(), 0);
-    
-    let result = vm.run();
-    vm.globals.clear();
-    vm.obj_array.free_objects();
-    return result;
-}
-
-impl VM<'_> {
-    fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top = self.stack_top + 1;
+    // Calls the method `name` of the instance's class, with the instance
+    // already in the receiver slot.
+    fn invoke_from_class(&mut self, frame: &CallFrame, class: *const ObjClass, name: *const ObjString, arg_count: usize) -> bool {
+        match unsafe { (*class).methods.get(&name).copied() } {
+            Some(method) => self.call(frame, method.as_closure(), arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", unsafe { (*name).as_str() });
+                self.runtime_error(frame, &message);
+                false
+            }
+        }
     }
 
-    fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack_top - 1 - distance]
+    // The method `name` that invoking it on `receiver` would call, if
+    // `receiver` is an instance without a field of that name.
+    fn instance_method(&self, receiver: Value, name: *const ObjString) -> Option<*const ObjClosure> {
+        if !receiver.is_instance() {
+            return None;
+        }
+        let instance = receiver.as_instance();
+        if unsafe { (*instance).fields.contains_key(&name) } {
+            return None;
+        }
+        let method = unsafe { (*(*instance).class).methods.get(&name).copied() }?;
+        Some(method.as_closure())
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack_top = self.stack_top - 1;
-        self.stack[self.stack_top]
-    }
-    
-    fn read_byte(&mut self, frame: &mut CallFrame) -> u8 {
-        let byte = frame.chunk().code[frame.ip];
-        frame.ip = frame.ip + 1;
-        return byte;
-    }
-    
-    fn read_short(&mut self, frame: &mut CallFrame) -> u16 {
-        let chunk = frame.chunk();
-        let short = (chunk.code[frame.ip] as u16) << 8 | chunk.code[frame.ip + 1] as u16;
-        frame.ip = frame.ip + 2;
-        return short;
+    // Whether calling `callee` from a tail call can replace the caller's
+    // frame: the call can't fail once the arity and room are checked.
+    fn can_reuse_frame(&self, callee: *const ObjClosure, arg_count: usize) -> bool {
+        let function = unsafe { (*callee).function };
+        let arity = unsafe { (*function).arity } as usize;
+        arity == arg_count && self.stack_top + frame_slots(function) <= self.stack.len()
     }
 
-    fn read_constant(&mut self, frame: &mut CallFrame) -> Value {
-        let byte = self.read_byte(frame) as usize;
-        return unsafe { (*(*frame.function).chunk).constants.values[byte] }
+    // Replaces `frame` with one calling `callee` on the callee and arguments
+    // on top of the stack, so that tail recursion runs in constant space.
+    fn tail_call(&mut self, frame: &CallFrame, callee: *const ObjClosure, arg_count: usize) {
+        #[cfg(feature = "hooks")]
+        if let Some(hook) = &mut self.hooks.on_return.0 {
+            hook(unsafe { &*frame.function() });
+        }
+        self.close_upvalues(frame.stack_top);
+        self.stack.copy_within(self.stack_top - arg_count - 1..self.stack_top, frame.stack_top);
+        self.stack_top = frame.stack_top + arg_count + 1;
+        self.frame_count -= 1;
+        if self.profile.is_some() {
+            self.profile_exit();
+        }
+        self.call(frame, callee, arg_count);
     }
 
-    fn runtime_error(&mut self, frame: &CallFrame, message: &str) {
-        eprintln!("{}", message);
-        self.print_frame(frame);
-        for i in (0..self.frame_count - 1).rev() {
-            self.print_frame(&self.frames[i]);
+    fn invoke(&mut self, frame: &CallFrame, name: Value, arg_count: usize) -> bool {
+        let receiver = self.peek(arg_count);
+        if receiver.is_coroutine() {
+            return self.invoke_coroutine(frame, receiver.as_coroutine(), name.as_str(), arg_count);
+        }
+        if receiver.is_module() {
+            let value = match self.module_variable(frame, receiver.as_module(), name.as_string()) {
+                Some(value) => value,
+                None => return false,
+            };
+            self.stack[self.stack_top - arg_count - 1] = value;
+            return self.call_value(frame, value, arg_count);
         }
+        if !receiver.is_instance() {
+            self.runtime_error(frame, "Only instances have methods.");
+            return false;
+        }
+        let instance = receiver.as_instance();
+        // A field holding a function shadows a method of the same name.
+        if let Some(value) = unsafe { (*instance).fields.get(&name.as_string()).copied() } {
+            self.stack[self.stack_top - arg_count - 1] = value;
+            return self.call_value(frame, value, arg_count);
+        }
+        self.invoke_from_class(frame, unsafe { (*instance).class }, name.as_string(), arg_count)
     }
 
-    fn print_frame(&self, frame: &CallFrame) {
-        let function = unsafe { (*frame.function).name };
-        let instruction = frame.ip - 1;
-        let line = frame.chunk().lines[instruction];
-        eprint!("[line {}] in ", line);
-        if function.is_null() {
-            eprintln!("script");
-        } else {
-            eprintln!("{}()", unsafe { (*function).as_str() });
-        }
+    // Replaces the instance on top of the stack with its method `name`,
+    // bound to it.
+    fn bind_method(&mut self, frame: &CallFrame, class: *const ObjClass, name: *const ObjString) -> bool {
+        let method = match unsafe { (*class).methods.get(&name).copied() } {
+            Some(method) => method,
+            None => {
+                let message = format!("Undefined property '{}'.", unsafe { (*name).as_str() });
+                self.runtime_error(frame, &message);
+                return false;
+            }
+        };
+        let bound = self.obj_array.new_bound_method(self.peek(0), method.as_closure());
+        self.pop();
+        self.push(Value::object(bound as *const Obj));
+        true
     }
 
-    fn concatenate(&mut self) {
-        let bv = self.pop();
-        let av = self.pop();
-        let b = bv.as_str();
-        let a = av.as_str();
+    // Runs until the frame count drops back to `base_frame`, leaving the
+    // returned value on the stack. When stepping, the top-level loop also
+    // returns Ok early with the frames saved, to be continued by another
+    // call.
+    // Whether anything needs to run before each instruction besides
+    // counting it and collecting garbage: stepping, tracing, a hook or a
+    // limit. Checked once per call or return instead of every instruction.
+    fn needs_instruction_checks(&self) -> bool {
+        #[cfg(feature = "hooks")]
+        if self.hooks.on_instruction.0.is_some() {
+            return true;
+        }
+        self.pause_at.is_some() || self.debug_stop.is_some() || self.config.trace_execution
+            || self.profile.is_some() || self.deadline.is_some()
+            || self.config.instruction_budget.is_some() || self.config.heap_limit.is_some()
+            || self.config.stress_gc || self.interrupted()
+    }
 
-        // TODO(nicks): Could avoid copy here.
-        let mut result = String::from(a);
-        result.push_str(b);
+    // Whether the interrupt flag is set. Checked on calls and loops, one of
+    // which a script that runs for long has to make.
+    fn interrupted(&self) -> bool {
+        self.interrupt.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
 
-        let val = self.obj_array.copy_string(result.as_str());
-        self.push(Value::object(val as *const Obj));
+    // Clears the interrupt flag, returning whether it was set.
+    pub(crate) fn take_interrupt(&mut self) -> bool {
+        self.interrupt.as_ref().is_some_and(|flag| flag.swap(false, Ordering::Relaxed))
     }
 
-    fn call(&mut self, orig_frame: &CallFrame, callee: *const ObjFunction, arg_count: usize) -> bool {
-        let arity = unsafe { (*callee).arity };
-        if arg_count != arity as usize {
-            self.runtime_error(orig_frame, "Wrong number of arguments.");
-            return false;
+    // Runs the checks that needs_instruction_checks() asks for. Returns the
+    // result to stop with, if the VM should stop.
+    fn check_instruction(&mut self, frame: &CallFrame, base_frame: usize) -> Option<InterpretResult> {
+        if let Some(pause_at) = self.pause_at {
+            if self.at_top_level(base_frame) && self.instruction_count >= pause_at {
+                self.frames[self.frame_count - 1] = *frame;
+                return Some(InterpretResult::Ok);
+            }
         }
-        if self.frame_count == FRAMES_MAX {
-            self.runtime_error(orig_frame, "Stack overflow.");
-            return false;
+        if self.at_top_level(base_frame) && self.should_pause(frame) {
+            self.frames[self.frame_count - 1] = *frame;
+            return Some(InterpretResult::Ok);
+        }
+        if self.config.trace_execution && self.should_trace(frame) {
+            self.trace_instruction(frame);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.count(frame.chunk().code[frame.ip]);
         }
-        
-        let mut frame = &mut self.frames[self.frame_count];
-        frame.function = callee;
-        frame.ip = 0;
-        frame.stack_top = self.stack_top - arg_count - 1;
-
-        self.frame_count += 1;
-        return true;
-    }
 
-    fn define_native(&mut self, name: &str, function: NativeFn) {
-        let val = self.obj_array.copy_string(name);
-        self.push(Value::object(val as *const Obj));
-        let native = self.obj_array.new_native(function);
-        self.push(Value::object(native as *const Obj));
-        
-        unsafe {
-            let n = self.peek(1).as_string();
-            let slice = std::slice::from_raw_parts((*n).chars, (*n).len);
-            let s = std::str::from_utf8(slice).unwrap();
-            self.globals.insert(s, self.peek(0));
+        #[cfg(feature = "hooks")]
+        if let Some(hook) = &mut self.hooks.on_instruction.0 {
+            hook(frame.chunk(), frame.ip);
         }
-        self.pop();
-        self.pop();
-    }
 
-    fn call_value(&mut self, frame: &CallFrame, callee: Value, arg_count: usize) -> bool {
-        if callee.is_function() {
-            return self.call(frame, callee.as_function(), arg_count);
+        // Errors are reported at the instruction about to run, as they would
+        // be from inside it.
+        let mut at = *frame;
+        at.ip += 1;
+        if let Some(budget) = self.config.instruction_budget {
+            if self.instruction_count >= budget {
+                self.limit_exceeded(&at, "Instruction budget exhausted.");
+                return Some(InterpretResult::RuntimeError);
+            }
         }
-        if callee.is_native() {
-            let native = callee.as_native();
-            let result = unsafe {
-                ((*native).function)(arg_count, &self.stack[self.stack_top..self.stack_top+arg_count])
-            };
-                
-            self.stack_top -= arg_count + 1;
-            self.push(result);
-            return true;
+        if let Some(deadline) = self.deadline {
+            if (self.instruction_count + 1).is_multiple_of(TIMEOUT_CHECK_INTERVAL) && self.platform.clock() > deadline {
+                self.limit_exceeded(&at, "Timeout exceeded.");
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+        if self.take_interrupt() {
+            self.runtime_error(&at, "Interrupted.");
+            if let Some(error) = &mut self.last_error {
+                error.kind = ErrorKind::Interrupted;
+            }
+            return Some(InterpretResult::RuntimeError);
         }
+        if self.config.stress_gc {
+            self.collect_garbage_for(GcReason::Stress);
+        }
+        if let Some(limit) = self.config.heap_limit {
+            // Only live objects count towards the limit.
+            if self.obj_array.bytes_allocated > limit {
+                self.collect_garbage_for(GcReason::HeapLimit);
+            }
+            if self.obj_array.bytes_allocated > limit {
+                self.limit_exceeded(&at, "Heap limit exceeded.");
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+        None
+    }
 
-        self.runtime_error(frame, "Can only call functions and classes.");
-        return false;
+    // Whether a run loop with `base_frame` is the one the host started,
+    // rather than one a native or coroutine started inside it.
+    fn at_top_level(&self, base_frame: usize) -> bool {
+        base_frame == 0 && self.coroutine.is_null()
     }
 
-    fn run(&mut self) -> InterpretResult {
-        let mut frame = std::mem::take(&mut self.frames[self.frame_count - 1]);
-        
+    // Runs until the frame count drops to `base_frame`, sending errors to
+    // the try blocks that catch them.
+    fn run(&mut self, base_frame: usize) -> InterpretResult {
+        loop {
+            let result = self.dispatch(base_frame);
+            if result != InterpretResult::RuntimeError || !self.catch_error(base_frame) {
+                return result;
+            }
+        }
+    }
+
+    fn dispatch(&mut self, base_frame: usize) -> InterpretResult {
+        let mut frame = self.frames[self.frame_count - 1];
+        let mut checked = self.needs_instruction_checks();
+
         loop {
-            if DEBUG {
-                print!("          ");
-                for i in 0..self.stack_top {
-                    print!("[ ");
-                    self.stack[i].print();
-                    print!(" ]");
-                }
-                println!();
-                
-                disassemble_instruction(frame.chunk(), frame.ip);
-            }
-            
+            if checked {
+                if let Some(result) = self.check_instruction(&frame, base_frame) {
+                    return result;
+                }
+            }
+            self.instruction_count += 1;
+            if self.obj_array.should_collect() {
+                self.collect_garbage_for(GcReason::HeapGrowth);
+            }
+
             let instruction = self.read_byte(&mut frame);
             match OpCode::try_from(instruction) {
                 Ok(OpCode::Print) => {
-                    self.pop().print();
-                    println!();
+                    let value = self.pop();
+                    let _ = writeln!(self.stdout, "{}", value.display());
                 }
                 Ok(OpCode::Pop) => {
                     self.pop();
                 }
+                Ok(OpCode::PopN) => {
+                    let count = self.read_byte(&mut frame) as usize;
+                    self.stack_top -= count;
+                }
+                Ok(OpCode::Dup) => {
+                    self.push(self.peek(0));
+                }
+                Ok(OpCode::Dup2) => {
+                    self.push(self.peek(1));
+                    self.push(self.peek(1));
+                }
+                Ok(OpCode::Swap) => {
+                    let top = self.stack_top - 1;
+                    self.stack.swap(top, top - 1);
+                }
                 Ok(OpCode::DefineGlobal) => {
                     let constant = self.read_constant(&mut frame);
                     let value = self.peek(0);
-                    unsafe {
-                        let name = constant.as_string();
-                        let slice = std::slice::from_raw_parts((*name).chars, (*name).len);
-                        let s = std::str::from_utf8(slice).unwrap();
-                        self.globals.insert(s, value);
-                    }
+                    self.frame_globals(&frame).define(constant.as_string(), value);
                     self.pop();
                 }
                 Ok(OpCode::SetGlobal) => {
                     let constant = self.read_constant(&mut frame);
                     let value = self.peek(0);
-                    match self.globals.get(constant.as_str()) {
-                        Some(_) => {
-                            unsafe {
-                                let name = constant.as_string();
-                                let slice = std::slice::from_raw_parts((*name).chars, (*name).len);
-                                let s = std::str::from_utf8(slice).unwrap();
-                                self.globals.insert(s, value);
-                            }
-                        }
+                    let slot = self.frame_globals(&frame).find(constant.as_string());
+                    if slot.is_some_and(|slot| self.frame_globals(&frame).is_constant(slot)) {
+                        let message = format!("Cannot assign to constant '{}'.", constant.as_str());
+                        self.runtime_error(&frame, &message);
+                        return InterpretResult::RuntimeError;
+                    }
+                    if !slot.is_some_and(|slot| self.frame_globals(&frame).set(slot, value)) {
+                        let message = format!("Undefined variable '{}'.", constant.as_str());
+                        self.runtime_error(&frame, &message);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Ok(OpCode::SetGlobalSlot) => {
+                    let slot = self.read_short(&mut frame) as usize;
+                    let value = self.peek(0);
+                    if self.frame_globals(&frame).is_constant(slot) {
+                        let name = unsafe { (*self.frame_globals(&frame).name(slot)).as_str() };
+                        let message = format!("Cannot assign to constant '{}'.", name);
+                        self.runtime_error(&frame, &message);
+                        return InterpretResult::RuntimeError;
+                    }
+                    if !self.frame_globals(&frame).set(slot, value) {
+                        self.undefined_global(&frame, slot);
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Ok(OpCode::GetGlobalSlot) => {
+                    let slot = self.read_short(&mut frame) as usize;
+                    match self.frame_globals(&frame).get(slot) {
+                        Some(value) => self.push(value),
                         None => {
-                            self.runtime_error(&mut frame, "Undefined variable.");
+                            self.undefined_global(&frame, slot);
                             return InterpretResult::RuntimeError;
                         }
                     }
                 }
                 Ok(OpCode::GetGlobal) => {
                     let constant = self.read_constant(&mut frame);
-                    let value = self.globals.get(constant.as_str());
-                    match value {
+                    let slot = self.frame_globals(&frame).find(constant.as_string());
+                    match slot.and_then(|slot| self.frame_globals(&frame).get(slot)) {
                         Some(v) => {
-                            self.push(*v);
+                            self.push(v);
                         }
                         None => {
-                            self.runtime_error(&mut frame, "Undefined variable.");
+                            let message = format!("Undefined variable '{}'.", constant.as_str());
+                            self.runtime_error(&frame, &message);
                             return InterpretResult::RuntimeError;
                         }
                     }
@@ -281,48 +3177,372 @@ impl VM<'_> {
                     let slot = self.read_byte(&mut frame) as usize;
                     self.stack[frame.stack_top + slot] = self.peek(0);
                 }
+                Ok(OpCode::GetLocalLong) => {
+                    let slot = self.read_short(&mut frame) as usize;
+                    self.push(self.stack[frame.stack_top + slot]);
+                }
+                Ok(OpCode::SetLocalLong) => {
+                    let slot = self.read_short(&mut frame) as usize;
+                    self.stack[frame.stack_top + slot] = self.peek(0);
+                }
                 Ok(OpCode::Jump) => {
                     let offset = self.read_short(&mut frame) as usize;
-                    frame.ip = frame.ip + offset;
+                    frame.ip += offset;
+                }
+                Ok(OpCode::PushHandler) => {
+                    let offset = self.read_short(&mut frame) as usize;
+                    self.handlers.push(Handler {
+                        frame_count: self.frame_count,
+                        stack_top: self.stack_top,
+                        catch_ip: frame.ip + offset,
+                    });
+                }
+                Ok(OpCode::PopHandler) => {
+                    self.handlers.pop();
+                }
+                Ok(OpCode::Import) => {
+                    let path = self.read_constant(&mut frame);
+                    // The module's code runs in a nested run loop, which
+                    // needs the frame to be up to date.
+                    self.frames[self.frame_count - 1] = frame;
+                    if !self.import(&frame, path.as_str()) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Ok(OpCode::Throw) => {
+                    let value = self.pop();
+                    // A rethrown Error is reported with its message.
+                    let message = self.error_message(value).unwrap_or(value);
+                    self.runtime_error(&frame, &format!("{}", message.display()));
+                    if let Some(error) = &mut self.last_error {
+                        error.value = unsafe { OwnedValue::from_value(value) };
+                    }
+                    self.thrown = Some(value);
+                    return InterpretResult::RuntimeError;
                 }
                 Ok(OpCode::Loop) => {
                     let offset = self.read_short(&mut frame) as usize;
-                    frame.ip = frame.ip - offset;
+                    frame.ip -= offset;
+                    checked = checked || self.interrupted();
+                }
+                Ok(OpCode::IterNext) => {
+                    let offset = self.read_short(&mut frame) as usize;
+                    let mut collection = self.peek(1);
+                    if collection.is_map() {
+                        // Maps are iterated over a copy of their keys, so
+                        // the loop body may change the map.
+                        let keys = unsafe { (*collection.as_map()).entries.keys().copied().collect() };
+                        collection = self.new_list(keys);
+                        self.stack[self.stack_top - 2] = collection;
+                    }
+                    let position = self.peek(0).as_number() as usize;
+                    let next = if collection.is_list() {
+                        let items = unsafe { &(*collection.as_list()).items };
+                        items.get(position).map(|item| (*item, position + 1))
+                    } else if collection.is_string() {
+                        // Strings are walked by byte offset, a character
+                        // at a time.
+                        let c = collection.as_str()[position..].chars().next();
+                        c.map(|c| (self.new_string(c.encode_utf8(&mut [0; 4])), position + c.len_utf8()))
+                    } else {
+                        self.runtime_error(&frame, "Can only iterate over lists, maps and strings.");
+                        return InterpretResult::RuntimeError;
+                    };
+                    match next {
+                        Some((item, position)) => {
+                            self.stack[self.stack_top - 1] = Value::number(position as f64);
+                            self.push(item);
+                        }
+                        None => frame.ip += offset,
+                    }
                 }
                 Ok(OpCode::JumpIfFalse) => {
                     let offset = self.read_short(&mut frame) as usize;
                     if self.peek(0).is_falsey() {
-                        frame.ip = frame.ip + offset;
+                        frame.ip += offset;
                     }
                 }
                 Ok(OpCode::Call) => {
                     let orig_frame = self.frame_count - 1;
                     let arg_count = self.read_byte(&mut frame) as usize;
+                    // Natives may re-enter the VM, so the frame has to be up
+                    // to date before the call.
+                    self.frames[orig_frame] = frame;
                     if !self.call_value(&frame, self.peek(arg_count), arg_count) {
                         return InterpretResult::RuntimeError;
                     }
+                    frame = self.frames[self.frame_count - 1];
+                    checked = self.needs_instruction_checks();
+                }
+                Ok(OpCode::TailCall) => {
+                    let arg_count = self.read_byte(&mut frame) as usize;
+                    let callee = self.peek(arg_count);
+                    if callee.is_closure() && self.can_reuse_frame(callee.as_closure(), arg_count) {
+                        self.tail_call(&frame, callee.as_closure(), arg_count);
+                    } else {
+                        // Called normally; the return after this passes the
+                        // result on.
+                        let orig_frame = self.frame_count - 1;
+                        self.frames[orig_frame] = frame;
+                        if !self.call_value(&frame, callee, arg_count) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                    frame = self.frames[self.frame_count - 1];
+                    checked = self.needs_instruction_checks();
+                }
+                Ok(OpCode::Invoke) => {
+                    let orig_frame = self.frame_count - 1;
+                    let name = self.read_constant(&mut frame);
+                    let arg_count = self.read_byte(&mut frame) as usize;
                     self.frames[orig_frame] = frame;
-                    frame = std::mem::take(&mut self.frames[self.frame_count - 1]);
+                    if !self.invoke(&frame, name, arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                    frame = self.frames[self.frame_count - 1];
+                    checked = self.needs_instruction_checks();
+                }
+                Ok(OpCode::TailInvoke) => {
+                    let name = self.read_constant(&mut frame);
+                    let arg_count = self.read_byte(&mut frame) as usize;
+                    match self.instance_method(self.peek(arg_count), name.as_string()) {
+                        Some(method) if self.can_reuse_frame(method, arg_count) => {
+                            // The receiver is already in the slot the
+                            // method's `this` takes.
+                            self.tail_call(&frame, method, arg_count);
+                        }
+                        _ => {
+                            let orig_frame = self.frame_count - 1;
+                            self.frames[orig_frame] = frame;
+                            if !self.invoke(&frame, name, arg_count) {
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                    }
+                    frame = self.frames[self.frame_count - 1];
+                    checked = self.needs_instruction_checks();
+                }
+                Ok(OpCode::Class) => {
+                    let name = self.read_constant(&mut frame).as_string();
+                    let class = self.obj_array.new_class(name);
+                    self.push(Value::object(class as *const Obj));
+                }
+                Ok(OpCode::Method) => {
+                    let name = self.read_constant(&mut frame);
+                    let method = self.peek(0);
+                    let class = self.peek(1).as_class();
+                    unsafe { (*class).methods.insert(name.as_string(), method) };
+                    self.pop();
+                }
+                Ok(OpCode::GetProperty) => {
+                    let name = self.read_constant(&mut frame);
+                    let target = self.peek(0);
+                    if target.is_instance() {
+                        let instance = target.as_instance();
+                        if let Some(value) = unsafe { (*instance).fields.get(&name.as_string()).copied() } {
+                            self.pop();
+                            self.push(value);
+                        } else if !self.bind_method(&frame, unsafe { (*instance).class }, name.as_string()) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else if target.is_module() {
+                        match self.module_variable(&frame, target.as_module(), name.as_string()) {
+                            Some(value) => {
+                                self.pop();
+                                self.push(value);
+                            }
+                            None => return InterpretResult::RuntimeError,
+                        }
+                    } else {
+                        self.runtime_error(&frame, "Only instances have properties.");
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Ok(OpCode::SetProperty) => {
+                    let name = self.read_constant(&mut frame);
+                    if !self.peek(1).is_instance() {
+                        self.runtime_error(&frame, "Only instances have fields.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let instance = self.peek(1).as_instance();
+                    unsafe { (*instance).fields.insert(name.as_string(), self.peek(0)) };
+                    let value = self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Ok(OpCode::BuildList) => {
+                    let count = self.read_byte(&mut frame) as usize;
+                    let items = self.stack[self.stack_top - count..self.stack_top].to_vec();
+                    let list = self.new_list(items);
+                    self.stack_top -= count;
+                    self.push(list);
+                }
+                Ok(OpCode::BuildMap) => {
+                    let count = self.read_byte(&mut frame) as usize;
+                    let mut entries = HashMap::new();
+                    for i in 0..count {
+                        let key = self.stack[self.stack_top - 2 * (count - i)];
+                        let value = self.stack[self.stack_top - 2 * (count - i) + 1];
+                        if let Err(message) = check_key(key) {
+                            self.runtime_error(&frame, &message);
+                            return InterpretResult::RuntimeError;
+                        }
+                        entries.insert(key, value);
+                    }
+                    let map = self.new_map(entries);
+                    self.stack_top -= 2 * count;
+                    self.push(map);
+                }
+                Ok(OpCode::IndexGet) => {
+                    let index = self.peek(0);
+                    let target = self.peek(1);
+                    let value = if target.is_list() {
+                        let items = unsafe { &(*target.as_list()).items };
+                        match checked_index(index, items.len()) {
+                            Ok(i) => items[i],
+                            Err(message) => {
+                                self.runtime_error(&frame, &message);
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                    } else if target.is_string() {
+                        // Strings are indexed by character, like charAt().
+                        let s = target.as_str();
+                        match checked_index(index, s.chars().count()) {
+                            Ok(i) => {
+                                let c = s.chars().nth(i).unwrap();
+                                self.new_string(c.encode_utf8(&mut [0; 4]))
+                            }
+                            Err(message) => {
+                                self.runtime_error(&frame, &message);
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                    } else if target.is_map() {
+                        let entries = unsafe { &(*target.as_map()).entries };
+                        match entries.get(&index) {
+                            Some(value) => *value,
+                            None => {
+                                self.runtime_error(&frame, &format!("Undefined key {}.", index.repr()));
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                    } else {
+                        self.runtime_error(&frame, "Only lists, maps and strings can be indexed.");
+                        return InterpretResult::RuntimeError;
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Ok(OpCode::IndexSet) => {
+                    let value = self.peek(0);
+                    let index = self.peek(1);
+                    let target = self.peek(2);
+                    if target.is_list() {
+                        let items = unsafe { &mut (*target.as_list()).items };
+                        match checked_index(index, items.len()) {
+                            Ok(i) => items[i] = value,
+                            Err(message) => {
+                                self.runtime_error(&frame, &message);
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                    } else if target.is_map() {
+                        if let Err(message) = check_key(index) {
+                            self.runtime_error(&frame, &message);
+                            return InterpretResult::RuntimeError;
+                        }
+                        unsafe { (*target.as_map()).entries.insert(index, value) };
+                    } else {
+                        self.runtime_error(&frame, "Only list and map items can be assigned.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    self.stack_top -= 3;
+                    self.push(value);
+                }
+                Ok(OpCode::Closure) => {
+                    let function = self.read_constant(&mut frame).as_function();
+                    let closure = self.obj_array.new_closure(unsafe { &*function });
+                    self.push(Value::object(closure as *const Obj));
+                    for _ in 0..unsafe { (*function).upvalue_count } {
+                        let is_local = self.read_byte(&mut frame) == 1;
+                        let index = self.read_byte(&mut frame) as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(frame.stack_top + index)
+                        } else {
+                            unsafe { (&(*frame.closure).upvalues)[index] }
+                        };
+                        unsafe { (*closure).upvalues.push(upvalue) };
+                    }
+                }
+                Ok(OpCode::GetUpvalue) => {
+                    let slot = self.read_byte(&mut frame) as usize;
+                    let upvalue = unsafe { (&(*frame.closure).upvalues)[slot] };
+                    self.push(unsafe { *(*upvalue).location });
+                }
+                Ok(OpCode::SetUpvalue) => {
+                    let slot = self.read_byte(&mut frame) as usize;
+                    let upvalue = unsafe { (&(*frame.closure).upvalues)[slot] };
+                    unsafe { *(*upvalue).location = self.peek(0) };
+                }
+                Ok(OpCode::CloseUpvalue) => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
+                }
+                Ok(OpCode::Yield) => {
+                    if self.coroutine.is_null() {
+                        self.runtime_error(&frame, "Can only yield inside a coroutine.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    if base_frame != 0 {
+                        self.runtime_error(&frame, "Can't yield across a native call.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    // The value stays on top of the stack for resume() to
+                    // take, and the value resume() is next called with
+                    // takes its place.
+                    self.frames[self.frame_count - 1] = frame;
+                    return InterpretResult::Ok;
                 }
                 Ok(OpCode::Return) => {
+                    #[cfg(feature = "hooks")]
+                    if let Some(hook) = &mut self.hooks.on_return.0 {
+                        hook(unsafe { &*frame.function() });
+                    }
                     let result = self.pop();
+                    self.close_upvalues(frame.stack_top);
                     self.frame_count -= 1;
-                    if self.frame_count == 0 {
-                        self.pop();
-                        return InterpretResult::Ok;
+                    if !self.handlers.is_empty() {
+                        self.discard_handlers(self.frame_count);
+                    }
+                    if self.profile.is_some() {
+                        self.profile_exit();
                     }
                     self.stack_top = frame.stack_top;
                     self.push(result);
-                    frame = std::mem::take(&mut self.frames[self.frame_count - 1]);
+                    if self.frame_count == base_frame {
+                        return InterpretResult::Ok;
+                    }
+                    frame = self.frames[self.frame_count - 1];
                 }
                 Ok(OpCode::Constant) => {
                     let constant = self.read_constant(&mut frame);
                     self.push(constant);
                 }
+                Ok(OpCode::ConstantLong) => {
+                    let high = self.read_byte(&mut frame) as usize;
+                    let index = high << 16 | self.read_short(&mut frame) as usize;
+                    self.push(frame.chunk().constants.values[index]);
+                }
+                Ok(OpCode::SmallInt) => {
+                    let n = self.read_byte(&mut frame);
+                    self.push(Value::number(n as f64));
+                }
                 Ok(OpCode::Negate) => {
                     let val = self.peek(0);
                     if !val.is_number() {
-                        self.runtime_error(&mut frame, "Operand must be a number.");
+                        self.runtime_error(&frame, "Operand must be a number.");
                         return InterpretResult::RuntimeError;
                     }
                     let a = self.pop();
@@ -331,18 +3551,21 @@ impl VM<'_> {
                 Ok(OpCode::Add) => {
                     if self.peek(0).is_string() && self.peek(1).is_string() {
                         self.concatenate();
+                    } else if self.config.coerce && self.mixes_string_and_number() {
+                        self.stringify_numbers();
+                        self.concatenate();
                     } else if self.peek(0).is_number() && self.peek(1).is_number() {
                         let b = self.pop();
                         let a = self.pop();
                         self.push(Value::number(a.as_number() + b.as_number()));
                     } else {
-                        self.runtime_error(&mut frame, "Operands must be two numbers or two strings.");
+                        self.runtime_error(&frame, "Operands must be two numbers or two strings.");
                         return InterpretResult::RuntimeError;
                     }
                 }
                 Ok(OpCode::Subtract) => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error(&mut frame, "Operands must be numbers.");
+                        self.runtime_error(&frame, "Operands must be numbers.");
                         return InterpretResult::RuntimeError;
                     }
                     let b = self.pop();
@@ -350,22 +3573,48 @@ impl VM<'_> {
                     self.push(Value::number(a.as_number() - b.as_number()));
                 }
                 Ok(OpCode::Multiply) => {
+                    if self.config.coerce && self.mixes_string_and_number() {
+                        if let Err(message) = self.repeat() {
+                            self.runtime_error(&frame, message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        let b = self.pop();
+                        let a = self.pop();
+                        self.push(Value::number(a.as_number() * b.as_number()));
+                    } else {
+                        self.runtime_error(&frame, "Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Ok(OpCode::Divide) => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error(&mut frame, "Operands must be numbers.");
+                        self.runtime_error(&frame, "Operands must be numbers.");
                         return InterpretResult::RuntimeError;
                     }
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::number(a.as_number() * b.as_number()));
+                    self.push(Value::number(a.as_number() / b.as_number()));
                 }
-                Ok(OpCode::Divide) => {
+                Ok(OpCode::ToString) => {
+                    // Converts the value the same way print does.
+                    let value = self.peek(0);
+                    if !value.is_string() {
+                        let text = format!("{}", value.display());
+                        let string = self.take_string(text);
+                        self.pop();
+                        self.push(string);
+                    }
+                }
+                Ok(OpCode::Modulo) => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error(&mut frame, "Operands must be numbers.");
+                        self.runtime_error(&frame, "Operands must be numbers.");
                         return InterpretResult::RuntimeError;
                     }
+                    // Like C's fmod, the result has the sign of the dividend.
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::number(a.as_number() / b.as_number()));
+                    self.push(Value::number(a.as_number() % b.as_number()));
                 }
                 Ok(OpCode::Nil) => self.push(Value::nil()),
                 Ok(OpCode::True) => self.push(Value::bool(true)),
@@ -373,7 +3622,7 @@ impl VM<'_> {
                 Ok(OpCode::Equal) => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::bool(a.equals(b)));
+                    self.push(Value::bool(values_equal(a, b)));
                 }
                 Ok(OpCode::Not) => {
                     let val = self.pop();
@@ -381,7 +3630,7 @@ impl VM<'_> {
                 }
                 Ok(OpCode::Greater) => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error(&mut frame, "Operands must be numbers.");
+                        self.runtime_error(&frame, "Operands must be numbers.");
                         return InterpretResult::RuntimeError;
                     }
                     let b = self.pop();
@@ -390,7 +3639,7 @@ impl VM<'_> {
                 }
                 Ok(OpCode::Less) => {
                     if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error(&mut frame, "Operands must be numbers.");
+                        self.runtime_error(&frame, "Operands must be numbers.");
                         return InterpretResult::RuntimeError;
                     }
                     let b = self.pop();
@@ -398,17 +3647,68 @@ impl VM<'_> {
                     self.push(Value::bool(a.as_number() < b.as_number()));
                 }
                 _ => {
-                    println!("Unknown opcode {}", instruction);
-                return InterpretResult::RuntimeError;
+                    self.runtime_error(&frame, &format!("Unknown opcode {}.", instruction));
+                    return InterpretResult::RuntimeError;
                 }
             }
         }
     }
 }
 
-fn new_clock_native() -> Box<dyn Fn(usize, &[Value]) -> Value> {
-    let start = Instant::now();
-    Box::new(move |_, _| {
-        return Value::number(start.elapsed().as_secs_f64())
-    })
+// Checks that `index` is a whole number that indexes a sequence of `len`
+// items.
+// Tags `function` and the functions nested in it with the source they were
+// compiled from.
+fn set_source(function: Value, source: &Arc<Source>) {
+    let function = unsafe { &mut *(function.as_function() as *mut ObjFunction) };
+    let Some(chunk) = Arc::get_mut(&mut function.chunk) else {
+        return;
+    };
+    chunk.source = Some(source.clone());
+    for constant in &chunk.constants.values {
+        if constant.is_function() {
+            set_source(*constant, source);
+        }
+    }
+}
+
+// Whether a reloaded function differs from the one it replaces, judged by
+// the text and position of their declarations.
+fn declaration_changed(old: &ObjFunction, new: &ObjFunction) -> bool {
+    fn text(function: &ObjFunction) -> Option<Vec<&str>> {
+        let source = function.chunk.source.as_ref()?;
+        Some((function.line..=function.end_line)
+            .map(|line| source.line(line).unwrap_or_default())
+            .collect())
+    }
+    match (text(old), text(new)) {
+        (Some(old_text), Some(new_text)) => old_text != new_text || old.line != new.line,
+        _ => true,
+    }
+}
+
+// The stack slots a frame for `function` may address: UINT8_COUNT, as in
+// clox, plus its locals past the first UINT8_COUNT if it has more.
+fn frame_slots(function: *const ObjFunction) -> usize {
+    UINT8_COUNT + unsafe { (*function).max_locals }.saturating_sub(UINT8_COUNT)
+}
+
+pub(crate) fn checked_index(index: Value, len: usize) -> Result<usize, String> {
+    if !index.is_number() || index.as_number().fract() != 0.0 {
+        return Err("Index must be an integer.".to_string());
+    }
+    let n = index.as_number();
+    if n < 0.0 || n >= len as f64 {
+        return Err(format!("Index {} out of bounds.", n));
+    }
+    Ok(n as usize)
+}
+
+// Checks that `key` can key a map. NaN is refused because it never equals
+// itself, so it could be stored but never found.
+pub(crate) fn check_key(key: Value) -> Result<(), String> {
+    if key.is_number() && key.as_number().is_nan() {
+        return Err("Map keys can't be NaN.".to_string());
+    }
+    Ok(())
 }
@@ -0,0 +1,153 @@
+// Purpose: JavaScript bindings for running Lox in the browser.
+//
+// Build with `make wasm`, which writes a wasm-bindgen package to pkg/, then:
+//
+//   import init_module, { evalLox } from "./pkg/rustlox.js";
+//   await init_module();
+//   const { result, stdout, errors } = evalLox("print 1 + 2;");
+//
+// Every eval runs on the same VM, so globals from one script are visible to
+// the next, as in the REPL. init() starts over with a fresh VM.
+
+use crate::error::ErrorKind;
+use crate::platform::CommandOutput;
+use crate::platform::Platform;
+use crate::policy::Capability;
+use crate::policy::Policy;
+use crate::vm::Capture;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::io;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date)]
+    fn now() -> f64;
+}
+
+const UNSUPPORTED: &str = "Not available in the browser.";
+
+// std::time::Instant panics on wasm32-unknown-unknown, so the clock comes
+// from JavaScript. There is no file system or process to offer.
+#[derive(Debug)]
+struct BrowserPlatform;
+
+impl Platform for BrowserPlatform {
+    fn clock(&self) -> f64 {
+        now() / 1000.0
+    }
+
+    fn random_seed(&self) -> u64 {
+        now().to_bits()
+    }
+
+    fn os_name(&self) -> &str {
+        "browser"
+    }
+
+    fn read_file(&self, _path: &str) -> Result<String, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn write_file(&self, _path: &str, _contents: &str) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn read_line(&self) -> Result<Option<String>, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn current_dir(&self) -> Result<String, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn set_current_dir(&self, _path: &str) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn exit(&self, _code: i32) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn env_var(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    fn set_env_var(&self, _name: &str, _value: &str) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    fn exec(&self, _command: &str) -> Result<CommandOutput, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+}
+
+thread_local! {
+    // The VM and the sink its stdout is written to.
+    static VM: RefCell<Option<(Vm, Capture)>> = const { RefCell::new(None) };
+}
+
+/// What one call to [`eval`] produced.
+#[wasm_bindgen(getter_with_clone)]
+pub struct EvalResult {
+    /// "ok", "compile error", "runtime error" or "limit exceeded".
+    pub result: String,
+    /// Everything the script printed.
+    pub stdout: String,
+    /// The compile errors, or the runtime error followed by its stack
+    /// trace, one line each.
+    pub errors: Vec<String>,
+}
+
+/// Replaces the VM with a fresh one, forgetting every global.
+#[wasm_bindgen]
+pub fn init() {
+    let out = Capture::default();
+    let mut vm = Vm::builder()
+        .platform(BrowserPlatform)
+        .policy(Policy::pure().allow(Capability::Time))
+        .build();
+    vm.set_stdout(out.clone());
+    // Errors are returned in EvalResult::errors instead.
+    vm.set_stderr(io::sink());
+    VM.with(|cell| *cell.borrow_mut() = Some((vm, out)));
+}
+
+/// Runs `source`, calling [`init`] first if no VM exists yet. JavaScript
+/// reserves `eval`, so it is exported as `evalLox`.
+#[wasm_bindgen(js_name = evalLox)]
+pub fn eval(source: &str) -> EvalResult {
+    if VM.with(|cell| cell.borrow().is_none()) {
+        init();
+    }
+    VM.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let (vm, out) = cell.as_mut().unwrap();
+        let outcome = vm.interpret(source);
+        let stdout = out.take();
+        match outcome {
+            Ok(_) => EvalResult { result: "ok".to_string(), stdout, errors: Vec::new() },
+            Err(error) => {
+                let result = match error.kind {
+                    ErrorKind::Compile => "compile error",
+                    ErrorKind::Runtime => "runtime error",
+                    ErrorKind::LimitExceeded => "limit exceeded",
+                    ErrorKind::Interrupted => "interrupted",
+                };
+                let errors = if error.kind == ErrorKind::Compile {
+                    error.diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect()
+                } else {
+                    let mut lines = vec![error.message.clone()];
+                    lines.extend(error.trace.iter().map(|frame| frame.to_string()));
+                    lines
+                };
+                EvalResult { result: result.to_string(), stdout, errors }
+            }
+        }
+    })
+}
@@ -0,0 +1,532 @@
+// Purpose: Generates random small Lox programs and checks that rustlox runs
+// them the way jlox would, using a tree-walking interpreter written here as
+// the oracle.
+//
+// The generator builds expression trees over numbers, strings, booleans and
+// nil, and wraps them in prints, assignments, ifs and loops that run a fixed
+// number of times. Programs are rendered with only the parentheses Lox's
+// precedence needs, so a parser that binds an operator differently computes
+// a different value. The oracle walks the same tree with jlox's rules for
+// truthiness, equality and operand checks, so a divergence in any of them
+// shows up as different output or a different runtime error.
+//
+// Both of rustlox's engines are checked: the bytecode VM, and the
+// tree-walking engine behind --engine=ast.
+//
+// The seeds are fixed, so every run checks the same programs. Set
+// LOX_DIFF_SEED to rerun a single failing program, or LOX_DIFF_CASES to
+// check more of them.
+
+use std::collections::HashMap;
+use std::env;
+
+use rustlox::vm::Engine;
+use rustlox::InterpretResult;
+use rustlox::Vm;
+
+const DEFAULT_CASES: u64 = 300;
+
+// xorshift64*, which is plenty for picking program shapes and keeps the test
+// free of dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Zero is a fixed point of xorshift, and nearby seeds should still
+        // give unrelated programs.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => format_number(*n),
+            Value::Str(s) => s.clone(),
+        }
+    }
+}
+
+// Numbers print as in clox: integers without a fraction, others in their
+// shortest form, and JavaScript's cutoffs for switching to an exponent.
+fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let magnitude = n.abs();
+    if magnitude == 0.0 || (1e-6..1e21).contains(&magnitude) {
+        return n.to_string();
+    }
+    let text = format!("{:e}", n);
+    let (mantissa, power) = text.split_once('e').unwrap();
+    let sign = if power.starts_with('-') { "" } else { "+" };
+    format!("{}e{}{}", mantissa, sign, power)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Str,
+    Bool,
+    Any,
+}
+
+enum Expr {
+    Literal(Value),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+}
+
+enum Stmt {
+    Print(Expr),
+    Assign(String, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    // A for loop counting its variable up from zero.
+    For(String, u64, Vec<Stmt>),
+    // A while loop over a counter declared in an enclosing block.
+    While(String, u64, Vec<Stmt>),
+}
+
+struct Program {
+    globals: Vec<(String, Expr)>,
+    body: Vec<Stmt>,
+}
+
+const UNARY: u8 = 7;
+const PRIMARY: u8 = 8;
+
+fn precedence(op: &str) -> u8 {
+    match op {
+        "or" => 1,
+        "and" => 2,
+        "==" | "!=" => 3,
+        "<" | "<=" | ">" | ">=" => 4,
+        "+" | "-" => 5,
+        "*" | "/" => 6,
+        _ => unreachable!("unknown operator {}", op),
+    }
+}
+
+// Renders an expression with the fewest parentheses that keep its shape,
+// returning the precedence of the outermost operator.
+fn render_expr(expr: &Expr) -> (String, u8) {
+    match expr {
+        Expr::Literal(Value::Str(s)) => (format!("\"{}\"", s), PRIMARY),
+        Expr::Literal(value) => (value.render(), PRIMARY),
+        Expr::Variable(name) => (name.clone(), PRIMARY),
+        Expr::Unary(op, operand) => {
+            let text = operand_text(operand, UNARY);
+            // "- -1" rather than "--1", which reads like a decrement.
+            let space = if text.starts_with(op) { " " } else { "" };
+            (format!("{}{}{}", op, space, text), UNARY)
+        }
+        Expr::Binary(left, op, right) => {
+            let prec = precedence(op);
+            // Binary operators are left-associative, so a right operand of
+            // the same precedence needs parentheses and a left one doesn't.
+            let left = operand_text(left, prec);
+            let right = operand_text(right, prec + 1);
+            (format!("{} {} {}", left, op, right), prec)
+        }
+    }
+}
+
+fn operand_text(expr: &Expr, min: u8) -> String {
+    let (text, prec) = render_expr(expr);
+    if prec < min {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn render_block(stmts: &[Stmt], indent: usize, out: &mut String) {
+    for stmt in stmts {
+        render_stmt(stmt, indent, out);
+    }
+}
+
+fn render_stmt(stmt: &Stmt, indent: usize, out: &mut String) {
+    let pad = "  ".repeat(indent);
+    match stmt {
+        Stmt::Print(expr) => out.push_str(&format!("{}print {};\n", pad, render_expr(expr).0)),
+        Stmt::Assign(name, expr) => out.push_str(&format!("{}{} = {};\n", pad, name, render_expr(expr).0)),
+        Stmt::If(condition, then_branch, else_branch) => {
+            out.push_str(&format!("{}if ({}) {{\n", pad, render_expr(condition).0));
+            render_block(then_branch, indent + 1, out);
+            if else_branch.is_empty() {
+                out.push_str(&format!("{}}}\n", pad));
+            } else {
+                out.push_str(&format!("{}}} else {{\n", pad));
+                render_block(else_branch, indent + 1, out);
+                out.push_str(&format!("{}}}\n", pad));
+            }
+        }
+        Stmt::For(name, count, body) => {
+            out.push_str(&format!("{}for (var {} = 0; {} < {}; {} = {} + 1) {{\n", pad, name, name, count, name, name));
+            render_block(body, indent + 1, out);
+            out.push_str(&format!("{}}}\n", pad));
+        }
+        Stmt::While(name, count, body) => {
+            let inner = "  ".repeat(indent + 1);
+            out.push_str(&format!("{}{{\n{}var {} = 0;\n", pad, inner, name));
+            out.push_str(&format!("{}while ({} < {}) {{\n", inner, name, count));
+            render_block(body, indent + 2, out);
+            out.push_str(&format!("{}  {} = {} + 1;\n{}}}\n{}}}\n", inner, name, name, inner, pad));
+        }
+    }
+}
+
+fn render_program(program: &Program) -> String {
+    let mut out = String::new();
+    for (name, init) in &program.globals {
+        out.push_str(&format!("var {} = {};\n", name, render_expr(init).0));
+    }
+    render_block(&program.body, 0, &mut out);
+    out
+}
+
+struct Generator {
+    rng: Rng,
+    // Visible variables, with whether statements may assign them. Loop
+    // counters are read but never assigned, so every loop ends.
+    scope: Vec<(String, Type, bool)>,
+    loops: usize,
+}
+
+impl Generator {
+    fn program(&mut self) -> Program {
+        let mut globals = Vec::new();
+        for (i, ty) in [Type::Number, Type::Number, Type::Str, Type::Bool].into_iter().enumerate() {
+            let init = self.expr(ty, 2);
+            let name = format!("{}{}", prefix(ty), i);
+            self.scope.push((name.clone(), ty, true));
+            globals.push((name, init));
+        }
+        let count = 3 + self.rng.below(6);
+        let body = self.block(count, 0);
+        Program { globals, body }
+    }
+
+    fn block(&mut self, count: u64, nesting: usize) -> Vec<Stmt> {
+        (0..count).map(|_| self.stmt(nesting)).collect()
+    }
+
+    fn stmt(&mut self, nesting: usize) -> Stmt {
+        let choice = if nesting < 2 { self.rng.below(10) } else { self.rng.below(6) };
+        match choice {
+            0..=3 => {
+                let ty = self.rng.pick(&[Type::Any, Type::Number, Type::Str, Type::Bool]);
+                Stmt::Print(self.expr(ty, 4))
+            }
+            4 | 5 => {
+                let targets: Vec<(String, Type)> =
+                    self.scope.iter().filter(|v| v.2).map(|v| (v.0.clone(), v.1)).collect();
+                let (name, ty) = targets[self.rng.below(targets.len() as u64) as usize].clone();
+                Stmt::Assign(name, self.expr(ty, 3))
+            }
+            6 | 7 => {
+                let ty = self.rng.pick(&[Type::Bool, Type::Any]);
+                let condition = self.expr(ty, 3);
+                let then_count = 1 + self.rng.below(3);
+                let then_branch = self.block(then_count, nesting + 1);
+                let else_count = self.rng.below(3);
+                let else_branch = self.block(else_count, nesting + 1);
+                Stmt::If(condition, then_branch, else_branch)
+            }
+            _ => {
+                let is_for = self.rng.below(2) == 0;
+                let name = format!("{}{}", if is_for { "i" } else { "w" }, self.loops);
+                self.loops += 1;
+                let count = self.rng.below(5);
+                self.scope.push((name.clone(), Type::Number, false));
+                let body_count = 1 + self.rng.below(3);
+                let body = self.block(body_count, nesting + 1);
+                self.scope.pop();
+                if is_for {
+                    Stmt::For(name, count, body)
+                } else {
+                    Stmt::While(name, count, body)
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, ty: Type, depth: u32) -> Expr {
+        if depth == 0 || self.rng.below(4) == 0 {
+            return self.leaf(ty);
+        }
+        let next = depth - 1;
+        match ty {
+            Type::Number => match self.rng.below(5) {
+                0 => Expr::Unary("-", Box::new(self.expr(Type::Number, next))),
+                _ => {
+                    let op = self.rng.pick(&["+", "-", "*", "/"]);
+                    self.binary(Type::Number, op, Type::Number, next)
+                }
+            },
+            Type::Str => self.binary(Type::Str, "+", Type::Str, next),
+            Type::Bool => match self.rng.below(4) {
+                0 => Expr::Unary("!", Box::new(self.expr(Type::Any, next))),
+                1 => {
+                    let op = self.rng.pick(&["==", "!="]);
+                    self.binary(Type::Any, op, Type::Any, next)
+                }
+                2 => {
+                    let op = self.rng.pick(&["<", "<=", ">", ">="]);
+                    self.binary(Type::Number, op, Type::Number, next)
+                }
+                _ => {
+                    let op = self.rng.pick(&["and", "or"]);
+                    self.binary(Type::Bool, op, Type::Bool, next)
+                }
+            },
+            Type::Any => match self.rng.below(5) {
+                0 => {
+                    // and/or yield one of their operands, not a boolean.
+                    let op = self.rng.pick(&["and", "or"]);
+                    self.binary(Type::Any, op, Type::Any, next)
+                }
+                _ => {
+                    let ty = self.rng.pick(&[Type::Number, Type::Str, Type::Bool]);
+                    self.expr(ty, depth)
+                }
+            },
+        }
+    }
+
+    fn binary(&mut self, left: Type, op: &'static str, right: Type, depth: u32) -> Expr {
+        let left = self.expr(left, depth);
+        let right = self.expr(right, depth);
+        Expr::Binary(Box::new(left), op, Box::new(right))
+    }
+
+    fn leaf(&mut self, ty: Type) -> Expr {
+        // Now and then put a value of the wrong type where an operand goes,
+        // so the operand checks and their messages get compared too.
+        let ty = if self.rng.below(30) == 0 { Type::Any } else { ty };
+        if self.rng.below(2) == 0 {
+            let names: Vec<String> =
+                self.scope.iter().filter(|v| ty == Type::Any || v.1 == ty).map(|v| v.0.clone()).collect();
+            if !names.is_empty() {
+                return Expr::Variable(names[self.rng.below(names.len() as u64) as usize].clone());
+            }
+        }
+        let value = match ty {
+            Type::Number => Value::Number(self.rng.pick(&[0.0, 1.0, 2.0, 3.0, 4.0, 7.0, 10.0, 0.5, 1.25, 0.1])),
+            Type::Str => Value::Str(self.rng.pick(&["", "a", "b", "lox", "1"]).to_string()),
+            Type::Bool => Value::Bool(self.rng.below(2) == 0),
+            Type::Any => match self.rng.below(4) {
+                0 => Value::Nil,
+                1 => return self.leaf(Type::Number),
+                2 => return self.leaf(Type::Str),
+                _ => return self.leaf(Type::Bool),
+            },
+        };
+        Expr::Literal(value)
+    }
+}
+
+fn prefix(ty: Type) -> &'static str {
+    match ty {
+        Type::Number => "n",
+        Type::Str => "s",
+        Type::Bool => "b",
+        Type::Any => "v",
+    }
+}
+
+// The reference interpreter: a direct walk over the tree with jlox's
+// semantics. Runtime errors carry the message clox and jlox both report.
+#[derive(Default)]
+struct Oracle {
+    globals: HashMap<String, Value>,
+    locals: Vec<(String, Value)>,
+    output: Vec<String>,
+}
+
+impl Oracle {
+    fn run(&mut self, program: &Program) -> Result<(), &'static str> {
+        for (name, init) in &program.globals {
+            let value = self.eval(init)?;
+            self.globals.insert(name.clone(), value);
+        }
+        self.block(&program.body)
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<(), &'static str> {
+        stmts.iter().try_for_each(|stmt| self.exec(stmt))
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<(), &'static str> {
+        match stmt {
+            Stmt::Print(expr) => {
+                let value = self.eval(expr)?;
+                self.output.push(value.render());
+            }
+            Stmt::Assign(name, expr) => {
+                let value = self.eval(expr)?;
+                self.globals.insert(name.clone(), value);
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                if self.eval(condition)?.is_truthy() {
+                    self.block(then_branch)?;
+                } else {
+                    self.block(else_branch)?;
+                }
+            }
+            Stmt::For(name, count, body) | Stmt::While(name, count, body) => {
+                for i in 0..*count {
+                    self.locals.push((name.clone(), Value::Number(i as f64)));
+                    let result = self.block(body);
+                    self.locals.pop();
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value, &'static str> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Variable(name) => {
+                let local = self.locals.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v);
+                Ok(local.or_else(|| self.globals.get(name)).cloned().expect("generated an undefined variable"))
+            }
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                match (*op, value) {
+                    ("!", value) => Ok(Value::Bool(!value.is_truthy())),
+                    (_, Value::Number(n)) => Ok(Value::Number(-n)),
+                    _ => Err("Operand must be a number."),
+                }
+            }
+            Expr::Binary(left, "and", right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    self.eval(right)
+                } else {
+                    Ok(left)
+                }
+            }
+            Expr::Binary(left, "or", right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    Ok(left)
+                } else {
+                    self.eval(right)
+                }
+            }
+            Expr::Binary(left, op, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                match (*op, left, right) {
+                    ("==", a, b) => Ok(Value::Bool(a == b)),
+                    ("!=", a, b) => Ok(Value::Bool(a != b)),
+                    ("+", Value::Str(a), Value::Str(b)) => Ok(Value::Str(a + &b)),
+                    ("+", Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                    ("+", _, _) => Err("Operands must be two numbers or two strings."),
+                    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+                    (op, Value::Number(a), Value::Number(b)) => Ok(match op {
+                        "-" => Value::Number(a - b),
+                        "*" => Value::Number(a * b),
+                        "/" => Value::Number(a / b),
+                        "<" => Value::Bool(a < b),
+                        // clox compiles a <= b as !(a > b), as the book does,
+                        // so the two disagree with jlox when one side is NaN.
+                        // Everything else about comparison should match.
+                        "<=" => Value::Bool(!(a > b)),
+                        ">" => Value::Bool(a > b),
+                        ">=" => Value::Bool(!(a < b)),
+                        _ => unreachable!("unknown operator {}", op),
+                    }),
+                    _ => Err("Operands must be numbers."),
+                }
+            }
+        }
+    }
+}
+
+// Runs one generated program through both interpreters, describing how they
+// differ if they do.
+fn check(seed: u64, engine: Engine) -> Result<(), String> {
+    let mut generator = Generator { rng: Rng::new(seed), scope: Vec::new(), loops: 0 };
+    let program = generator.program();
+    let source = render_program(&program);
+
+    let mut oracle = Oracle::default();
+    let expected = oracle.run(&program);
+    let expected_out: String = oracle.output.iter().map(|line| format!("{}\n", line)).collect();
+
+    let (result, stdout, stderr) = Vm::builder().engine(engine).build().interpret_captured(&source);
+    let matches = stdout == expected_out
+        && match expected {
+            Ok(()) => result == InterpretResult::Ok,
+            Err(message) => result == InterpretResult::RuntimeError && stderr.lines().any(|line| line == message),
+        };
+    if matches {
+        return Ok(());
+    }
+    Err(format!(
+        "seed {} diverged from the oracle with the {:?} engine.\n--- program\n{}--- expected ({:?})\n{}--- got ({:?})\n{}{}",
+        seed, engine, source, expected, expected_out, result, stdout, stderr
+    ))
+}
+
+fn check_all(engine: Engine) {
+    let seeds: Vec<u64> = match env::var("LOX_DIFF_SEED") {
+        Ok(seed) => vec![seed.parse().expect("LOX_DIFF_SEED must be a number")],
+        Err(_) => {
+            let cases = env::var("LOX_DIFF_CASES").ok().and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_CASES);
+            (0..cases).collect()
+        }
+    };
+    let failures: Vec<String> = seeds.into_iter().filter_map(|seed| check(seed, engine).err()).collect();
+    if let Some(first) = failures.first() {
+        panic!("{} of the generated programs diverged; the first:\n{}", failures.len(), first);
+    }
+}
+
+#[test]
+fn matches_reference_interpreter() {
+    check_all(Engine::Bytecode);
+}
+
+#[test]
+fn ast_engine_matches_reference_interpreter() {
+    check_all(Engine::Ast);
+}
@@ -0,0 +1,74 @@
+// Purpose: Builds the extension in tests/fixtures/extension.rs into a shared
+// library and checks loadExtension() against it: that the natives it
+// registers can be called across the C ABI, and the errors for a library
+// that can't be opened or has no entry point.
+
+#![cfg(all(feature = "extensions", unix))]
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+use rustlox::InterpretResult;
+use rustlox::Vm;
+
+// Compiles the fixture with `cfgs` into a shared library named `name`.
+fn build_fixture(name: &str, cfgs: &[&str]) -> PathBuf {
+    let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/extension.rs");
+    let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("lib{}.so", name));
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let mut command = Command::new(rustc);
+    command.args(["--edition", "2021", "--crate-type", "cdylib", "-o"]).arg(&output).arg(&source);
+    for cfg in cfgs {
+        command.args(["--cfg", cfg]);
+    }
+    let status = command.status().expect("could not run rustc");
+    assert!(status.success(), "could not build {}", source.display());
+    output
+}
+
+fn run(source: &str) -> (InterpretResult, String, String) {
+    Vm::builder().allow_extensions(true).build().interpret_captured(source)
+}
+
+#[test]
+fn calls_registered_natives() {
+    let library = build_fixture("lox_fixture", &[]);
+    let (result, stdout, stderr) = run(&format!(
+        "loadExtension(\"{}\");
+         print add(1, 2);
+         print add(0.5, -2);",
+        library.display()
+    ));
+    assert_eq!(stderr, "");
+    assert!(matches!(result, InterpretResult::Ok));
+    assert_eq!(stdout, "3\n-1.5\n");
+
+    // An extension function's error message is raised in the script.
+    let (result, _, stderr) = run(&format!("loadExtension(\"{}\"); add(\"1\", 2);", library.display()));
+    assert!(matches!(result, InterpretResult::RuntimeError));
+    assert!(stderr.starts_with("add() takes two numbers.\n"), "{}", stderr);
+}
+
+#[test]
+fn missing_entry_point() {
+    let library = build_fixture("lox_fixture_no_entry_point", &["no_entry_point"]);
+    let (result, _, stderr) = run(&format!("loadExtension(\"{}\");", library.display()));
+    assert!(matches!(result, InterpretResult::RuntimeError));
+    let expected = format!("'{}' does not export lox_extension_init.\n", library.display());
+    assert!(stderr.starts_with(&expected), "{}", stderr);
+}
+
+#[test]
+fn missing_library() {
+    let (result, _, stderr) = run("loadExtension(\"/no/such/library.so\");");
+    assert!(matches!(result, InterpretResult::RuntimeError));
+    assert!(stderr.starts_with("Could not load extension '/no/such/library.so': "), "{}", stderr);
+}
+
+#[test]
+fn needs_allow_extensions() {
+    let (result, _, stderr) = Vm::new().interpret_captured("loadExtension(\"/no/such/library.so\");");
+    assert!(matches!(result, InterpretResult::RuntimeError));
+    assert!(stderr.starts_with("Undefined variable 'loadExtension'."), "{}", stderr);
+}
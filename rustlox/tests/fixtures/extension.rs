@@ -0,0 +1,65 @@
+// Purpose: A small native extension that tests/extension.rs compiles into a
+// shared library with rustc and loads with loadExtension().
+//
+// It depends on nothing, not even rustlox, so it declares its own copies of
+// the #[repr(C)] types in src/extension.rs, the way an extension written in
+// another language would. Built with `--cfg no_entry_point`, it doesn't
+// export lox_extension_init, for checking the error loading such a library
+// gives.
+
+use std::ffi::c_char;
+use std::ffi::c_void;
+
+const ABI_VERSION: u32 = 1;
+
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+
+#[repr(C)]
+pub struct ExtensionValue {
+    tag: u8,
+    number: f64,
+    string: *const u8,
+    len: usize,
+}
+
+type ExtensionFn = extern "C" fn(args: *const ExtensionValue, arg_count: usize, result: *mut ExtensionValue) -> bool;
+
+#[repr(C)]
+pub struct ExtensionRegistrar {
+    abi_version: u32,
+    context: *mut c_void,
+    register: extern "C" fn(context: *mut c_void, name: *const c_char, function: ExtensionFn),
+}
+
+fn fail(result: &mut ExtensionValue, message: &'static str) -> bool {
+    result.tag = TAG_STRING;
+    result.string = message.as_ptr();
+    result.len = message.len();
+    false
+}
+
+// add(a, b) returns the sum of two numbers.
+extern "C" fn add(args: *const ExtensionValue, arg_count: usize, result: *mut ExtensionValue) -> bool {
+    let args = unsafe { std::slice::from_raw_parts(args, arg_count) };
+    let result = unsafe { &mut *result };
+    if args.len() != 2 || args.iter().any(|arg| arg.tag != TAG_NUMBER) {
+        return fail(result, "add() takes two numbers.");
+    }
+    result.tag = TAG_NUMBER;
+    result.number = args[0].number + args[1].number;
+    true
+}
+
+// Without #[no_mangle] the symbol gets a mangled name, so the library has
+// no lox_extension_init for loadExtension() to find.
+#[cfg_attr(not(no_entry_point), no_mangle)]
+pub extern "C" fn lox_extension_init(registrar: *const ExtensionRegistrar) -> bool {
+    let registrar = unsafe { &*registrar };
+    if registrar.abi_version != ABI_VERSION {
+        return false;
+    }
+    (registrar.register)(registrar.context, c"add".as_ptr(), add);
+    true
+}
+
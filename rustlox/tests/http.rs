@@ -0,0 +1,123 @@
+// Purpose: Checks httpGet and httpPost against a one-shot HTTP server on a
+// local port.
+//
+// Each server thread accepts a single connection, hands back the raw request
+// it read, and answers with a canned response, so the tests see both what
+// the natives send and what they make of the reply.
+
+#![cfg(feature = "http")]
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::thread::JoinHandle;
+
+use rustlox::InterpretResult;
+use rustlox::Vm;
+
+// Serves `response` to one client and returns the request it sent.
+fn serve_once(response: &'static str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            request.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+        request
+    });
+    (url, server)
+}
+
+fn run(source: &str) -> String {
+    let (result, stdout, stderr) = Vm::new().interpret_captured(source);
+    assert!(matches!(result, InterpretResult::Ok), "{}", stderr);
+    stdout
+}
+
+#[test]
+fn get_returns_status_headers_and_body() {
+    let (url, server) = serve_once("HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 7\r\n\r\nmissing");
+    let stdout = run(&format!(
+        "var response = httpGet(\"{}/thing?x=1\");
+         print response[\"status\"];
+         print response[\"headers\"][\"content-type\"];
+         print response[\"body\"];",
+        url
+    ));
+    assert_eq!(stdout, "404\ntext/plain\nmissing\n");
+    let request = server.join().unwrap();
+    assert!(request.starts_with("GET /thing?x=1 HTTP/1.1\r\n"), "{}", request);
+}
+
+#[test]
+fn post_sends_body_and_headers() {
+    let (url, server) = serve_once("HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n");
+    let stdout = run(&format!(
+        "var response = httpPost(\"{}/items\", \"name=lox\", {{\"X-Token\": \"secret\"}});
+         print response[\"status\"];
+         print response[\"body\"];",
+        url
+    ));
+    assert_eq!(stdout, "201\nabcde\n");
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /items HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("X-Token: secret\r\n"), "{}", request);
+    assert!(request.contains("Content-Length: 8\r\n"), "{}", request);
+    assert!(request.ends_with("\r\n\r\nname=lox"), "{}", request);
+}
+
+#[test]
+fn only_http_urls_are_supported() {
+    let (_, stdout, stderr) = Vm::new().interpret_captured("httpGet(\"https://example.com\");");
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("Only http:// URLs are supported."), "{}", stderr);
+}
+
+#[test]
+fn line_breaks_in_headers_and_urls_are_rejected() {
+    // Lox strings may span lines, so the line breaks go in as they are.
+    let (_, stdout, stderr) = Vm::new().interpret_captured(
+        "httpGet(\"http://127.0.0.1:1/\", {\"X-A\": \"1\r\nX-Injected: 2\"});");
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("Header names and values can't contain line breaks."), "{}", stderr);
+    let (_, _, stderr) = Vm::new().interpret_captured(
+        "httpGet(\"http://127.0.0.1:1/ HTTP/1.1\r\nX-Injected: 2\r\n\");");
+    assert!(stderr.contains("URL can't contain whitespace or control characters."), "{}", stderr);
+}
+
+#[test]
+fn bodies_larger_than_the_heap_limit_are_rejected() {
+    let (url, server) = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\nabc");
+    let mut vm = Vm::builder().heap_limit(1 << 20).build();
+    let (_, _, stderr) = vm.interpret_captured(&format!("httpGet(\"{}/\");", url));
+    assert!(stderr.contains("is too large."), "{}", stderr);
+    server.join().unwrap();
+}
+
+#[test]
+fn oversized_chunks_are_rejected() {
+    let (url, server) = serve_once("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\nffffffffffffffff\r\nde\r\n0\r\n\r\n");
+    let mut vm = Vm::builder().heap_limit(1 << 20).build();
+    let (_, _, stderr) = vm.interpret_captured(&format!("httpGet(\"{}/\");", url));
+    assert!(stderr.contains("is too large."), "{}", stderr);
+    server.join().unwrap();
+}
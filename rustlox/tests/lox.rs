@@ -0,0 +1,162 @@
+// Purpose: Runs every .lox file under tests/lox through the rustlox binary
+// and checks its output against the expectations written in its comments.
+//
+// The comments use the same format as the craftinginterpreters test suite,
+// so its files can be dropped in as they are:
+//
+//   print 1 + 2; // expect: 3
+//   -nil;        // expect runtime error: Operand must be a number.
+//   var = 1;     // Error at '=': Expect variable name.
+//   var x = 1;   // Warning at 'x': Local variable is never read.
+//   // [line 3] Error at end: Expect '}' after block.
+//   // [c line 3] ...   (only checked here; [java line N] is ignored)
+//   // flags: --no-io  (passed to rustlox before the file)
+//
+// A file expecting compile errors must exit with 65, one expecting a runtime
+// error with 70, and any other file with 0.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+struct Expectations {
+    flags: Vec<String>,
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    warnings: Vec<String>,
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Expectations {
+        let mut expectations = Expectations {
+            flags: Vec::new(),
+            output: Vec::new(),
+            compile_errors: Vec::new(),
+            warnings: Vec::new(),
+            runtime_error: None,
+        };
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let comment = match line.find("// ") {
+                Some(start) => &line[start + 3..],
+                None => continue,
+            };
+            if let Some(flags) = comment.strip_prefix("flags: ") {
+                expectations.flags.extend(flags.split_whitespace().map(str::to_string));
+            } else if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), line_number));
+            } else if comment.starts_with("Error") {
+                expectations.compile_errors.push(format!("[line {}] {}", line_number, comment));
+            } else if comment.starts_with("Warning") {
+                expectations.warnings.push(format!("[line {}] {}", line_number, comment));
+            } else if let Some(rest) = comment.strip_prefix('[') {
+                let rest = rest.strip_prefix("c ").unwrap_or(rest);
+                if rest.starts_with("line ") && rest.contains("] Error") {
+                    expectations.compile_errors.push(format!("[{}", rest));
+                } else if rest.starts_with("line ") && rest.contains("] Warning") {
+                    expectations.warnings.push(format!("[{}", rest));
+                }
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+// Runs one test file and returns a description of each way it failed.
+fn run(path: &Path) -> Vec<String> {
+    let source = fs::read_to_string(path).unwrap();
+    let expected = Expectations::parse(&source);
+    let result = Command::new(env!("CARGO_BIN_EXE_rustlox"))
+        .args(&expected.flags)
+        .arg(path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    let mut failures = Vec::new();
+
+    let output: Vec<&str> = stdout.lines().collect();
+    for (index, line) in output.iter().enumerate() {
+        match expected.output.get(index) {
+            Some(expected) if expected == line => {}
+            Some(expected) => failures.push(format!("Expected output '{}' and got '{}'.", expected, line)),
+            None => failures.push(format!("Got output '{}' when none was expected.", line)),
+        }
+    }
+    for missing in expected.output.iter().skip(output.len()) {
+        failures.push(format!("Missing expected output '{}'.", missing));
+    }
+
+    let (warnings, errors): (Vec<&str>, Vec<&str>) = stderr.lines()
+        .partition(|line| line.starts_with("[line ") && line.contains("] Warning"));
+    if warnings != expected.warnings {
+        failures.push(format!("Expected warnings {:?} and got {:?}.", expected.warnings, warnings));
+    }
+    if !expected.compile_errors.is_empty() {
+        if errors != expected.compile_errors {
+            failures.push(format!("Expected compile errors {:?} and got {:?}.", expected.compile_errors, errors));
+        }
+    } else if let Some((message, line)) = &expected.runtime_error {
+        let trace = format!("[line {}]", line);
+        if errors.first() != Some(&message.as_str()) {
+            failures.push(format!("Expected runtime error '{}' and got {:?}.", message, errors));
+        } else if !errors.get(1).is_some_and(|frame| frame.starts_with(&trace)) {
+            failures.push(format!("Expected runtime error on {} and got {:?}.", trace, errors));
+        }
+    } else if !errors.is_empty() {
+        failures.push(format!("Unexpected errors {:?}.", errors));
+    }
+
+    if result.status.code() != Some(expected.exit_code()) {
+        failures.push(format!("Expected exit code {} and got {:?}.", expected.exit_code(), result.status.code()));
+    }
+    failures
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn lox_test_suite() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let mut files = Vec::new();
+    collect(&root, &mut files);
+    files.sort();
+    assert!(!files.is_empty(), "No tests found in {}.", root.display());
+
+    let mut report = String::new();
+    let mut failed = 0;
+    for path in &files {
+        let failures = run(path);
+        if !failures.is_empty() {
+            failed += 1;
+            report.push_str(&format!("{}\n", path.strip_prefix(&root).unwrap().display()));
+            for failure in failures {
+                report.push_str(&format!("    {}\n", failure));
+            }
+        }
+    }
+    assert!(failed == 0, "{} of {} Lox tests failed:\n{}", failed, files.len(), report);
+}
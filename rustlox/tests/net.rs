@@ -0,0 +1,98 @@
+// Purpose: Checks the TCP socket natives over loopback, with Rust on the
+// other end of each connection.
+
+#![cfg(feature = "net")]
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use rustlox::InterpretResult;
+use rustlox::Vm;
+
+fn run(source: &str) -> String {
+    let (result, stdout, stderr) = Vm::new().interpret_captured(source);
+    assert!(matches!(result, InterpretResult::Ok), "{}", stderr);
+    stdout
+}
+
+#[test]
+fn connect_writes_and_reads() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        reader.get_mut().write_all(format!("echo: {}", line).as_bytes()).unwrap();
+    });
+    let stdout = run(&format!(
+        "var socket = tcpConnect(\"127.0.0.1\", {});
+         print tcpWrite(socket, \"hello\n\");
+         print tcpReadLine(socket);
+         print tcpRead(socket);
+         tcpClose(socket);",
+        port
+    ));
+    assert_eq!(stdout, "6\necho: hello\n\nnil\n");
+    server.join().unwrap();
+}
+
+#[test]
+fn listen_accepts_on_loopback() {
+    // A port that was free a moment ago.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        Vm::new().interpret_captured(&format!(
+            "var listener = tcpListen({});
+             var client = tcpAccept(listener);
+             tcpWrite(client, tcpReadLine(client));
+             tcpClose(client);
+             tcpClose(listener);",
+            port
+        ))
+    });
+    let stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    reader.get_mut().write_all(b"ping\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "ping\n");
+    let (result, _, stderr) = server.join().unwrap();
+    assert!(matches!(result, InterpretResult::Ok), "{}", stderr);
+}
+
+#[test]
+fn handles_must_be_non_negative_integers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    for handle in ["socket + 0.9", "-1", "\"1\""] {
+        let (result, _, stderr) = Vm::new().interpret_captured(&format!(
+            "var socket = tcpConnect(\"127.0.0.1\", {}); tcpWrite({}, \"x\");",
+            port, handle
+        ));
+        assert!(matches!(result, InterpretResult::RuntimeError));
+        assert!(stderr.contains("Socket handle must be a non-negative integer."), "{}", stderr);
+    }
+}
+
+#[test]
+fn closed_sockets_are_invalid() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (_, _, stderr) = Vm::new().interpret_captured(&format!(
+        "var socket = tcpConnect(\"127.0.0.1\", {}); tcpClose(socket); tcpClose(socket);",
+        port
+    ));
+    assert!(stderr.contains("Invalid socket handle."), "{}", stderr);
+}
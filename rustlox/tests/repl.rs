@@ -0,0 +1,74 @@
+// Purpose: Checks the REPL server's token check and input limits over
+// loopback, polling the server between reads on the client's side.
+
+#![cfg(feature = "net")]
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use rustlox::repl::ReplServer;
+use rustlox::Vm;
+
+fn server() -> ReplServer {
+    ReplServer::bind("127.0.0.1:0", Some("secret".to_string())).unwrap()
+}
+
+// Sends `input` and returns everything the server writes back before it
+// closes the connection.
+fn session(server: &mut ReplServer, vm: &mut Vm, input: &[u8]) -> String {
+    let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+    client.write_all(input).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut output = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        server.poll(vm);
+        match client.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => output.extend_from_slice(&chunk[..n]),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => panic!("{}", err),
+        }
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn wrong_token_is_rejected() {
+    let mut server = server();
+    let mut vm = Vm::new();
+    let output = session(&mut server, &mut vm, b"guess\nprint 1 + 2;\n");
+    assert_eq!(output, "Authentication failed.\n");
+}
+
+#[test]
+fn correct_token_starts_a_session() {
+    let mut server = server();
+    let mut vm = Vm::new();
+    let output = session(&mut server, &mut vm, b"secret\nprint 1 + 2;\nvar a = {\n");
+    assert_eq!(output, "> 3\n> ... ");
+}
+
+#[test]
+fn oversize_input_before_the_token_is_rejected() {
+    let mut server = server();
+    let mut vm = Vm::new();
+    let output = session(&mut server, &mut vm, &[b'a'; 2000]);
+    assert_eq!(output, "Authentication failed.\n");
+}
+
+#[test]
+fn oversize_lines_after_the_token_are_rejected() {
+    let mut server = server();
+    let mut vm = Vm::new();
+    let mut input = b"secret\nprint \"".to_vec();
+    input.extend_from_slice(&[b'a'; 70_000]);
+    input.extend_from_slice(b"\";\n");
+    let output = session(&mut server, &mut vm, &input);
+    assert_eq!(output, "> Line too long.\n");
+}